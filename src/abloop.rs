@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::Source;
+
+// How far behind what's been decoded point A can be marked and still be
+// kept, which covers what's waiting to be played out
+const HISTORY: Duration = Duration::from_secs(2);
+
+// The longest loop kept in memory; a longer one goes back to A by playing
+// the track again from the file
+const MAX_KEPT: Duration = Duration::from_secs(5 * 60);
+
+// For a point that isn't marked
+const UNSET: u64 = u64::MAX;
+
+/// The A-B loop marked in the track playing, set by the player and read by
+/// the audio thread, which keeps what's played from A on in memory and goes
+/// back to it by itself at B.
+pub struct Points {
+    /// In microseconds into the track.
+    start_us: AtomicU64,
+    end_us: AtomicU64,
+    /// Whether the audio thread has all of the loop from A on, so it'll go
+    /// back to A without being started again there.
+    kept: AtomicBool,
+}
+
+impl Default for Points {
+    fn default() -> Points {
+        Points { start_us: AtomicU64::new(UNSET), end_us: AtomicU64::new(UNSET), kept: AtomicBool::new(false) }
+    }
+}
+
+impl Points {
+    pub fn set(&self, start: Option<Duration>, end: Option<Duration>) {
+        let micros = |at: Option<Duration>| at.map_or(UNSET, |at| at.as_micros() as u64);
+        self.start_us.store(micros(start), Ordering::SeqCst);
+        self.end_us.store(micros(end), Ordering::SeqCst);
+    }
+
+    /// Whether reaching B goes back to A by itself. When it doesn't, the
+    /// track has to be started again from A.
+    pub fn kept(&self) -> bool {
+        self.kept.load(Ordering::SeqCst)
+    }
+}
+
+/// How many samples of `source` come before the point `at` into it, a whole
+/// number of frames.
+pub fn samples_at(at: Duration, source: &(impl Source<Item = i16> + ?Sized)) -> u64 {
+    (at.as_secs_f64() * source.sample_rate() as f64) as u64 * source.channels() as u64
+}
+
+/// A decoder that keeps what it gives from point A of the loop on, and once
+/// it reaches point B gives that again, over and over, rather than the
+/// track having to be decoded from the start up to A every time round.
+pub struct Replay<S> {
+    source: S,
+    points: Arc<Points>,
+    /// The points as they were last looked at, in microseconds and in
+    /// samples into the track.
+    seen_us: (u64, u64),
+    seen: (Option<u64>, Option<u64>),
+    /// Samples into the track the source has got to.
+    position: u64,
+    /// The last of what the source gave, for a point A marked just behind
+    /// `position`.
+    history: VecDeque<i16>,
+    history_len: usize,
+    /// What's been given from `kept_from` on, with what the source gives
+    /// added to it while `keeping`.
+    kept_from: u64,
+    kept: Vec<i16>,
+    keeping: bool,
+    max_kept: usize,
+    /// Where in `kept` it's giving samples from, after going back to A.
+    replaying: Option<usize>,
+}
+
+impl<S: Source<Item = i16>> Replay<S> {
+    /// Keep the loop marked in `points` from `source`, which has already
+    /// been read `position` samples into the track.
+    pub fn new(source: S, position: u64, points: Arc<Points>) -> Replay<S> {
+        points.kept.store(false, Ordering::SeqCst);
+        let history_len = samples_at(HISTORY, &source) as usize;
+        let max_kept = samples_at(MAX_KEPT, &source) as usize;
+        Replay {
+            source,
+            points,
+            seen_us: (UNSET, UNSET),
+            seen: (None, None),
+            position,
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            kept_from: 0,
+            kept: Vec::new(),
+            keeping: false,
+            max_kept,
+            replaying: None,
+        }
+    }
+
+    /// Catch up with the points being marked or cleared.
+    fn notice(&mut self) {
+        let seen_us = (self.points.start_us.load(Ordering::SeqCst), self.points.end_us.load(Ordering::SeqCst));
+        if seen_us == self.seen_us {
+            return;
+        }
+        let samples = |us: u64| (us != UNSET).then(|| samples_at(Duration::from_micros(us), &self.source));
+        let seen = (samples(seen_us.0), samples(seen_us.1));
+        self.seen_us = seen_us;
+        if seen.0 != self.seen.0 {
+            self.keep_from(seen.0);
+        }
+        self.seen = seen;
+        self.publish();
+    }
+
+    /// Start keeping what's given from `start` on, or stop keeping anything.
+    /// Whatever's being given again carries on to the end of what's kept, so
+    /// clearing the loop carries on from where it's got to.
+    fn keep_from(&mut self, start: Option<u64>) {
+        let at = self.replaying.map_or(self.position, |index| self.kept_from + index as u64);
+        let was_kept = self.keeping || self.replaying.is_some();
+        self.keeping = false;
+        match start {
+            // Marked again within what's kept, so the start of it's dropped
+            Some(start) if was_kept && (self.kept_from..=at).contains(&start) => {
+                let dropped = (start - self.kept_from) as usize;
+                self.kept.drain(..dropped);
+                self.replaying = self.replaying.map(|index| index - dropped);
+                self.kept_from = start;
+                self.keeping = self.kept.len() as u64 == self.position - start;
+            }
+            _ if self.replaying.is_some() => {}
+            Some(start) if start <= self.position && self.position - start <= self.history.len() as u64 => {
+                let behind = (self.position - start) as usize;
+                self.kept.clear();
+                self.kept.extend(self.history.range(self.history.len() - behind..));
+                self.kept_from = start;
+                self.keeping = true;
+            }
+            // Cleared, or ahead, to be kept from once it's reached, or too
+            // far behind
+            _ => self.kept.clear(),
+        }
+    }
+
+    /// How far into `kept` point B is, when it's all there from A on.
+    fn loop_len(&self) -> Option<usize> {
+        match self.seen {
+            (Some(start), Some(end)) if self.keeping && self.kept_from == start && end > start => Some((end - start) as usize),
+            _ => None,
+        }
+    }
+
+    fn publish(&self) {
+        let kept = self.keeping && Some(self.kept_from) == self.seen.0;
+        self.points.kept.store(kept, Ordering::SeqCst);
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Replay<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        self.notice();
+        if let Some(index) = self.replaying {
+            let index = match self.loop_len() {
+                Some(len) if index >= len => 0,
+                _ => index,
+            };
+            if let Some(&sample) = self.kept.get(index) {
+                self.replaying = Some(index + 1);
+                return Some(sample);
+            }
+            self.replaying = None;
+            if !self.keeping {
+                self.kept.clear();
+            }
+        }
+        if self.loop_len().is_some_and(|len| self.position >= self.kept_from + len as u64) {
+            self.replaying = Some(1);
+            return self.kept.first().copied();
+        }
+        if !self.keeping && self.seen.0 == Some(self.position) {
+            self.kept.clear();
+            self.kept_from = self.position;
+            self.keeping = true;
+            self.publish();
+        }
+
+        let sample = self.source.next()?;
+        self.position += 1;
+        if self.keeping {
+            if self.kept.len() < self.max_kept {
+                self.kept.push(sample);
+            } else {
+                self.keeping = false;
+                self.kept = Vec::new();
+                self.publish();
+            }
+        }
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(sample);
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Replay<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
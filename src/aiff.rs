@@ -0,0 +1,177 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use rodio::Source;
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    BigEndian,
+    LittleEndian,
+    Float,
+}
+
+/// Decoder for uncompressed AIFF and AIFF-C files. Samples are big-endian
+/// PCM in plain AIFF; AIFF-C adds a compression type, of which only the
+/// uncompressed variants (`NONE`, `twos`, `sowt`, `fl32`) are supported.
+pub struct AiffDecoder<R> {
+    reader: R,
+    channels: u16,
+    sample_rate: u32,
+    total_frames: u32,
+    bytes_per_sample: usize,
+    encoding: Encoding,
+    remaining: u64,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Convert an 80-bit IEEE 754 extended float, as used for the COMM sample rate.
+pub fn extended_to_f64(bytes: [u8; 10]) -> f64 {
+    let exponent = (((bytes[0] & 0x7F) as i32) << 8) | bytes[1] as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if bytes[0] & 0x80 != 0 {
+        -value
+    } else {
+        value
+    }
+}
+
+impl<R: Read + Seek> AiffDecoder<R> {
+    pub fn new(mut reader: R) -> io::Result<AiffDecoder<R>> {
+        let mut form = [0u8; 12];
+        reader.read_exact(&mut form)?;
+        let compressed = match (&form[..4], &form[8..]) {
+            (b"FORM", b"AIFF") => false,
+            (b"FORM", b"AIFC") => true,
+            _ => return Err(invalid("not an AIFF file".to_string())),
+        };
+
+        let mut comm: Option<(u16, u32, u16, u32, Encoding)> = None;
+        let mut chunk = [0u8; 8];
+        loop {
+            if reader.read_exact(&mut chunk).is_err() {
+                return Err(invalid("AIFF file has no sound data".to_string()));
+            }
+            let len = u32::from_be_bytes(chunk[4..].try_into().unwrap()) as u64;
+            let padded = len + (len & 1);
+            match &chunk[..4] {
+                b"COMM" => {
+                    let mut body = vec![0u8; len as usize];
+                    reader.read_exact(&mut body)?;
+                    if body.len() < 18 {
+                        return Err(invalid("truncated COMM chunk".to_string()));
+                    }
+                    let channels = u16::from_be_bytes([body[0], body[1]]);
+                    let frames = u32::from_be_bytes(body[2..6].try_into().unwrap());
+                    let bits = u16::from_be_bytes([body[6], body[7]]);
+                    let rate = extended_to_f64(body[8..18].try_into().unwrap()).round() as u32;
+                    let encoding = if compressed {
+                        let kind = body.get(18..22).unwrap_or(b"NONE");
+                        match kind {
+                            b"NONE" | b"twos" => Encoding::BigEndian,
+                            b"sowt" => Encoding::LittleEndian,
+                            b"fl32" | b"FL32" => Encoding::Float,
+                            other => {
+                                return Err(invalid(format!(
+                                    "unsupported AIFF-C compression: {}",
+                                    String::from_utf8_lossy(other)
+                                )))
+                            }
+                        }
+                    } else {
+                        Encoding::BigEndian
+                    };
+                    reader.seek(SeekFrom::Current((padded - len) as i64))?;
+                    comm = Some((channels, frames, bits, rate, encoding));
+                }
+                b"SSND" => {
+                    let Some((channels, total_frames, bits, sample_rate, encoding)) = comm else {
+                        return Err(invalid("SSND chunk before COMM chunk".to_string()));
+                    };
+                    let bytes_per_sample = match (encoding, bits) {
+                        (Encoding::Float, 32) => 4,
+                        (Encoding::Float, _) => return Err(invalid(format!("unsupported {}-bit float AIFF", bits))),
+                        (_, 1..=32) => bits.div_ceil(8) as usize,
+                        _ => return Err(invalid(format!("unsupported AIFF sample size: {} bits", bits))),
+                    };
+                    if channels == 0 || sample_rate == 0 {
+                        return Err(invalid("AIFF file has no channels or sample rate".to_string()));
+                    }
+                    let mut ssnd = [0u8; 8];
+                    reader.read_exact(&mut ssnd)?;
+                    let offset = u32::from_be_bytes(ssnd[..4].try_into().unwrap()) as u64;
+                    reader.seek(SeekFrom::Current(offset as i64))?;
+                    let data_len = (total_frames as u64 * channels as u64 * bytes_per_sample as u64)
+                        .min(len.saturating_sub(8 + offset));
+                    return Ok(AiffDecoder {
+                        reader,
+                        channels,
+                        sample_rate,
+                        total_frames,
+                        bytes_per_sample,
+                        encoding,
+                        remaining: data_len,
+                    });
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current(padded as i64))?;
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for AiffDecoder<R> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.remaining < self.bytes_per_sample as u64 {
+            return None;
+        }
+        let mut buf = [0u8; 4];
+        let bytes = &mut buf[..self.bytes_per_sample];
+        self.reader.read_exact(bytes).ok()?;
+        self.remaining -= self.bytes_per_sample as u64;
+
+        let sample = match self.encoding {
+            Encoding::Float => {
+                let value = f32::from_be_bytes(buf);
+                (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+            }
+            Encoding::BigEndian | Encoding::LittleEndian => {
+                if let Encoding::LittleEndian = self.encoding {
+                    bytes.reverse();
+                }
+                // Left-justify the big-endian bytes into an i32, keeping the sign
+                let mut word = [0u8; 4];
+                word[..bytes.len()].copy_from_slice(bytes);
+                (i32::from_be_bytes(word) >> 16) as i16
+            }
+        };
+        Some(sample)
+    }
+}
+
+impl<R: Read> Source for AiffDecoder<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(self.total_frames as f64 / self.sample_rate as f64))
+    }
+}
@@ -0,0 +1,694 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::probe::{find_mp4_atom, mp4_children};
+use crate::reader::Failure;
+
+// Adaptive Golomb coding constants from Apple's reference decoder
+const QBSHIFT: u32 = 9;
+const QB: u32 = 1 << QBSHIFT;
+const MMULSHIFT: u32 = 2;
+const MDENSHIFT: u32 = QBSHIFT - MMULSHIFT - 1;
+const MOFF: u32 = 1 << (MDENSHIFT - 2);
+const BITOFF: u32 = 24;
+const MAX_PREFIX_16: u32 = 9;
+const MAX_PREFIX_32: u32 = 9;
+const MAX_DATATYPE_BITS_16: u32 = 16;
+const N_MAX_MEAN_CLAMP: u32 = 0xFFFF;
+const N_MEAN_CLAMP_VAL: u32 = 0xFFFF;
+
+// Syntax element tags
+const ID_SCE: u32 = 0;
+const ID_CPE: u32 = 1;
+const ID_CCE: u32 = 2;
+const ID_LFE: u32 = 3;
+const ID_DSE: u32 = 4;
+const ID_PCE: u32 = 5;
+const ID_FIL: u32 = 6;
+const ID_END: u32 = 7;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("ALAC: {}", message))
+}
+
+/// The `ALACSpecificConfig` "magic cookie" stored in the sample description.
+#[derive(Clone, Copy)]
+struct Config {
+    frame_length: u32,
+    bit_depth: u32,
+    pb: u32,
+    mb: u32,
+    kb: u32,
+    channels: u32,
+    max_run: u32,
+    sample_rate: u32,
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits_left(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.pos)
+    }
+
+    /// Peek 32 bits starting at an arbitrary bit offset, zero-padded past the end.
+    fn peek32_at(&self, pos: usize) -> u32 {
+        let byte = pos / 8;
+        let mut word = [0u8; 5];
+        for (i, b) in word.iter_mut().enumerate() {
+            *b = self.data.get(byte + i).copied().unwrap_or(0);
+        }
+        let wide = u64::from_be_bytes([0, 0, 0, word[0], word[1], word[2], word[3], word[4]]);
+        ((wide << (pos % 8)) >> 8) as u32
+    }
+
+    fn read_at(&self, pos: usize, bits: u32) -> u32 {
+        match bits {
+            0 => 0,
+            // Only the low 32 bits of wider values can be kept
+            33.. => self.peek32_at(pos + (bits - 32) as usize),
+            _ => self.peek32_at(pos) >> (32 - bits),
+        }
+    }
+
+    fn read(&mut self, bits: u32) -> io::Result<u32> {
+        if self.bits_left() < bits as usize {
+            return Err(invalid("unexpected end of packet"));
+        }
+        let value = self.read_at(self.pos, bits);
+        self.pos += bits as usize;
+        Ok(value)
+    }
+
+    fn advance(&mut self, bits: usize) {
+        self.pos += bits;
+    }
+
+    fn byte_align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+fn sign_of(value: i32) -> i32 {
+    value.signum()
+}
+
+/// Leading zero count of a 32-bit value, `lead` in the reference code.
+fn lead(value: u32) -> u32 {
+    value.leading_zeros()
+}
+
+fn lg3a(value: u32) -> u32 {
+    31 - lead(value + 3)
+}
+
+/// Decode one adaptive Golomb value, used for residuals.
+fn dyn_get_32bit(bits: &BitReader, pos: &mut usize, m: u32, k: u32, max_bits: u32) -> u32 {
+    let stream = bits.peek32_at(*pos);
+    let prefix = lead(!stream);
+    if prefix >= MAX_PREFIX_32 {
+        let value = bits.read_at(*pos + MAX_PREFIX_32 as usize, max_bits);
+        *pos += (MAX_PREFIX_32 + max_bits) as usize;
+        return value;
+    }
+    let v = (((stream as u64) << (prefix + 1)) as u32).checked_shr(32 - k).unwrap_or(0);
+    *pos += (prefix + 1 + k) as usize;
+    let mut result = prefix * m;
+    if v >= 2 {
+        result += v - 1;
+    } else {
+        *pos -= 1;
+    }
+    result
+}
+
+/// Decode one adaptive Golomb value limited to 16 bits, used for zero runs.
+fn dyn_get(bits: &BitReader, pos: &mut usize, m: u32, k: u32) -> u32 {
+    let stream = bits.peek32_at(*pos);
+    let prefix = lead(!stream);
+    if prefix >= MAX_PREFIX_16 {
+        let value = bits.read_at(*pos + MAX_PREFIX_16 as usize, MAX_DATATYPE_BITS_16);
+        *pos += (MAX_PREFIX_16 + MAX_DATATYPE_BITS_16) as usize;
+        return value;
+    }
+    let v = (((stream as u64) << (prefix + 1)) as u32).checked_shr(32 - k).unwrap_or(0);
+    *pos += (prefix + 1 + k) as usize;
+    if v < 2 {
+        *pos -= 1;
+        prefix * m
+    } else {
+        prefix * m + v - 1
+    }
+}
+
+/// Entropy-decode `out.len()` prediction residuals.
+fn dyn_decomp(bits: &mut BitReader, config: &Config, pb: u32, out: &mut [i32], max_size: u32) -> io::Result<()> {
+    let num_samples = out.len();
+    let wb = (1u32 << config.kb) - 1;
+    let max_pos = bits.data.len() * 8;
+    let mut pos = bits.pos;
+    let mut mb = config.mb;
+    let mut zmode = 0u32;
+    let mut c = 0usize;
+
+    while c < num_samples {
+        if pos >= max_pos {
+            return Err(invalid("residuals run past the end of the packet"));
+        }
+        let k = lg3a(mb >> QBSHIFT).min(config.kb);
+        let m = (1u32 << k) - 1;
+        let n = dyn_get_32bit(bits, &mut pos, m, k, max_size);
+
+        // The least significant bit is the sign
+        let decoded = n.wrapping_add(zmode);
+        let magnitude = (decoded.wrapping_add(1) >> 1) as i32;
+        out[c] = if decoded & 1 != 0 { -magnitude } else { magnitude };
+        c += 1;
+
+        mb = pb
+            .wrapping_mul(n.wrapping_add(zmode))
+            .wrapping_add(mb)
+            .wrapping_sub(pb.wrapping_mul(mb) >> QBSHIFT);
+        if n > N_MAX_MEAN_CLAMP {
+            mb = N_MEAN_CLAMP_VAL;
+        }
+
+        zmode = 0;
+        if (mb << MMULSHIFT) < QB && c < num_samples {
+            zmode = 1;
+            let k = lead(mb) - BITOFF + ((mb + MOFF) >> MDENSHIFT);
+            let mz = ((1u32 << k) - 1) & wb;
+            let run = dyn_get(bits, &mut pos, mz, k) as usize;
+            if c + run > num_samples {
+                return Err(invalid("zero run past the end of the frame"));
+            }
+            out[c..c + run].fill(0);
+            c += run;
+            if run >= 65535 {
+                zmode = 0;
+            }
+            mb = 0;
+        }
+    }
+
+    bits.pos = pos;
+    Ok(())
+}
+
+/// Undo the adaptive linear prediction. `coefs` are updated as we go, just
+/// like on the encoder side.
+fn unpc_block(pc: &[i32], out: &mut [i32], coefs: &mut [i16], num_active: usize, chan_bits: u32, den_shift: u32) {
+    let num = pc.len();
+    if num == 0 {
+        return;
+    }
+    let chan_shift = 32 - chan_bits;
+    let wrap = |value: i32| (value << chan_shift) >> chan_shift;
+    let den_half = if den_shift > 0 { 1i32 << (den_shift - 1) } else { 0 };
+
+    out[0] = pc[0];
+    if num_active == 0 {
+        out[1..num].copy_from_slice(&pc[1..num]);
+        return;
+    }
+    if num_active == 31 {
+        let mut prev = out[0];
+        for j in 1..num {
+            prev = wrap(pc[j].wrapping_add(prev));
+            out[j] = prev;
+        }
+        return;
+    }
+
+    for j in 1..=num_active.min(num - 1) {
+        out[j] = wrap(pc[j].wrapping_add(out[j - 1]));
+    }
+
+    let lim = num_active + 1;
+    for j in lim..num {
+        let top = out[j - lim];
+        let mut sum = 0i32;
+        for (k, &coef) in coefs.iter().enumerate().take(num_active) {
+            sum = sum.wrapping_add((coef as i32).wrapping_mul(out[j - 1 - k].wrapping_sub(top)));
+        }
+
+        let mut del = pc[j];
+        let mut del0 = del;
+        let sg = sign_of(del);
+        del = del.wrapping_add(top).wrapping_add(sum.wrapping_add(den_half) >> den_shift);
+        out[j] = wrap(del);
+
+        if sg > 0 {
+            for k in (0..num_active).rev() {
+                let dd = top.wrapping_sub(out[j - 1 - k]);
+                let sgn = sign_of(dd);
+                coefs[k] = coefs[k].wrapping_sub(sgn as i16);
+                del0 = del0.wrapping_sub((num_active - k) as i32 * (sgn.wrapping_mul(dd) >> den_shift));
+                if del0 <= 0 {
+                    break;
+                }
+            }
+        } else if sg < 0 {
+            for k in (0..num_active).rev() {
+                let dd = top.wrapping_sub(out[j - 1 - k]);
+                let sgn = sign_of(dd);
+                coefs[k] = coefs[k].wrapping_add(sgn as i16);
+                del0 = del0.wrapping_sub((num_active - k) as i32 * ((-sgn).wrapping_mul(dd) >> den_shift));
+                if del0 >= 0 {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+struct ChannelParams {
+    mode: u32,
+    den_shift: u32,
+    pb_factor: u32,
+    coefs: [i16; 32],
+    num_coefs: usize,
+}
+
+fn read_channel_params(bits: &mut BitReader) -> io::Result<ChannelParams> {
+    let header = bits.read(8)?;
+    let mode = header >> 4;
+    let den_shift = header & 0x0F;
+    let header = bits.read(8)?;
+    let pb_factor = header >> 5;
+    let num_coefs = (header & 0x1F) as usize;
+    let mut coefs = [0i16; 32];
+    for coef in coefs.iter_mut().take(num_coefs) {
+        *coef = bits.read(16)? as u16 as i16;
+    }
+    Ok(ChannelParams {
+        mode,
+        den_shift,
+        pb_factor,
+        coefs,
+        num_coefs,
+    })
+}
+
+/// Residual decode plus prediction for one channel of a compressed element.
+fn decode_channel(bits: &mut BitReader, config: &Config, params: &mut ChannelParams, out: &mut [i32], chan_bits: u32) -> io::Result<()> {
+    let mut predictor = vec![0i32; out.len()];
+    dyn_decomp(bits, config, (config.pb * params.pb_factor) / 4, &mut predictor, chan_bits)?;
+    if params.mode == 0 {
+        unpc_block(&predictor, out, &mut params.coefs, params.num_coefs, chan_bits, params.den_shift);
+    } else {
+        // Mode 1 runs a first-order predictor before the regular one
+        let mut first_pass = vec![0i32; out.len()];
+        unpc_block(&predictor, &mut first_pass, &mut [], 31, chan_bits, 0);
+        unpc_block(&first_pass, out, &mut params.coefs, params.num_coefs, chan_bits, params.den_shift);
+    }
+    Ok(())
+}
+
+/// Decode one ALAC packet into interleaved samples at the stream's bit depth.
+fn decode_packet(data: &[u8], config: &Config, output: &mut Vec<i32>) -> io::Result<()> {
+    let mut bits = BitReader { data, pos: 0 };
+    let channels = config.channels as usize;
+    let mut channel_index = 0usize;
+    let mut frame_samples: Option<usize> = None;
+
+    while bits.bits_left() >= 3 {
+        let tag = bits.read(3)?;
+        match tag {
+            ID_SCE | ID_LFE | ID_CPE => {
+                let pair = tag == ID_CPE;
+                let element_channels = if pair { 2 } else { 1 };
+                if channel_index + element_channels > channels {
+                    return Err(invalid("more channels than the stream declares"));
+                }
+                let _element_instance = bits.read(4)?;
+                if bits.read(12)? != 0 {
+                    return Err(invalid("unexpected header bits"));
+                }
+                let header = bits.read(4)?;
+                let partial_frame = header >> 3;
+                let mut bytes_shifted = (header >> 1) & 0x3;
+                let escape = header & 1 != 0;
+                if bytes_shifted == 3 {
+                    return Err(invalid("invalid shift"));
+                }
+
+                let mut num_samples = config.frame_length as usize;
+                if partial_frame != 0 {
+                    num_samples = ((bits.read(16)? << 16) | bits.read(16)?) as usize;
+                }
+                if num_samples > config.frame_length as usize {
+                    return Err(invalid("frame longer than the configured frame length"));
+                }
+                if *frame_samples.get_or_insert(num_samples) != num_samples {
+                    return Err(invalid("channels with different frame lengths"));
+                }
+                if output.len() < num_samples * channels {
+                    output.resize(num_samples * channels, 0);
+                }
+
+                let mut u = vec![0i32; num_samples];
+                let mut v = vec![0i32; if pair { num_samples } else { 0 }];
+                let mut mix_bits = 0u32;
+                let mut mix_res = 0i32;
+                let mut shift_start = None;
+
+                if !escape {
+                    let chan_bits = config.bit_depth - bytes_shifted * 8 + if pair { 1 } else { 0 };
+                    if chan_bits > 32 {
+                        return Err(invalid("unsupported sample width"));
+                    }
+                    mix_bits = bits.read(8)?;
+                    mix_res = bits.read(8)? as u8 as i8 as i32;
+                    let mut params_u = read_channel_params(&mut bits)?;
+                    let mut params_v = if pair { Some(read_channel_params(&mut bits)?) } else { None };
+
+                    // The low-order "shifted" bytes come before the residuals; read them later
+                    if bytes_shifted != 0 {
+                        shift_start = Some(bits.pos);
+                        bits.advance((bytes_shifted * 8) as usize * element_channels * num_samples);
+                    }
+
+                    decode_channel(&mut bits, config, &mut params_u, &mut u, chan_bits)?;
+                    if let Some(params_v) = params_v.as_mut() {
+                        decode_channel(&mut bits, config, params_v, &mut v, chan_bits)?;
+                    }
+                } else {
+                    // Uncompressed frame: samples stored verbatim, interleaved for pairs
+                    let chan_bits = config.bit_depth;
+                    for i in 0..num_samples {
+                        u[i] = sign_extend(bits.read(chan_bits)?, chan_bits);
+                        if pair {
+                            v[i] = sign_extend(bits.read(chan_bits)?, chan_bits);
+                        }
+                    }
+                    bytes_shifted = 0;
+                }
+
+                let shift = bytes_shifted * 8;
+                let mut shift_bits = shift_start.map(|pos| BitReader { data, pos });
+
+                for i in 0..num_samples {
+                    let (mut left, mut right) = if pair && mix_res != 0 {
+                        let l = u[i]
+                            .wrapping_add(v[i])
+                            .wrapping_sub(mix_res.wrapping_mul(v[i]) >> mix_bits);
+                        (l, l.wrapping_sub(v[i]))
+                    } else {
+                        (u[i], if pair { v[i] } else { 0 })
+                    };
+                    if let Some(shift_bits) = shift_bits.as_mut() {
+                        left = (left << shift) | shift_bits.read(shift)? as i32;
+                        if pair {
+                            right = (right << shift) | shift_bits.read(shift)? as i32;
+                        }
+                    }
+                    output[i * channels + channel_index] = left;
+                    if pair {
+                        output[i * channels + channel_index + 1] = right;
+                    }
+                }
+                channel_index += element_channels;
+            }
+            ID_DSE => {
+                let _element_instance = bits.read(4)?;
+                let align = bits.read(1)? != 0;
+                let mut count = bits.read(8)?;
+                if count == 255 {
+                    count += bits.read(8)?;
+                }
+                if align {
+                    bits.byte_align();
+                }
+                bits.advance(count as usize * 8);
+            }
+            ID_FIL => {
+                let mut count = bits.read(4)?;
+                if count == 15 {
+                    count += bits.read(8)? - 1;
+                }
+                bits.advance(count as usize * 8);
+            }
+            ID_END => break,
+            ID_CCE | ID_PCE => return Err(invalid("unsupported syntax element")),
+            _ => unreachable!(),
+        }
+        if channel_index >= channels {
+            break;
+        }
+    }
+
+    output.truncate(frame_samples.unwrap_or(0) * channels);
+    Ok(())
+}
+
+/// Decoder for Apple Lossless audio in an MP4 (`.m4a`) container.
+pub struct AlacDecoder<R> {
+    reader: R,
+    config: Config,
+    // File offset and size of every packet, in playback order
+    packets: Vec<(u64, u32)>,
+    next_packet: usize,
+    total_frames: u64,
+    buffer: Vec<i32>,
+    buffer_pos: usize,
+    // Bits to drop to bring samples down to 16 bits
+    down_shift: u32,
+    failure: Failure,
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_atom<R: Read + Seek>(reader: &mut R, (start, end): (u64, u64)) -> io::Result<Vec<u8>> {
+    let mut body = vec![0u8; (end - start) as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+impl<R: Read + Seek> AlacDecoder<R> {
+    /// Open the ALAC track in the MP4 file `reader` reads, noting in
+    /// `failure` any packet that can't be decoded as it plays.
+    pub fn new(mut reader: R, failure: Failure) -> io::Result<AlacDecoder<R>> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let (moov_start, moov_end) =
+            find_mp4_atom(&mut reader, 0, len, &[b"moov"])?.ok_or_else(|| invalid("no moov atom"))?;
+
+        // Find the track whose sample description is ALAC
+        for (kind, trak_start, trak_end) in mp4_children(&mut reader, moov_start, moov_end)? {
+            if &kind != b"trak" {
+                continue;
+            }
+            let find = |reader: &mut R, path: &[&[u8; 4]]| find_mp4_atom(reader, trak_start, trak_end, path);
+            let Some(stbl) = find(&mut reader, &[b"mdia", b"minf", b"stbl"])? else {
+                continue;
+            };
+            let Some(stsd) = find_mp4_atom(&mut reader, stbl.0, stbl.1, &[b"stsd"])? else {
+                continue;
+            };
+
+            // Sample entry: skip version/flags and entry count
+            let entries = mp4_children(&mut reader, stsd.0 + 8, stsd.1)?;
+            let Some(&(entry_kind, entry_start, entry_end)) = entries.first() else {
+                continue;
+            };
+            if &entry_kind != b"alac" {
+                continue;
+            }
+            // The audio sample entry fields take 28 bytes, followed by the `alac` cookie atom
+            let cookie = mp4_children(&mut reader, entry_start + 28, entry_end)?
+                .into_iter()
+                .find(|(k, _, _)| k == b"alac")
+                .ok_or_else(|| invalid("missing ALAC configuration"))?;
+            let cookie = read_atom(&mut reader, (cookie.1, cookie.2))?;
+            if cookie.len() < 28 {
+                return Err(invalid("truncated ALAC configuration"));
+            }
+            let cookie = &cookie[4..];
+            let config = Config {
+                frame_length: read_u32(cookie, 0),
+                bit_depth: cookie[5] as u32,
+                pb: cookie[6] as u32,
+                mb: cookie[7] as u32,
+                kb: cookie[8] as u32,
+                channels: cookie[9] as u32,
+                max_run: u16::from_be_bytes([cookie[10], cookie[11]]) as u32,
+                sample_rate: read_u32(cookie, 20),
+            };
+            if !matches!(config.bit_depth, 16 | 20 | 24 | 32) || config.channels == 0 || config.sample_rate == 0 {
+                return Err(invalid("unsupported stream configuration"));
+            }
+            let _ = config.max_run;
+
+            let packets = packet_table(&mut reader, stbl)?;
+            let total_frames = match find(&mut reader, &[b"mdia", b"mdhd"])? {
+                Some(mdhd) => {
+                    let mdhd = read_atom(&mut reader, mdhd)?;
+                    if mdhd.len() < if mdhd.first() == Some(&1) { 32 } else { 20 } {
+                        return Err(invalid("truncated media header"));
+                    }
+                    let (timescale, units) = if mdhd[0] == 1 {
+                        (read_u32(&mdhd, 20) as u64, u64::from_be_bytes(mdhd[24..32].try_into().unwrap()))
+                    } else {
+                        (read_u32(&mdhd, 12) as u64, read_u32(&mdhd, 16) as u64)
+                    };
+                    units * config.sample_rate as u64 / timescale.max(1)
+                }
+                None => packets.len() as u64 * config.frame_length as u64,
+            };
+
+            return Ok(AlacDecoder {
+                reader,
+                config,
+                packets,
+                next_packet: 0,
+                total_frames,
+                buffer: Vec::new(),
+                buffer_pos: 0,
+                down_shift: config.bit_depth - 16,
+                failure,
+            });
+        }
+
+        Err(invalid("no ALAC track in file"))
+    }
+
+    fn decode_next_packet(&mut self) -> io::Result<bool> {
+        let Some(&(offset, size)) = self.packets.get(self.next_packet) else {
+            return Ok(false);
+        };
+        self.next_packet += 1;
+        let mut data = vec![0u8; size as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut data)?;
+        decode_packet(&data, &self.config, &mut self.buffer)?;
+        self.buffer_pos = 0;
+        Ok(true)
+    }
+}
+
+/// Work out where each packet lives from the `stsz`, `stsc` and `stco`/`co64` tables.
+fn packet_table<R: Read + Seek>(reader: &mut R, (stbl_start, stbl_end): (u64, u64)) -> io::Result<Vec<(u64, u32)>> {
+    let mut sizes = Vec::new();
+    let mut chunk_offsets = Vec::new();
+    let mut sample_to_chunk = Vec::new();
+    let file_len = reader.seek(SeekFrom::End(0))?;
+
+    for (kind, start, end) in mp4_children(reader, stbl_start, stbl_end)? {
+        let body = read_atom(reader, (start, end))?;
+        if body.len() < 8 {
+            continue;
+        }
+        match &kind {
+            b"stsz" if body.len() >= 12 => {
+                let fixed = read_u32(&body, 4);
+                let count = read_u32(&body, 8) as usize;
+                sizes = if fixed != 0 {
+                    // Every packet takes up room in the file, so there can't be more than fit in it
+                    if count as u64 * fixed as u64 > file_len {
+                        return Err(invalid("packet sizes run past the end of the file"));
+                    }
+                    vec![fixed; count]
+                } else {
+                    (0..count).filter(|i| 12 + i * 4 + 4 <= body.len()).map(|i| read_u32(&body, 12 + i * 4)).collect()
+                };
+            }
+            b"stco" => {
+                let count = read_u32(&body, 4) as usize;
+                chunk_offsets = (0..count)
+                    .filter(|i| 8 + i * 4 + 4 <= body.len())
+                    .map(|i| read_u32(&body, 8 + i * 4) as u64)
+                    .collect();
+            }
+            b"co64" => {
+                let count = read_u32(&body, 4) as usize;
+                chunk_offsets = (0..count)
+                    .filter(|i| 8 + i * 8 + 8 <= body.len())
+                    .map(|i| u64::from_be_bytes(body[8 + i * 8..16 + i * 8].try_into().unwrap()))
+                    .collect();
+            }
+            b"stsc" => {
+                let count = read_u32(&body, 4) as usize;
+                sample_to_chunk = (0..count)
+                    .filter(|i| 8 + i * 12 + 12 <= body.len())
+                    .map(|i| (read_u32(&body, 8 + i * 12), read_u32(&body, 12 + i * 12)))
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    let mut packets = Vec::with_capacity(sizes.len());
+    let mut sample = 0usize;
+    for (chunk, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        // stsc entries are 1-based runs of chunks sharing a samples-per-chunk count
+        let per_chunk = sample_to_chunk
+            .iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk as usize <= chunk + 1)
+            .map(|&(_, n)| n)
+            .unwrap_or(1);
+        let mut offset = chunk_offset;
+        for _ in 0..per_chunk {
+            let Some(&size) = sizes.get(sample) else {
+                return Ok(packets);
+            };
+            packets.push((offset, size));
+            offset += size as u64;
+            sample += 1;
+        }
+    }
+    Ok(packets)
+}
+
+impl<R: Read + Seek> Iterator for AlacDecoder<R> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        while self.buffer_pos >= self.buffer.len() {
+            // A corrupt packet ends the track rather than taking down the
+            // playback thread, noted so it's skipped with the reason
+            match self.decode_next_packet() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    self.failure.note(&e);
+                    return None;
+                }
+            }
+        }
+        let sample = self.buffer[self.buffer_pos] >> self.down_shift;
+        self.buffer_pos += 1;
+        Some(sample as i16)
+    }
+}
+
+impl<R: Read + Seek> Source for AlacDecoder<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.config.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.config.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        Some(Duration::from_secs_f64(self.total_frames as f64 / self.config.sample_rate as f64))
+    }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::reader::{Failure, Retry};
+use crate::{format_time, music_extension, open_source, probe, verify};
+
+// How far short of the length a file gives its audio can stop before it's
+// taken to have been cut off, as some only give an estimate
+const SHORT_BY: Duration = Duration::from_secs(1);
+
+/// What decoding the whole of a file found.
+pub enum Outcome {
+    Ok,
+    /// Decoding went wrong this far into the audio, if it got started, for
+    /// this reason.
+    Failed(Option<Duration>, String),
+    /// There's no decoder for it, so it can't be told whether it's sound.
+    Unsupported(String),
+}
+
+/// Decode the whole of the file at `path`, reading it as `retry` says. The
+/// decoders just stop where the audio can't be read any further, as at the
+/// end, so a file whose audio stops well short of the length it gives has
+/// been cut off, but for WAV files, which are filled out with silence, so
+/// they're measured instead. FLAC files are decoded with claxon, which says
+/// what's wrong.
+pub fn check(path: &Path, retry: &Retry) -> Outcome {
+    match music_extension(path) {
+        Some("flac") => return check_flac(path),
+        Some("wav") => match probe::wav_cut_off(path) {
+            Ok(Some((there, expected))) if there + SHORT_BY < expected => return Outcome::Failed(Some(there), stops_short(expected)),
+            Ok(_) => {}
+            Err(e) => return Outcome::Failed(None, e.to_string()),
+        },
+        _ => {}
+    }
+    let failure = Failure::default();
+    let source = match open_source(path, None, retry, &failure) {
+        Ok(source) => source,
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::Unsupported) => return Outcome::Unsupported(e.to_string()),
+        Err(e) => return Outcome::Failed(None, e.to_string()),
+    };
+    let rate = f64::from(source.sample_rate()) * f64::from(source.channels());
+    let expected = source.total_duration().or_else(|| probe::estimate_duration(path));
+    let decoded = Duration::from_secs_f64(source.count() as f64 / rate.max(1.0));
+    if let Some(e) = failure.take() {
+        return Outcome::Failed(Some(decoded), e.to_string());
+    }
+    match expected {
+        Some(expected) if decoded + SHORT_BY < expected => Outcome::Failed(Some(decoded), stops_short(expected)),
+        _ => Outcome::Ok,
+    }
+}
+
+fn check_flac(path: &Path) -> Outcome {
+    let mut reader = match verify::open(path) {
+        Ok(reader) => reader,
+        Err(e) => return Outcome::Failed(None, e.to_string()),
+    };
+    let info = reader.streaminfo();
+    let rate = f64::from(info.sample_rate.max(1));
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+    let mut decoded = 0;
+    loop {
+        match blocks.read_next_or_eof(buffer) {
+            Ok(Some(block)) => {
+                decoded += u64::from(block.duration());
+                buffer = block.into_buffer();
+            }
+            Ok(None) => break,
+            Err(e) => return Outcome::Failed(Some(Duration::from_secs_f64(decoded as f64 / rate)), e.to_string()),
+        }
+    }
+    // Cut off between frames, what's left decodes without an error
+    match info.samples {
+        Some(samples) if decoded < samples => Outcome::Failed(Some(Duration::from_secs_f64(decoded as f64 / rate)), stops_short(Duration::from_secs_f64(samples as f64 / rate))),
+        _ => Outcome::Ok,
+    }
+}
+
+fn stops_short(expected: Duration) -> String {
+    format!("the audio stops short of the {} the file says it lasts", format_time(expected.as_secs()))
+}
@@ -0,0 +1,621 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::keys::{self, Keymap};
+use crate::nowplaying::Template;
+use crate::replaygain::{self, MAX_GAIN_DB};
+use crate::sort::SortKey;
+use crate::theme::{self, Theme};
+use crate::{dsp, BarStyle, MAX_COVER_SIZE, MAX_FADE_MS, MAX_LOCAL_CACHE_MB, MAX_PREFETCH_MB, MAX_READ_RETRIES, MAX_RETRY_DELAY_MS, MAX_STATUS_INTERVAL, MAX_VOLUME};
+
+/// A value in the subset of TOML the config file supports.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a number with a decimal point",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// One `key = value` line, with the table it's in.
+struct Entry {
+    line: usize,
+    table: String,
+    key: String,
+    value: Value,
+}
+
+/// Settings read from the config file. Options left out of the file are
+/// None, so the command line and then the built-in defaults decide them.
+#[derive(Default)]
+pub struct Config {
+    /// The file these settings came from, if one was found.
+    pub path: Option<PathBuf>,
+    /// A path, or a list of them.
+    pub music_path: Option<Vec<String>>,
+    /// Patterns for what to leave out when looking for music.
+    pub exclude: Option<Vec<String>>,
+    /// Whether to leave out directories with a `.nomedia` file in them.
+    pub skip_nomedia: Option<bool>,
+    /// Whether to look at hidden files and directories too.
+    pub hidden: Option<bool>,
+    /// Whether to look in directories that links lead to.
+    pub follow_symlinks: Option<bool>,
+    pub default_volume: Option<u32>,
+    pub shuffle: Option<bool>,
+    pub bar_style: Option<BarStyle>,
+    pub theme: Option<Theme>,
+    pub sort: Option<SortKey>,
+    pub cover_size: Option<u16>,
+    pub replaygain: Option<replaygain::Mode>,
+    /// In dB.
+    pub replaygain_preamp: Option<f32>,
+    pub replaygain_fallback: Option<f32>,
+    pub fade_ms: Option<u32>,
+    pub keep_speed: Option<bool>,
+    pub prefetch: Option<bool>,
+    /// In megabytes.
+    pub prefetch_limit: Option<u64>,
+    pub read_retries: Option<u32>,
+    pub retry_delay_ms: Option<u32>,
+    /// Where to make the local cache, or None for none.
+    pub local_cache: Option<Option<PathBuf>>,
+    /// In megabytes.
+    pub local_cache_size: Option<u64>,
+    pub mono: Option<bool>,
+    pub balance: Option<i32>,
+    pub skip_silence: Option<bool>,
+    pub silence_threshold: Option<f32>,
+    pub silence_min: Option<u32>,
+    pub resume: Option<bool>,
+    pub play_counts: Option<bool>,
+    pub write_tags: Option<bool>,
+    /// Where to say what's playing, for a status bar to read.
+    pub status_file: Option<PathBuf>,
+    /// In seconds.
+    pub status_interval: Option<u32>,
+    /// Where to say what's playing as plain text, and how.
+    pub now_playing_file: Option<PathBuf>,
+    pub now_playing_format: Option<Template>,
+    #[cfg(feature = "notify")]
+    pub notify: Option<bool>,
+    pub keymap: Keymap,
+}
+
+/// Where the config file lives: `$XDG_CONFIG_HOME/sdsupreme/config.toml`,
+/// falling back to `~/.config` when that isn't set.
+pub fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("sdsupreme").join("config.toml"))
+}
+
+/// Where what's kept between runs goes, like where tracks were stopped:
+/// `$XDG_DATA_HOME/sdsupreme`, falling back to `~/.local/share`.
+pub fn data_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))?;
+    Some(base.join("sdsupreme"))
+}
+
+/// Where what can be worked out again is kept, like the index of what's on
+/// a card: `$XDG_CACHE_HOME/sdsupreme`, falling back to `~/.cache`.
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("sdsupreme"))
+}
+
+/// Load the config file at `path`, or the default one when `path` is None.
+/// A missing default file just means the defaults; a missing file that was
+/// asked for explicitly is an error.
+pub fn load(path: Option<&Path>) -> Result<Config, String> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+    match fs::read_to_string(&path) {
+        Ok(text) => {
+            let config = parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+            Ok(Config { path: Some(path), ..config })
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Parse the text of a config file.
+fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    for entry in parse_toml(text)? {
+        let at = |message: String| format!("line {}: {}", entry.line, message);
+        let expected = |what: &str| at(format!("'{}' must be {}, not {}", entry.key, what, entry.value.type_name()));
+        match entry.table.as_str() {
+            "" => match (entry.key.as_str(), &entry.value) {
+                ("music_path", Value::String(path)) => config.music_path = Some(vec![expand_home(path)]),
+                ("music_path", Value::Array(values)) => {
+                    let paths = values
+                        .iter()
+                        .map(|value| match value {
+                            Value::String(path) => Ok(expand_home(path)),
+                            other => Err(at(format!("paths must be strings, not {}", other.type_name()))),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    config.music_path = Some(paths).filter(|paths| !paths.is_empty());
+                }
+                ("music_path", _) => return Err(expected("a string or a list of them")),
+                ("exclude", Value::String(pattern)) => config.exclude = Some(vec![pattern.clone()]),
+                ("exclude", Value::Array(values)) => {
+                    let patterns = values
+                        .iter()
+                        .map(|value| match value {
+                            Value::String(pattern) => Ok(pattern.clone()),
+                            other => Err(at(format!("patterns must be strings, not {}", other.type_name()))),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    config.exclude = Some(patterns);
+                }
+                ("exclude", _) => return Err(expected("a string or a list of them")),
+                ("default_volume", Value::Integer(percent)) => match u32::try_from(*percent) {
+                    Ok(percent) if percent <= MAX_VOLUME => config.default_volume = Some(percent),
+                    _ => return Err(at(format!("'default_volume' must be a percentage from 0 to {}", MAX_VOLUME))),
+                },
+                ("default_volume", _) => return Err(expected("an integer")),
+                ("skip_nomedia", Value::Boolean(skip)) => config.skip_nomedia = Some(*skip),
+                ("skip_nomedia", _) => return Err(expected("true or false")),
+                ("hidden", Value::Boolean(hidden)) => config.hidden = Some(*hidden),
+                ("hidden", _) => return Err(expected("true or false")),
+                ("follow_symlinks", Value::Boolean(follow)) => config.follow_symlinks = Some(*follow),
+                ("follow_symlinks", _) => return Err(expected("true or false")),
+                ("shuffle", Value::Boolean(shuffle)) => config.shuffle = Some(*shuffle),
+                ("shuffle", _) => return Err(expected("true or false")),
+                ("bar_style", Value::String(name)) => match BarStyle::from_name(name) {
+                    Some(style) => config.bar_style = Some(style),
+                    None => return Err(at(format!("unknown bar style '{}', expected 'ascii' or 'unicode'", name))),
+                },
+                ("bar_style", _) => return Err(expected("a string")),
+                ("theme", Value::String(name)) => match theme::by_name(name) {
+                    Some(theme) => config.theme = Some(theme),
+                    None => return Err(at(format!("unknown theme '{}', expected one of {}", name, theme::names()))),
+                },
+                ("theme", _) => return Err(expected("a string")),
+                ("sort", Value::String(name)) => match SortKey::from_name(name) {
+                    Some(key) => config.sort = Some(key),
+                    None => return Err(at(format!("unknown sort order '{}', expected 'path', 'name', 'mtime', 'mtime-asc', 'size', 'size-asc' or 'track'", name))),
+                },
+                ("sort", _) => return Err(expected("a string")),
+                ("cover_size", Value::Integer(columns)) => match u16::try_from(*columns) {
+                    Ok(columns) if columns <= MAX_COVER_SIZE => config.cover_size = Some(columns),
+                    _ => return Err(at(format!("'cover_size' must be a number of columns from 0 to {}", MAX_COVER_SIZE))),
+                },
+                ("cover_size", _) => return Err(expected("an integer")),
+                ("fade_ms", Value::Integer(ms)) => match u32::try_from(*ms) {
+                    Ok(ms) if ms <= MAX_FADE_MS => config.fade_ms = Some(ms),
+                    _ => return Err(at(format!("'fade_ms' must be milliseconds from 0 to {}", MAX_FADE_MS))),
+                },
+                ("fade_ms", _) => return Err(expected("an integer")),
+                ("keep_speed", Value::Boolean(keep)) => config.keep_speed = Some(*keep),
+                ("keep_speed", _) => return Err(expected("true or false")),
+                ("prefetch", Value::Boolean(prefetch)) => config.prefetch = Some(*prefetch),
+                ("prefetch", _) => return Err(expected("true or false")),
+                ("prefetch_limit", Value::Integer(mb)) => match u64::try_from(*mb) {
+                    Ok(mb) if (1..=MAX_PREFETCH_MB).contains(&mb) => config.prefetch_limit = Some(mb),
+                    _ => return Err(at(format!("'prefetch_limit' must be megabytes from 1 to {}", MAX_PREFETCH_MB))),
+                },
+                ("prefetch_limit", _) => return Err(expected("an integer")),
+                ("read_retries", Value::Integer(retries)) => match u32::try_from(*retries) {
+                    Ok(retries) if retries <= MAX_READ_RETRIES => config.read_retries = Some(retries),
+                    _ => return Err(at(format!("'read_retries' must be from 0 to {}", MAX_READ_RETRIES))),
+                },
+                ("read_retries", _) => return Err(expected("an integer")),
+                ("retry_delay_ms", Value::Integer(ms)) => match u32::try_from(*ms) {
+                    Ok(ms) if ms <= MAX_RETRY_DELAY_MS => config.retry_delay_ms = Some(ms),
+                    _ => return Err(at(format!("'retry_delay_ms' must be milliseconds from 0 to {}", MAX_RETRY_DELAY_MS))),
+                },
+                ("retry_delay_ms", _) => return Err(expected("an integer")),
+                ("local_cache", Value::Boolean(local)) => config.local_cache = Some(local.then(env::temp_dir)),
+                ("local_cache", Value::String(dir)) => config.local_cache = Some(Some(PathBuf::from(expand_home(dir)))),
+                ("local_cache", _) => return Err(expected("true, false or a directory")),
+                ("local_cache_size", Value::Integer(mb)) => match u64::try_from(*mb) {
+                    Ok(mb) if (1..=MAX_LOCAL_CACHE_MB).contains(&mb) => config.local_cache_size = Some(mb),
+                    _ => return Err(at(format!("'local_cache_size' must be megabytes from 1 to {}", MAX_LOCAL_CACHE_MB))),
+                },
+                ("local_cache_size", _) => return Err(expected("an integer")),
+                ("mono", Value::Boolean(mono)) => config.mono = Some(*mono),
+                ("mono", _) => return Err(expected("true or false")),
+                ("balance", Value::Integer(balance)) => match i32::try_from(*balance) {
+                    Ok(balance) if balance.abs() <= dsp::MAX_BALANCE => config.balance = Some(balance),
+                    _ => return Err(at(format!("'balance' must be from -{} to {}", dsp::MAX_BALANCE, dsp::MAX_BALANCE))),
+                },
+                ("balance", _) => return Err(expected("an integer")),
+                ("skip_silence", Value::Boolean(skip)) => config.skip_silence = Some(*skip),
+                ("skip_silence", _) => return Err(expected("true or false")),
+                ("silence_threshold", value) => {
+                    let db = match value {
+                        Value::Integer(db) => *db as f64,
+                        Value::Float(db) => *db,
+                        _ => return Err(expected("a number")),
+                    };
+                    let (lowest, highest) = dsp::SILENCE_DB_RANGE;
+                    if !(lowest as f64..=highest as f64).contains(&db) {
+                        return Err(at(format!("'silence_threshold' must be dBFS from {} to {}", lowest, highest)));
+                    }
+                    config.silence_threshold = Some(db as f32);
+                }
+                ("silence_min", Value::Integer(seconds)) => match u32::try_from(*seconds) {
+                    Ok(seconds @ 1..) if seconds <= dsp::MAX_SILENCE_SECONDS => config.silence_min = Some(seconds),
+                    _ => return Err(at(format!("'silence_min' must be seconds from 1 to {}", dsp::MAX_SILENCE_SECONDS))),
+                },
+                ("silence_min", _) => return Err(expected("an integer")),
+                ("resume", Value::Boolean(resume)) => config.resume = Some(*resume),
+                ("resume", _) => return Err(expected("true or false")),
+                ("play_counts", Value::Boolean(show)) => config.play_counts = Some(*show),
+                ("play_counts", _) => return Err(expected("true or false")),
+                ("write_tags", Value::Boolean(write)) => config.write_tags = Some(*write),
+                ("write_tags", _) => return Err(expected("true or false")),
+                ("status_file", Value::String(path)) => config.status_file = Some(PathBuf::from(expand_home(path))),
+                ("status_file", _) => return Err(expected("a string")),
+                ("status_interval", Value::Integer(seconds)) => match u32::try_from(*seconds) {
+                    Ok(seconds @ 1..) if seconds <= MAX_STATUS_INTERVAL => config.status_interval = Some(seconds),
+                    _ => return Err(at(format!("'status_interval' must be seconds from 1 to {}", MAX_STATUS_INTERVAL))),
+                },
+                ("status_interval", _) => return Err(expected("an integer")),
+                ("now_playing_file", Value::String(path)) => config.now_playing_file = Some(PathBuf::from(expand_home(path))),
+                ("now_playing_file", _) => return Err(expected("a string")),
+                ("now_playing_format", Value::String(text)) => match Template::parse(text) {
+                    Ok(template) => config.now_playing_format = Some(template),
+                    Err(e) => return Err(at(format!("'now_playing_format': {}", e))),
+                },
+                ("now_playing_format", _) => return Err(expected("a string")),
+                #[cfg(feature = "notify")]
+                ("notify", Value::Boolean(notify)) => config.notify = Some(*notify),
+                #[cfg(feature = "notify")]
+                ("notify", _) => return Err(expected("true or false")),
+                #[cfg(not(feature = "notify"))]
+                ("notify", _) => return Err(at("'notify' needs sdsupreme built with the notify feature".to_string())),
+                ("replaygain", Value::String(name)) => match replaygain::Mode::from_name(name) {
+                    Some(mode) => config.replaygain = Some(mode),
+                    None => return Err(at(format!("unknown ReplayGain mode '{}', expected 'track', 'album' or 'off'", name))),
+                },
+                ("replaygain", _) => return Err(expected("a string")),
+                (key @ ("replaygain_preamp" | "replaygain_fallback"), value) => {
+                    let db = match value {
+                        Value::Integer(db) => *db as f64,
+                        Value::Float(db) => *db,
+                        _ => return Err(expected("a number")),
+                    };
+                    if db.abs() > MAX_GAIN_DB as f64 {
+                        return Err(at(format!("'{}' must be a gain in dB from -{} to {}", key, MAX_GAIN_DB, MAX_GAIN_DB)));
+                    }
+                    if key == "replaygain_preamp" {
+                        config.replaygain_preamp = Some(db as f32);
+                    } else {
+                        config.replaygain_fallback = Some(db as f32);
+                    }
+                }
+                (key, _) => return Err(at(format!("unknown setting '{}'", key))),
+            },
+            "keys" => {
+                let names = match &entry.value {
+                    Value::String(name) => vec![name.clone()],
+                    Value::Array(values) => values
+                        .iter()
+                        .map(|value| match value {
+                            Value::String(name) => Ok(name.clone()),
+                            other => Err(at(format!("key names must be strings, not {}", other.type_name()))),
+                        })
+                        .collect::<Result<_, _>>()?,
+                    other => return Err(at(format!("'{}' must be a key name or a list of them, not {}", entry.key, other.type_name()))),
+                };
+                let keys = names.iter().map(|name| keys::parse_key(name)).collect::<Result<Vec<_>, _>>().map_err(at)?;
+                config.keymap.set(&entry.key, &keys).map_err(at)?;
+            }
+            table => return Err(at(format!("unknown section [{}]", table))),
+        }
+    }
+    Ok(config)
+}
+
+/// Expand a leading `~/` to the home directory, as a shell would.
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest).to_string_lossy().into_owned(),
+        _ => path.to_string(),
+    }
+}
+
+/// Parse the TOML subset used by the config file: `[table]` headers, bare
+/// or quoted keys, and strings, integers, decimal numbers, booleans and
+/// single-line arrays of them as values, with `#` comments.
+fn parse_toml(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut table = String::new();
+    for (index, raw) in text.lines().enumerate() {
+        let line = index + 1;
+        let at = |message: &str| format!("line {}: {}", line, message);
+        let mut cursor = Cursor { rest: raw.trim_start() };
+        if cursor.at_end() {
+            continue;
+        }
+
+        if cursor.eat('[') {
+            let name = cursor.take_while(|c| c != ']').trim().to_string();
+            if !cursor.eat(']') || name.is_empty() {
+                return Err(at("expected a section name like [keys]"));
+            }
+            if !cursor.at_end() {
+                return Err(at("unexpected text after the section name"));
+            }
+            table = name;
+            continue;
+        }
+
+        let key = if cursor.peek() == Some('"') {
+            cursor.string().map_err(|e| at(&e))?
+        } else {
+            cursor.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-').to_string()
+        };
+        cursor.skip_spaces();
+        if key.is_empty() || !cursor.eat('=') {
+            return Err(at("expected 'name = value'"));
+        }
+        cursor.skip_spaces();
+        let value = cursor.value().map_err(|e| at(&e))?;
+        if !cursor.at_end() {
+            return Err(at("unexpected text after the value"));
+        }
+        if entries.iter().any(|entry| entry.table == table && entry.key == key) {
+            return Err(at(&format!("'{}' is set more than once", key)));
+        }
+        entries.push(Entry { line, table: table.clone(), key, value });
+    }
+    Ok(entries)
+}
+
+/// Reads one line of the config file from left to right.
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        match self.rest.strip_prefix(expected) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    /// Whether only whitespace or a comment is left.
+    fn at_end(&mut self) -> bool {
+        self.skip_spaces();
+        self.rest.is_empty() || self.rest.starts_with('#')
+    }
+
+    fn take_while(&mut self, keep: impl Fn(char) -> bool) -> &'a str {
+        let end = self.rest.find(|c| !keep(c)).unwrap_or(self.rest.len());
+        let (taken, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        taken
+    }
+
+    /// A double-quoted string with the common escapes.
+    fn string(&mut self) -> Result<String, String> {
+        self.eat('"');
+        let mut text = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[index + 1..];
+                    return Ok(text);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => text.push('\n'),
+                    Some((_, 't')) => text.push('\t'),
+                    Some((_, '"')) => text.push('"'),
+                    Some((_, '\\')) => text.push('\\'),
+                    _ => return Err("unknown escape in string".to_string()),
+                },
+                c => text.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.eat('[');
+                let mut values = Vec::new();
+                loop {
+                    self.skip_spaces();
+                    if self.eat(']') {
+                        return Ok(Value::Array(values));
+                    }
+                    values.push(self.value()?);
+                    self.skip_spaces();
+                    if !self.eat(',') && self.peek() != Some(']') {
+                        return Err("expected ',' or ']' in array".to_string());
+                    }
+                }
+            }
+            _ => {
+                let word = self.take_while(|c| c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '_' || c == '.');
+                let number = word.replace('_', "");
+                let found = || format!("expected a value, found '{}'", if word.is_empty() { self.rest } else { word });
+                match word {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    // Only plain decimals, not inf or nan
+                    _ if word.contains('.') && number.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+')) => {
+                        number.parse().map(Value::Float).map_err(|_| found())
+                    }
+                    _ => number.parse().map(Value::Integer).map_err(|_| found()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse(text).err().unwrap()
+    }
+
+    #[test]
+    fn empty_file_leaves_everything_unset() {
+        for text in ["", "\n\n", "# just a comment\n   \n"] {
+            let config = parse(text).unwrap();
+            assert!(config.music_path.is_none());
+            assert!(config.default_volume.is_none());
+            assert!(config.shuffle.is_none());
+            assert!(config.bar_style.is_none());
+            assert!(config.sort.is_none());
+            assert!(config.keymap.conflicts().is_empty());
+        }
+    }
+
+    #[test]
+    fn settings_are_read() {
+        let config = parse(
+            "# My card\nmusic_path = \"/media/card\"  # mounted here\ndefault_volume = 80\nshuffle = true\nbar_style = \"unicode\"\nreplaygain_preamp = -3.5\nreplaygain_fallback = -6\n",
+        )
+        .unwrap();
+        assert_eq!(config.music_path, Some(vec!["/media/card".to_string()]));
+        assert_eq!(config.default_volume, Some(80));
+        assert_eq!(config.shuffle, Some(true));
+        assert!(config.bar_style == Some(BarStyle::Unicode));
+        assert_eq!(config.replaygain_preamp, Some(-3.5));
+        assert_eq!(config.replaygain_fallback, Some(-6.0));
+        assert!(config.path.is_none());
+    }
+
+    #[test]
+    fn music_path_can_be_a_list() {
+        let config = parse("music_path = [\"/media/a\", \"/media/b\",]").unwrap();
+        assert_eq!(config.music_path, Some(vec!["/media/a".to_string(), "/media/b".to_string()]));
+        assert!(parse("music_path = []").unwrap().music_path.is_none());
+        assert_eq!(error("music_path = [\"/a\", 1]"), "line 1: paths must be strings, not an integer");
+    }
+
+    #[test]
+    fn strings_have_escapes() {
+        let config = parse(r#"music_path = "C:\\Music\t\"x\"""#).unwrap();
+        assert_eq!(config.music_path, Some(vec!["C:\\Music\t\"x\"".to_string()]));
+        assert_eq!(error(r#"music_path = "\q""#), "line 1: unknown escape in string");
+        assert_eq!(error("music_path = \"/media"), "line 1: unterminated string");
+    }
+
+    #[test]
+    fn keys_section_rebinds() {
+        let config = parse("[keys]\nquit = \"Ctrl-q\"\ndown = [\"Down\", \"n\"]\n").unwrap();
+        assert!(config.keymap.config_lines().contains(&"quit = [\"Ctrl-q\"]".to_string()));
+        assert!(config.keymap.config_lines().contains(&"down = [\"Down\", \"n\"]".to_string()));
+        assert_eq!(error("[keys]\nquit = \"Hyper-q\""), "line 2: unknown key 'Hyper-q'");
+        assert!(error("[keys]\ndance = \"d\"").starts_with("line 2: unknown action 'dance'"));
+        assert_eq!(error("[keys]\nquit = 1"), "line 2: 'quit' must be a key name or a list of them, not an integer");
+    }
+
+    #[test]
+    fn wrong_types_are_rejected() {
+        assert_eq!(error("default_volume = \"loud\""), "line 1: 'default_volume' must be an integer, not a string");
+        assert_eq!(error("shuffle = 1"), "line 1: 'shuffle' must be true or false, not an integer");
+        assert_eq!(error("bar_style = true"), "line 1: 'bar_style' must be a string, not a boolean");
+        assert_eq!(error("\n\nreplaygain_preamp = \"3\""), "line 3: 'replaygain_preamp' must be a number, not a string");
+    }
+
+    #[test]
+    fn values_out_of_range_are_rejected() {
+        assert_eq!(error("default_volume = -1"), format!("line 1: 'default_volume' must be a percentage from 0 to {}", MAX_VOLUME));
+        assert!(error(&format!("default_volume = {}", MAX_VOLUME + 1)).contains("'default_volume' must be a percentage"));
+        assert_eq!(error("bar_style = \"fancy\""), "line 1: unknown bar style 'fancy', expected 'ascii' or 'unicode'");
+        assert!(error("replaygain_preamp = 100.0").contains("'replaygain_preamp' must be a gain in dB"));
+        assert!(error("silence_min = 0").contains("'silence_min' must be seconds from 1"));
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert_eq!(error("shuffle"), "line 1: expected 'name = value'");
+        assert_eq!(error("= true"), "line 1: expected 'name = value'");
+        assert_eq!(error("shuffle = true false"), "line 1: unexpected text after the value");
+        assert_eq!(error("shuffle = yes"), "line 1: expected a value, found 'yes'");
+        assert_eq!(error("shuffle ="), "line 1: expected a value, found ''");
+        assert_eq!(error("music_path = [\"/a\" \"/b\"]"), "line 1: expected ',' or ']' in array");
+        assert_eq!(error("fade_ms = inf.0"), "line 1: expected a value, found 'inf.0'");
+    }
+
+    #[test]
+    fn malformed_sections_are_rejected() {
+        assert_eq!(error("[keys"), "line 1: expected a section name like [keys]");
+        assert_eq!(error("[]"), "line 1: expected a section name like [keys]");
+        assert_eq!(error("[keys] quit = \"q\""), "line 1: unexpected text after the section name");
+        assert_eq!(error("[colors]\nred = 1"), "line 2: unknown section [colors]");
+    }
+
+    #[test]
+    fn unknown_and_repeated_settings_are_rejected() {
+        assert_eq!(error("volume = 50"), "line 1: unknown setting 'volume'");
+        assert_eq!(error("shuffle = true\nshuffle = false"), "line 2: 'shuffle' is set more than once");
+        // The same name in different sections is fine
+        assert!(parse("shuffle = true\n[keys]\nshuffle = \"z\"").is_ok());
+    }
+
+    #[test]
+    fn missing_file_asked_for_is_an_error() {
+        let path = env::temp_dir().join(format!("sdsupreme-test-no-config-{}.toml", std::process::id()));
+        let error = load(Some(&path)).err().unwrap();
+        assert!(error.starts_with(&path.display().to_string()), "{}", error);
+    }
+
+    #[test]
+    fn errors_in_a_file_name_it() {
+        let path = env::temp_dir().join(format!("sdsupreme-test-config-{}.toml", std::process::id()));
+        fs::write(&path, "default_volume = 80\n").unwrap();
+        let config = load(Some(&path)).unwrap();
+        assert_eq!(config.path.as_deref(), Some(path.as_path()));
+        assert_eq!(config.default_volume, Some(80));
+        fs::write(&path, "default_volume = 80\nshuffle = maybe\n").unwrap();
+        let error = load(Some(&path)).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error, format!("{}: line 2: expected a value, found 'maybe'", path.display()));
+    }
+}
@@ -0,0 +1,422 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crossterm::{cursor, execute};
+
+use crate::jpeg;
+use crate::probe::{find_mp4_atom, mp4_children};
+use crate::tags::{flac_blocks, id3v2_frames, FLAC_PICTURE};
+
+// Largest tag read for pictures; cover art is rarely more than a few megabytes
+const MAX_PICTURE_READ: usize = 16 << 20;
+
+// The picture type of a front cover, in the numbering ID3 and FLAC share
+const FRONT_COVER: u32 = 3;
+
+const PICTURE_KINDS: [&str; 21] = [
+    "other",
+    "file icon",
+    "other file icon",
+    "front cover",
+    "back cover",
+    "leaflet page",
+    "media",
+    "lead artist",
+    "artist",
+    "conductor",
+    "band",
+    "composer",
+    "lyricist",
+    "recording location",
+    "during recording",
+    "during performance",
+    "screen capture",
+    "a bright coloured fish",
+    "illustration",
+    "band logo",
+    "publisher logo",
+];
+
+// Size in pixels a terminal cell is taken to be, for shrinking decoded
+// images before sending them; the terminal scales them to the cells anyway
+const CELL_WIDTH: usize = 10;
+const CELL_HEIGHT: usize = 20;
+
+/// An image embedded in a music file.
+pub struct Picture {
+    /// What the picture shows, such as the front cover or the artist.
+    pub kind: u32,
+    /// The MIME type the file gives, which isn't always right.
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Jpeg,
+    Png,
+}
+
+impl Picture {
+    /// What the image data actually is, going by its first bytes.
+    fn format(&self) -> Option<Format> {
+        if self.data.starts_with(&[0xFF, 0xD8]) {
+            Some(Format::Jpeg)
+        } else if self.data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Format::Png)
+        } else {
+            None
+        }
+    }
+
+    /// The MIME type, going by the data when the file gives none or gets it
+    /// wrong.
+    pub fn mime_type(&self) -> &str {
+        match self.format() {
+            Some(Format::Jpeg) => "image/jpeg",
+            Some(Format::Png) => "image/png",
+            None if self.mime.contains('/') => &self.mime,
+            None => "application/octet-stream",
+        }
+    }
+
+    /// The file extension for saving it.
+    pub fn extension(&self) -> &str {
+        match self.mime_type() {
+            "image/jpeg" => "jpg",
+            "application/octet-stream" => "bin",
+            mime => mime.rsplit('/').next().unwrap_or("bin"),
+        }
+    }
+
+    /// What the picture shows, as ID3 and FLAC name the types.
+    pub fn kind_name(&self) -> &'static str {
+        PICTURE_KINDS.get(self.kind as usize).copied().unwrap_or("unknown type")
+    }
+
+    /// Width and height in pixels, from the image's own header.
+    pub fn dimensions(&self) -> Option<(usize, usize)> {
+        match self.format()? {
+            Format::Jpeg => jpeg::dimensions(&self.data),
+            Format::Png => {
+                let header = self.data.get(16..24)?;
+                let width = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+                let height = u32::from_be_bytes(header[4..].try_into().ok()?) as usize;
+                Some((width, height))
+            }
+        }
+    }
+}
+
+/// Every picture embedded in the file at `path`: FLAC PICTURE blocks, ID3
+/// APIC frames and MP4 `covr` atoms.
+pub fn pictures(path: &Path) -> io::Result<Vec<Picture>> {
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("").to_ascii_lowercase();
+    let mut file = fs::File::open(path)?;
+    let mut pictures = Vec::new();
+    match extension.as_str() {
+        "flac" => {
+            read_id3v2_pictures(&mut file, &mut pictures)?;
+            for block in flac_blocks(&mut file, FLAC_PICTURE, false)? {
+                pictures.extend(parse_flac_picture(&block));
+            }
+        }
+        "mp3" => read_id3v2_pictures(&mut file, &mut pictures)?,
+        "m4a" => read_mp4_pictures(&mut file, &mut pictures)?,
+        _ => {}
+    }
+    Ok(pictures)
+}
+
+/// The picture to show for a track: its front cover, or failing that the
+/// first picture it has.
+pub fn front_cover(pictures: Vec<Picture>) -> Option<Picture> {
+    let front = pictures.iter().position(|picture| picture.kind == FRONT_COVER).unwrap_or(0);
+    pictures.into_iter().nth(front)
+}
+
+/// A FLAC PICTURE block: type, MIME type, description, four numbers
+/// describing the image, then the image, with lengths and numbers all 32-bit
+/// big-endian.
+fn parse_flac_picture(block: &[u8]) -> Option<Picture> {
+    let number = |at: usize| -> Option<u32> { Some(u32::from_be_bytes(block.get(at..at + 4)?.try_into().ok()?)) };
+    let kind = number(0)?;
+    let mime_len = number(4)? as usize;
+    let mime = String::from_utf8_lossy(block.get(8..8 + mime_len)?).into_owned();
+    let description_end = 12 + mime_len + number(8 + mime_len)? as usize;
+    let data_len = number(description_end + 16)? as usize;
+    let data = block.get(description_end + 20..description_end + 20 + data_len)?.to_vec();
+    Some(Picture { kind, mime, data })
+}
+
+fn read_id3v2_pictures<R: Read + Seek>(file: &mut R, pictures: &mut Vec<Picture>) -> io::Result<()> {
+    id3v2_frames(file, MAX_PICTURE_READ, |id, body| {
+        if let Some(picture) = parse_id3_picture(id, &body) {
+            pictures.push(picture);
+        }
+    })
+}
+
+/// An ID3 APIC frame: text encoding, MIME type, picture type, description
+/// and the image. Version 2.2's PIC has a three-letter format instead of the
+/// MIME type.
+fn parse_id3_picture(id: &str, body: &[u8]) -> Option<Picture> {
+    let (&encoding, rest) = body.split_first()?;
+    let (mime, rest) = match id {
+        "APIC" => {
+            let end = rest.iter().position(|&b| b == 0)?;
+            (String::from_utf8_lossy(&rest[..end]).into_owned(), &rest[end + 1..])
+        }
+        "PIC" => {
+            let format = String::from_utf8_lossy(rest.get(..3)?).to_ascii_lowercase();
+            (format!("image/{}", if format == "jpg" { "jpeg" } else { &format }), &rest[3..])
+        }
+        _ => return None,
+    };
+    let (&kind, rest) = rest.split_first()?;
+    // The description ends with a zero as wide as the encoding's characters
+    let data = if encoding == 1 || encoding == 2 {
+        let end = rest.chunks_exact(2).position(|pair| pair == [0, 0])?;
+        &rest[end * 2 + 2..]
+    } else {
+        &rest[rest.iter().position(|&b| b == 0)? + 1..]
+    };
+    Some(Picture { kind: kind as u32, mime, data: data.to_vec() })
+}
+
+/// The `data` atoms under `moov/udta/meta/ilst/covr`, which say whether
+/// they're JPEG or PNG but not what they show.
+fn read_mp4_pictures<R: Read + Seek>(file: &mut R, pictures: &mut Vec<Picture>) -> io::Result<()> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let Some((meta_start, meta_end)) = find_mp4_atom(file, 0, len, &[b"moov", b"udta", b"meta"])? else {
+        return Ok(());
+    };
+    // `meta` has a version and flags before its children
+    let Some((covr_start, covr_end)) = find_mp4_atom(file, meta_start + 4, meta_end, &[b"ilst", b"covr"])? else {
+        return Ok(());
+    };
+    for (kind, start, end) in mp4_children(file, covr_start, covr_end)? {
+        if &kind != b"data" || end < start + 8 || end - start > MAX_PICTURE_READ as u64 {
+            continue;
+        }
+        let mut atom = vec![0u8; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut atom)?;
+        // A type indicator and locale come before the image
+        let mime = match u32::from_be_bytes(atom[..4].try_into().unwrap()) & 0xFF_FFFF {
+            13 => "image/jpeg",
+            14 => "image/png",
+            _ => "",
+        };
+        pictures.push(Picture { kind: FRONT_COVER, mime: mime.to_string(), data: atom.split_off(8) });
+    }
+    Ok(())
+}
+
+/// How images are drawn in the terminal.
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    /// The kitty graphics protocol, which takes PNG or raw pixels.
+    Kitty,
+    /// iTerm2's inline images, which take the image file as it is.
+    Iterm,
+}
+
+/// Which graphics protocol the terminal speaks, going by the variables
+/// terminals that have one set. Anything unknown, or a multiplexer like tmux
+/// in between that wouldn't pass the images on, means no art.
+pub fn detect() -> Option<Protocol> {
+    let var = |name: &str| env::var(name).unwrap_or_default();
+    if env::var_os("TMUX").is_some() || env::var_os("STY").is_some() {
+        return None;
+    }
+    if var("TERM") == "xterm-kitty" || env::var_os("KITTY_WINDOW_ID").is_some() || var("TERM_PROGRAM") == "ghostty" {
+        return Some(Protocol::Kitty);
+    }
+    if matches!(var("TERM_PROGRAM").as_str(), "iTerm.app" | "WezTerm") || var("LC_TERMINAL") == "iTerm2" {
+        return Some(Protocol::Iterm);
+    }
+    None
+}
+
+/// Art ready to draw: the escape sequence that shows it at the cursor, and
+/// how many cells it covers.
+struct Art {
+    escape: String,
+    columns: u16,
+    rows: u16,
+}
+
+/// The cover of the track playing, loaded on a thread of its own so that
+/// reading and decoding it doesn't hold up the interface.
+pub struct CoverArt {
+    protocol: Protocol,
+    requests: Sender<(usize, PathBuf)>,
+    loaded: Receiver<(usize, Option<Art>)>,
+    /// The track whose art was asked for last.
+    track: Option<usize>,
+    /// That track's art once it's loaded, if it has any that can be shown.
+    art: Option<Art>,
+    /// Where the art was last drawn, until the screen is cleared.
+    drawn_at: Option<(u16, u16)>,
+}
+
+impl CoverArt {
+    /// Start the loading thread. Art is drawn `columns` cells wide, and as
+    /// tall as keeps its shape.
+    pub fn new(protocol: Protocol, columns: u16) -> CoverArt {
+        let (requests, pending) = mpsc::channel::<(usize, PathBuf)>();
+        let (done, loaded) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(request) = pending.recv() {
+                // Skip straight to the latest track when they change quickly
+                let (track, path) = pending.try_iter().last().unwrap_or(request);
+                let art = pictures(&path).ok().and_then(front_cover).and_then(|picture| prepare(protocol, &picture, columns));
+                if done.send((track, art)).is_err() {
+                    return;
+                }
+            }
+        });
+        CoverArt { protocol, requests, loaded, track: None, art: None, drawn_at: None }
+    }
+
+    /// The cells the art for `track` covers, as columns and rows, once it's
+    /// loaded. A track that hasn't been asked for yet is asked for.
+    pub fn size(&mut self, track: Option<usize>, music_files: &[PathBuf]) -> io::Result<Option<(u16, u16)>> {
+        if track != self.track {
+            self.hide()?;
+            self.track = track;
+            self.art = None;
+            if let Some(track) = track {
+                let _ = self.requests.send((track, music_files[track].clone()));
+            }
+        }
+        for (track, art) in self.loaded.try_iter() {
+            if Some(track) == self.track {
+                self.art = art;
+            }
+        }
+        Ok(self.art.as_ref().map(|art| (art.columns, art.rows)))
+    }
+
+    /// Draw the art with its top left corner at `at`, unless it's there
+    /// already. None takes it away.
+    pub fn draw(&mut self, at: Option<(u16, u16)>) -> io::Result<()> {
+        if self.drawn_at == at {
+            return Ok(());
+        }
+        self.hide()?;
+        let (Some(art), Some((column, row))) = (&self.art, at) else {
+            return Ok(());
+        };
+        let mut stdout = io::stdout();
+        execute!(stdout, cursor::MoveTo(column, row))?;
+        stdout.write_all(art.escape.as_bytes())?;
+        stdout.flush()?;
+        self.drawn_at = at;
+        Ok(())
+    }
+
+    /// Take the art off the screen. iTerm2's images are text cells that
+    /// drawing over or clearing the screen removes; kitty's sit above the
+    /// text until they're deleted.
+    pub fn hide(&mut self) -> io::Result<()> {
+        if self.drawn_at.take().is_some() {
+            if let Protocol::Kitty = self.protocol {
+                let mut stdout = io::stdout();
+                stdout.write_all(b"\x1b_Ga=d,d=A,q=2\x1b\\")?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turn `picture` into the escape sequence that draws it `columns` cells
+/// wide. Images the terminal can't be given, or that don't decode, give None.
+fn prepare(protocol: Protocol, picture: &Picture, columns: u16) -> Option<Art> {
+    let format = picture.format()?;
+    let (width, height) = picture.dimensions().filter(|&(width, height)| width > 0 && height > 0)?;
+    // Cells are about twice as tall as they're wide
+    let rows = ((columns as usize * height).div_ceil(width * 2)).max(1) as u16;
+    let escape = match (protocol, format) {
+        (Protocol::Iterm, _) => format!(
+            "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=1:{}\x07",
+            picture.data.len(),
+            columns,
+            rows,
+            base64(&picture.data)
+        ),
+        (Protocol::Kitty, Format::Png) => kitty_escape("f=100", &picture.data, columns, rows),
+        (Protocol::Kitty, Format::Jpeg) => {
+            let image = shrink(jpeg::decode(&picture.data)?, columns as usize * CELL_WIDTH, rows as usize * CELL_HEIGHT);
+            kitty_escape(&format!("f=24,s={},v={}", image.width, image.height), &image.rgb, columns, rows)
+        }
+    };
+    Some(Art { escape, columns, rows })
+}
+
+/// Kitty's escape for showing `data` at the cursor, scaled to `columns` by
+/// `rows` cells. Its payload has to be sent in chunks of at most 4096 bytes.
+/// The cursor stays put, and the terminal isn't asked to reply.
+fn kitty_escape(format: &str, data: &[u8], columns: u16, rows: u16) -> String {
+    let encoded = base64(data);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+    let mut escape = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = if index + 1 < chunks.len() { 1 } else { 0 };
+        let chunk = std::str::from_utf8(chunk).unwrap_or_default();
+        if index == 0 {
+            escape.push_str(&format!("\x1b_Ga=T,{},c={},r={},C=1,q=2,m={};{}\x1b\\", format, columns, rows, more, chunk));
+        } else {
+            escape.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    escape
+}
+
+/// Scale `image` down by a whole factor until it fits in `width` by
+/// `height`, averaging each square of pixels.
+fn shrink(image: jpeg::Image, width: usize, height: usize) -> jpeg::Image {
+    let factor = image.width.div_ceil(width.max(1)).max(image.height.div_ceil(height.max(1)));
+    if factor <= 1 {
+        return image;
+    }
+    let (new_width, new_height) = (image.width / factor, image.height / factor);
+    let mut rgb = Vec::with_capacity(new_width * new_height * 3);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            for channel in 0..3 {
+                let mut sum = 0usize;
+                for dy in 0..factor {
+                    for dx in 0..factor {
+                        sum += image.rgb[((y * factor + dy) * image.width + x * factor + dx) * 3 + channel] as usize;
+                    }
+                }
+                rgb.push((sum / (factor * factor)) as u8);
+            }
+        }
+    }
+    jpeg::Image { width: new_width, height: new_height, rgb }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let triple = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for index in 0..4 {
+            if index <= chunk.len() {
+                out.push(ALPHABET[(triple >> (18 - index * 6) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
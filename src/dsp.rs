@@ -0,0 +1,429 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::f64::consts::PI;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rodio::Source;
+
+// Balance from full left to full right
+pub const MAX_BALANCE: i32 = 100;
+
+// Furthest the bass and treble go either way, in dB
+pub const MAX_TONE_DB: i32 = 12;
+// Where the shelves for the bass and treble start, in Hz
+const BASS_FREQUENCY: f64 = 100.0;
+const TREBLE_FREQUENCY: f64 = 10000.0;
+
+// How quiet counts as silence, in dBFS, and for how long, in seconds,
+// before it's skipped
+pub const DEFAULT_SILENCE_DB: f32 = -60.0;
+pub const SILENCE_DB_RANGE: (f32, f32) = (-96.0, -20.0);
+pub const DEFAULT_SILENCE_SECONDS: u32 = 5;
+pub const MAX_SILENCE_SECONDS: u32 = 600;
+
+// Frames the tap keeps, and how many the audio thread collects before
+// handing them over
+const TAP_FRAMES: usize = 4096;
+const TAP_BATCH: usize = 256;
+
+// Frames played at one speed before it's looked at again, so a change is
+// heard within a few milliseconds
+const SPEED_FRAMES: usize = 256;
+
+/// How the audio is changed on its way from the decoder to the sink. The UI
+/// thread sets these and the audio thread reads them for every frame, so
+/// changes are heard straight away, mid-track.
+#[derive(Default)]
+pub struct Effects {
+    /// Mix every channel into one, played on all of them.
+    pub mono: AtomicBool,
+    /// From -MAX_BALANCE, only the left channel, to MAX_BALANCE, only the
+    /// right, by turning the other one down.
+    pub balance: AtomicI32,
+    /// Boost or cut below BASS_FREQUENCY and above TREBLE_FREQUENCY, in dB
+    /// up to MAX_TONE_DB either way.
+    pub bass: AtomicI32,
+    pub treble: AtomicI32,
+    /// Skip silence lasting longer than this, or play it all for None.
+    pub skip_silence: Option<Silence>,
+    /// Microseconds of the track skipped as silence, for the player to move
+    /// its clock on by and then set back to 0.
+    pub skipped_us: AtomicU64,
+}
+
+/// What counts as silence worth skipping.
+#[derive(Clone, Copy)]
+pub struct Silence {
+    /// No sample in any channel louder than this, as a fraction of full
+    /// scale.
+    pub threshold: f32,
+    /// For at least this long; anything shorter, like a quiet passage in
+    /// classical music, is played.
+    pub min: Duration,
+}
+
+impl Silence {
+    pub fn new(threshold_db: f32, seconds: u32) -> Silence {
+        Silence { threshold: 10f32.powf(threshold_db / 20.0), min: Duration::from_secs(seconds as u64) }
+    }
+
+    fn holds(&self, frame: &[i16]) -> bool {
+        let threshold = (self.threshold * 32768.0) as i32;
+        !frame.is_empty() && frame.iter().all(|&sample| (sample as i32).abs() <= threshold)
+    }
+}
+
+/// The last frames played, and the loudest sample in each channel since
+/// they were last asked for, for the UI thread to show. Samples are a
+/// fraction of full scale.
+#[derive(Default)]
+pub struct Tap {
+    recent: Mutex<Recent>,
+}
+
+#[derive(Default)]
+struct Recent {
+    /// Left and right.
+    frames: VecDeque<[f32; 2]>,
+    sample_rate: u32,
+    peaks: [f32; 2],
+}
+
+impl Tap {
+    /// Up to the last `len` frames, oldest first, and their sample rate.
+    pub fn latest(&self, len: usize) -> (Vec<[f32; 2]>, u32) {
+        let recent = self.recent.lock().unwrap();
+        let skip = recent.frames.len().saturating_sub(len);
+        (recent.frames.iter().skip(skip).copied().collect(), recent.sample_rate)
+    }
+
+    /// The highest left and right samples since the last call, however
+    /// many frames ago they were.
+    pub fn take_peaks(&self) -> [f32; 2] {
+        std::mem::take(&mut self.recent.lock().unwrap().peaks)
+    }
+
+    /// Add `frames` from the audio thread, unless the UI thread is reading
+    /// at the moment. Waiting for it could hold up playback, so they're
+    /// kept for the next try instead.
+    fn offer(&self, frames: &mut Vec<[f32; 2]>, sample_rate: u32) {
+        let Ok(mut recent) = self.recent.try_lock() else {
+            let excess = frames.len().saturating_sub(TAP_FRAMES);
+            frames.drain(..excess);
+            return;
+        };
+        if recent.sample_rate != sample_rate {
+            recent.frames.clear();
+            recent.sample_rate = sample_rate;
+        }
+        for frame in frames.iter() {
+            for (peak, sample) in recent.peaks.iter_mut().zip(frame) {
+                *peak = peak.max(sample.abs());
+            }
+        }
+        recent.frames.extend(frames.drain(..));
+        let excess = recent.frames.len().saturating_sub(TAP_FRAMES);
+        recent.frames.drain(..excess);
+    }
+
+    /// Forget what was played, when a track starts.
+    pub fn clear(&self) {
+        self.recent.lock().unwrap().frames.clear();
+    }
+}
+
+/// A second-order filter, in transposed direct form II.
+#[derive(Clone, Copy)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// With `a` leaving out a0, which the rest have already been divided by.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad { b, a, state: [0.0; 2] }
+    }
+
+    /// The RBJ cookbook shelf, of `gain_db` below `frequency` or above it
+    /// for a high shelf, with a slope of 1.
+    fn shelf(sample_rate: u32, frequency: f64, gain_db: f64, high: bool) -> Biquad {
+        // Kept under Nyquist, where the filter stops making sense
+        let frequency = frequency.min(sample_rate as f64 * 0.45);
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let cos = w0.cos();
+        let alpha = w0.sin() / 2.0 * 2f64.sqrt();
+        let root = 2.0 * a.sqrt() * alpha;
+        // A high shelf is a low shelf with the sign of cos flipped
+        let (cos, sign) = if high { (-cos, -1.0) } else { (cos, 1.0) };
+        let a0 = (a + 1.0) + (a - 1.0) * cos + root;
+        Biquad::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + root) / a0,
+                sign * 2.0 * a * ((a - 1.0) - (a + 1.0) * cos) / a0,
+                a * ((a + 1.0) - (a - 1.0) * cos - root) / a0,
+            ],
+            [sign * -2.0 * ((a - 1.0) + (a + 1.0) * cos) / a0, ((a + 1.0) + (a - 1.0) * cos - root) / a0],
+        )
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        // Dying away to nothing in silence, the state would otherwise end up
+        // in denormals, which are slow to work with
+        for state in &mut self.state {
+            if state.abs() < 1e-30 {
+                *state = 0.0;
+            }
+        }
+        y
+    }
+}
+
+/// A source with `Effects` applied, a frame of samples at a time, and what
+/// comes out copied to a `Tap`. Mono sources come out as stereo, so the
+/// balance still does something.
+pub struct Process<S> {
+    source: S,
+    effects: Arc<Effects>,
+    tap: Arc<Tap>,
+    /// Frames waiting to go to the tap.
+    tapped: Vec<[f32; 2]>,
+    /// Of the source; there are at least two coming out.
+    channels: usize,
+    frame: Vec<i16>,
+    next: usize,
+    /// The bass, treble and sample rate `filters` were made for, and a bass
+    /// and treble shelf for each channel. Empty when both are at 0 dB, so
+    /// the samples are passed through untouched.
+    tone: (i32, i32, u32),
+    filters: Vec<[Biquad; 2]>,
+    /// Frames of silence in a row so far.
+    silent: u64,
+}
+
+impl<S: Source<Item = i16>> Process<S> {
+    pub fn new(source: S, effects: Arc<Effects>, tap: Arc<Tap>) -> Process<S> {
+        let channels = source.channels().max(1) as usize;
+        Process {
+            source,
+            effects,
+            tap,
+            tapped: Vec::with_capacity(TAP_BATCH),
+            channels,
+            frame: Vec::with_capacity(channels.max(2)),
+            next: 0,
+            tone: (0, 0, 0),
+            filters: Vec::new(),
+            silent: 0,
+        }
+    }
+
+    /// Read the next frame and apply the effects to it. Leaves the frame
+    /// empty at the end of the source.
+    fn fill(&mut self) {
+        self.frame.clear();
+        self.next = 0;
+        self.frame.extend(self.source.by_ref().take(self.channels));
+        self.skip_silence();
+        if self.effects.mono.load(Ordering::Relaxed) {
+            downmix(&mut self.frame);
+        }
+        if self.frame.len() == 1 {
+            self.frame.push(self.frame[0]);
+        }
+        self.tone();
+        balance(&mut self.frame, self.effects.balance.load(Ordering::Relaxed));
+
+        if let [left, right, ..] = self.frame[..] {
+            self.tapped.push([left as f32 / 32768.0, right as f32 / 32768.0]);
+        }
+        if self.tapped.len() >= TAP_BATCH || (self.frame.is_empty() && !self.tapped.is_empty()) {
+            self.tap.offer(&mut self.tapped, self.source.sample_rate());
+        }
+    }
+
+    /// Once silence has gone on for long enough, read on to where it ends,
+    /// leaving the frame after it.
+    fn skip_silence(&mut self) {
+        let Some(silence) = self.effects.skip_silence else {
+            return;
+        };
+        if !silence.holds(&self.frame) {
+            self.silent = 0;
+            return;
+        }
+        self.silent += 1;
+        let rate = self.source.sample_rate().max(1) as u64;
+        if self.silent < silence.min.as_secs() * rate {
+            return;
+        }
+        let mut skipped: u64 = 0;
+        while silence.holds(&self.frame) {
+            self.frame.clear();
+            self.frame.extend(self.source.by_ref().take(self.channels));
+            skipped += 1;
+        }
+        self.silent = 0;
+        self.effects.skipped_us.fetch_add(skipped * 1_000_000 / rate, Ordering::SeqCst);
+    }
+
+    /// Apply the bass and treble to the frame, working out the filters
+    /// again when they or the sample rate have changed.
+    fn tone(&mut self) {
+        let bass = self.effects.bass.load(Ordering::Relaxed).clamp(-MAX_TONE_DB, MAX_TONE_DB);
+        let treble = self.effects.treble.load(Ordering::Relaxed).clamp(-MAX_TONE_DB, MAX_TONE_DB);
+        if bass == 0 && treble == 0 {
+            self.filters.clear();
+            return;
+        }
+        let tone = (bass, treble, self.source.sample_rate().max(1));
+        if tone != self.tone || self.filters.len() != self.frame.len() {
+            let rate = tone.2;
+            let filters = [
+                Biquad::shelf(rate, BASS_FREQUENCY, bass as f64, false),
+                Biquad::shelf(rate, TREBLE_FREQUENCY, treble as f64, true),
+            ];
+            // What's already going through the filters carries on, so
+            // there's no click
+            self.filters.resize(self.frame.len(), filters);
+            for channel in &mut self.filters {
+                for (filter, new) in channel.iter_mut().zip(filters) {
+                    filter.b = new.b;
+                    filter.a = new.a;
+                }
+            }
+            self.tone = tone;
+        }
+        for (sample, [bass, treble]) in self.frame.iter_mut().zip(&mut self.filters) {
+            let filtered = treble.process(bass.process(*sample as f64));
+            *sample = filtered.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+        }
+    }
+}
+
+/// Replace every channel of `frame` with their sum scaled by 1/√channels:
+/// 3 dB down for stereo, so a sound only in one channel doesn't get much
+/// quieter. Anything in every channel at once that would clip is held at
+/// full scale instead.
+fn downmix(frame: &mut [i16]) {
+    if frame.len() < 2 {
+        return;
+    }
+    let sum: f32 = frame.iter().map(|&sample| sample as f32).sum();
+    let mixed = (sum / (frame.len() as f32).sqrt()).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    frame.fill(mixed);
+}
+
+/// Turn down the left channel of `frame` for a positive `balance`, or the
+/// right for a negative one, in proportion. With more than two channels
+/// only the front pair is changed.
+fn balance(frame: &mut [i16], balance: i32) {
+    let (channel, by) = match balance {
+        0 => return,
+        1.. => (0, balance),
+        _ => (1, -balance),
+    };
+    if let Some(sample) = frame.get_mut(channel) {
+        *sample = (*sample as i32 * (MAX_BALANCE - by.min(MAX_BALANCE)) / MAX_BALANCE) as i16;
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Process<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.next == self.frame.len() {
+            self.fill();
+        }
+        let sample = self.frame.get(self.next).copied();
+        self.next += 1;
+        sample
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Process<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        let buffered = self.frame.len().saturating_sub(self.next);
+        let len = self.source.current_frame_len()?;
+        Some(len * self.channels.max(2) / self.channels + buffered)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels.max(2) as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+/// A source played faster or slower, and higher or lower, as `speed` in
+/// percent says. It goes by a sample rate that's that much higher, for the
+/// sink to convert, so it's split into frames of SPEED_FRAMES and a change
+/// to `speed` is picked up at the next one without starting over.
+pub struct Varispeed<S> {
+    source: S,
+    speed: Arc<AtomicU32>,
+    /// What the sample rate is multiplied by, and how many samples are left
+    /// of the frame that's playing at it.
+    factor: f32,
+    left: usize,
+}
+
+impl<S: Source<Item = i16>> Varispeed<S> {
+    pub fn new(source: S, speed: Arc<AtomicU32>) -> Varispeed<S> {
+        let mut varispeed = Varispeed { source, speed, factor: 1.0, left: 0 };
+        varispeed.next_frame();
+        varispeed
+    }
+
+    /// Start a frame at the speed there is now, ending where the source's
+    /// own frame does if that's sooner.
+    fn next_frame(&mut self) {
+        self.factor = self.speed.load(Ordering::Relaxed) as f32 / 100.0;
+        let len = SPEED_FRAMES * self.source.channels().max(1) as usize;
+        self.left = self.source.current_frame_len().filter(|&own| own > 0).map_or(len, |own| own.min(len));
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Varispeed<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.source.next()?;
+        self.left = self.left.saturating_sub(1);
+        if self.left == 0 {
+            self.next_frame();
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Varispeed<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.left)
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        ((self.source.sample_rate() as f32 * self.factor) as u32).max(1)
+    }
+
+    // It changes with the speed, and the player keeps its own clock
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use rodio::Source;
+
+use crate::md5::Md5;
+use crate::open_source;
+use crate::reader::{Failure, Retry, Retrying};
+
+// How much of a file is hashed at a time
+const CHUNK: usize = 256 * 1024;
+
+/// What's taken to tell whether two files are the same.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// An MD5 of the decoded audio, with its sample rate and channels, so the
+    /// same audio in different kinds of file matches.
+    Audio([u8; 16]),
+    /// An MD5 of the file as it is, for those taken byte for byte and those
+    /// that couldn't be decoded.
+    File([u8; 16]),
+}
+
+/// Files found to be the same as each other.
+pub struct Group {
+    /// The files, by path, with how big each is.
+    pub files: Vec<(PathBuf, u64)>,
+}
+
+impl Group {
+    /// How much room all but the biggest of the files take up.
+    pub fn wasted(&self) -> u64 {
+        let total: u64 = self.files.iter().map(|(_, size)| size).sum();
+        total - self.files.iter().map(|&(_, size)| size).max().unwrap_or(0)
+    }
+}
+
+/// Which of `files`, as paths with their sizes, are the same size as
+/// another, the only ones that can be the same byte for byte.
+pub fn same_size(files: &[(PathBuf, u64)]) -> Vec<usize> {
+    let mut sizes: HashMap<u64, usize> = HashMap::new();
+    for &(_, size) in files {
+        *sizes.entry(size).or_default() += 1;
+    }
+    (0..files.len()).filter(|&index| sizes[&files[index].1] > 1).collect()
+}
+
+/// Group `files`, as paths with their sizes and keys, by key, leaving out
+/// those the same as no other. The groups come out with the most room wasted
+/// first, each with its files in the order given.
+pub fn group(files: Vec<(PathBuf, u64, Key)>) -> Vec<Group> {
+    let mut keys: Vec<Key> = Vec::new();
+    let mut groups: HashMap<Key, Group> = HashMap::new();
+    for (path, size, key) in files {
+        groups
+            .entry(key)
+            .or_insert_with(|| {
+                keys.push(key);
+                Group { files: Vec::new() }
+            })
+            .files
+            .push((path, size));
+    }
+    let mut groups: Vec<Group> = keys.iter().filter_map(|key| groups.remove(key)).filter(|group| group.files.len() > 1).collect();
+    // Stable, so groups wasting as much stay in the order they were found
+    groups.sort_by_key(|group| std::cmp::Reverse(group.wasted()));
+    groups
+}
+
+/// The key of the file at `path`: of its audio unless `by_file` says to take
+/// it byte for byte, or it can't be decoded. Reads are tried again as `retry`
+/// says.
+pub fn key(path: &Path, by_file: bool, retry: &Retry) -> io::Result<Key> {
+    if !by_file {
+        if let Ok(source) = open_source(path, None, retry, &Failure::default()) {
+            let mut md5 = Md5::new();
+            md5.update(&source.sample_rate().to_le_bytes());
+            md5.update(&source.channels().to_le_bytes());
+            let mut bytes = Vec::with_capacity(CHUNK);
+            for sample in source {
+                bytes.extend_from_slice(&sample.to_le_bytes());
+                if bytes.len() >= CHUNK {
+                    md5.update(&bytes);
+                    bytes.clear();
+                }
+            }
+            md5.update(&bytes);
+            return Ok(Key::Audio(md5.finish()));
+        }
+    }
+    let mut file = Retrying::new(File::open(path)?, retry.clone());
+    let mut md5 = Md5::new();
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        let count = file.read(&mut chunk)?;
+        if count == 0 {
+            return Ok(Key::File(md5.finish()));
+        }
+        md5.update(&chunk[..count]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{scratch, write_wav};
+    use std::fs;
+    use std::time::Duration;
+
+    fn key_of(path: &Path, by_file: bool) -> Key {
+        key(path, by_file, &Retry::new(0, Duration::ZERO)).unwrap()
+    }
+
+    fn paths(group: &Group) -> Vec<&str> {
+        group.files.iter().map(|(path, _)| path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn files_with_the_same_key_are_grouped() {
+        let a = Key::File([1; 16]);
+        let b = Key::Audio([1; 16]);
+        let groups = group(vec![
+            (PathBuf::from("one"), 10, a),
+            (PathBuf::from("two"), 20, b),
+            (PathBuf::from("three"), 10, a),
+            (PathBuf::from("four"), 30, Key::File([2; 16])),
+            (PathBuf::from("five"), 25, b),
+        ]);
+        assert_eq!(groups.len(), 2);
+        // Wasting 20 bytes, then 10
+        assert_eq!(paths(&groups[0]), ["two", "five"]);
+        assert_eq!(groups[0].wasted(), 20);
+        assert_eq!(paths(&groups[1]), ["one", "three"]);
+        assert_eq!(groups[1].wasted(), 10);
+    }
+
+    #[test]
+    fn groups_wasting_as_much_stay_in_order() {
+        let groups = group(vec![
+            (PathBuf::from("b1"), 5, Key::File([2; 16])),
+            (PathBuf::from("a1"), 5, Key::File([1; 16])),
+            (PathBuf::from("a2"), 5, Key::File([1; 16])),
+            (PathBuf::from("b2"), 5, Key::File([2; 16])),
+        ]);
+        assert_eq!(paths(&groups[0]), ["b1", "b2"]);
+        assert_eq!(paths(&groups[1]), ["a1", "a2"]);
+    }
+
+    #[test]
+    fn nothing_alike_is_no_groups() {
+        assert!(group(Vec::new()).is_empty());
+        assert!(group(vec![(PathBuf::from("one"), 1, Key::File([1; 16])), (PathBuf::from("two"), 1, Key::File([2; 16]))]).is_empty());
+    }
+
+    #[test]
+    fn wasted_is_all_but_the_biggest() {
+        let group = Group { files: vec![(PathBuf::from("a"), 100), (PathBuf::from("b"), 300), (PathBuf::from("c"), 50)] };
+        assert_eq!(group.wasted(), 150);
+    }
+
+    #[test]
+    fn only_files_of_a_size_with_another_can_match() {
+        let files = [(PathBuf::from("a"), 1), (PathBuf::from("b"), 2), (PathBuf::from("c"), 1), (PathBuf::from("d"), 3)];
+        assert_eq!(same_size(&files), [0, 2]);
+        assert!(same_size(&files[..2]).is_empty());
+    }
+
+    #[test]
+    fn same_audio_matches_in_files_that_differ() {
+        let dir = scratch("dupes-audio");
+        let samples: Vec<i16> = (0..800).map(|n| (n * 37 % 2000) as i16 - 1000).collect();
+        write_wav(&dir.join("plain.wav"), 8000, 1, &samples, false);
+        write_wav(&dir.join("copy.wav"), 8000, 1, &samples, false);
+        write_wav(&dir.join("tagged.wav"), 8000, 1, &samples, true);
+        write_wav(&dir.join("different.wav"), 8000, 1, &samples[1..], false);
+        let plain = key_of(&dir.join("plain.wav"), false);
+        assert!(matches!(plain, Key::Audio(_)));
+        assert!(key_of(&dir.join("copy.wav"), false) == plain);
+        assert!(key_of(&dir.join("tagged.wav"), false) == plain);
+        assert!(key_of(&dir.join("different.wav"), false) != plain);
+    }
+
+    #[test]
+    fn by_file_only_matches_files_byte_for_byte() {
+        let dir = scratch("dupes-file");
+        let samples = [1, 2, 3, 4];
+        write_wav(&dir.join("plain.wav"), 8000, 1, &samples, false);
+        write_wav(&dir.join("copy.wav"), 8000, 1, &samples, false);
+        write_wav(&dir.join("tagged.wav"), 8000, 1, &samples, true);
+        let plain = key_of(&dir.join("plain.wav"), true);
+        assert!(matches!(plain, Key::File(_)));
+        assert!(key_of(&dir.join("copy.wav"), true) == plain);
+        assert!(key_of(&dir.join("tagged.wav"), true) != plain);
+    }
+
+    #[test]
+    fn files_that_do_not_decode_are_taken_byte_for_byte() {
+        let dir = scratch("dupes-broken");
+        fs::write(dir.join("one.flac"), b"not really a flac").unwrap();
+        fs::write(dir.join("two.mp3"), b"not really a flac").unwrap();
+        let one = key_of(&dir.join("one.flac"), false);
+        assert!(matches!(one, Key::File(_)));
+        assert!(key_of(&dir.join("two.mp3"), false) == one);
+        assert!(key(&dir.join("missing.flac"), false, &Retry::new(0, Duration::ZERO)).is_err());
+    }
+}
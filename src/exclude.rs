@@ -0,0 +1,200 @@
+use std::path::{Component, Path};
+
+/// Patterns for what to leave out when looking for music, matched against
+/// paths from where the search starts, with `/` between directories. `*`
+/// matches any part of a name and `?` any single character of it, while
+/// `**` matches any number of directories, so `Recordings` leaves out the
+/// directory at the top and `**/Recordings` one anywhere. A pattern ending
+/// in `/` only matches directories.
+#[derive(Clone, Default)]
+pub struct Exclude {
+    patterns: Vec<Pattern>,
+    /// Whether directories with a `.nomedia` file in them are left out, as
+    /// Android has media scanners do.
+    pub nomedia: bool,
+    /// Whether hidden files and directories, whose names start with `.`,
+    /// are left out.
+    pub hidden: bool,
+    /// How many directories down files are looked for, where 1 is only
+    /// those in the directory the search starts from.
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Clone)]
+struct Pattern {
+    parts: Vec<String>,
+    directories_only: bool,
+}
+
+impl Exclude {
+    /// Parse `patterns`, as given with `--exclude` or in the config file.
+    pub fn new(patterns: &[String]) -> Result<Exclude, String> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                let trimmed = pattern.trim_start_matches("./").trim_start_matches('/');
+                let directories_only = trimmed.ends_with('/');
+                let parts: Vec<String> = trimmed.split('/').filter(|part| !part.is_empty()).map(str::to_string).collect();
+                if parts.is_empty() {
+                    return Err(format!("Invalid exclude pattern '{}': it doesn't name anything", pattern));
+                }
+                Ok(Pattern { parts, directories_only })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Exclude { patterns, nomedia: false, hidden: false, max_depth: None })
+    }
+
+    /// Whether `path`, under `root`, is left out. Directories that are
+    /// aren't looked in at all. The `._` files macOS writes next to others
+    /// on cards are always left out: they hold what the Finder knows about
+    /// the file, not music, even when they end in `.flac`.
+    pub fn excludes(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        if !is_dir && name.starts_with("._") {
+            return true;
+        }
+        if self.patterns.is_empty() && !self.nomedia && !self.hidden {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(root) else {
+            return false;
+        };
+        let names: Vec<String> = relative
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        // The root itself is never left out
+        if names.is_empty() {
+            return false;
+        }
+        if self.hidden && name.starts_with('.') {
+            return true;
+        }
+        if is_dir && self.nomedia && path.join(".nomedia").exists() {
+            return true;
+        }
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.patterns.iter().any(|pattern| (is_dir || !pattern.directories_only) && matches_parts(&pattern.parts, &names))
+    }
+}
+
+/// Whether the parts of a pattern match the names along a path, with `**`
+/// standing for any number of them.
+fn matches_parts(parts: &[String], names: &[&str]) -> bool {
+    match parts.split_first() {
+        None => names.is_empty(),
+        Some((part, rest)) if part == "**" => (0..=names.len()).any(|skipped| matches_parts(rest, &names[skipped..])),
+        Some((part, rest)) => match names.split_first() {
+            Some((name, names)) => matches_name(part, name) && matches_parts(rest, names),
+            None => false,
+        },
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for any one.
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it had taken then
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the `*` take one more character and try again
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exclude(patterns: &[&str]) -> Exclude {
+        Exclude::new(&patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn excludes(patterns: &[&str], path: &str, is_dir: bool) -> bool {
+        exclude(patterns).excludes(Path::new("/card"), &Path::new("/card").join(path), is_dir)
+    }
+
+    #[test]
+    fn name_wildcards() {
+        assert!(matches_name("*.flac", "song.flac"));
+        assert!(matches_name("*.flac", ".flac"));
+        assert!(!matches_name("*.flac", "song.flac.part"));
+        assert!(matches_name("s?ng*", "song.mp3"));
+        assert!(!matches_name("s?ng", "sng"));
+        assert!(matches_name("*demo*", "a demo take"));
+        assert!(matches_name("Recordings", "Recordings"));
+        assert!(!matches_name("Recordings", "recordings"));
+    }
+
+    #[test]
+    fn pattern_goes_from_where_the_search_starts() {
+        assert!(excludes(&["Recordings"], "Recordings", true));
+        assert!(!excludes(&["Recordings"], "Music/Recordings", true));
+        assert!(excludes(&["Music/Recordings"], "Music/Recordings", true));
+        assert!(excludes(&["/Recordings"], "Recordings", true));
+        assert!(excludes(&["./Recordings"], "Recordings", true));
+        // Only the directory matches; what's in it is left out by not
+        // looking in it
+        assert!(!excludes(&["Recordings"], "Recordings/a.flac", false));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        let pattern = ["**/*.demo.flac"];
+        assert!(excludes(&pattern, "a.demo.flac", false));
+        assert!(excludes(&pattern, "Artist/a.demo.flac", false));
+        assert!(excludes(&pattern, "Artist/Album/Disc 2/a.demo.flac", false));
+        assert!(!excludes(&pattern, "Artist/a.flac", false));
+        assert!(excludes(&["**/Recordings"], "A/B/Recordings", true));
+        assert!(excludes(&["Music/**/Live"], "Music/Live", true));
+        assert!(excludes(&["Music/**/Live"], "Music/Artist/Live", true));
+        assert!(!excludes(&["Music/**/Live"], "Other/Artist/Live", true));
+        assert!(excludes(&["Backup/**"], "Backup/old/a.flac", false));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_directories() {
+        assert!(excludes(&["**/Demos/"], "Artist/Demos", true));
+        assert!(!excludes(&["**/Demos/"], "Artist/Demos", false));
+        assert!(excludes(&["**/Demos"], "Artist/Demos", false));
+    }
+
+    #[test]
+    fn root_is_never_excluded() {
+        let exclude = Exclude { hidden: true, ..exclude(&["**"]) };
+        assert!(!exclude.excludes(Path::new("/card/.music"), Path::new("/card/.music"), true));
+        assert!(exclude.excludes(Path::new("/card/.music"), Path::new("/card/.music/a.flac"), false));
+    }
+
+    #[test]
+    fn empty_pattern_is_rejected() {
+        for pattern in ["", "/", "./", "//"] {
+            assert!(Exclude::new(&[pattern.to_string()]).is_err(), "{:?}", pattern);
+        }
+        assert_eq!(Exclude::new(&["/".to_string()]).err().unwrap(), "Invalid exclude pattern '/': it doesn't name anything");
+    }
+}
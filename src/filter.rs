@@ -0,0 +1,175 @@
+use crate::tags::Tags;
+
+/// One `key:value` term of a filter.
+#[derive(Clone)]
+enum Term {
+    /// Genres containing this, lowercased.
+    Genre(String),
+    /// Years from the first to the second, inclusive. Either end can be left
+    /// open.
+    Year(Option<u32>, Option<u32>),
+}
+
+/// Narrow the track list by tags, like `genre:jazz year:1970-1979`. A track
+/// has to match every term; a filter with no terms matches everything.
+#[derive(Clone, Default)]
+pub struct Filter {
+    terms: Vec<Term>,
+    /// The text it was parsed from, to show while it's applied.
+    text: String,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether a track with `tags` is kept. Tracks without the tag a term
+    /// looks at never match it.
+    pub fn matches(&self, tags: &Tags) -> bool {
+        self.terms.iter().all(|term| match term {
+            Term::Genre(genre) => tags.genre.as_ref().is_some_and(|value| value.to_lowercase().contains(genre.as_str())),
+            Term::Year(from, to) => tags.year.is_some_and(|year| from.is_none_or(|from| year >= from) && to.is_none_or(|to| year <= to)),
+        })
+    }
+}
+
+/// Parse what was typed at the filter prompt: terms separated by spaces,
+/// each `genre:` followed by part of a genre, ignoring case, or `year:`
+/// followed by a year or a range like `1970-1979`, `1970-` or `-1979`.
+pub fn parse(text: &str) -> Result<Filter, String> {
+    let mut terms = Vec::new();
+    for word in text.split_whitespace() {
+        let Some((key, value)) = word.split_once(':') else {
+            return Err(format!("'{}' should look like genre:jazz or year:1970-1979", word));
+        };
+        if value.is_empty() {
+            return Err(format!("'{}' needs a value after the colon", word));
+        }
+        let term = match key.to_lowercase().as_str() {
+            "genre" => Term::Genre(value.to_lowercase()),
+            "year" => parse_years(value)?,
+            _ => return Err(format!("unknown filter '{}'; use genre or year", key)),
+        };
+        terms.push(term);
+    }
+    Ok(Filter { terms, text: text.split_whitespace().collect::<Vec<_>>().join(" ") })
+}
+
+fn parse_years(value: &str) -> Result<Term, String> {
+    let year = |text: &str| -> Result<Option<u32>, String> {
+        if text.is_empty() {
+            return Ok(None);
+        }
+        match text.parse() {
+            Ok(year) if text.chars().all(|c| c.is_ascii_digit()) => Ok(Some(year)),
+            _ => Err(format!("'{}' isn't a year", text)),
+        }
+    };
+    let (from, to) = match value.split_once('-') {
+        Some((from, to)) => (year(from)?, year(to)?),
+        None => {
+            let year = year(value)?;
+            (year, year)
+        }
+    };
+    match (from, to) {
+        (None, None) => Err("a year range needs at least one end".to_string()),
+        (Some(from), Some(to)) if from > to => Err(format!("the range {}-{} ends before it starts", from, to)),
+        _ => Ok(Term::Year(from, to)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(genre: Option<&str>, year: Option<u32>) -> Tags {
+        Tags { genre: genre.map(str::to_string), year, ..Tags::default() }
+    }
+
+    fn keeps(filter: &str, tags: &Tags) -> bool {
+        parse(filter).unwrap().matches(tags)
+    }
+
+    fn error(filter: &str) -> String {
+        parse(filter).err().unwrap()
+    }
+
+    #[test]
+    fn empty_filter_keeps_everything() {
+        for text in ["", "   "] {
+            let filter = parse(text).unwrap();
+            assert!(filter.is_empty());
+            assert_eq!(filter.text(), "");
+            assert!(filter.matches(&Tags::default()));
+        }
+    }
+
+    #[test]
+    fn genre_ignores_case() {
+        let jazz = track(Some("Smooth Jazz"), None);
+        assert!(keeps("genre:jazz", &jazz));
+        assert!(keeps("genre:JAZZ", &jazz));
+        assert!(keeps("GENRE:smooth", &jazz));
+        assert!(!keeps("genre:rock", &jazz));
+        assert!(!keeps("genre:jazz", &track(None, Some(1970))));
+    }
+
+    #[test]
+    fn genre_matches_non_ascii_ignoring_case() {
+        assert!(keeps("genre:électro", &track(Some("ÉLECTRO"), None)));
+    }
+
+    #[test]
+    fn single_year_and_closed_range() {
+        assert!(keeps("year:1975", &track(None, Some(1975))));
+        assert!(!keeps("year:1975", &track(None, Some(1976))));
+        let range = "year:1970-1979";
+        assert!(keeps(range, &track(None, Some(1970))));
+        assert!(keeps(range, &track(None, Some(1979))));
+        assert!(!keeps(range, &track(None, Some(1969))));
+        assert!(!keeps(range, &track(None, Some(1980))));
+        assert!(!keeps(range, &track(Some("Jazz"), None)));
+    }
+
+    #[test]
+    fn ranges_can_be_open_ended() {
+        assert!(keeps("year:1970-", &track(None, Some(2020))));
+        assert!(!keeps("year:1970-", &track(None, Some(1969))));
+        assert!(keeps("year:-1979", &track(None, Some(1900))));
+        assert!(!keeps("year:-1979", &track(None, Some(1980))));
+    }
+
+    #[test]
+    fn terms_must_all_match() {
+        let filter = "genre:jazz  year:1970-1979";
+        assert_eq!(parse(filter).unwrap().text(), "genre:jazz year:1970-1979");
+        assert!(keeps(filter, &track(Some("Jazz"), Some(1975))));
+        assert!(!keeps(filter, &track(Some("Jazz"), Some(1985))));
+        assert!(!keeps(filter, &track(Some("Rock"), Some(1975))));
+        assert!(!keeps("genre:jazz genre:rock", &track(Some("Jazz"), None)));
+    }
+
+    #[test]
+    fn malformed_terms_are_rejected() {
+        assert_eq!(error("jazz"), "'jazz' should look like genre:jazz or year:1970-1979");
+        assert_eq!(error("genre:"), "'genre:' needs a value after the colon");
+        assert_eq!(error("artist:miles"), "unknown filter 'artist'; use genre or year");
+        assert_eq!(error("genre:jazz year"), "'year' should look like genre:jazz or year:1970-1979");
+    }
+
+    #[test]
+    fn malformed_years_are_rejected() {
+        assert_eq!(error("year:seventies"), "'seventies' isn't a year");
+        assert_eq!(error("year:+1970"), "'+1970' isn't a year");
+        assert_eq!(error("year:1970-79x"), "'79x' isn't a year");
+        assert_eq!(error("year:-"), "a year range needs at least one end");
+        assert_eq!(error("year:1979-1970"), "the range 1979-1970 ends before it starts");
+        assert!(parse("year:1970-1980-1990").is_err());
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{config, tags};
+
+/// A track that was played to the end, or most of the way through.
+pub struct Entry {
+    /// When it stopped playing, in seconds since 1970 UTC.
+    pub time: u64,
+    /// Its full path, however it was found.
+    pub path: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub length: Duration,
+    /// How much of it was played; all of it when it played to the end.
+    pub played: Duration,
+}
+
+impl Entry {
+    /// The entry for `file` stopping after `played` of `length`, or none
+    /// when less than half of it was played.
+    pub fn new(file: &Path, played: Duration, length: Duration, finished: bool) -> Option<Entry> {
+        if !finished && (length.is_zero() || played * 2 < length) {
+            return None;
+        }
+        let tags = tags::read(file).unwrap_or_default();
+        Some(Entry {
+            time: now(),
+            path: fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()).to_string_lossy().into_owned(),
+            artist: tags.artist,
+            title: tags.title,
+            length,
+            played: if finished { length } else { played },
+        })
+    }
+
+    /// The artist and title, or the file name without tags.
+    pub fn name(&self) -> String {
+        let tags = tags::Tags { artist: self.artist.clone(), title: self.title.clone(), ..Default::default() };
+        tags.display_name()
+            .unwrap_or_else(|| Path::new(&self.path).file_name().map_or(self.path.clone(), |name| name.to_string_lossy().into_owned()))
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = format!("{{\"time\":{},\"path\":{}", self.time, quote(&self.path));
+        if let Some(artist) = &self.artist {
+            json += &format!(",\"artist\":{}", quote(artist));
+        }
+        if let Some(title) = &self.title {
+            json += &format!(",\"title\":{}", quote(title));
+        }
+        json += &format!(",\"length_ms\":{},\"played_ms\":{}}}", self.length.as_millis(), self.played.as_millis());
+        json
+    }
+
+    fn from_json(line: &str) -> Option<Entry> {
+        let fields = parse_object(line)?;
+        let text = |key: &str| fields.iter().find(|(name, _)| name == key).and_then(|(_, value)| value.text());
+        let number = |key: &str| fields.iter().find(|(name, _)| name == key).and_then(|(_, value)| value.number());
+        Some(Entry {
+            time: number("time")?,
+            path: text("path")?,
+            artist: text("artist"),
+            title: text("title"),
+            length: Duration::from_millis(number("length_ms").unwrap_or(0)),
+            played: Duration::from_millis(number("played_ms").unwrap_or(0)),
+        })
+    }
+}
+
+/// `history.jsonl` in the data directory, with an object on each line for
+/// each track played, oldest first.
+pub fn path() -> Option<PathBuf> {
+    Some(config::data_dir()?.join("history.jsonl"))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+/// Add `entry` to the end of the log at `path`. The line goes out in a
+/// single write to a file opened for appending, so copies of the program
+/// playing at the same time don't mix their lines up. A line left half
+/// written by being killed is finished off first, so it's only that line
+/// that's lost.
+pub fn append(path: &Path, entry: &Entry) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let mut line = entry.to_json();
+    line.push('\n');
+    if file.metadata()?.len() > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, '\n');
+        }
+    }
+    file.write_all(line.as_bytes())
+}
+
+/// Every entry in the log at `path`, oldest first, skipping lines that
+/// don't make sense. A missing log has none.
+pub fn read(path: &Path) -> Vec<Entry> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    text.lines().filter_map(Entry::from_json).collect()
+}
+
+/// Parse `--since`: a date like `2024-05-01`, taken as midnight UTC, or how
+/// long ago, like `7d`, `12h` or `30m`. Gives the time in seconds since 1970.
+pub fn parse_since(text: &str, now: u64) -> Result<u64, String> {
+    let invalid = || format!("Invalid time '{}': expected a date like 2024-05-01, or how long ago like 7d, 12h or 30m", text);
+    let text = text.trim();
+    let mut parts = text.splitn(3, '-');
+    if let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next()) {
+        let (year, month, day): (i64, u32, u32) = (
+            year.parse().map_err(|_| invalid())?,
+            month.parse().map_err(|_| invalid())?,
+            day.parse().map_err(|_| invalid())?,
+        );
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        return u64::try_from(days_from_civil(year, month, day) * 86400).map_err(|_| invalid());
+    }
+    let split = text.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let amount: u64 = text[..split].parse().map_err(|_| invalid())?;
+    let unit = match &text[split..] {
+        "w" => 7 * 86400,
+        "d" => 86400,
+        "h" => 3600,
+        "m" => 60,
+        _ => return Err(invalid()),
+    };
+    Ok(now.saturating_sub(amount.saturating_mul(unit)))
+}
+
+/// `time`, in seconds since 1970, as a UTC date and time like
+/// `2024-05-01 21:04`.
+pub fn format_time(time: u64) -> String {
+    let (days, seconds) = ((time / 86400) as i64, time % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
+
+/// `time` in seconds since 1970 as a UTC date, like `2024-03-09`.
+pub fn format_date(time: u64) -> String {
+    let (year, month, day) = civil_from_days((time / 86400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Days to and from the proleptic Gregorian calendar, counting from
+// 1970-01-01, after Howard Hinnant's algorithms
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u32;
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A value in the log: only strings and whole numbers are written.
+enum Value {
+    Text(String),
+    Number(u64),
+    Other,
+}
+
+impl Value {
+    fn text(&self) -> Option<String> {
+        match self {
+            Value::Text(text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    fn number(&self) -> Option<u64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+/// `text` as a JSON string.
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The fields of a flat JSON object, like the ones `Entry::to_json` writes.
+/// Nested objects and arrays aren't needed, so they don't parse.
+fn parse_object(line: &str) -> Option<Vec<(String, Value)>> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = Vec::new();
+    let skip_space = |chars: &mut Peekable<Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_space(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return chars.next().is_none().then_some(fields);
+    }
+    loop {
+        skip_space(&mut chars);
+        let name = parse_string(&mut chars)?;
+        skip_space(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_space(&mut chars);
+        let value = match chars.peek()? {
+            '"' => Value::Text(parse_string(&mut chars)?),
+            c if c.is_ascii_digit() || *c == '-' => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.')) {
+                    number.push(c);
+                }
+                number.parse().map_or(Value::Other, Value::Number)
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                if !matches!(word.as_str(), "true" | "false" | "null") {
+                    return None;
+                }
+                Value::Other
+            }
+        };
+        fields.push((name, value));
+        skip_space(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(text),
+            '\\' => match chars.next()? {
+                '"' => text.push('"'),
+                '\\' => text.push('\\'),
+                '/' => text.push('/'),
+                'b' => text.push('\u{8}'),
+                'f' => text.push('\u{c}'),
+                'n' => text.push('\n'),
+                'r' => text.push('\r'),
+                't' => text.push('\t'),
+                'u' => {
+                    let code: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                    let code = u32::from_str_radix(&code, 16).ok()?;
+                    // Only what `quote` writes: nothing outside the first plane
+                    text.push(char::from_u32(code)?);
+                }
+                _ => return None,
+            },
+            c => text.push(c),
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rodio::Source;
+
+// How long to wait for the server to answer, and for more of the body
+// before the connection counts as dropped
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Redirects followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+// How much decoded audio is kept ready ahead of what's playing
+const AHEAD: Duration = Duration::from_secs(5);
+
+// The whole body is kept in memory, so a longer one isn't streamed, and a
+// chunk bigger than this is taken to be a server gone wrong
+const MAX_BODY: u64 = 1 << 30;
+const MAX_CHUNK: usize = 16 << 20;
+
+// Samples decoded at a time, as whole frames of up to this many channels
+const CHUNK_FRAMES: usize = 4096;
+
+/// Whether `path` is a URL to stream rather than a path on the card.
+pub fn is_url(path: &str) -> bool {
+    let scheme = path.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+    matches!(scheme.as_deref(), Some("http" | "https"))
+}
+
+/// The part of `url` naming the file, without any query or fragment, so the
+/// extension can be told from it.
+pub fn file_part(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+/// The body of a response, as far as it's been downloaded. It's read and
+/// seeked like a file, waiting for the part asked for to arrive. All of it
+/// is kept, since the decoders go back to the start to work out the format.
+pub struct Body {
+    download: Arc<Download>,
+    position: u64,
+}
+
+struct Download {
+    state: Mutex<State>,
+    arrived: Condvar,
+}
+
+struct State {
+    data: Vec<u8>,
+    /// From `Content-Length`, when the server sends it.
+    length: Option<u64>,
+    finished: bool,
+    /// Why the download stopped short.
+    error: Option<String>,
+}
+
+/// Start downloading `url`, giving the body once the server has answered.
+/// Only plain http can be streamed: there's nothing to talk TLS with. A
+/// body of more than `MAX_BODY` bytes stops there with an error.
+pub fn get(url: &str) -> io::Result<Body> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        let (host, port, path) = parse_url(&url)?;
+        let stream = connect(&host, port)?;
+        let mut request = stream.try_clone()?;
+        let authority = if port == 80 { host.clone() } else { format!("{}:{}", host, port) };
+        write!(request, "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: sdsupreme\r\nAccept: */*\r\nConnection: close\r\n\r\n", path, authority)?;
+        let mut reader = BufReader::new(stream);
+        let Head { status, reason, headers } = read_head(&mut reader)?;
+        let header = |name: &str| headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+        match status {
+            200 => {
+                let length = header("Content-Length").and_then(|length| length.parse().ok());
+                if let Some(length) = length.filter(|length| *length > MAX_BODY) {
+                    return Err(io::Error::other(format!("it's {} MB, more than can be streamed", length >> 20)));
+                }
+                let chunked = header("Transfer-Encoding").is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+                return Ok(Body::start(reader, length, chunked));
+            }
+            301 | 302 | 303 | 307 | 308 => {
+                let location = header("Location").ok_or_else(|| invalid("a redirect without a Location"))?;
+                url = if is_url(location) {
+                    location.to_string()
+                } else if location.starts_with('/') {
+                    format!("http://{}{}", authority, location)
+                } else {
+                    format!("http://{}{}{}", authority, &path[..path.rfind('/').map_or(0, |slash| slash + 1)], location)
+                };
+            }
+            _ => return Err(io::Error::other(format!("the server answered {} {}", status, reason))),
+        }
+    }
+    Err(io::Error::other(format!("more than {} redirects", MAX_REDIRECTS)))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("the server sent {}", what))
+}
+
+/// The host, port and path of an http URL.
+fn parse_url(url: &str) -> io::Result<(String, u16, String)> {
+    let (scheme, rest) = url.split_once("://").ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "not a URL"))?;
+    if scheme.eq_ignore_ascii_case("https") {
+        return Err(io::Error::new(ErrorKind::Unsupported, "https isn't supported, only plain http"));
+    }
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    // The fragment is only for the client
+    let path = path.split('#').next().unwrap_or(path).replace(' ', "%20");
+    // An IPv6 address is in brackets, with colons of its own
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => {
+            (host, port.parse().map_err(|_| io::Error::new(ErrorKind::InvalidInput, format!("invalid port '{}'", port)))?)
+        }
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "no host in the URL"));
+    }
+    Ok((host.to_string(), port, path))
+}
+
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(ErrorKind::NotFound, format!("{} wasn't found", host));
+    for address in (host.trim_matches(['[', ']']), port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+/// The start of a response, before the body.
+struct Head {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+fn read_head(reader: &mut impl BufRead) -> io::Result<Head> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    // Like `HTTP/1.1 404 Not Found`
+    let mut parts = line.trim_end().splitn(3, ' ');
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => status.parse().map_err(|_| invalid("a status that isn't a number"))?,
+        _ => return Err(invalid("something that isn't HTTP")),
+    };
+    let reason = parts.next().unwrap_or("").to_string();
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("too little"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(Head { status, reason, headers });
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+}
+
+impl Body {
+    /// Download the rest of `reader` in the background.
+    fn start(mut reader: BufReader<TcpStream>, length: Option<u64>, chunked: bool) -> Body {
+        let download = Arc::new(Download {
+            state: Mutex::new(State { data: Vec::new(), length: length.filter(|_| !chunked), finished: false, error: None }),
+            arrived: Condvar::new(),
+        });
+        let filling = Arc::clone(&download);
+        thread::spawn(move || {
+            let result = if chunked { read_chunked(&mut reader, &filling) } else { read_plain(&mut reader, &filling) };
+            let mut state = filling.state.lock().unwrap();
+            match result {
+                Err(e) => state.error = Some(format!("the download stopped: {}", e)),
+                Ok(()) => match state.length {
+                    Some(length) if (state.data.len() as u64) < length => {
+                        state.error = Some(format!("the connection dropped after {} of {} bytes", state.data.len(), length));
+                    }
+                    _ => {}
+                },
+            }
+            state.finished = true;
+            filling.arrived.notify_all();
+        });
+        Body { download, position: 0 }
+    }
+
+    /// How long the body is, when the server said.
+    pub fn length(&self) -> Option<u64> {
+        self.download.state.lock().unwrap().length
+    }
+
+    /// Why the download stopped short, if it did.
+    pub fn error(&self) -> Option<String> {
+        self.download.state.lock().unwrap().error.clone()
+    }
+
+    /// The first `len` bytes, or all of them if there are fewer, waiting
+    /// for them to arrive.
+    pub fn start_bytes(&self, len: usize) -> Vec<u8> {
+        let mut state = self.download.state.lock().unwrap();
+        while state.data.len() < len && !state.finished {
+            state = self.download.arrived.wait(state).unwrap();
+        }
+        state.data[..len.min(state.data.len())].to_vec()
+    }
+
+    /// Another reader of the same download, from the start.
+    pub fn reopen(&self) -> Body {
+        Body { download: Arc::clone(&self.download), position: 0 }
+    }
+}
+
+impl Download {
+    fn append(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if (state.data.len() + bytes.len()) as u64 > MAX_BODY {
+            return Err(io::Error::other(format!("it went on past {} MB, more than can be streamed", MAX_BODY >> 20)));
+        }
+        state.data.extend_from_slice(bytes);
+        self.arrived.notify_all();
+        Ok(())
+    }
+}
+
+/// Whether anything still reads `download`, besides the thread filling it.
+fn wanted(download: &Arc<Download>) -> bool {
+    Arc::strong_count(download) > 1
+}
+
+fn read_plain(reader: &mut impl Read, download: &Arc<Download>) -> io::Result<()> {
+    let mut buffer = vec![0; 64 * 1024];
+    while wanted(download) {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => download.append(&buffer[..read])?,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// A body sent in chunks, each after its size in hex on a line of its own,
+/// ending with an empty one.
+fn read_chunked(reader: &mut impl BufRead, download: &Arc<Download>) -> io::Result<()> {
+    let mut line = String::new();
+    while wanted(download) {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "it ended in the middle"));
+        }
+        let size = line.trim().split(';').next().unwrap_or("");
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("a chunk without a size"))?;
+        if size == 0 {
+            return Ok(());
+        }
+        if size > MAX_CHUNK {
+            return Err(invalid("a chunk too big to be real"));
+        }
+        let mut chunk = vec![0; size];
+        reader.read_exact(&mut chunk)?;
+        download.append(&chunk)?;
+        // The line break after the chunk
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+    Ok(())
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.download.state.lock().unwrap();
+        while state.data.len() as u64 <= self.position && !state.finished {
+            state = self.download.arrived.wait(state).unwrap();
+        }
+        let start = (self.position as usize).min(state.data.len());
+        let available = &state.data[start..];
+        if available.is_empty() {
+            return match &state.error {
+                Some(error) => Err(io::Error::other(error.clone())),
+                None => Ok(0),
+            };
+        }
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for Body {
+    fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+        let position = match seek {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => {
+                // Without a length, the end is only known once it's all here
+                let mut state = self.download.state.lock().unwrap();
+                while state.length.is_none() && !state.finished {
+                    state = self.download.arrived.wait(state).unwrap();
+                }
+                state.length.unwrap_or(state.data.len() as u64).checked_add_signed(offset)
+            }
+        };
+        self.position = position.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+/// How a streamed track is getting on, shared with the thread decoding it.
+#[derive(Default)]
+pub struct Feed {
+    /// What's playing has caught up with what's been downloaded.
+    stalled: AtomicBool,
+    /// Why it stopped before the end, once it has.
+    error: Mutex<Option<String>>,
+}
+
+impl Feed {
+    pub fn stalled(&self) -> bool {
+        self.stalled.load(Ordering::SeqCst)
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+/// A streamed track, decoded ahead on a thread of its own so that waiting
+/// for the network never holds up the audio thread. When it runs dry it
+/// plays silence until there's more, rather than stuttering.
+pub struct Prefetch {
+    shared: Arc<Shared>,
+    feed: Arc<Feed>,
+    chunk: Vec<i16>,
+    next: usize,
+    sample_rate: u32,
+    channels: u16,
+    total_duration: Option<Duration>,
+}
+
+struct Shared {
+    chunks: Mutex<Decoded>,
+    taken: Condvar,
+}
+
+#[derive(Default)]
+struct Decoded {
+    chunks: VecDeque<Vec<i16>>,
+    samples: usize,
+    finished: bool,
+    /// Nothing's playing it any more.
+    dropped: bool,
+}
+
+impl Prefetch {
+    /// Decode `source`, read from `body`, ahead of it being played.
+    pub fn new(mut source: Box<dyn Source<Item = i16> + Send>, body: &Body) -> Prefetch {
+        let (sample_rate, channels, total_duration) = (source.sample_rate(), source.channels(), source.total_duration());
+        let shared = Arc::new(Shared { chunks: Mutex::new(Decoded::default()), taken: Condvar::new() });
+        let feed = Arc::new(Feed::default());
+        let ahead = (AHEAD.as_secs_f64() * sample_rate as f64) as usize * channels as usize;
+        let chunk_len = CHUNK_FRAMES * channels.max(1) as usize;
+        let (decoding, failed, download) = (Arc::clone(&shared), Arc::clone(&feed), body.reopen());
+        thread::spawn(move || loop {
+            let chunk: Vec<i16> = source.by_ref().take(chunk_len).collect();
+            let mut decoded = decoding.chunks.lock().unwrap();
+            if chunk.is_empty() {
+                // The decoders just stop when the reads fail
+                *failed.error.lock().unwrap() = download.error();
+                decoded.finished = true;
+                return;
+            }
+            decoded.samples += chunk.len();
+            decoded.chunks.push_back(chunk);
+            while decoded.samples > ahead && !decoded.dropped {
+                decoded = decoding.taken.wait(decoded).unwrap();
+            }
+            if decoded.dropped {
+                return;
+            }
+        });
+        Prefetch { shared, feed, chunk: Vec::new(), next: 0, sample_rate, channels, total_duration }
+    }
+
+    /// How it's getting on, for after it's gone to the audio thread.
+    pub fn feed(&self) -> Arc<Feed> {
+        Arc::clone(&self.feed)
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.shared.chunks.lock().unwrap().dropped = true;
+        self.shared.taken.notify_all();
+    }
+}
+
+impl Iterator for Prefetch {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.next >= self.chunk.len() {
+            let mut decoded = self.shared.chunks.lock().unwrap();
+            match decoded.chunks.pop_front() {
+                Some(chunk) => {
+                    decoded.samples -= chunk.len();
+                    self.chunk = chunk;
+                    self.feed.stalled.store(false, Ordering::SeqCst);
+                    self.shared.taken.notify_all();
+                }
+                None if decoded.finished => return None,
+                None => {
+                    // A frame of silence at a time, so the channels stay in step
+                    self.chunk = vec![0; self.channels.max(1) as usize];
+                    self.feed.stalled.store(true, Ordering::SeqCst);
+                }
+            }
+            self.next = 0;
+        }
+        self.next += 1;
+        Some(self.chunk[self.next - 1])
+    }
+}
+
+impl Source for Prefetch {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.total_duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn download() -> Arc<Download> {
+        Arc::new(Download { state: Mutex::new(State { data: Vec::new(), length: None, finished: false, error: None }), arrived: Condvar::new() })
+    }
+
+    #[test]
+    fn chunks_are_put_together() {
+        let download = download();
+        let reading = Arc::clone(&download);
+        read_chunked(&mut Cursor::new(b"3\r\nabc\r\n2;name=value\r\nde\r\n0\r\n\r\n"), &download).unwrap();
+        assert_eq!(reading.state.lock().unwrap().data, b"abcde");
+    }
+
+    #[test]
+    fn chunk_too_big_is_an_error_before_anything_is_kept_for_it() {
+        let download = download();
+        let reading = Arc::clone(&download);
+        let error = read_chunked(&mut Cursor::new(format!("{:x}\r\n", MAX_CHUNK + 1)), &download).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(reading.state.lock().unwrap().data.is_empty());
+    }
+}
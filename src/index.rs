@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::tags::Tags;
+use crate::{mounts, store};
+
+// The first line of an index. Any other means one written by another
+// version, which is looked through again rather than misread.
+const HEADER: &str = "sdsupreme index 2";
+
+// FAT keeps times to two seconds, so a directory changed just before the
+// index was written can look the same as it did then
+const TIME_RESOLUTION: Duration = Duration::from_secs(2);
+
+/// What's known about the music under a path from the last time it was
+/// looked through, kept in the cache directory: what was in each
+/// directory, going by when it last changed, and the tags and length of
+/// each file, going by its size and when it last changed. Paths in it are from the
+/// path looked through, and a path on a card is known by the card's file
+/// system, so it carries over when the card's mounted somewhere else.
+pub struct Index {
+    /// Where it's kept, if anywhere.
+    file: Option<PathBuf>,
+    /// What it's kept for, to tell two paths apart that came out the same.
+    key: String,
+    /// When what's in it about the directories was read, going by when the
+    /// scan started.
+    written: SystemTime,
+    directories: HashMap<String, Directory>,
+    tags: HashMap<String, Stamped>,
+    lengths: HashMap<String, Timed>,
+    /// Set once it's been thrown away, so it isn't written again.
+    forgotten: bool,
+}
+
+/// What was in a directory, by name, and when it last changed.
+#[derive(Clone)]
+pub struct Directory {
+    pub modified: u128,
+    pub entries: Vec<(String, Kind)>,
+}
+
+/// What an entry in a directory is, going by what the directory says,
+/// which for a link isn't what it leads to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Directory,
+    Link,
+    Other,
+}
+
+impl Kind {
+    fn letter(self) -> char {
+        match self {
+            Kind::File => 'f',
+            Kind::Directory => 'd',
+            Kind::Link => 'l',
+            Kind::Other => 'o',
+        }
+    }
+
+    fn from_letter(letter: &str) -> Option<Kind> {
+        match letter {
+            "f" => Some(Kind::File),
+            "d" => Some(Kind::Directory),
+            "l" => Some(Kind::Link),
+            "o" => Some(Kind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// The tags of a file, with its size and when it last changed when they
+/// were read, so they're only used while it's still the same.
+#[derive(Clone)]
+pub struct Stamped {
+    pub size: u64,
+    pub modified: u128,
+    pub tags: Tags,
+}
+
+/// How long a file lasts, or None when its headers don't say, with its size
+/// and when it last changed when that was read, like `Stamped`.
+#[derive(Clone)]
+pub struct Timed {
+    pub size: u64,
+    pub modified: u128,
+    pub length: Option<Duration>,
+}
+
+/// The size of `path` and when it last changed, in nanoseconds since 1970.
+pub fn stamp(path: &Path) -> Option<(u64, u128)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.len(), nanoseconds(metadata.modified().ok()?)))
+}
+
+/// `time` in nanoseconds since 1970.
+pub fn nanoseconds(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos())
+}
+
+impl Index {
+    /// The index for `root`, kept in the `cache` directory, or an empty one
+    /// when there isn't one, it can't be read or it's from another version.
+    /// With `rescan`, it starts empty anyway, so everything's looked through
+    /// again. Without a `cache`, it's never kept.
+    pub fn load(root: &Path, cache: Option<&Path>, rescan: bool) -> Index {
+        let key = key(root);
+        let file = cache.map(|dir| dir.join("index").join(format!("{:016x}", fnv1a(key.as_bytes()))));
+        let empty = |file| Index { file, key: key.clone(), written: UNIX_EPOCH, directories: HashMap::new(), tags: HashMap::new(), lengths: HashMap::new(), forgotten: false };
+        if rescan {
+            return empty(file);
+        }
+        let Some(text) = file.as_ref().and_then(|file| fs::read_to_string(file).ok()) else {
+            return empty(file);
+        };
+        match parse(&text, &key) {
+            Some((written, directories, tags, lengths)) => Index { file, key: key.clone(), written, directories, tags, lengths, forgotten: false },
+            None => empty(file),
+        }
+    }
+
+    /// What was in the directory at `relative` when it last changed at
+    /// `modified`, if it hasn't changed since.
+    pub fn listing(&self, relative: &str, modified: u128) -> Option<&[(String, Kind)]> {
+        let directory = self.directories.get(relative)?;
+        // Only trusted when the index was written well after the change
+        let settled = nanoseconds(self.written).saturating_sub(TIME_RESOLUTION.as_nanos()) > modified;
+        (directory.modified == modified && settled).then_some(directory.entries.as_slice())
+    }
+
+    /// The tags of the file at `relative`, as they were last read.
+    pub fn tags(&self, relative: &str) -> Option<&Stamped> {
+        self.tags.get(relative)
+    }
+
+    /// How long the file at `relative` lasts, as it was last read.
+    pub fn length(&self, relative: &str) -> Option<&Timed> {
+        self.lengths.get(relative)
+    }
+
+    /// Replace what's known about the directories with what was found
+    /// looking through them in a scan started at `started`.
+    pub fn set_directories(&mut self, directories: Vec<(String, Directory)>, started: SystemTime) {
+        self.directories = directories.into_iter().collect();
+        self.written = started;
+    }
+
+    /// A copy of what's known about the directories alone, to look through
+    /// them with while this one's in use. It's never kept.
+    pub fn snapshot(&self) -> Index {
+        Index { file: None, key: self.key.clone(), written: self.written, directories: self.directories.clone(), tags: HashMap::new(), lengths: HashMap::new(), forgotten: false }
+    }
+
+    /// Each directory known, and when it last changed.
+    pub fn directories(&self) -> impl Iterator<Item = (&str, u128)> {
+        self.directories.iter().map(|(relative, directory)| (relative.as_str(), directory.modified))
+    }
+
+    /// Keep the tags of the file at `relative`.
+    pub fn set_tags(&mut self, relative: String, tags: Stamped) {
+        self.tags.insert(relative, tags);
+    }
+
+    /// Keep how long the file at `relative` lasts.
+    pub fn set_length(&mut self, relative: String, length: Timed) {
+        self.lengths.insert(relative, length);
+    }
+
+    /// Throw the index away, so everything's looked through again next time.
+    pub fn forget(&mut self) -> io::Result<()> {
+        self.forgotten = true;
+        match &self.file {
+            Some(file) => fs::remove_file(file).or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) }),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the index, with the tags and lengths of only those of `files`
+    /// it has.
+    pub fn save(&self, files: &[String]) -> io::Result<()> {
+        let Some(file) = self.file.as_ref().filter(|_| !self.forgotten) else {
+            return Ok(());
+        };
+        let mut text = format!("{}\nkey {}\nwritten {}\n", HEADER, escape(&self.key), nanoseconds(self.written));
+        let mut directories: Vec<_> = self.directories.iter().collect();
+        directories.sort_by(|a, b| a.0.cmp(b.0));
+        for (relative, directory) in directories {
+            text += &format!("d {} {}\n", directory.modified, escape(relative));
+            for (name, kind) in &directory.entries {
+                text += &format!("e {} {}\n", kind.letter(), escape(name));
+            }
+        }
+        for relative in files {
+            let Some(stamped) = self.tags.get(relative) else {
+                continue;
+            };
+            let tags = &stamped.tags;
+            let fields = [
+                escape(relative),
+                optional(&tags.artist),
+                optional(&tags.title),
+                optional(&tags.album),
+                number(tags.track),
+                optional(&tags.genre),
+                number(tags.year),
+                number(tags.track_gain),
+                number(tags.album_gain),
+                number(tags.track_peak),
+                number(tags.album_peak),
+            ];
+            text += &format!("t {} {} {}\n", stamped.size, stamped.modified, fields.join("\t"));
+        }
+        for relative in files {
+            if let Some(timed) = self.lengths.get(relative) {
+                text += &format!("l {} {} {} {}\n", timed.size, timed.modified, number(timed.length.map(|length| length.as_millis())), escape(relative));
+            }
+        }
+        store::update(file, |_| text)
+    }
+}
+
+/// What the index for `root` is kept under: the file system it's on and
+/// where it is in that when that's known, or else the path.
+fn key(root: &Path) -> String {
+    let full = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    match mounts::filesystem_uuid(&full) {
+        Some((uuid, mount)) => format!("uuid {} {}", uuid, full.strip_prefix(&mount).unwrap_or(&full).display()),
+        None => format!("path {}", full.display()),
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`, for a file name from any path.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+type Parsed = (SystemTime, HashMap<String, Directory>, HashMap<String, Stamped>, HashMap<String, Timed>);
+
+/// What an index written for `key` says, or None when anything in it
+/// doesn't make sense, so that it's looked through again instead.
+fn parse(text: &str, key: &str) -> Option<Parsed> {
+    let mut lines = text.lines();
+    if lines.next()? != HEADER || unescape(lines.next()?.strip_prefix("key ")?)? != key {
+        return None;
+    }
+    let written = UNIX_EPOCH + Duration::from_nanos(lines.next()?.strip_prefix("written ")?.parse().ok()?);
+    let (mut directories, mut tags, mut lengths) = (HashMap::new(), HashMap::new(), HashMap::new());
+    let mut current: Option<(String, Directory)> = None;
+    for line in lines {
+        let (kind, rest) = line.split_once(' ')?;
+        match kind {
+            "d" => {
+                let (modified, relative) = rest.split_once(' ')?;
+                if let Some((relative, directory)) = current.replace((unescape(relative)?, Directory { modified: modified.parse().ok()?, entries: Vec::new() })) {
+                    directories.insert(relative, directory);
+                }
+            }
+            "e" => {
+                let (kind, name) = rest.split_once(' ')?;
+                current.as_mut()?.1.entries.push((unescape(name)?, Kind::from_letter(kind)?));
+            }
+            "t" => {
+                let mut parts = rest.splitn(3, ' ');
+                let (size, modified) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+                let fields: Vec<&str> = parts.next()?.split('\t').collect();
+                let [relative, artist, title, album, track, genre, year, track_gain, album_gain, track_peak, album_peak] = fields[..] else {
+                    return None;
+                };
+                let tags_read = Tags {
+                    artist: unoptional(artist)?,
+                    title: unoptional(title)?,
+                    album: unoptional(album)?,
+                    track: unnumber(track)?,
+                    genre: unoptional(genre)?,
+                    year: unnumber(year)?,
+                    track_gain: unnumber(track_gain)?,
+                    album_gain: unnumber(album_gain)?,
+                    track_peak: unnumber(track_peak)?,
+                    album_peak: unnumber(album_peak)?,
+                };
+                tags.insert(unescape(relative)?, Stamped { size, modified, tags: tags_read });
+            }
+            "l" => {
+                let mut parts = rest.splitn(4, ' ');
+                let (size, modified) = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+                let length = unnumber(parts.next()?)?.map(Duration::from_millis);
+                lengths.insert(unescape(parts.next()?)?, Timed { size, modified, length });
+            }
+            _ => return None,
+        }
+    }
+    if let Some((relative, directory)) = current {
+        directories.insert(relative, directory);
+    }
+    Some((written, directories, tags, lengths))
+}
+
+/// `text` with backslashes, tabs and line breaks written as `\\`, `\t`
+/// and `\n`, so it fits in a field of a line.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped)
+}
+
+/// A tag that may not be there: `-` when it isn't, and `=` before the
+/// value when it is, so a value of `-` can't be mistaken for none.
+fn optional(value: &Option<String>) -> String {
+    value.as_ref().map_or("-".to_string(), |value| format!("={}", escape(value)))
+}
+
+fn unoptional(field: &str) -> Option<Option<String>> {
+    match field {
+        "-" => Some(None),
+        _ => Some(Some(unescape(field.strip_prefix('=')?)?)),
+    }
+}
+
+fn number<T: ToString>(value: Option<T>) -> String {
+    value.map_or("-".to_string(), |value| value.to_string())
+}
+
+fn unnumber<T: std::str::FromStr>(field: &str) -> Option<Option<T>> {
+    match field {
+        "-" => Some(None),
+        _ => Some(Some(field.parse().ok()?)),
+    }
+}
@@ -0,0 +1,435 @@
+// A decoder for baseline JPEG, the kind nearly all embedded cover art is.
+// Progressive and arithmetic-coded images aren't supported and decode to
+// nothing, as does anything malformed.
+
+use std::f32::consts::PI;
+
+/// Decoded pixels, three bytes of RGB each, row by row.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+// Position in the 8x8 block of each coefficient, in the order they're stored
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21,
+    28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54,
+    47, 55, 62, 63,
+];
+
+// Biggest image decoded, so a corrupt size can't ask for gigabytes
+const MAX_PIXELS: usize = 64 << 20;
+
+/// A Huffman table, looked up the way the standard describes: codes of
+/// each length are consecutive, so a code's value says which symbol it is.
+#[derive(Clone, Default)]
+struct Huffman {
+    /// Largest code of each length from 1 to 16, or -1 for none.
+    max_code: [i32; 17],
+    /// Index into `symbols` of the first code of each length, less that code.
+    offset: [i32; 17],
+    symbols: Vec<u8>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8], symbols: &[u8]) -> Huffman {
+        let mut table = Huffman { max_code: [-1; 17], offset: [0; 17], symbols: symbols.to_vec() };
+        let (mut code, mut index) = (0i32, 0i32);
+        for len in 1..=16 {
+            let count = counts[len - 1] as i32;
+            table.offset[len] = index - code;
+            if count > 0 {
+                table.max_code[len] = code + count - 1;
+            }
+            code = (code + count) << 1;
+            index += count;
+        }
+        table
+    }
+}
+
+#[derive(Clone)]
+struct Component {
+    id: u8,
+    horizontal: usize,
+    vertical: usize,
+    quantization: usize,
+    dc_table: usize,
+    ac_table: usize,
+    /// Decoded samples, a whole number of blocks wide and tall.
+    samples: Vec<u8>,
+    stride: usize,
+    /// Last DC coefficient, which the next is coded relative to.
+    dc: i32,
+}
+
+/// Reads the entropy-coded data between markers a bit at a time, taking out
+/// the zero byte stuffed after each 0xFF.
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bit(&mut self) -> Option<u32> {
+        if self.count == 0 {
+            let byte = *self.data.get(self.position)?;
+            if byte == 0xFF {
+                match self.data.get(self.position + 1) {
+                    Some(0) => self.position += 1,
+                    // A marker: the data has run out
+                    _ => return None,
+                }
+            }
+            self.position += 1;
+            self.buffer = byte as u32;
+            self.count = 8;
+        }
+        self.count -= 1;
+        Some((self.buffer >> self.count) & 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Option<i32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()? as i32;
+        }
+        Some(value)
+    }
+
+    fn decode(&mut self, table: &Huffman) -> Option<u8> {
+        let mut code = 0i32;
+        for len in 1..=16 {
+            code = (code << 1) | self.bit()? as i32;
+            if code <= table.max_code[len] {
+                return table.symbols.get((code + table.offset[len]) as usize).copied();
+            }
+        }
+        None
+    }
+
+    /// A coefficient of `size` bits, whose top bit says whether it's positive.
+    fn extended(&mut self, size: u8) -> Option<i32> {
+        if size == 0 {
+            return Some(0);
+        }
+        let value = self.bits(size as u32)?;
+        Some(if value < 1 << (size - 1) { value - (1 << size) + 1 } else { value })
+    }
+
+    /// Drop what's left of the current byte and skip a restart marker.
+    fn restart(&mut self) {
+        self.count = 0;
+        while self.position + 1 < self.data.len() {
+            let (byte, next) = (self.data[self.position], self.data[self.position + 1]);
+            self.position += 1;
+            if byte == 0xFF && (0xD0..=0xD7).contains(&next) {
+                self.position += 1;
+                return;
+            }
+        }
+    }
+}
+
+/// Width and height of a JPEG image of any kind, from its frame header.
+pub fn dimensions(data: &[u8]) -> Option<(usize, usize)> {
+    let mut position = 2;
+    loop {
+        if *data.get(position)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(position + 1)?;
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        let segment = data.get(position + 4..)?;
+        // Every SOF marker, leaving out DHT, JPG and DAC that share the range
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            let height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]) as usize;
+            let width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]) as usize;
+            return Some((width, height));
+        }
+        position += 2 + u16::from_be_bytes([*data.get(position + 2)?, *data.get(position + 3)?]) as usize;
+    }
+}
+
+/// Decode a baseline JPEG image to RGB.
+pub fn decode(data: &[u8]) -> Option<Image> {
+    if data.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut quantization = [[0u16; 64]; 4];
+    let mut dc_tables = vec![Huffman::default(); 4];
+    let mut ac_tables = vec![Huffman::default(); 4];
+    let mut components: Vec<Component> = Vec::new();
+    let (mut width, mut height) = (0, 0);
+    let mut restart_interval = 0;
+    let mut position = 2;
+    loop {
+        // Markers can be padded with any number of 0xFF bytes
+        while *data.get(position)? == 0xFF && *data.get(position + 1)? == 0xFF {
+            position += 1;
+        }
+        if *data.get(position)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(position + 1)?;
+        if marker == 0xD9 {
+            break;
+        }
+        let len = u16::from_be_bytes([*data.get(position + 2)?, *data.get(position + 3)?]) as usize;
+        let segment = data.get(position + 4..position + 2 + len)?;
+        position += 2 + len;
+        match marker {
+            0xDB => {
+                let mut rest = segment;
+                while let Some((&info, tail)) = rest.split_first() {
+                    let (wide, table) = (info >> 4 != 0, (info & 3) as usize);
+                    let size = if wide { 128 } else { 64 };
+                    let values = tail.get(..size)?;
+                    for (index, value) in quantization[table].iter_mut().enumerate() {
+                        *value = if wide { u16::from_be_bytes([values[index * 2], values[index * 2 + 1]]) } else { values[index] as u16 };
+                    }
+                    rest = &tail[size..];
+                }
+            }
+            0xC4 => {
+                let mut rest = segment;
+                while let Some((&info, tail)) = rest.split_first() {
+                    let counts = tail.get(..16)?;
+                    let total: usize = counts.iter().map(|&count| count as usize).sum();
+                    let symbols = tail.get(16..16 + total)?;
+                    let table = Huffman::new(counts, symbols);
+                    let slot = (info & 3) as usize;
+                    if info >> 4 == 0 {
+                        dc_tables[slot] = table;
+                    } else {
+                        ac_tables[slot] = table;
+                    }
+                    rest = &tail[16 + total..];
+                }
+            }
+            // Baseline and extended sequential, Huffman coded
+            0xC0 | 0xC1 => {
+                if *segment.first()? != 8 {
+                    return None;
+                }
+                height = u16::from_be_bytes([*segment.get(1)?, *segment.get(2)?]) as usize;
+                width = u16::from_be_bytes([*segment.get(3)?, *segment.get(4)?]) as usize;
+                let count = *segment.get(5)? as usize;
+                for index in 0..count {
+                    let spec = segment.get(6 + index * 3..9 + index * 3)?;
+                    let (horizontal, vertical) = ((spec[1] >> 4) as usize, (spec[1] & 15) as usize);
+                    if !(1..=4).contains(&horizontal) || !(1..=4).contains(&vertical) {
+                        return None;
+                    }
+                    components.push(Component {
+                        id: spec[0],
+                        horizontal,
+                        vertical,
+                        quantization: (spec[2] & 3) as usize,
+                        dc_table: 0,
+                        ac_table: 0,
+                        samples: Vec::new(),
+                        stride: 0,
+                        dc: 0,
+                    });
+                }
+                if width == 0 || height == 0 || width * height > MAX_PIXELS || !(count == 1 || count == 3) {
+                    return None;
+                }
+            }
+            // Progressive, lossless, hierarchical and arithmetic coding
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return None,
+            0xDD => restart_interval = u16::from_be_bytes([*segment.first()?, *segment.get(1)?]) as usize,
+            0xDA => {
+                if components.is_empty() {
+                    return None;
+                }
+                let count = *segment.first()? as usize;
+                let mut scan = Vec::new();
+                for index in 0..count {
+                    let spec = segment.get(1 + index * 2..3 + index * 2)?;
+                    let component = components.iter().position(|component| component.id == spec[0])?;
+                    components[component].dc_table = (spec[1] >> 4 & 3) as usize;
+                    components[component].ac_table = (spec[1] & 3) as usize;
+                    scan.push(component);
+                }
+                let mut bits = Bits { data, position, buffer: 0, count: 0 };
+                let tables = Tables { quantization: &quantization, dc: &dc_tables, ac: &ac_tables };
+                decode_scan(&mut components, &scan, &tables, &mut bits, (width, height), restart_interval)?;
+                // Carry on from the marker after the entropy-coded data,
+                // which may be followed by padding or a stray restart marker
+                position = bits.position;
+                while position + 1 < data.len() {
+                    let next = data[position + 1];
+                    if data[position] == 0xFF && next != 0 && !(0xD0..=0xD7).contains(&next) {
+                        break;
+                    }
+                    position += 1;
+                }
+                // A single scan holds every component of a baseline image
+                if components.iter().all(|component| !component.samples.is_empty()) {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    if components.is_empty() || components.iter().any(|component| component.samples.is_empty()) {
+        return None;
+    }
+    Some(to_rgb(&components, width, height))
+}
+
+struct Tables<'a> {
+    quantization: &'a [[u16; 64]; 4],
+    dc: &'a [Huffman],
+    ac: &'a [Huffman],
+}
+
+fn decode_scan(
+    components: &mut [Component],
+    scan: &[usize],
+    tables: &Tables,
+    bits: &mut Bits,
+    (width, height): (usize, usize),
+    restart_interval: usize,
+) -> Option<()> {
+    let max_horizontal = components.iter().map(|component| component.horizontal).max()?;
+    let max_vertical = components.iter().map(|component| component.vertical).max()?;
+    let mcus_across = width.div_ceil(8 * max_horizontal);
+    let mcus_down = height.div_ceil(8 * max_vertical);
+    for &index in scan {
+        let component = &mut components[index];
+        component.stride = mcus_across * component.horizontal * 8;
+        component.samples = vec![0; component.stride * mcus_down * component.vertical * 8];
+        component.dc = 0;
+    }
+    // A scan of one component has a block per MCU, covering only that
+    // component's own area
+    let (across, down) = match scan {
+        [only] => {
+            let component = &components[*only];
+            let component_width = (width * component.horizontal).div_ceil(max_horizontal);
+            let component_height = (height * component.vertical).div_ceil(max_vertical);
+            (component_width.div_ceil(8), component_height.div_ceil(8))
+        }
+        _ => (mcus_across, mcus_down),
+    };
+    let cosines = cosine_table();
+    let mut block = [0i32; 64];
+    for mcu in 0..across * down {
+        if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+            bits.restart();
+            for &index in scan {
+                components[index].dc = 0;
+            }
+        }
+        let (mcu_x, mcu_y) = (mcu % across, mcu / across);
+        for &index in scan {
+            let component = &mut components[index];
+            let (blocks_across, blocks_down) = if scan.len() == 1 { (1, 1) } else { (component.horizontal, component.vertical) };
+            for block_y in 0..blocks_down {
+                for block_x in 0..blocks_across {
+                    decode_block(component, tables, bits, &mut block)?;
+                    let x = (mcu_x * blocks_across + block_x) * 8;
+                    let y = (mcu_y * blocks_down + block_y) * 8;
+                    idct(&block, &cosines, &mut component.samples, y * component.stride + x, component.stride);
+                }
+            }
+        }
+    }
+    Some(())
+}
+
+fn decode_block(component: &mut Component, tables: &Tables, bits: &mut Bits, block: &mut [i32; 64]) -> Option<()> {
+    let quantization = &tables.quantization[component.quantization];
+    *block = [0; 64];
+    let size = bits.decode(&tables.dc[component.dc_table])?;
+    component.dc += bits.extended(size)?;
+    block[0] = component.dc * quantization[0] as i32;
+    let mut k = 1;
+    while k < 64 {
+        let symbol = bits.decode(&tables.ac[component.ac_table])?;
+        let (run, size) = ((symbol >> 4) as usize, symbol & 15);
+        if size == 0 {
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        if k > 63 {
+            return None;
+        }
+        block[ZIGZAG[k]] = bits.extended(size)? * quantization[k] as i32;
+        k += 1;
+    }
+    Some(())
+}
+
+/// The factors of the inverse DCT: the cosine of each sample position `x`
+/// and frequency `u`, with the DC term scaled down.
+fn cosine_table() -> [[f32; 8]; 8] {
+    let mut table = [[0f32; 8]; 8];
+    for (x, row) in table.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            let scale = if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+            *value = scale * (((2 * x + 1) * u) as f32 * PI / 16.0).cos();
+        }
+    }
+    table
+}
+
+/// Inverse DCT of `block` into 8x8 samples at `start` in `out`, done as
+/// two passes of one-dimensional transforms.
+fn idct(block: &[i32; 64], table: &[[f32; 8]; 8], out: &mut [u8], start: usize, stride: usize) {
+    let mut rows = [0f32; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            rows[y * 8 + x] = (0..8).map(|u| table[x][u] * block[y * 8 + u] as f32).sum::<f32>() / 2.0;
+        }
+    }
+    for x in 0..8 {
+        for y in 0..8 {
+            let value = (0..8).map(|v| table[y][v] * rows[v * 8 + x]).sum::<f32>() / 2.0;
+            out[start + y * stride + x] = (value + 128.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+/// Combine the components into RGB, stretching subsampled ones back to the
+/// full size by repeating their samples.
+fn to_rgb(components: &[Component], width: usize, height: usize) -> Image {
+    let max_horizontal = components.iter().map(|component| component.horizontal).max().unwrap_or(1);
+    let max_vertical = components.iter().map(|component| component.vertical).max().unwrap_or(1);
+    let sample = |component: &Component, x: usize, y: usize| {
+        let x = x * component.horizontal / max_horizontal;
+        let y = y * component.vertical / max_vertical;
+        component.samples[y * component.stride + x] as f32
+    };
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            match components {
+                [luma, blue, red] => {
+                    let (luma, blue, red) = (sample(luma, x, y), sample(blue, x, y) - 128.0, sample(red, x, y) - 128.0);
+                    for value in [luma + 1.402 * red, luma - 0.344136 * blue - 0.714136 * red, luma + 1.772 * blue] {
+                        rgb.push(value.round().clamp(0.0, 255.0) as u8);
+                    }
+                }
+                _ => {
+                    let gray = sample(&components[0], x, y) as u8;
+                    rgb.extend_from_slice(&[gray, gray, gray]);
+                }
+            }
+        }
+    }
+    Image { width, height, rgb }
+}
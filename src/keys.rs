@@ -0,0 +1,598 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// What the terminal is currently showing. Keys mean different things in
+/// each view.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum View {
+    List,
+    /// Artists, their albums and the albums' tracks.
+    Library,
+    Playing,
+}
+
+impl View {
+    fn label(self) -> &'static str {
+        match self {
+            View::List => "track list",
+            View::Library => "library view",
+            View::Playing => "now playing view",
+        }
+    }
+}
+
+/// What a key does once it's been looked up in `BINDINGS`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    HalfPageUp,
+    HalfPageDown,
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+    Search,
+    /// Narrow the list by genre and year.
+    Filter,
+    Play,
+    /// Jump to the next file starting with the key pressed.
+    JumpToInitial,
+    /// Add the highlighted track to the end of the queue that's playing, or
+    /// to play next when true. Jumps by letter like `JumpToInitial` when
+    /// nothing's playing.
+    Enqueue(bool),
+    /// Sort the list and queue by the next sort key.
+    CycleSort,
+    /// Show the next of the sets of columns in the list: none, size, date,
+    /// or both.
+    CycleColumns,
+    /// List what's been played lately, or go back to the whole list.
+    ShowRecent,
+    /// List what's been played most, or go back to the whole list.
+    ShowMostPlayed,
+    /// Show only the favorites in the list, or every file again.
+    ShowFavorites,
+    /// Look through the directories again for music put there or taken
+    /// away, without waiting for the change to be noticed.
+    Refresh,
+    /// Throw away the index of what's on the card, so it's all looked
+    /// through again.
+    ForgetIndex,
+    /// Browse by artist and album.
+    ShowLibrary,
+    /// Leave the library for the list of every file.
+    ShowFiles,
+    /// Open the highlighted artist or album, or play from the highlighted track.
+    Open,
+    /// Go up from an album to its artist, or from an artist to all artists.
+    Back,
+    ShowPlaying,
+    TogglePause,
+    Next,
+    Previous,
+    Restart,
+    /// Jump to the tenth of the track given by the digit pressed.
+    SeekToDigit,
+    Seek(i64),
+    Faster,
+    Slower,
+    /// Mark the start of an A-B loop.
+    LoopStart,
+    /// Mark its end, and start looping.
+    LoopEnd,
+    ClearLoop,
+    /// Go back to where the track was stopped last time.
+    ResumeSaved,
+    /// Add a bookmark where the track has got to.
+    Bookmark,
+    NextBookmark,
+    PreviousBookmark,
+    /// List the track's bookmarks, to jump to or delete.
+    ShowBookmarks,
+    /// Show what's coming up in the queue, to reorder or take out.
+    ShowQueue,
+    /// Ask where to write the queue as a playlist.
+    ExportQueue,
+    /// Make the track a favorite, or not.
+    ToggleFavorite,
+    /// Give the track this many stars, or clear them if it has them already.
+    Rate(u8),
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    /// Mix down to mono, or back to stereo.
+    Mono,
+    /// Move the balance towards the right, or the left for false.
+    Balance(bool),
+    /// Turn the bass or treble up, or down for false.
+    Bass(bool),
+    Treble(bool),
+    Shuffle,
+    Repeat,
+    ToggleRemaining,
+    /// Show or hide the lyrics of tracks with an `.lrc` file.
+    ToggleLyrics,
+    /// Show or hide the spectrum under the progress bar.
+    ToggleSpectrum,
+    /// Show or hide the level meter.
+    ToggleMeter,
+    Stop,
+    /// Ask how long until playback stops and the program quits.
+    Sleep,
+    ShowList,
+    Help,
+    Quit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// The key on its own, or with Shift for characters.
+    Code(KeyCode),
+    /// A letter pressed with Ctrl.
+    Ctrl(char),
+    /// A key other than a character pressed with Shift, like Shift-Left.
+    Shift(KeyCode),
+    /// The same character pressed twice in a row, like `gg`.
+    Twice(char),
+    /// Any letter or digit without a binding of its own earlier in the table.
+    AnyAlphanumeric,
+}
+
+pub struct Binding {
+    pub view: View,
+    /// What the config file calls the binding. Bindings in different views
+    /// can share a name, and are then configured together.
+    pub name: &'static str,
+    pub keys: &'static [Key],
+    pub action: Action,
+    pub help: &'static str,
+}
+
+const fn char_key(c: char) -> Key {
+    Key::Code(KeyCode::Char(c))
+}
+
+/// The default key bindings. Earlier bindings win when more than one matches.
+const BINDINGS: &[Binding] = &[
+    Binding { view: View::List, name: "up", keys: &[Key::Code(KeyCode::Up), char_key('k')], action: Action::MoveUp, help: "move up" },
+    Binding { view: View::List, name: "down", keys: &[Key::Code(KeyCode::Down), char_key('j')], action: Action::MoveDown, help: "move down" },
+    Binding { view: View::List, name: "half_page_up", keys: &[Key::Ctrl('u')], action: Action::HalfPageUp, help: "move up half a page" },
+    Binding { view: View::List, name: "half_page_down", keys: &[Key::Ctrl('d')], action: Action::HalfPageDown, help: "move down half a page" },
+    Binding {
+        view: View::List,
+        name: "page_up",
+        keys: &[Key::Code(KeyCode::PageUp), Key::Code(KeyCode::Left)],
+        action: Action::PageUp,
+        help: "previous page",
+    },
+    Binding {
+        view: View::List,
+        name: "page_down",
+        keys: &[Key::Code(KeyCode::PageDown), Key::Code(KeyCode::Right)],
+        action: Action::PageDown,
+        help: "next page",
+    },
+    Binding { view: View::List, name: "top", keys: &[Key::Code(KeyCode::Home), Key::Twice('g')], action: Action::Top, help: "first file" },
+    Binding { view: View::List, name: "bottom", keys: &[Key::Code(KeyCode::End), char_key('G')], action: Action::Bottom, help: "last file" },
+    Binding { view: View::List, name: "play", keys: &[Key::Code(KeyCode::Enter)], action: Action::Play, help: "play the highlighted file" },
+    Binding { view: View::List, name: "search", keys: &[char_key('/')], action: Action::Search, help: "search; type to filter, Esc to cancel" },
+    Binding {
+        view: View::List,
+        name: "filter",
+        keys: &[Key::Ctrl('f')],
+        action: Action::Filter,
+        help: "filter by tags, like genre:jazz year:1970-1979",
+    },
+    Binding { view: View::List, name: "sort", keys: &[Key::Ctrl('s')], action: Action::CycleSort, help: "sort by path, name, date, size or track" },
+    Binding { view: View::List, name: "columns", keys: &[Key::Ctrl('t')], action: Action::CycleColumns, help: "show size and date columns" },
+    Binding { view: View::List, name: "library", keys: &[Key::Ctrl('b')], action: Action::ShowLibrary, help: "browse by artist and album" },
+    Binding { view: View::List, name: "recent", keys: &[Key::Ctrl('r')], action: Action::ShowRecent, help: "recently played, or back to every file" },
+    Binding { view: View::List, name: "most_played", keys: &[Key::Ctrl('p')], action: Action::ShowMostPlayed, help: "most played, or back to every file" },
+    Binding { view: View::List, name: "favorites", keys: &[char_key('*')], action: Action::ShowFavorites, help: "only favorites, or every file" },
+    Binding { view: View::List, name: "refresh", keys: &[Key::Code(KeyCode::F(5))], action: Action::Refresh, help: "look for music put on the card or taken off" },
+    Binding { view: View::List, name: "forget_index", keys: &[Key::Ctrl('e')], action: Action::ForgetIndex, help: "forget the index, so the next start looks through everything" },
+    Binding { view: View::List, name: "now_playing", keys: &[Key::Code(KeyCode::Tab)], action: Action::ShowPlaying, help: "back to what's playing" },
+    Binding { view: View::List, name: "help", keys: &[char_key('?')], action: Action::Help, help: "show this help" },
+    Binding { view: View::List, name: "quit", keys: &[char_key('q'), Key::Code(KeyCode::Esc)], action: Action::Quit, help: "quit" },
+    Binding { view: View::List, name: "enqueue", keys: &[char_key('a')], action: Action::Enqueue(false), help: "add to the end of the queue while playing" },
+    Binding { view: View::List, name: "play_next", keys: &[char_key('A')], action: Action::Enqueue(true), help: "play next while playing" },
+    Binding { view: View::List, name: "jump_to_letter", keys: &[Key::AnyAlphanumeric], action: Action::JumpToInitial, help: "jump to a file starting with it" },
+    Binding { view: View::Library, name: "up", keys: &[Key::Code(KeyCode::Up), char_key('k')], action: Action::MoveUp, help: "move up" },
+    Binding { view: View::Library, name: "down", keys: &[Key::Code(KeyCode::Down), char_key('j')], action: Action::MoveDown, help: "move down" },
+    Binding { view: View::Library, name: "half_page_up", keys: &[Key::Ctrl('u')], action: Action::HalfPageUp, help: "move up half a page" },
+    Binding { view: View::Library, name: "half_page_down", keys: &[Key::Ctrl('d')], action: Action::HalfPageDown, help: "move down half a page" },
+    Binding {
+        view: View::Library,
+        name: "page_up",
+        keys: &[Key::Code(KeyCode::PageUp), Key::Code(KeyCode::Left)],
+        action: Action::PageUp,
+        help: "previous page",
+    },
+    Binding {
+        view: View::Library,
+        name: "page_down",
+        keys: &[Key::Code(KeyCode::PageDown), Key::Code(KeyCode::Right)],
+        action: Action::PageDown,
+        help: "next page",
+    },
+    Binding { view: View::Library, name: "top", keys: &[Key::Code(KeyCode::Home), Key::Twice('g')], action: Action::Top, help: "first entry" },
+    Binding { view: View::Library, name: "bottom", keys: &[Key::Code(KeyCode::End), char_key('G')], action: Action::Bottom, help: "last entry" },
+    Binding { view: View::Library, name: "play", keys: &[Key::Code(KeyCode::Enter)], action: Action::Open, help: "open, or play the album from this track" },
+    Binding { view: View::Library, name: "back", keys: &[Key::Code(KeyCode::Backspace)], action: Action::Back, help: "up a level" },
+    Binding { view: View::Library, name: "library", keys: &[Key::Ctrl('b')], action: Action::ShowFiles, help: "back to the file list" },
+    Binding { view: View::Library, name: "now_playing", keys: &[Key::Code(KeyCode::Tab)], action: Action::ShowPlaying, help: "back to what's playing" },
+    Binding { view: View::Library, name: "help", keys: &[char_key('?')], action: Action::Help, help: "show this help" },
+    Binding { view: View::Library, name: "quit", keys: &[char_key('q'), Key::Code(KeyCode::Esc)], action: Action::Quit, help: "quit" },
+    Binding { view: View::Library, name: "enqueue", keys: &[char_key('a')], action: Action::Enqueue(false), help: "add the track to the end of the queue while playing" },
+    Binding { view: View::Library, name: "play_next", keys: &[char_key('A')], action: Action::Enqueue(true), help: "play the track next while playing" },
+    Binding { view: View::Library, name: "jump_to_letter", keys: &[Key::AnyAlphanumeric], action: Action::JumpToInitial, help: "jump to an entry starting with it" },
+    Binding { view: View::Playing, name: "pause", keys: &[char_key('p'), char_key(' ')], action: Action::TogglePause, help: "pause or resume" },
+    Binding { view: View::Playing, name: "next", keys: &[char_key('n')], action: Action::Next, help: "next track" },
+    Binding { view: View::Playing, name: "previous", keys: &[char_key('b')], action: Action::Previous, help: "previous track, or restart after 3s" },
+    Binding { view: View::Playing, name: "restart", keys: &[Key::Code(KeyCode::Home), char_key('0')], action: Action::Restart, help: "restart the track" },
+    Binding {
+        view: View::Playing,
+        name: "seek_percent",
+        keys: &[char_key('1'), char_key('2'), char_key('3'), char_key('4'), char_key('5'), char_key('6'), char_key('7'), char_key('8'), char_key('9')],
+        action: Action::SeekToDigit,
+        help: "jump to 10%-90% of the track",
+    },
+    Binding { view: View::Playing, name: "balance_left", keys: &[Key::Shift(KeyCode::Left)], action: Action::Balance(false), help: "balance towards the left" },
+    Binding { view: View::Playing, name: "balance_right", keys: &[Key::Shift(KeyCode::Right)], action: Action::Balance(true), help: "balance towards the right" },
+    Binding { view: View::Playing, name: "bass_down", keys: &[Key::Code(KeyCode::F(1))], action: Action::Bass(false), help: "less bass" },
+    Binding { view: View::Playing, name: "bass_up", keys: &[Key::Code(KeyCode::F(2))], action: Action::Bass(true), help: "more bass" },
+    Binding { view: View::Playing, name: "treble_down", keys: &[Key::Code(KeyCode::F(3))], action: Action::Treble(false), help: "less treble" },
+    Binding { view: View::Playing, name: "treble_up", keys: &[Key::Code(KeyCode::F(4))], action: Action::Treble(true), help: "more treble" },
+    Binding { view: View::Playing, name: "seek_back", keys: &[Key::Code(KeyCode::Left), char_key('h')], action: Action::Seek(-5), help: "back 5 seconds" },
+    Binding { view: View::Playing, name: "seek_forward", keys: &[Key::Code(KeyCode::Right), char_key('l')], action: Action::Seek(5), help: "forward 5 seconds" },
+    Binding { view: View::Playing, name: "seek_back_long", keys: &[Key::Code(KeyCode::Down)], action: Action::Seek(-60), help: "back a minute" },
+    Binding { view: View::Playing, name: "seek_forward_long", keys: &[Key::Code(KeyCode::Up)], action: Action::Seek(60), help: "forward a minute" },
+    Binding { view: View::Playing, name: "faster", keys: &[char_key('>')], action: Action::Faster, help: "play 5% faster" },
+    Binding { view: View::Playing, name: "slower", keys: &[char_key('<')], action: Action::Slower, help: "play 5% slower" },
+    Binding { view: View::Playing, name: "loop_start", keys: &[char_key('[')], action: Action::LoopStart, help: "mark point A of a loop" },
+    Binding { view: View::Playing, name: "loop_end", keys: &[char_key(']')], action: Action::LoopEnd, help: "mark point B and loop between them" },
+    Binding { view: View::Playing, name: "clear_loop", keys: &[char_key('\\')], action: Action::ClearLoop, help: "stop looping" },
+    Binding { view: View::Playing, name: "volume_up", keys: &[char_key('+'), char_key('=')], action: Action::VolumeUp, help: "volume up" },
+    Binding { view: View::Playing, name: "volume_down", keys: &[char_key('-')], action: Action::VolumeDown, help: "volume down" },
+    Binding { view: View::Playing, name: "mute", keys: &[char_key('m')], action: Action::Mute, help: "mute or unmute" },
+    Binding { view: View::Playing, name: "mono", keys: &[char_key('M')], action: Action::Mono, help: "mono or stereo" },
+    Binding { view: View::Playing, name: "shuffle", keys: &[char_key('z')], action: Action::Shuffle, help: "shuffle on or off" },
+    Binding { view: View::Playing, name: "repeat", keys: &[char_key('r')], action: Action::Repeat, help: "repeat off, one or all" },
+    Binding { view: View::Playing, name: "toggle_remaining", keys: &[char_key('t')], action: Action::ToggleRemaining, help: "show time played or left" },
+    Binding { view: View::Playing, name: "lyrics", keys: &[char_key('L')], action: Action::ToggleLyrics, help: "show or hide lyrics" },
+    Binding { view: View::Playing, name: "spectrum", keys: &[char_key('v')], action: Action::ToggleSpectrum, help: "show or hide the spectrum" },
+    Binding { view: View::Playing, name: "meter", keys: &[char_key('V')], action: Action::ToggleMeter, help: "show or hide the level meter" },
+    Binding { view: View::Playing, name: "resume", keys: &[char_key('R')], action: Action::ResumeSaved, help: "carry on from where the track was stopped last time" },
+    Binding { view: View::Playing, name: "bookmark", keys: &[char_key('B')], action: Action::Bookmark, help: "bookmark this point" },
+    Binding { view: View::Playing, name: "next_bookmark", keys: &[char_key('N')], action: Action::NextBookmark, help: "next bookmark" },
+    Binding { view: View::Playing, name: "previous_bookmark", keys: &[char_key('P')], action: Action::PreviousBookmark, help: "previous bookmark" },
+    Binding { view: View::Playing, name: "bookmarks", keys: &[char_key('\'')], action: Action::ShowBookmarks, help: "list bookmarks, to jump to or delete" },
+    Binding { view: View::Playing, name: "queue", keys: &[char_key('u')], action: Action::ShowQueue, help: "show the queue, to reorder or take tracks out" },
+    Binding { view: View::Playing, name: "export_queue", keys: &[char_key('w')], action: Action::ExportQueue, help: "write the queue to a playlist" },
+    Binding { view: View::Playing, name: "favorite", keys: &[char_key('f')], action: Action::ToggleFavorite, help: "favorite or not" },
+    Binding { view: View::Playing, name: "rate_1", keys: &[char_key('!')], action: Action::Rate(1), help: "rate 1 star, or unrate (Shift-1)" },
+    Binding { view: View::Playing, name: "rate_2", keys: &[char_key('@')], action: Action::Rate(2), help: "rate 2 stars, or unrate (Shift-2)" },
+    Binding { view: View::Playing, name: "rate_3", keys: &[char_key('#')], action: Action::Rate(3), help: "rate 3 stars, or unrate (Shift-3)" },
+    Binding { view: View::Playing, name: "rate_4", keys: &[char_key('$')], action: Action::Rate(4), help: "rate 4 stars, or unrate (Shift-4)" },
+    Binding { view: View::Playing, name: "rate_5", keys: &[char_key('%')], action: Action::Rate(5), help: "rate 5 stars, or unrate (Shift-5)" },
+    Binding { view: View::Playing, name: "stop", keys: &[char_key('s')], action: Action::Stop, help: "stop and go back to the list" },
+    Binding { view: View::Playing, name: "sleep", keys: &[char_key('S')], action: Action::Sleep, help: "set, extend or cancel the sleep timer" },
+    Binding { view: View::Playing, name: "browse", keys: &[Key::Code(KeyCode::Tab)], action: Action::ShowList, help: "browse the list while playing" },
+    Binding { view: View::Playing, name: "help", keys: &[char_key('?')], action: Action::Help, help: "show this help" },
+    Binding { view: View::Playing, name: "quit", keys: &[char_key('q'), Key::Code(KeyCode::Esc)], action: Action::Quit, help: "quit" },
+];
+
+impl Key {
+    /// Whether `event` presses this key. `previous` is the character pressed
+    /// just before, if it was recent enough to count towards a `Twice`.
+    fn matches(self, event: &KeyEvent, previous: Option<char>) -> bool {
+        let control = event.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT);
+        let shift = event.modifiers.contains(KeyModifiers::SHIFT) && !matches!(event.code, KeyCode::Char(_));
+        match (self, event.code) {
+            (Key::Code(code), pressed) => !control && !shift && code == pressed,
+            (Key::Shift(code), pressed) => !control && shift && code == pressed,
+            (Key::Ctrl(c), KeyCode::Char(pressed)) => event.modifiers.contains(KeyModifiers::CONTROL) && c == pressed,
+            (Key::Twice(c), KeyCode::Char(pressed)) => !control && c == pressed && previous == Some(c),
+            (Key::AnyAlphanumeric, KeyCode::Char(pressed)) => !control && pressed.is_alphanumeric(),
+            _ => false,
+        }
+    }
+
+    fn label(self) -> String {
+        match self {
+            Key::Code(KeyCode::Char(' ')) => "Space".to_string(),
+            Key::Code(KeyCode::Char(c)) => c.to_string(),
+            Key::Code(KeyCode::PageUp) => "PgUp".to_string(),
+            Key::Code(KeyCode::PageDown) => "PgDn".to_string(),
+            Key::Code(KeyCode::F(n)) => format!("F{}", n),
+            Key::Code(code) => format!("{:?}", code),
+            Key::Ctrl(c) => format!("Ctrl-{}", c),
+            Key::Shift(code) => format!("Shift-{}", Key::Code(code).label()),
+            Key::Twice(c) => format!("{}{}", c, c),
+            Key::AnyAlphanumeric => "other letters".to_string(),
+        }
+    }
+}
+
+/// Parse a key name from the config file: a single character, a named key
+/// like `Space` or `PgDn`, `Ctrl-` and a letter, `Shift-` and a named key,
+/// or a character doubled up like `gg`.
+pub fn parse_key(name: &str) -> Result<Key, String> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(c), None, _) => return Ok(Key::Code(KeyCode::Char(c))),
+        (Some(first), Some(second), None) if first == second => return Ok(Key::Twice(first)),
+        _ => {}
+    }
+    let lower = name.to_ascii_lowercase();
+    if let Some(letter) = lower.strip_prefix("ctrl-").or_else(|| lower.strip_prefix("ctrl+")) {
+        let mut chars = letter.chars();
+        return match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => Ok(Key::Ctrl(c)),
+            _ => Err(format!("unknown key '{}': Ctrl- needs a single letter", name)),
+        };
+    }
+    if let Some(key) = lower.strip_prefix("shift-").or_else(|| lower.strip_prefix("shift+")) {
+        return match named_key(key) {
+            Some(KeyCode::Char(_)) | None => Err(format!("unknown key '{}': Shift- needs a named key like Left; write shifted characters as they are", name)),
+            Some(code) => Ok(Key::Shift(code)),
+        };
+    }
+    named_key(&lower).map(Key::Code).ok_or_else(|| format!("unknown key '{}'", name))
+}
+
+/// The key called `name`, in lowercase, in the config file.
+fn named_key(name: &str) -> Option<KeyCode> {
+    let code = match name {
+        "space" => KeyCode::Char(' '),
+        "enter" | "return" => KeyCode::Enter,
+        "tab" => KeyCode::Tab,
+        "esc" | "escape" => KeyCode::Esc,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" | "ins" => KeyCode::Insert,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pgup" | "pageup" => KeyCode::PageUp,
+        "pgdn" | "pagedown" => KeyCode::PageDown,
+        other => match other.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+            Some(n @ 1..=12) => KeyCode::F(n),
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+/// The bindings in use: the defaults, with whatever keys the config file
+/// assigned instead.
+pub struct Keymap {
+    bindings: Vec<(&'static Binding, Vec<Key>)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap { bindings: BINDINGS.iter().map(|binding| (binding, binding.keys.to_vec())).collect() }
+    }
+}
+
+impl Keymap {
+    /// Replace the keys of the bindings called `name`.
+    pub fn set(&mut self, name: &str, keys: &[Key]) -> Result<(), String> {
+        let mut found = false;
+        for (binding, bound) in &mut self.bindings {
+            if binding.name == name {
+                *bound = keys.to_vec();
+                found = true;
+            }
+        }
+        if found {
+            Ok(())
+        } else {
+            let mut names: Vec<&str> = Vec::new();
+            for binding in BINDINGS {
+                if !names.contains(&binding.name) {
+                    names.push(binding.name);
+                }
+            }
+            Err(format!("unknown action '{}', expected one of: {}", name, names.join(", ")))
+        }
+    }
+
+    /// Describe every key bound to more than one action in the same view.
+    /// The binding found first wins, so that's the one reported as used.
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut messages = Vec::new();
+        for (index, (binding, keys)) in self.bindings.iter().enumerate() {
+            for key in keys.iter().filter(|key| !matches!(key, Key::AnyAlphanumeric)) {
+                let earlier = self.bindings[..index]
+                    .iter()
+                    .find(|(other, other_keys)| other.view == binding.view && other_keys.contains(key));
+                if let Some((other, _)) = earlier {
+                    messages.push(format!(
+                        "key '{}' is bound to both '{}' and '{}' in the {}; using '{}'",
+                        key.label(),
+                        other.name,
+                        binding.name,
+                        binding.view.label(),
+                        other.name
+                    ));
+                }
+            }
+        }
+        messages
+    }
+
+    fn in_view(&self, view: View) -> impl Iterator<Item = &(&'static Binding, Vec<Key>)> {
+        self.bindings.iter().filter(move |(binding, _)| binding.view == view)
+    }
+
+    /// What `event` does in `view`, if anything.
+    pub fn lookup(&self, view: View, event: &KeyEvent, previous: Option<char>) -> Option<Action> {
+        // The first half of a two-key binding waits for the second rather than
+        // falling through to `AnyAlphanumeric`
+        if let KeyCode::Char(c) = event.code {
+            if previous != Some(c) && self.starts_twice(view, c) {
+                return None;
+            }
+        }
+        self.in_view(view)
+            .find(|(_, keys)| keys.iter().any(|key| key.matches(event, previous)))
+            .map(|(binding, _)| binding.action)
+    }
+
+    /// Whether `c` is the first half of a two-key binding in `view`, so it
+    /// should be remembered for the next key press.
+    pub fn starts_twice(&self, view: View, c: char) -> bool {
+        self.in_view(view).any(|(_, keys)| keys.contains(&Key::Twice(c)))
+    }
+
+    /// The `[keys]` section of a config file that sets up these bindings.
+    /// Bindings that take any letter can't be written down, so they're left
+    /// out.
+    pub fn config_lines(&self) -> Vec<String> {
+        let mut lines = vec!["[keys]".to_string()];
+        let mut written: Vec<&str> = Vec::new();
+        for (binding, keys) in &self.bindings {
+            if written.contains(&binding.name) || keys.contains(&Key::AnyAlphanumeric) {
+                continue;
+            }
+            written.push(binding.name);
+            let names: Vec<String> = keys.iter().map(|key| format!("{:?}", key.label())).collect();
+            lines.push(format!("{} = [{}]", binding.name, names.join(", ")));
+        }
+        lines
+    }
+
+    /// The help overlay's text: every binding, grouped by view.
+    pub fn help_lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (view, title) in [(View::List, "Track list"), (View::Library, "Library"), (View::Playing, "Now playing")] {
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+            lines.push(title.to_string());
+            for (binding, keys) in self.in_view(view).filter(|(_, keys)| !keys.is_empty()) {
+                lines.push(format!("  {:<16} {}", keys_label(keys), binding.help));
+            }
+        }
+        lines
+    }
+}
+
+/// The keys of a binding for the help overlay. A run of digits is shown as a
+/// range rather than each digit in turn.
+fn keys_label(keys: &[Key]) -> String {
+    let digits: Option<Vec<u32>> = keys
+        .iter()
+        .map(|key| match key {
+            Key::Code(KeyCode::Char(c)) => c.to_digit(10),
+            _ => None,
+        })
+        .collect();
+    if let Some(digits) = digits.filter(|digits| digits.len() > 2 && digits.windows(2).all(|pair| pair[1] == pair[0] + 1)) {
+        return format!("{}-{}", digits[0], digits[digits.len() - 1]);
+    }
+    keys.iter().map(|key| key.label()).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn single_and_doubled_characters() {
+        assert!(parse_key("q") == Ok(char_key('q')));
+        assert!(parse_key("G") == Ok(char_key('G')));
+        assert!(parse_key("é") == Ok(char_key('é')));
+        assert!(parse_key("gg") == Ok(Key::Twice('g')));
+        assert!(parse_key("gh").is_err());
+    }
+
+    #[test]
+    fn ctrl_takes_a_single_letter() {
+        assert!(parse_key("Ctrl-x") == Ok(Key::Ctrl('x')));
+        assert!(parse_key("ctrl+X") == Ok(Key::Ctrl('x')));
+        assert!(parse_key("CTRL-f") == Ok(Key::Ctrl('f')));
+        assert_eq!(parse_key("Ctrl-1").err().unwrap(), "unknown key 'Ctrl-1': Ctrl- needs a single letter");
+        assert!(parse_key("Ctrl-ab").is_err());
+        assert!(parse_key("Ctrl-").is_err());
+    }
+
+    #[test]
+    fn shift_takes_a_named_key() {
+        assert!(parse_key("Shift-Left") == Ok(Key::Shift(KeyCode::Left)));
+        assert!(parse_key("shift+pgdn") == Ok(Key::Shift(KeyCode::PageDown)));
+        let error = parse_key("Shift-a").err().unwrap();
+        assert!(error.starts_with("unknown key 'Shift-a': Shift- needs a named key"), "{}", error);
+        assert!(parse_key("Shift-Space").is_err());
+    }
+
+    #[test]
+    fn named_keys_in_any_case() {
+        assert!(parse_key("Space") == Ok(char_key(' ')));
+        assert!(parse_key("ENTER") == Ok(Key::Code(KeyCode::Enter)));
+        assert!(parse_key("return") == Ok(Key::Code(KeyCode::Enter)));
+        assert!(parse_key("Esc") == Ok(Key::Code(KeyCode::Esc)));
+        assert!(parse_key("PageUp") == Ok(Key::Code(KeyCode::PageUp)));
+        assert!(parse_key("PgDn") == Ok(Key::Code(KeyCode::PageDown)));
+        assert!(parse_key("F1") == Ok(Key::Code(KeyCode::F(1))));
+        assert!(parse_key("f12") == Ok(Key::Code(KeyCode::F(12))));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert_eq!(parse_key("nonsense").err().unwrap(), "unknown key 'nonsense'");
+        assert!(parse_key("F13").is_err());
+        assert!(parse_key("F0").is_err());
+        assert!(parse_key("").is_err());
+    }
+
+    #[test]
+    fn unknown_action_is_rejected() {
+        let error = Keymap::default().set("dance", &[char_key('d')]).err().unwrap();
+        assert!(error.starts_with("unknown action 'dance', expected one of: up, down"), "{}", error);
+    }
+
+    #[test]
+    fn defaults_do_not_conflict() {
+        assert!(Keymap::default().conflicts().is_empty(), "{:?}", Keymap::default().conflicts());
+    }
+
+    #[test]
+    fn two_actions_on_one_key_are_reported() {
+        let mut keymap = Keymap::default();
+        keymap.set("down", &[char_key('k')]).unwrap();
+        let conflicts = keymap.conflicts();
+        assert!(conflicts.contains(&"key 'k' is bound to both 'up' and 'down' in the track list; using 'up'".to_string()), "{:?}", conflicts);
+        assert!(conflicts.iter().all(|conflict| conflict.starts_with("key 'k' is bound to both 'up' and 'down'")), "{:?}", conflicts);
+        let k = press(KeyCode::Char('k'), KeyModifiers::NONE);
+        assert!(keymap.lookup(View::List, &k, None) == Some(Action::MoveUp));
+    }
+
+    #[test]
+    fn rebound_keys_are_looked_up() {
+        let mut keymap = Keymap::default();
+        keymap.set("quit", &[Key::Ctrl('c')]).unwrap();
+        let q = press(KeyCode::Char('q'), KeyModifiers::NONE);
+        let ctrl_c = press(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert!(Keymap::default().lookup(View::Playing, &q, None) == Some(Action::Quit));
+        assert!(keymap.lookup(View::Playing, &q, None) != Some(Action::Quit));
+        assert!(keymap.lookup(View::Playing, &ctrl_c, None) == Some(Action::Quit));
+    }
+
+    #[test]
+    fn doubled_key_waits_for_the_second_press() {
+        let keymap = Keymap::default();
+        let g = press(KeyCode::Char('g'), KeyModifiers::NONE);
+        assert!(keymap.lookup(View::List, &g, None).is_none());
+        assert!(keymap.lookup(View::List, &g, Some('g')) == Some(Action::Top));
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::index::{self, Timed};
+use crate::probe;
+
+/// How long each track lasts, read from its headers on a thread of its own
+/// so the list can be shown straight away, filling in as they're read, and
+/// how big its file is and when it last changed, looked up on the way. Those
+/// being shown are read first. Lengths from the index are used while the
+/// file's the same as when they were read.
+pub struct Lengths {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    /// The file of each track not looked at yet, with its length from the
+    /// index.
+    pending: Vec<Option<(PathBuf, Option<Timed>)>>,
+    /// What was found for each track that has been.
+    known: Vec<Option<Found>>,
+    /// Tracks to look at before the rest, as they're being shown.
+    first: Vec<usize>,
+    /// Where to carry on from through the rest.
+    next: usize,
+    closed: bool,
+}
+
+struct Found {
+    length: Option<Duration>,
+    /// The file's size and when it last changed, if it could be told.
+    stamp: Option<(u64, u128)>,
+}
+
+impl State {
+    /// Take the next track to look at, if there's one left.
+    fn take(&mut self) -> Option<(usize, PathBuf, Option<Timed>)> {
+        let track = match self.first.iter().copied().find(|&track| self.pending.get(track).is_some_and(Option::is_some)) {
+            Some(track) => track,
+            None => {
+                while self.pending.get(self.next).is_some_and(Option::is_none) {
+                    self.next += 1;
+                }
+                self.next
+            }
+        };
+        let (file, indexed) = self.pending.get_mut(track)?.take()?;
+        Some((track, file, indexed))
+    }
+}
+
+impl Lengths {
+    /// Read the lengths of the tracks in `files`, with those `indexed` for
+    /// the tracks the index has.
+    pub fn new(files: &[PathBuf], indexed: Vec<Option<Timed>>) -> Lengths {
+        let pending = files.iter().cloned().zip(indexed).map(Some).collect();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { pending, known: files.iter().map(|_| None).collect(), first: Vec::new(), next: 0, closed: false }),
+            changed: Condvar::new(),
+        });
+        let reading = Arc::clone(&shared);
+        thread::spawn(move || read_lengths(&reading));
+        Lengths { shared }
+    }
+
+    /// Read the length of a track found after the list was made, at `file`.
+    pub fn push(&self, file: PathBuf) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.pending.push(Some((file, None)));
+        state.known.push(None);
+        self.shared.changed.notify_all();
+    }
+
+    /// Read the lengths of `tracks` before any others, as they're shown.
+    pub fn show_first(&self, tracks: &[usize]) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.first != tracks {
+            state.first = tracks.to_vec();
+            self.shared.changed.notify_all();
+        }
+    }
+
+    /// How long `track` lasts: None until it's been read, and Some(None)
+    /// once it turns out it can't be told from the headers.
+    pub fn get(&self, track: usize) -> Option<Option<Duration>> {
+        self.shared.state.lock().unwrap().known.get(track)?.as_ref().map(|found| found.length)
+    }
+
+    /// The size of `track`'s file and when it last changed, in nanoseconds
+    /// since 1970, as they were when its length was read.
+    pub fn stamp(&self, track: usize) -> Option<(u64, u128)> {
+        self.shared.state.lock().unwrap().known.get(track)?.as_ref()?.stamp
+    }
+
+    /// The length of `track` if it's been read, for the index to keep.
+    pub fn read_so_far(&self, track: usize) -> Option<Timed> {
+        let state = self.shared.state.lock().unwrap();
+        let found = state.known.get(track)?.as_ref()?;
+        let (size, modified) = found.stamp?;
+        Some(Timed { size, modified, length: found.length })
+    }
+}
+
+impl Drop for Lengths {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Read the lengths of the tracks a file at a time, until there are no more
+/// and it's closed.
+fn read_lengths(shared: &Shared) {
+    loop {
+        let (track, file, indexed) = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.closed {
+                    return;
+                }
+                match state.take() {
+                    Some(next) => break next,
+                    None => state = shared.changed.wait(state).unwrap(),
+                }
+            }
+        };
+        let stamp = index::stamp(&file);
+        let length = match indexed {
+            Some(indexed) if stamp == Some((indexed.size, indexed.modified)) => indexed.length,
+            _ => probe::estimate_duration(&file),
+        };
+        shared.state.lock().unwrap().known[track] = Some(Found { length, stamp });
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::sort::natural_cmp;
+use crate::tags::TagCache;
+use crate::track_name;
+use crate::tracklist::TrackList;
+
+// Artist for files without one in their tags
+const UNKNOWN: &str = "Unknown";
+
+pub struct Album {
+    pub name: String,
+    /// By track number, then file name.
+    pub tracks: Vec<usize>,
+}
+
+pub struct Artist {
+    pub name: String,
+    pub albums: Vec<Album>,
+}
+
+/// Group `tracks` by artist and album. This reads every file's tags, so
+/// it's only done once the library is first opened. Tracks without an
+/// artist go under "Unknown", and tracks without an album are grouped by the
+/// directory they're in.
+pub fn build(music_files: &[PathBuf], tags: &TagCache, tracks: &[usize]) -> Vec<Artist> {
+    let mut artists: Vec<Artist> = Vec::new();
+    // Artist and album keys ignore case, so "The Band" and "the band" meet
+    let mut artist_index: HashMap<String, usize> = HashMap::new();
+    let mut album_index: HashMap<(usize, String), usize> = HashMap::new();
+
+    for &track in tracks {
+        let file = &music_files[track];
+        let track_tags = tags.get(track, file);
+        let artist_name = track_tags.artist.as_deref().unwrap_or(UNKNOWN);
+        let artist = *artist_index.entry(artist_name.to_lowercase()).or_insert_with(|| {
+            artists.push(Artist { name: artist_name.to_string(), albums: Vec::new() });
+            artists.len() - 1
+        });
+        let directory = file.parent().unwrap_or(Path::new(""));
+        let (album_key, album_name) = match &track_tags.album {
+            Some(album) => (format!("album:{}", album.to_lowercase()), album.clone()),
+            None => (
+                format!("directory:{}", directory.display()),
+                directory.file_name().map_or_else(|| directory.display().to_string(), |name| name.to_string_lossy().into_owned()),
+            ),
+        };
+        let albums = &mut artists[artist].albums;
+        let album = *album_index.entry((artist, album_key)).or_insert_with(|| {
+            albums.push(Album { name: album_name, tracks: Vec::new() });
+            albums.len() - 1
+        });
+        albums[album].tracks.push(track);
+    }
+
+    for artist in &mut artists {
+        for album in &mut artist.albums {
+            album.tracks.sort_by(|&a, &b| {
+                let (a_number, b_number) = (tags.get(a, &music_files[a]).track, tags.get(b, &music_files[b]).track);
+                // Tracks without a number go after the numbered ones
+                a_number
+                    .is_none()
+                    .cmp(&b_number.is_none())
+                    .then(a_number.cmp(&b_number))
+                    .then_with(|| natural_cmp(&track_name(&music_files[a]), &track_name(&music_files[b])))
+            });
+        }
+        artist.albums.sort_by(|a, b| natural_cmp(&a.name, &b.name));
+    }
+    artists.sort_by(|a, b| (a.name == UNKNOWN).cmp(&(b.name == UNKNOWN)).then_with(|| natural_cmp(&a.name, &b.name)));
+    artists
+}
+
+/// Where the library view is: the list of artists, one artist's albums, or
+/// one album's tracks.
+pub struct Browser {
+    artists: Vec<Artist>,
+    artist: Option<usize>,
+    album: Option<usize>,
+    /// The rows of the level that's open: artist or album indices, or tracks.
+    pub rows: TrackList,
+    /// The rows of the levels above, so going back returns to the same place.
+    parents: Vec<TrackList>,
+}
+
+impl Browser {
+    pub fn new(artists: Vec<Artist>) -> Browser {
+        let rows = TrackList::new((0..artists.len()).collect());
+        Browser { artists, artist: None, album: None, rows, parents: Vec::new() }
+    }
+
+    fn open_album(&self) -> Option<&Album> {
+        Some(&self.artists[self.artist?].albums[self.album?])
+    }
+
+    /// The heading for the level that's open.
+    pub fn title(&self) -> String {
+        match (self.artist, self.open_album()) {
+            (Some(artist), Some(album)) => format!("{} – {}", self.artists[artist].name, album.name),
+            (Some(artist), None) => self.artists[artist].name.clone(),
+            _ => count(self.artists.len(), "artist"),
+        }
+    }
+
+    /// What the row for `entry` shows. Tracks are left to `track_label`.
+    pub fn label(&self, entry: usize, track_label: impl Fn(usize) -> String) -> String {
+        match (self.artist, self.album) {
+            (Some(_), Some(_)) => track_label(entry),
+            (Some(artist), None) => {
+                let album = &self.artists[artist].albums[entry];
+                format!("{} ({})", album.name, count(album.tracks.len(), "track"))
+            }
+            _ => {
+                let artist = &self.artists[entry];
+                format!("{} ({})", artist.name, count(artist.albums.len(), "album"))
+            }
+        }
+    }
+
+    /// Whether the rows are tracks, rather than artists or albums.
+    pub fn showing_tracks(&self) -> bool {
+        self.album.is_some()
+    }
+
+    /// The highlighted track, on an album's tracks.
+    pub fn selected_track(&self) -> Option<usize> {
+        self.rows.selected().filter(|_| self.showing_tracks())
+    }
+
+    /// Open the highlighted artist or album. On an album's tracks, returns
+    /// the album's tracks and the highlighted one, to play from there.
+    pub fn open(&mut self) -> Option<(Vec<usize>, usize)> {
+        let selected = self.rows.selected()?;
+        let entries = match (self.artist, self.album) {
+            (Some(_), Some(_)) => return Some((self.open_album()?.tracks.clone(), selected)),
+            (Some(artist), None) => {
+                self.album = Some(selected);
+                self.artists[artist].albums[selected].tracks.clone()
+            }
+            _ => {
+                self.artist = Some(selected);
+                (0..self.artists[selected].albums.len()).collect()
+            }
+        };
+        self.parents.push(mem::replace(&mut self.rows, TrackList::new(entries)));
+        None
+    }
+
+    /// Go up a level. Returns false when already at the top.
+    pub fn back(&mut self) -> bool {
+        let Some(parent) = self.parents.pop() else {
+            return false;
+        };
+        self.rows = parent;
+        if self.album.take().is_none() {
+            self.artist = None;
+        }
+        true
+    }
+
+    /// Move to the next artist or album starting with `initial`, as in the
+    /// file list.
+    pub fn jump_to_initial(&mut self, initial: char, height: usize) {
+        let names: Vec<String> = match (self.artist, self.album) {
+            (Some(_), Some(_)) => return,
+            (Some(artist), None) => self.artists[artist].albums.iter().map(|album| album.name.clone()).collect(),
+            _ => self.artists.iter().map(|artist| artist.name.clone()).collect(),
+        };
+        self.rows.jump_to_initial(initial, &names, height);
+    }
+}
+
+/// `n` and `noun`, made plural unless there's just one.
+fn count(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", n, noun)
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::history::quote;
+use crate::tags::{self, Tags};
+use crate::{music_extension, probe};
+
+/// What `sdsupreme list` says about a music file.
+pub struct Listed {
+    pub size: u64,
+    pub extension: &'static str,
+    /// How long it lasts, if its headers say.
+    pub length: Option<Duration>,
+    /// Its tags, if they could be read.
+    pub tags: Option<Tags>,
+}
+
+/// Look at the file at `path`, reading only as much of it as it takes to
+/// find its length and tags, which is quick.
+pub fn look(path: &Path) -> io::Result<Listed> {
+    let size = fs::metadata(path)?.len();
+    Ok(Listed {
+        size,
+        extension: music_extension(path).unwrap_or_default(),
+        length: probe::estimate_duration(path),
+        tags: tags::read(path).ok(),
+    })
+}
+
+/// The file at `path` as a JSON object, with what was found or what went
+/// wrong looking at it. Every field is there whether or not it's known,
+/// null when it isn't.
+pub fn to_json(path: &Path, listed: &Result<Listed, String>) -> String {
+    let path = quote(&path.to_string_lossy());
+    let listed = match listed {
+        Ok(listed) => listed,
+        Err(e) => return format!("{{\"path\":{},\"error\":{}}}", path, quote(e)),
+    };
+    let text = |text: &Option<String>| text.as_deref().map_or("null".to_string(), quote);
+    let number = |number: Option<u32>| number.map_or("null".to_string(), |number| number.to_string());
+    let tags = match &listed.tags {
+        Some(tags) => format!(
+            "{{\"artist\":{},\"title\":{},\"album\":{},\"track\":{},\"genre\":{},\"year\":{}}}",
+            text(&tags.artist),
+            text(&tags.title),
+            text(&tags.album),
+            number(tags.track),
+            text(&tags.genre),
+            number(tags.year)
+        ),
+        None => "null".to_string(),
+    };
+    format!(
+        "{{\"path\":{},\"size\":{},\"extension\":{},\"duration_ms\":{},\"tags\":{}}}",
+        path,
+        listed.size,
+        quote(listed.extension),
+        listed.length.map_or("null".to_string(), |length| length.as_millis().to_string()),
+        tags
+    )
+}
+
+/// The absolute form of `path`, without following links, so the paths
+/// listed can be used from anywhere.
+pub fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
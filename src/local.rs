@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::reader::{Retry, Retrying};
+
+// How much of a file is copied at a time, between looking to see whether
+// something else is wanted first
+const CHUNK: usize = 256 * 1024;
+
+/// How the copy of a file is getting on.
+#[derive(Clone)]
+pub enum State {
+    /// This many bytes of it have been copied so far, of how many.
+    Copying(u64, u64),
+    Copied(PathBuf),
+    /// It couldn't be, for this reason, so it's played from where it is.
+    Failed(String),
+}
+
+/// Copies of the tracks about to play, made on a thread of their own in a
+/// directory on the local disk, to play from instead of a card that can't be
+/// relied on. Copies go once they aren't wanted any more, or to make room,
+/// and the directory goes with this.
+pub struct LocalCache {
+    directory: PathBuf,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    copies: Mutex<Copies>,
+    changed: Condvar,
+}
+
+struct Copies {
+    /// The files to copy, the most pressing first.
+    wanted: Vec<PathBuf>,
+    /// Those that are being copied, or have been or couldn't be.
+    files: HashMap<PathBuf, State>,
+    /// How much room the copies take up, in bytes.
+    used: u64,
+    /// For naming the next copy, as different files can have the same name.
+    made: usize,
+    closed: bool,
+}
+
+impl Copies {
+    /// The first of the files wanted that isn't copied yet.
+    fn next(&self) -> Option<&PathBuf> {
+        self.wanted.iter().find(|file| matches!(self.files.get(*file), None | Some(State::Copying(..))))
+    }
+
+    /// Forget `file`, deleting any copy of it.
+    fn drop_copy(&mut self, file: &Path) {
+        if let Some(State::Copied(copy)) = self.files.remove(file) {
+            self.used -= fs::metadata(&copy).map_or(0, |metadata| metadata.len());
+            let _ = fs::remove_file(copy);
+        }
+    }
+
+    /// Forget the files that aren't wanted any more, deleting their copies.
+    /// Those that couldn't be copied are tried again if they're wanted again.
+    fn drop_unwanted(&mut self) {
+        let unwanted: Vec<PathBuf> = self.files.keys().filter(|known| !self.wanted.contains(known)).cloned().collect();
+        for known in unwanted {
+            self.drop_copy(&known);
+        }
+    }
+
+    /// Make room for `length` more bytes, if need be, by deleting copies of
+    /// those wanted after `file`, the last first. Whether there's room now.
+    fn make_room(&mut self, file: &Path, length: u64, limit: u64) -> bool {
+        let later: Vec<PathBuf> = self.wanted.iter().rev().take_while(|wanted| *wanted != file).cloned().collect();
+        for wanted in later {
+            if self.used + length <= limit {
+                break;
+            }
+            self.drop_copy(&wanted);
+        }
+        self.used + length <= limit
+    }
+}
+
+impl LocalCache {
+    /// Keep copies in a directory of their own in `parent`, taking up no
+    /// more than `limit` bytes, reading the files as `retry` says.
+    pub fn new(parent: &Path, limit: u64, retry: Retry) -> io::Result<LocalCache> {
+        let directory = parent.join(format!("sdsupreme-{}", process::id()));
+        fs::create_dir_all(&directory)?;
+        let shared = Arc::new(Shared {
+            copies: Mutex::new(Copies { wanted: Vec::new(), files: HashMap::new(), used: 0, made: 0, closed: false }),
+            changed: Condvar::new(),
+        });
+        let (into, copying) = (directory.clone(), Arc::clone(&shared));
+        thread::spawn(move || copy_wanted(&into, limit, &retry, &copying));
+        Ok(LocalCache { directory, shared })
+    }
+
+    /// Copy `files`, the most pressing first, and only those. Copies of
+    /// any others are deleted.
+    pub fn want(&self, files: Vec<PathBuf>) {
+        self.shared.copies.lock().unwrap().wanted = files;
+        self.shared.changed.notify_all();
+    }
+
+    /// How the copy of `file` is getting on, if it's been started.
+    pub fn state(&self, file: &Path) -> Option<State> {
+        self.shared.copies.lock().unwrap().files.get(file).cloned()
+    }
+}
+
+impl Drop for LocalCache {
+    fn drop(&mut self) {
+        self.shared.copies.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// Copy whatever's wanted into `directory`, one file at a time, until the
+/// cache is closed.
+fn copy_wanted(directory: &Path, limit: u64, retry: &Retry, shared: &Shared) {
+    loop {
+        let (file, copy, length) = {
+            let mut copies = shared.copies.lock().unwrap();
+            let file = loop {
+                if copies.closed {
+                    return;
+                }
+                copies.drop_unwanted();
+                match copies.next() {
+                    Some(file) => break file.clone(),
+                    None => copies = shared.changed.wait(copies).unwrap(),
+                }
+            };
+            let length = match fs::metadata(&file) {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    copies.files.insert(file, State::Failed(e.to_string()));
+                    continue;
+                }
+            };
+            if !copies.make_room(&file, length, limit) {
+                copies.files.insert(file, State::Failed("it's bigger than there's room for in the local cache".to_string()));
+                continue;
+            }
+            copies.made += 1;
+            let name = file.file_name().map_or_else(Default::default, |name| name.to_string_lossy().into_owned());
+            let copy = directory.join(format!("{}-{}", copies.made, name));
+            copies.files.insert(file.clone(), State::Copying(0, length));
+            (file, copy, length)
+        };
+        let state = match copy_file(&file, &copy, length, retry, shared) {
+            Ok(true) => State::Copied(copy),
+            // Something else is wanted first, so it'll be started again later
+            Ok(false) => {
+                let _ = fs::remove_file(&copy);
+                shared.copies.lock().unwrap().files.remove(&file);
+                continue;
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&copy);
+                State::Failed(match e.kind() {
+                    io::ErrorKind::StorageFull => "the local disk is full".to_string(),
+                    _ => e.to_string(),
+                })
+            }
+        };
+        let mut copies = shared.copies.lock().unwrap();
+        if let State::Copied(copy) = &state {
+            copies.used += fs::metadata(copy).map_or(0, |metadata| metadata.len());
+        }
+        copies.files.insert(file, state);
+    }
+}
+
+/// Copy `file`, `length` bytes long, to `copy`, saying how far it's got as
+/// it goes. False if something else came to be wanted first meanwhile, or
+/// the cache was closed.
+fn copy_file(file: &Path, copy: &Path, length: u64, retry: &Retry, shared: &Shared) -> io::Result<bool> {
+    let mut from = Retrying::new(File::open(file)?, retry.clone());
+    let mut to = File::create(copy)?;
+    let mut chunk = vec![0; CHUNK];
+    let mut copied = 0;
+    loop {
+        let count = from.read(&mut chunk)?;
+        if count == 0 {
+            to.sync_all()?;
+            return Ok(true);
+        }
+        to.write_all(&chunk[..count])?;
+        copied += count as u64;
+        let mut copies = shared.copies.lock().unwrap();
+        if copies.closed || copies.next().map(PathBuf::as_path) != Some(file) {
+            return Ok(false);
+        }
+        copies.files.insert(file.to_path_buf(), State::Copying(copied, length));
+    }
+}
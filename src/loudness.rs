@@ -0,0 +1,143 @@
+use std::f64::consts::PI;
+
+use crate::dsp::Biquad;
+
+// ReplayGain 2.0 plays everything at this loudness, in LUFS
+const REFERENCE_LUFS: f64 = -18.0;
+
+// Blocks quieter than this, in LUFS, are left out entirely
+const ABSOLUTE_GATE: f64 = -70.0;
+
+// Then so are blocks more than this many LU quieter than the rest
+const RELATIVE_GATE: f64 = -10.0;
+
+/// The two stages of the K-weighting of ITU-R BS.1770: a shelf boosting
+/// what the head makes louder, then a high-pass cutting off rumble. The
+/// standard only gives coefficients for 48 kHz, so they're worked out from
+/// the analog filters for other rates.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+    [shelf, high_pass]
+}
+
+/// Measures the loudness of a track as its samples are fed in, following
+/// EBU R128: K-weighted power over 400ms blocks that overlap by 300ms.
+pub struct Meter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    /// Samples per channel in a 100ms step between blocks.
+    step_len: usize,
+    /// Weighted power summed over the step being filled, and how far in it is.
+    step: f64,
+    step_filled: usize,
+    channel: usize,
+    /// The last four steps, which make up a block.
+    recent: [f64; 4],
+    steps_seen: usize,
+    loudness: Loudness,
+}
+
+/// What a meter found: the power of each block, and the highest sample.
+#[derive(Default)]
+pub struct Loudness {
+    blocks: Vec<f64>,
+    /// As a fraction of full scale.
+    pub peak: f32,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32, channels: u16) -> Meter {
+        let channels = channels.max(1) as usize;
+        // In 5.1 the LFE doesn't count and the surrounds count for more
+        let weights = (0..channels)
+            .map(|channel| match (channels, channel) {
+                (6, 3) => 0.0,
+                (6, 4 | 5) => 1.41,
+                _ => 1.0,
+            })
+            .collect();
+        Meter {
+            channels,
+            filters: vec![k_weighting(sample_rate); channels],
+            weights,
+            step_len: (sample_rate as usize / 10).max(1),
+            step: 0.0,
+            step_filled: 0,
+            channel: 0,
+            recent: [0.0; 4],
+            steps_seen: 0,
+            loudness: Loudness::default(),
+        }
+    }
+
+    /// Add the next sample; channels are interleaved.
+    pub fn add(&mut self, sample: i16) {
+        let x = sample as f64 / 32768.0;
+        self.loudness.peak = self.loudness.peak.max(x.abs() as f32);
+        let [shelf, high_pass] = &mut self.filters[self.channel];
+        let y = high_pass.process(shelf.process(x));
+        self.step += self.weights[self.channel] * y * y;
+
+        self.channel += 1;
+        if self.channel < self.channels {
+            return;
+        }
+        self.channel = 0;
+        self.step_filled += 1;
+        if self.step_filled < self.step_len {
+            return;
+        }
+        self.recent[self.steps_seen % 4] = self.step;
+        self.steps_seen += 1;
+        self.step = 0.0;
+        self.step_filled = 0;
+        if self.steps_seen >= 4 {
+            let block = self.recent.iter().sum::<f64>() / (4 * self.step_len) as f64;
+            self.loudness.blocks.push(block);
+        }
+    }
+
+    /// What was measured, leaving out the part of a block at the end.
+    pub fn finish(self) -> Loudness {
+        self.loudness
+    }
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// The gated loudness in LUFS of everything measured in `measurements`
+/// together, as for an album. None when there's nothing loud enough to
+/// measure, such as silence or less than 400ms of audio.
+pub fn integrated(measurements: &[&Loudness]) -> Option<f64> {
+    let blocks = || measurements.iter().flat_map(|loudness| loudness.blocks.iter().copied());
+    let mean = |kept: &dyn Fn(f64) -> bool| {
+        let (sum, count) = blocks().filter(|&power| kept(power)).fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+        (count > 0).then(|| sum / count as f64)
+    };
+    let ungated = mean(&|power| lufs(power) > ABSOLUTE_GATE)?;
+    let threshold = lufs(ungated) + RELATIVE_GATE;
+    mean(&|power| lufs(power) > ABSOLUTE_GATE && lufs(power) > threshold).map(lufs)
+}
+
+/// The ReplayGain, in dB, that brings something `lufs` loud to the reference.
+pub fn gain(lufs: f64) -> f32 {
+    (REFERENCE_LUFS - lufs) as f32
+}
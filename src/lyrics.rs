@@ -0,0 +1,233 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The lyrics of a track, from an `.lrc` file next to it.
+pub struct Lyrics {
+    /// In the order they're sung.
+    pub lines: Vec<String>,
+    /// When each line starts; empty for plain lyrics without timestamps.
+    times: Vec<Duration>,
+}
+
+impl Lyrics {
+    /// The line being sung at `position`: the last one started by then.
+    /// None before the first line, and always for plain lyrics.
+    pub fn current(&self, position: Duration) -> Option<usize> {
+        self.times.partition_point(|&time| time <= position).checked_sub(1)
+    }
+}
+
+/// Read the lyrics for the music file at `path`, from the file beside it
+/// with the same name but an `.lrc` extension.
+pub fn load(path: &Path) -> Option<Lyrics> {
+    let data = fs::read(path.with_extension("lrc")).ok()?;
+    let lyrics = parse(&String::from_utf8_lossy(&data));
+    (!lyrics.lines.is_empty()).then_some(lyrics)
+}
+
+/// Parse an LRC file. Each line starts with one timestamp like `[01:23.45]`
+/// or several when it's sung more than once, and `[offset:+500]` moves
+/// every line earlier by that many milliseconds. Other tags, like `[ar:...]`
+/// for the artist, are skipped. A file without any timestamps is taken as
+/// plain lyrics and kept as it is; in a timed one, lines without a
+/// timestamp are left out.
+pub fn parse(text: &str) -> Lyrics {
+    let mut timed: Vec<(Duration, String)> = Vec::new();
+    let mut plain = Vec::new();
+    let mut offset_ms: i64 = 0;
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        let mut tagged = false;
+        // A bracket that isn't a timestamp or a tag, like "[Chorus]", is
+        // part of the text
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|inner| inner.split_once(']')) {
+            if let Some(time) = parse_timestamp(tag) {
+                times.push(time);
+            } else if let Some((key, value)) = tag.split_once(':').filter(|(key, _)| is_tag_key(key)) {
+                if key.eq_ignore_ascii_case("offset") {
+                    offset_ms = value.trim().trim_start_matches('+').parse().unwrap_or(offset_ms);
+                }
+                tagged = true;
+            } else {
+                break;
+            }
+            rest = after.trim_start();
+        }
+        if !times.is_empty() {
+            timed.extend(times.into_iter().map(|time| (time, rest.to_string())));
+        } else if !tagged {
+            plain.push(rest.to_string());
+        }
+    }
+
+    if timed.is_empty() {
+        // Blank lines between verses are kept, but not at either end
+        let start = plain.iter().position(|line| !line.is_empty()).unwrap_or(plain.len());
+        let end = plain.iter().rposition(|line| !line.is_empty()).map_or(start, |last| last + 1);
+        return Lyrics { lines: plain[start..end].to_vec(), times: Vec::new() };
+    }
+    // Lines with several timestamps come back around, so put them in order;
+    // lines at the same time keep the order they were written in
+    timed.sort_by_key(|(time, _)| *time);
+    let (times, lines) = timed
+        .into_iter()
+        .map(|(time, text)| (shift(time, offset_ms), text))
+        .unzip();
+    Lyrics { lines, times }
+}
+
+/// `mm:ss`, `mm:ss.xx` or `mm:ss:xx`, with any number of digits for the
+/// minutes and the fraction.
+fn parse_timestamp(tag: &str) -> Option<Duration> {
+    let (minutes, rest) = tag.split_once(':')?;
+    let (seconds, fraction) = match rest.split_once(['.', ':']) {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (rest, ""),
+    };
+    let digits = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_digit());
+    if !digits(minutes) || !digits(seconds) || !(fraction.is_empty() || digits(fraction)) {
+        return None;
+    }
+    let seconds: u64 = seconds.parse().ok()?;
+    if seconds >= 60 {
+        return None;
+    }
+    let fraction: f64 = format!("0.{}", fraction).parse().unwrap_or(0.0);
+    let whole = minutes.parse::<u64>().ok()?.checked_mul(60)? + seconds;
+    Some(Duration::from_secs(whole) + Duration::from_secs_f64(fraction))
+}
+
+fn is_tag_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+/// `time` made earlier by `offset_ms`, or later when it's negative, stopping
+/// at zero.
+fn shift(time: Duration, offset_ms: i64) -> Duration {
+    let offset = Duration::from_millis(offset_ms.unsigned_abs());
+    if offset_ms >= 0 {
+        time.saturating_sub(offset)
+    } else {
+        time + offset
+    }
+}
+
+/// The lyrics of whichever track is playing, read again when it changes.
+#[derive(Default)]
+pub struct Sidecar {
+    track: Option<usize>,
+    lyrics: Option<Lyrics>,
+}
+
+impl Sidecar {
+    pub fn get(&mut self, track: Option<usize>, music_files: &[PathBuf]) -> Option<&Lyrics> {
+        if track != self.track {
+            self.track = track;
+            self.lyrics = track.and_then(|track| load(&music_files[track]));
+        }
+        self.lyrics.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// When each line starts, to the millisecond.
+    fn millis(lyrics: &Lyrics) -> Vec<u64> {
+        lyrics.times.iter().map(|time| (time.as_secs_f64() * 1000.0).round() as u64).collect()
+    }
+
+    #[test]
+    fn timed_lines_are_read() {
+        let lyrics = parse("[00:01.50]One\n[00:04.25] Two \n[01:02]Three\n");
+        assert_eq!(lyrics.lines, ["One", "Two", "Three"]);
+        assert_eq!(millis(&lyrics), [1500, 4250, 62000]);
+    }
+
+    #[test]
+    fn timestamps_can_have_any_precision() {
+        let lyrics = parse("[0:01]a\n[00:02.5]b\n[00:03:25]c\n[100:00.125]d");
+        assert_eq!(millis(&lyrics), [1000, 2500, 3250, 6_000_125]);
+    }
+
+    #[test]
+    fn several_timestamps_repeat_a_line() {
+        let lyrics = parse("[00:10.00][00:30.00]Chorus\n[00:20.00]Verse\n[00:40.00] [00:05.00]Both ends\n");
+        assert_eq!(lyrics.lines, ["Both ends", "Chorus", "Verse", "Chorus", "Both ends"]);
+        assert_eq!(millis(&lyrics), [5000, 10000, 20000, 30000, 40000]);
+    }
+
+    #[test]
+    fn lines_at_the_same_time_keep_their_order() {
+        let lyrics = parse("[00:01]first\n[00:01]second\n");
+        assert_eq!(lyrics.lines, ["first", "second"]);
+    }
+
+    #[test]
+    fn offset_moves_lines_earlier() {
+        let lyrics = parse("[offset:+500]\n[00:01.00]a\n[00:00.20]b\n");
+        assert_eq!(millis(&lyrics), [0, 500]);
+        assert_eq!(lyrics.lines, ["b", "a"]);
+    }
+
+    #[test]
+    fn negative_offset_moves_lines_later() {
+        let lyrics = parse("[00:01.00]a\n[OFFSET: -250]\n");
+        assert_eq!(millis(&lyrics), [1250]);
+    }
+
+    #[test]
+    fn malformed_offset_is_ignored() {
+        let lyrics = parse("[offset:soon]\n[00:01.00]a\n");
+        assert_eq!(millis(&lyrics), [1000]);
+    }
+
+    #[test]
+    fn tags_are_skipped() {
+        let lyrics = parse("\u{feff}[ar:Someone]\n[ti:Something]\n[00:01]Line\n");
+        assert_eq!(lyrics.lines, ["Line"]);
+    }
+
+    #[test]
+    fn malformed_lines_are_left_out_of_timed_lyrics() {
+        let lyrics = parse("[00:01]Good\nno timestamp\n[00:75]bad seconds\n[xx:01]bad minutes\n[00:02\n[]\n[00:03]Also good\n");
+        assert_eq!(lyrics.lines, ["Good", "Also good"]);
+        assert_eq!(millis(&lyrics), [1000, 3000]);
+    }
+
+    #[test]
+    fn brackets_that_are_not_tags_are_text() {
+        let lyrics = parse("[00:01][Chorus] La la\n");
+        assert_eq!(lyrics.lines, ["[Chorus] La la"]);
+    }
+
+    #[test]
+    fn plain_lyrics_are_kept_as_they_are() {
+        let lyrics = parse("\n[ar:Someone]\nFirst verse\n\nSecond verse\n[Chorus]\n\n");
+        assert_eq!(lyrics.lines, ["First verse", "", "Second verse", "[Chorus]"]);
+        assert!(lyrics.current(Duration::from_secs(10)).is_none());
+    }
+
+    #[test]
+    fn nothing_but_tags_is_no_lyrics() {
+        assert!(parse("[ar:Someone]\n\n").lines.is_empty());
+        assert!(parse("").lines.is_empty());
+    }
+
+    #[test]
+    fn current_line_follows_the_position() {
+        let lyrics = parse("[00:01]a\n[00:03]b\n[00:05]c\n");
+        let at = |seconds: f64| lyrics.current(Duration::from_secs_f64(seconds));
+        assert_eq!(at(0.0), None);
+        assert_eq!(at(0.99), None);
+        assert_eq!(at(1.0), Some(0));
+        assert_eq!(at(4.0), Some(1));
+        assert_eq!(at(5.0), Some(2));
+        assert_eq!(at(500.0), Some(2));
+        // Going back, as after a seek
+        assert_eq!(at(2.0), Some(0));
+    }
+}
@@ -1,165 +1,5558 @@
+use std::cmp::Reverse;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs;
-use std::io::{self, BufReader, Write};
-use std::path::Path;
+use std::mem;
+use std::io::{self, BufReader, Cursor, IsTerminal, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::thread;
-use std::time::{Duration, Instant};
-use walkdir::WalkDir;
-use rodio::{Decoder, OutputStream, Sink};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use indicatif::{ProgressBar, ProgressStyle};
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
 use crossterm::{
-    event::{self, KeyCode},
+    event::{self, DisableMouseCapture, EnableMouseCapture, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEventKind},
     execute,
-    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
+    terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
     cursor,
 };
-use ctrlc;
 use rodio::Source;
 
-fn list_music_files(path: &Path) -> Vec<String> {
-    let mut music_files = Vec::new();
-    for entry in WalkDir::new(path) {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("flac") {
-            music_files.push(path.to_string_lossy().into_owned());
+mod abloop;
+mod aiff;
+mod alac;
+mod check;
+mod config;
+mod cover;
+mod dsp;
+mod dupes;
+mod exclude;
+mod filter;
+mod history;
+mod http;
+mod index;
+mod jpeg;
+mod keys;
+mod lengths;
+mod library;
+mod listing;
+mod local;
+mod playlist;
+mod plays;
+mod positions;
+mod queue;
+mod ratings;
+mod reader;
+mod loudness;
+mod lyrics;
+mod md5;
+mod meter;
+mod mounts;
+#[cfg(feature = "notify")]
+mod notify;
+mod nowplaying;
+mod probe;
+mod replaygain;
+mod scan;
+mod sleep;
+mod sort;
+mod spectrum;
+mod stats;
+mod status;
+mod store;
+mod tags;
+#[cfg(test)]
+mod testutil;
+mod theme;
+mod tracklist;
+mod verify;
+mod watch;
+
+use cover::CoverArt;
+use exclude::Exclude;
+use filter::Filter;
+use index::Index;
+use keys::{Action, View};
+use lengths::Lengths;
+use library::Browser;
+use local::LocalCache;
+use loudness::{Loudness, Meter};
+use lyrics::{Lyrics, Sidecar};
+use meter::LevelMeter;
+#[cfg(feature = "notify")]
+use notify::Notifier;
+use nowplaying::{NowPlayingFile, Template};
+use plays::PlayCounts;
+use positions::Positions;
+use queue::Queue;
+use ratings::{Rating, Ratings};
+use reader::{Failure, Loaded, Noting, Retry, Retrying};
+use replaygain::{ReplayGain, MAX_GAIN_DB};
+use scan::{list_music_files, unreadable_note, Scanned};
+use sort::{Order, SortKey};
+use spectrum::Spectrum;
+use status::StatusFile;
+use tags::{TagCache, Tags};
+use theme::Theme;
+use tracklist::TrackList;
+use watch::{Change, Watcher};
+
+// How many recently played tracks shuffle avoids repeating
+const SHUFFLE_HISTORY: usize = 10;
+
+// Row of the playing view the progress bar is drawn on
+const PROGRESS_ROW: u16 = 2;
+
+// How long a notice like "Queued: ..." stays in the list's footer
+const NOTICE_TIME: Duration = Duration::from_secs(3);
+
+// Most entries the queue shows at once in the playing view
+const QUEUE_ROWS: usize = 10;
+
+// Rows the track list scrolls per notch of the mouse wheel
+const WHEEL_ROWS: isize = 3;
+
+// How long the first key of a two-key binding like `gg` waits for the second
+const PENDING_KEY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Narrower than this and the progress bar is dropped, leaving just the time
+const MIN_BAR_WIDTH: usize = 10;
+
+const VOLUME_STEP: u32 = 5;
+// Playback speed in percent, and how far each key press changes it
+const SPEED_STEP: u32 = 5;
+const MIN_SPEED: u32 = 50;
+const MAX_SPEED: u32 = 200;
+// Fades on pausing, resuming and stopping, in milliseconds
+const DEFAULT_FADE_MS: u32 = 150;
+const MAX_FADE_MS: u32 = 2000;
+// The biggest track read into memory with --prefetch, in megabytes, unless
+// the limit's set otherwise
+const DEFAULT_PREFETCH_MB: u64 = 200;
+const MAX_PREFETCH_MB: u64 = 4096;
+// How many times a read from the card that fails is tried again, and how
+// long to wait before the first time, in milliseconds, doubling each time
+const DEFAULT_READ_RETRIES: u32 = 3;
+const MAX_READ_RETRIES: u32 = 10;
+const DEFAULT_RETRY_DELAY_MS: u32 = 50;
+const MAX_RETRY_DELAY_MS: u32 = 1000;
+// The most put in the local cache with --local-cache, in megabytes, unless
+// it's set otherwise
+const DEFAULT_LOCAL_CACHE_MB: u64 = 2048;
+const MAX_LOCAL_CACHE_MB: u64 = 1024 * 1024;
+// How many tracks after the one playing are copied to the local cache
+const LOCAL_CACHE_AHEAD: usize = 3;
+// How long a track waits to start, being read into memory or copied, before
+// it says so, so quick ones don't flash it up
+const WAIT_QUIET_TIME: Duration = Duration::from_millis(300);
+// How much of a name the list keeps room for before it leaves columns out
+const MIN_NAME_WIDTH: usize = 30;
+// How often a fade changes the volume
+const FADE_STEP: Duration = Duration::from_millis(5);
+// Seeking decodes the track from the start, so going further in than this
+// says it'll take a while
+const SLOW_SEEK: Duration = Duration::from_secs(120);
+const MAX_VOLUME: u32 = 200;
+// How far each key press moves the balance, out of dsp::MAX_BALANCE
+const BALANCE_STEP: i32 = 10;
+
+// How often the time into the track is written to the status file at
+// most, in seconds
+const DEFAULT_STATUS_INTERVAL: u32 = 1;
+const MAX_STATUS_INTERVAL: u32 = 3600;
+
+// Width in columns of the cover art in the playing view
+const DEFAULT_COVER_SIZE: u16 = 20;
+const MAX_COVER_SIZE: u16 = 100;
+
+// Tracks `sdsupreme history` and `sdsupreme stats` print without -n
+const DEFAULT_HISTORY_COUNT: usize = 20;
+
+// Opus and raw AAC (.aac) aren't here, as there's no decoder for either in
+// this build; their files would only be listed to fail when played. AAC in
+// an .m4a is found when it's probed, and skipped as an unsupported codec.
+const MUSIC_EXTENSIONS: &[&str] = &["flac", "mp3", "ogg", "wav", "m4a", "aiff", "aif"];
+
+/// The supported extension of a music file, lowercased, matched case-insensitively
+/// (`TRACK01.FLAC` and `song.v2.Flac` are both "flac"). Files without an
+/// extension, or whose extension isn't UTF-8, are not music files.
+fn music_extension(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?;
+    MUSIC_EXTENSIONS
+        .iter()
+        .copied()
+        .find(|known| known.eq_ignore_ascii_case(extension))
+}
+
+/// Parse a comma-separated `--ext` value such as `flac, wav,flac` into a set
+/// of supported extensions. `all` selects everything we can decode.
+fn parse_extensions(value: &str) -> Result<HashSet<&'static str>, String> {
+    let mut extensions = HashSet::new();
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        if name.eq_ignore_ascii_case("all") {
+            extensions.extend(MUSIC_EXTENSIONS);
+            continue;
         }
+        let name = name.trim_start_matches('.');
+        match MUSIC_EXTENSIONS.iter().find(|known| known.eq_ignore_ascii_case(name)) {
+            Some(known) => {
+                extensions.insert(*known);
+            }
+            None => {
+                return Err(format!(
+                    "Unknown extension '{}'. Supported extensions: {}",
+                    name,
+                    MUSIC_EXTENSIONS.join(", ")
+                ))
+            }
+        }
+    }
+    if extensions.is_empty() {
+        return Err(format!("No extensions given. Supported extensions: {}", MUSIC_EXTENSIONS.join(", ")));
     }
-    music_files
+    Ok(extensions)
 }
 
-fn play_music(file_path: String, is_paused: Arc<AtomicBool>, sink: Arc<Mutex<Sink>>) -> Result<(), Box<dyn std::error::Error>> {
-    let file = fs::File::open(file_path)?;
-    let source = Decoder::new(BufReader::new(file))?;
-    let duration = source.total_duration().unwrap_or(Duration::new(0, 0));
-    let start_time = Instant::now();
-    sink.lock().unwrap().append(source);
+/// What was given on the command line. Anything left out falls back to the
+/// config file and then to the defaults; see `Settings`.
+struct Options {
+    /// Every path given, in order.
+    paths: Vec<String>,
+    extensions: HashSet<&'static str>,
+    /// Every --exclude pattern, if any were given.
+    exclude: Option<Vec<String>>,
+    skip_nomedia: Option<bool>,
+    hidden: Option<bool>,
+    max_depth: Option<usize>,
+    follow_symlinks: Option<bool>,
+    shuffle: Option<bool>,
+    volume: Option<u32>,
+    bar_style: Option<BarStyle>,
+    theme: Option<Theme>,
+    sort: Option<SortKey>,
+    cover_size: Option<u16>,
+    replaygain: Option<replaygain::Mode>,
+    /// In dB.
+    replaygain_preamp: Option<f32>,
+    replaygain_fallback: Option<f32>,
+    fade_ms: Option<u32>,
+    keep_speed: Option<bool>,
+    prefetch: Option<bool>,
+    /// In megabytes.
+    prefetch_limit: Option<u64>,
+    read_retries: Option<u32>,
+    retry_delay_ms: Option<u32>,
+    /// Where to make the directory the local cache goes in, or None for
+    /// no local cache.
+    local_cache: Option<Option<PathBuf>>,
+    /// In megabytes.
+    local_cache_size: Option<u64>,
+    mono: Option<bool>,
+    balance: Option<i32>,
+    skip_silence: Option<bool>,
+    silence_threshold: Option<f32>,
+    silence_min: Option<u32>,
+    sleep: Option<Duration>,
+    resume: Option<bool>,
+    play_counts: Option<bool>,
+    write_tags: Option<bool>,
+    status_file: Option<PathBuf>,
+    /// In seconds.
+    status_interval: Option<u32>,
+    now_playing_file: Option<PathBuf>,
+    now_playing_format: Option<Template>,
+    #[cfg(feature = "notify")]
+    notify: Option<bool>,
+    /// Config file given with --config, instead of the default one.
+    config: Option<String>,
+    print_config: bool,
+    /// Queue the files after the one given in its directory.
+    and_following: bool,
+    /// Look through everything again rather than going by the index.
+    rescan: bool,
+}
 
-    // Handle pausing, resuming and progress bar
-    loop {
-        if is_paused.load(Ordering::SeqCst) {
-            sink.lock().unwrap().pause();
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BarStyle {
+    /// `=` and `-`, which every terminal can show.
+    Ascii,
+    /// Unicode block characters, with eighth blocks for the partly played cell.
+    Unicode,
+}
+
+impl BarStyle {
+    fn from_name(name: &str) -> Option<BarStyle> {
+        match name {
+            "ascii" => Some(BarStyle::Ascii),
+            "unicode" => Some(BarStyle::Unicode),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            BarStyle::Ascii => "ascii",
+            BarStyle::Unicode => "unicode",
+        }
+    }
+}
+
+fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} [--ext <list>] [--exclude <pattern>] [--no-nomedia] [--hidden] [--max-depth <n>] [--follow-symlinks] [--rescan] [--shuffle] [--volume <percent>] [--bar <style>] [--theme <name>] [--sort <order>] [--cover-size <columns>] [--replaygain <mode>] [--fade <ms>] [--keep-speed] [--prefetch] [--prefetch-limit <MB>] [--read-retries <n>] [--retry-delay <ms>] [--local-cache[=<dir>]] [--local-cache-size <MB>] [--mono] [--balance <n>] [--skip-silence] [--sleep <time>] [--resume] [--play-counts] [--write-tags] [--status-file <file>] [--status-interval <seconds>] [--now-playing-file <file>] [--now-playing-format <template>] {notify_synopsis}[--config <file>] [--print-config] [--and-following] [<SD card path>...]\n\n  --ext <list>  comma-separated extensions to scan, or 'all' (default: all)\n  --exclude <pattern> leave out what matches, from where the search starts, like 'Recordings' or '**/*.demo.flac'; '**' matches any number of directories (can be given more than once)\n  --no-nomedia  look in directories with a .nomedia file in them too, which are left out otherwise, as Android's are\n  --hidden      look at hidden files and directories too, whose names start with '.', like .Trashes\n  --max-depth <n> only look this many directories down, where 1 is only the files in the path itself (default: no limit)\n  --follow-symlinks look in directories that links lead to, as well as playing files they lead to; a file reached more than one way is only listed once\n  --rescan      look through every directory and read every file's tags again, rather than going by what's kept from last time about those that haven't changed\n  --shuffle     play tracks in random order (toggle with 'z' while playing)\n  --no-shuffle  play tracks in order, even if the config file says to shuffle\n  --volume <n>  starting volume in percent, 0-200 (default: 100)\n  --bar <style> progress bar style, 'ascii' or 'unicode' (default: ascii)\n  --theme <name> colors to use: 'dark', 'light' or 'no-color' (default: dark, or no-color when NO_COLOR is set)\n  --sort <order> 'path', 'name', 'mtime' (newest first), 'mtime-asc' (oldest first), 'size' (biggest first), 'size-asc' (smallest first) or 'track' (by album and track number from the tags; reads every file's tags) (default: name)\n  --cover-size <n> width in columns of the cover art shown while playing, in terminals that can show images; 0 for none (default: {})\n  --replaygain <mode> volume from ReplayGain tags: 'track', 'album' or 'off' (default: off)\n  --replaygain-preamp <dB> added to the ReplayGain of tagged tracks (default: 0)\n  --replaygain-fallback <dB> gain for tracks without ReplayGain tags, so they aren't louder than the rest (default: -6)\n  --fade <ms>   fade in and out over this long when pausing, resuming and stopping; 0 for none (default: {})\n  --keep-speed  keep the playback speed set with '<' and '>' from one track to the next, instead of going back to normal speed\n  --prefetch    read each track into memory before playing it, so a card that's slow to answer can't make it drop out\n  --prefetch-limit <MB> tracks bigger than this are played from the card even with --prefetch (default: {})\n  --read-retries <n> how many times to try again when reading the card fails, before going on to the next track (default: {})\n  --retry-delay <ms> how long to wait before trying again the first time, doubling each time after (default: {})\n  --local-cache[=<dir>] copy each track and the few after it in the queue to the local disk, in the temporary directory or <dir>, and play them from there, for a card that can't be relied on\n  --local-cache-size <MB> the most the copies take up at once (default: {})\n  --mono        mix stereo down to mono, for a single speaker (toggle with 'M' while playing)\n  --balance <n> from -{} for only the left channel to {} for only the right (default: 0)\n  --skip-silence skip past silence longer than --silence-min, such as before a hidden track\n  --silence-threshold <dB> samples this quiet or quieter count as silence, from {} to {} dBFS (default: {})\n  --silence-min <seconds> how long silence has to last before it's skipped, up to {} (default: {})\n  --sleep <time> fade out and quit after this long, like 45m or 1h30m (set or change it with 'S' while playing)\n  --resume      carry on from where long tracks were stopped last time, instead of offering to with 'R'\n  --play-counts show how many times each track has been played in the list\n  --write-tags  also write star ratings to the RATING tag of FLAC files, for other players to see\n  --status-file <file> keep a small JSON file saying what's playing, for a status bar to read: state ('playing', 'paused' or 'stopped'), path, title, artist, duration_ms, elapsed_ms, paused, volume and muted, with null for what isn't known; it's replaced whole each time, never left half written, and says 'stopped' once sdsupreme quits\n  --status-interval <seconds> how often at most the status file is written just for the time into the track to change (default: {})\n  --now-playing-file <file> keep a plain text file saying what's playing, for a text source in OBS or the like to watch; it's empty while nothing is\n  --now-playing-format <template> what the now playing file says, with {{artist}}, {{title}}, {{album}}, {{elapsed}}, {{duration}} and {{filename}} filled in, and {{{{ and }}}} for braces; the artist and album are 'Unknown artist' and 'Unknown album' when the tags don't say, the title the file name, and with the time in it, it's written every second (default: {:?}){notify_help}\n  --config <file> config file to use (default: ~/.config/sdsupreme/config.toml)\n  --print-config print the settings in effect, after combining the config file and these options\n  --and-following when the path is a music file, queue the ones after it in the same directory to play next\n\nGiving more than one path, like two cards mounted at once, lists the files in all of them together, each only once. A path can also be an M3U or PLS playlist; given on its own, its tracks are listed in its order. A music file given on its own plays straight away, and so does an http:// URL, which is streamed; only plain HTTP can be, not https://. Paths can be left out when the config file sets music_path, to a path or a list of them; with neither, removable media with music on it, like an SD card, is looked for.\n\n{} cover <music file> writes its embedded cover art to a file; see {} cover --help.\n{} scan-gain <path> writes ReplayGain tags to FLAC files; see {} scan-gain --help.\n{} verify <path> checks FLAC files against the MD5 of their audio; see {} verify --help.\n{} check <path> decodes music files of any kind to find those that are damaged; see {} check --help.\n{} dupes <path> finds music files that are the same as each other; see {} dupes --help.\n{} history prints the tracks played lately; see {} history --help.\n{} stats prints the most played tracks, or with a path how much music there is there; see {} stats --help.\n{} list <path> prints the music files there, as JSON with --json; see {} list --help.\n{} export-queue <playlist> writes the queue from the last time it quit to a playlist; see {} export-queue --help.",
+        program, DEFAULT_COVER_SIZE, DEFAULT_FADE_MS, DEFAULT_PREFETCH_MB, DEFAULT_READ_RETRIES, DEFAULT_RETRY_DELAY_MS, DEFAULT_LOCAL_CACHE_MB, dsp::MAX_BALANCE,
+        dsp::MAX_BALANCE,
+        dsp::SILENCE_DB_RANGE.0,
+        dsp::SILENCE_DB_RANGE.1,
+        dsp::DEFAULT_SILENCE_DB,
+        dsp::MAX_SILENCE_SECONDS,
+        dsp::DEFAULT_SILENCE_SECONDS,
+        DEFAULT_STATUS_INTERVAL,
+        nowplaying::DEFAULT_FORMAT,
+        program, program, program, program, program, program, program, program, program, program, program, program, program, program, program, program, program, program,
+        notify_synopsis = NOTIFY_USAGE.0,
+        notify_help = NOTIFY_USAGE.1,
+    )
+}
+
+// What the usage says of --notify, which is only there when built with the
+// notify feature
+#[cfg(feature = "notify")]
+const NOTIFY_USAGE: (&str, &str) = (
+    "[--notify] ",
+    "\n  --notify      show a desktop notification of each track as it starts, with its cover art, using notify-send; skipping through several at once only shows the one stopped at",
+);
+#[cfg(not(feature = "notify"))]
+const NOTIFY_USAGE: (&str, &str) = ("", "");
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut paths = Vec::new();
+    let mut extensions: HashSet<&'static str> = MUSIC_EXTENSIONS.iter().copied().collect();
+    let mut exclude: Option<Vec<String>> = None;
+    let mut skip_nomedia = None;
+    let mut hidden = None;
+    let mut max_depth = None;
+    let mut follow_symlinks = None;
+    let mut shuffle = None;
+    let mut volume = None;
+    let mut bar_style = None;
+    let mut theme = None;
+    let mut sort = None;
+    let mut cover_size = None;
+    let mut replaygain = None;
+    let mut replaygain_preamp = None;
+    let mut replaygain_fallback = None;
+    let mut fade_ms = None;
+    let mut keep_speed = None;
+    let mut prefetch = None;
+    let mut prefetch_limit = None;
+    let mut read_retries = None;
+    let mut retry_delay_ms = None;
+    let mut local_cache = None;
+    let mut local_cache_size = None;
+    let mut mono = None;
+    let mut balance = None;
+    let mut skip_silence = None;
+    let mut silence_threshold = None;
+    let mut silence_min = None;
+    let mut sleep = None;
+    let mut resume = None;
+    let mut play_counts = None;
+    let mut write_tags = None;
+    let mut status_file = None;
+    let mut status_interval = None;
+    let mut now_playing_file = None;
+    let mut now_playing_format = None;
+    #[cfg(feature = "notify")]
+    let mut notify = None;
+    let mut config = None;
+    let mut print_config = false;
+    let mut and_following = false;
+    let mut rescan = false;
+
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--ext=") {
+            extensions = parse_extensions(value)?;
+        } else if arg == "--ext" {
+            let value = args.next().ok_or("--ext needs a value")?;
+            extensions = parse_extensions(value)?;
+        } else if arg == "--exclude" {
+            let value = args.next().ok_or("--exclude needs a pattern")?;
+            exclude.get_or_insert_with(Vec::new).push(value.clone());
+        } else if arg == "--no-nomedia" {
+            skip_nomedia = Some(false);
+        } else if arg == "--hidden" {
+            hidden = Some(true);
+        } else if arg == "--max-depth" {
+            let value = args.next().ok_or("--max-depth needs a value")?;
+            max_depth = match value.parse::<usize>() {
+                Ok(depth) if depth > 0 => Some(depth),
+                _ => return Err(format!("Invalid depth '{}': expected a whole number of directories, 1 or more, where 1 is only the files in the path itself", value)),
+            };
+        } else if arg == "--follow-symlinks" {
+            follow_symlinks = Some(true);
+        } else if arg == "--volume" {
+            let value = args.next().ok_or("--volume needs a value")?;
+            volume = match value.parse::<u32>() {
+                Ok(percent) if percent <= MAX_VOLUME => Some(percent),
+                _ => return Err(format!("Invalid volume '{}': expected a percentage from 0 to {}", value, MAX_VOLUME)),
+            };
+        } else if arg == "--bar" {
+            let value = args.next().ok_or("--bar needs a value")?;
+            bar_style = match BarStyle::from_name(value) {
+                Some(style) => Some(style),
+                None => return Err(format!("Invalid bar style '{}': expected 'ascii' or 'unicode'", value)),
+            };
+        } else if arg == "--theme" {
+            let value = args.next().ok_or("--theme needs a value")?;
+            theme = match theme::by_name(value) {
+                Some(theme) => Some(theme),
+                None => return Err(format!("Invalid theme '{}': expected one of {}", value, theme::names())),
+            };
+        } else if arg == "--sort" {
+            let value = args.next().ok_or("--sort needs a value")?;
+            sort = match SortKey::from_name(value) {
+                Some(key) => Some(key),
+                None => return Err(format!("Invalid sort order '{}': expected 'path', 'name', 'mtime', 'mtime-asc', 'size', 'size-asc' or 'track'", value)),
+            };
+        } else if arg == "--cover-size" {
+            let value = args.next().ok_or("--cover-size needs a value")?;
+            cover_size = match value.parse::<u16>() {
+                Ok(columns) if columns <= MAX_COVER_SIZE => Some(columns),
+                _ => return Err(format!("Invalid cover size '{}': expected a number of columns from 0 to {}", value, MAX_COVER_SIZE)),
+            };
+        } else if arg == "--replaygain" {
+            let value = args.next().ok_or("--replaygain needs a value")?;
+            replaygain = match replaygain::Mode::from_name(value) {
+                Some(mode) => Some(mode),
+                None => return Err(format!("Invalid ReplayGain mode '{}': expected 'track', 'album' or 'off'", value)),
+            };
+        } else if arg == "--replaygain-preamp" || arg == "--replaygain-fallback" {
+            let value = args.next().ok_or(format!("{} needs a value", arg))?;
+            let db = match value.parse::<f32>() {
+                Ok(db) if db.abs() <= MAX_GAIN_DB => db,
+                _ => return Err(format!("Invalid gain '{}': expected dB from -{} to {}", value, MAX_GAIN_DB, MAX_GAIN_DB)),
+            };
+            if arg == "--replaygain-preamp" {
+                replaygain_preamp = Some(db);
+            } else {
+                replaygain_fallback = Some(db);
+            }
+        } else if arg == "--fade" {
+            let value = args.next().ok_or("--fade needs a value")?;
+            fade_ms = match value.parse::<u32>() {
+                Ok(ms) if ms <= MAX_FADE_MS => Some(ms),
+                _ => return Err(format!("Invalid fade '{}': expected milliseconds from 0 to {}", value, MAX_FADE_MS)),
+            };
+        } else if arg == "--read-retries" {
+            let value = args.next().ok_or("--read-retries needs a value")?;
+            read_retries = match value.parse::<u32>() {
+                Ok(retries) if retries <= MAX_READ_RETRIES => Some(retries),
+                _ => return Err(format!("Invalid read retries '{}': expected from 0 to {}", value, MAX_READ_RETRIES)),
+            };
+        } else if arg == "--retry-delay" {
+            let value = args.next().ok_or("--retry-delay needs a value")?;
+            retry_delay_ms = match value.parse::<u32>() {
+                Ok(ms) if ms <= MAX_RETRY_DELAY_MS => Some(ms),
+                _ => return Err(format!("Invalid retry delay '{}': expected milliseconds from 0 to {}", value, MAX_RETRY_DELAY_MS)),
+            };
+        } else if arg == "--local-cache" {
+            local_cache = Some(Some(env::temp_dir()));
+        } else if let Some(dir) = arg.strip_prefix("--local-cache=") {
+            local_cache = Some(Some(PathBuf::from(dir)));
+        } else if arg == "--local-cache-size" {
+            let value = args.next().ok_or("--local-cache-size needs a value")?;
+            local_cache_size = match value.parse::<u64>() {
+                Ok(mb) if (1..=MAX_LOCAL_CACHE_MB).contains(&mb) => Some(mb),
+                _ => return Err(format!("Invalid local cache size '{}': expected megabytes from 1 to {}", value, MAX_LOCAL_CACHE_MB)),
+            };
+        } else if arg == "--prefetch-limit" {
+            let value = args.next().ok_or("--prefetch-limit needs a value")?;
+            prefetch_limit = match value.parse::<u64>() {
+                Ok(mb) if (1..=MAX_PREFETCH_MB).contains(&mb) => Some(mb),
+                _ => return Err(format!("Invalid prefetch limit '{}': expected megabytes from 1 to {}", value, MAX_PREFETCH_MB)),
+            };
+        } else if arg == "--config" {
+            config = Some(args.next().ok_or("--config needs a path")?.clone());
+        } else if arg == "--print-config" {
+            print_config = true;
+        } else if arg == "--and-following" {
+            and_following = true;
+        } else if arg == "--rescan" {
+            rescan = true;
+        } else if arg == "--mono" {
+            mono = Some(true);
+        } else if arg == "--skip-silence" {
+            skip_silence = Some(true);
+        } else if arg == "--silence-threshold" {
+            let value = args.next().ok_or("--silence-threshold needs a value")?;
+            let (lowest, highest) = dsp::SILENCE_DB_RANGE;
+            silence_threshold = match value.parse::<f32>() {
+                Ok(db) if (lowest..=highest).contains(&db) => Some(db),
+                _ => return Err(format!("Invalid silence threshold '{}': expected dBFS from {} to {}", value, lowest, highest)),
+            };
+        } else if arg == "--silence-min" {
+            let value = args.next().ok_or("--silence-min needs a value")?;
+            silence_min = match value.parse::<u32>() {
+                Ok(seconds @ 1..) if seconds <= dsp::MAX_SILENCE_SECONDS => Some(seconds),
+                _ => return Err(format!("Invalid silence length '{}': expected seconds from 1 to {}", value, dsp::MAX_SILENCE_SECONDS)),
+            };
+        } else if arg == "--sleep" {
+            let value = args.next().ok_or("--sleep needs a value")?;
+            sleep = Some(sleep::parse_duration(value)?);
+        } else if arg == "--balance" {
+            let value = args.next().ok_or("--balance needs a value")?;
+            balance = match value.parse::<i32>() {
+                Ok(balance) if balance.abs() <= dsp::MAX_BALANCE => Some(balance),
+                _ => return Err(format!("Invalid balance '{}': expected a number from -{} to {}", value, dsp::MAX_BALANCE, dsp::MAX_BALANCE)),
+            };
+        } else if arg == "--resume" {
+            resume = Some(true);
+        } else if arg == "--play-counts" {
+            play_counts = Some(true);
+        } else if arg == "--write-tags" {
+            write_tags = Some(true);
+        } else if arg == "--notify" {
+            #[cfg(feature = "notify")]
+            {
+                notify = Some(true);
+            }
+            #[cfg(not(feature = "notify"))]
+            return Err("--notify needs sdsupreme built with the notify feature".to_string());
+        } else if arg == "--status-file" {
+            status_file = Some(PathBuf::from(args.next().ok_or("--status-file needs a path")?));
+        } else if arg == "--status-interval" {
+            let value = args.next().ok_or("--status-interval needs a value")?;
+            status_interval = match value.parse::<u32>() {
+                Ok(seconds @ 1..) if seconds <= MAX_STATUS_INTERVAL => Some(seconds),
+                _ => return Err(format!("Invalid status interval '{}': expected seconds from 1 to {}", value, MAX_STATUS_INTERVAL)),
+            };
+        } else if arg == "--now-playing-file" {
+            now_playing_file = Some(PathBuf::from(args.next().ok_or("--now-playing-file needs a path")?));
+        } else if arg == "--now-playing-format" {
+            let value = args.next().ok_or("--now-playing-format needs a template")?;
+            now_playing_format = Some(Template::parse(value).map_err(|e| format!("Invalid now playing format: {}", e))?);
+        } else if arg == "--keep-speed" {
+            keep_speed = Some(true);
+        } else if arg == "--prefetch" {
+            prefetch = Some(true);
+        } else if arg == "--shuffle" {
+            shuffle = Some(true);
+        } else if arg == "--no-shuffle" {
+            shuffle = Some(false);
+        } else if arg.starts_with("--") {
+            return Err(format!("Unknown option '{}'\n{}", arg, usage(program)));
         } else {
-            sink.lock().unwrap().play();
+            paths.push(arg.clone());
         }
+    }
+
+    Ok(Options {
+        paths,
+        extensions,
+        exclude,
+        skip_nomedia,
+        hidden,
+        max_depth,
+        follow_symlinks,
+        shuffle,
+        volume,
+        bar_style,
+        theme,
+        sort,
+        cover_size,
+        replaygain,
+        replaygain_preamp,
+        replaygain_fallback,
+        fade_ms,
+        keep_speed,
+        prefetch,
+        prefetch_limit,
+        read_retries,
+        retry_delay_ms,
+        local_cache,
+        local_cache_size,
+        mono,
+        balance,
+        skip_silence,
+        silence_threshold,
+        silence_min,
+        sleep,
+        resume,
+        play_counts,
+        write_tags,
+        status_file,
+        status_interval,
+        now_playing_file,
+        now_playing_format,
+        #[cfg(feature = "notify")]
+        notify,
+        config,
+        print_config,
+        and_following,
+        rescan,
+    })
+}
 
-        // Display progress bar
-        let elapsed = start_time.elapsed().as_secs();
-        let total = duration.as_secs();
-        if total > 0 {
-            let progress = elapsed as f64 / total as f64;
-            print_progress_bar(progress, elapsed, total);
+fn cover_usage(program: &str) -> String {
+    format!(
+        "Usage: {} cover <music file> [-o <output>] [--all]\n\n  -o <output>  file to write the picture to (default: cover.jpg or cover.png, going by the image)\n  --all        write every embedded picture, numbered like cover-1.jpg, instead of just the front cover",
+        program
+    )
+}
+
+/// A subcommand like `sdsupreme cover`, given all the arguments, saying
+/// what went wrong when it fails.
+type Command = fn(&[String]) -> Result<(), String>;
+
+/// `sdsupreme cover`: write the front cover embedded in a music file to
+/// disk, or with --all every picture it has, saying what each one is.
+fn cover_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut file = None;
+    let mut output = None;
+    let mut all = false;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        if arg == "-o" || arg == "--output" {
+            output = Some(rest.next().ok_or(format!("{} needs a path", arg))?.clone());
+        } else if arg == "--all" {
+            all = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", cover_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, cover_usage(program)));
+        } else if file.is_none() {
+            file = Some(arg.clone());
+        } else {
+            return Err(cover_usage(program));
         }
+    }
+    let file = file.ok_or_else(|| cover_usage(program))?;
+
+    let pictures = cover::pictures(Path::new(&file)).map_err(|e| format!("Can't read {}: {}", file, e))?;
+    if pictures.is_empty() {
+        return Err(format!("{} has no embedded pictures", file));
+    }
+    let pictures = if all { pictures } else { cover::front_cover(pictures).into_iter().collect() };
+    let numbered = pictures.len() > 1;
+    for (index, picture) in pictures.iter().enumerate() {
+        // Numbered files are named after the output given, keeping each
+        // picture's own extension
+        let path = match (&output, numbered) {
+            (Some(output), false) => PathBuf::from(output),
+            (output, _) => {
+                let output = Path::new(output.as_deref().unwrap_or("cover"));
+                let stem = output.file_stem().map_or("cover".into(), |stem| stem.to_string_lossy());
+                let name = if numbered {
+                    format!("{}-{}.{}", stem, index + 1, picture.extension())
+                } else {
+                    format!("{}.{}", stem, picture.extension())
+                };
+                output.with_file_name(name)
+            }
+        };
+        fs::write(&path, &picture.data).map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        let size = picture.dimensions().map_or("unknown size".to_string(), |(width, height)| format!("{}x{}", width, height));
+        println!("{}: {}, {}, {}", path.display(), picture.mime_type(), size, picture.kind_name());
+    }
+    Ok(())
+}
 
-        thread::sleep(Duration::from_millis(100));
+fn history_usage(program: &str) -> String {
+    format!(
+        "Usage: {} history [-n <count>] [--since <when>]\n\nPrints the tracks played to the end or past halfway, oldest first, with when they stopped in UTC and how long they are.\n\n  -n <count>      how many of the latest to print (default: {})\n  --since <when>  only those played since a date like 2024-05-01, or since how long ago like 7d, 12h or 30m",
+        program, DEFAULT_HISTORY_COUNT
+    )
+}
 
-        if elapsed >= total {
-            break;
+/// `sdsupreme history`: print the latest tracks from the history log.
+fn history_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut count = DEFAULT_HISTORY_COUNT;
+    let mut since = None;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        if arg == "-n" || arg == "--count" {
+            let value = rest.next().ok_or(format!("{} needs a number", arg))?;
+            count = value.parse().map_err(|_| format!("Invalid count '{}'", value))?;
+        } else if arg == "--since" {
+            let value = rest.next().ok_or("--since needs a date or how long ago")?;
+            since = Some(history::parse_since(value, history::now())?);
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", history_usage(program));
+            return Ok(());
+        } else {
+            return Err(format!("Unknown option '{}'\n{}", arg, history_usage(program)));
         }
     }
 
+    let path = history::path().ok_or("Can't find the history: neither XDG_DATA_HOME nor HOME is set")?;
+    let mut entries = history::read(&path);
+    if let Some(since) = since {
+        entries.retain(|entry| entry.time >= since);
+    }
+    if entries.is_empty() {
+        println!("Nothing played yet");
+        return Ok(());
+    }
+    for entry in &entries[entries.len().saturating_sub(count)..] {
+        let mut line = format!("{}  {:>7}  {}", history::format_time(entry.time), format_time(entry.length.as_secs()), entry.name());
+        if entry.played < entry.length {
+            line += &format!("  (stopped at {})", format_time(entry.played.as_secs()));
+        }
+        println!("{}", line);
+    }
     Ok(())
 }
 
-fn print_progress_bar(progress: f64, elapsed: u64, total: u64) {
-    let bar_length = 50;
-    let filled_length = (bar_length as f64 * progress) as usize;
-    let bar: String = "=".repeat(filled_length) + &"-".repeat(bar_length - filled_length);
+fn verify_usage(program: &str) -> String {
+    format!(
+        "Usage: {} verify <path> [--jobs <n>]\n\nDecodes the FLAC files at <path>, or in the directories under it, and checks their audio against the MD5 of it each keeps, to find those that have gone bad. Files whose encoder left the MD5 out are said to be unverifiable.\n\n  --jobs <n>  how many files to check at once (default: {}, one for each core)",
+        program,
+        default_jobs()
+    )
+}
 
-    print!(
-        "\r[{}] {:02}:{:02}/{:02}:{:02}",
-        bar,
-        elapsed / 60,
-        elapsed % 60,
-        total / 60,
-        total % 60
-    );
-    io::stdout().flush().unwrap();
+/// How many files to work on at once, unless told otherwise.
+fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |cores| cores.get())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <SD card path>", args[0]);
-        return Ok(());
+/// What a subcommand goes through: the files with `extensions` in `path`,
+/// in order of their paths, or an error when `path` isn't there.
+fn scan_for_command(path: &Path, extensions: &[&'static str]) -> Result<Scanned, String> {
+    if !path.exists() {
+        return Err(format!("{} does not exist", path.display()));
     }
+    let mut scanned = list_music_files(path, &extensions.iter().copied().collect(), &Exclude::default(), false, None, None);
+    scanned.files.sort_by(|a, b| sort::natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(scanned)
+}
+
+/// Run `f` on each of `files`, `jobs` at a time, with a progress bar saying
+/// which it's got to, and give what it made of each in the same order.
+fn parallel_map<T: Send>(files: &[PathBuf], jobs: usize, f: impl Fn(&Path) -> T + Sync) -> Vec<T> {
+    let progress = ProgressBar::new(files.len() as u64).with_style(ProgressStyle::with_template("{bar:30} {pos}/{len} {wide_msg}").expect("the template is valid"));
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<T>>> = files.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(files.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(file) = files.get(index) else {
+                    return;
+                };
+                progress.set_message(file.display().to_string());
+                *results[index].lock().unwrap() = Some(f(file));
+                progress.inc(1);
+            });
+        }
+    });
+    progress.finish_and_clear();
+    results.into_iter().map(|result| result.into_inner().unwrap().expect("every file was done")).collect()
+}
+
+/// `sdsupreme verify`: check FLAC files against the MD5 of their audio,
+/// several at once, as decoding takes a while.
+fn verify_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut path = None;
+    let mut jobs = default_jobs();
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        if arg == "--jobs" || arg == "-j" {
+            let value = rest.next().ok_or(format!("{} needs a number", arg))?;
+            jobs = value.parse().ok().filter(|&jobs| jobs > 0).ok_or(format!("Invalid number of jobs '{}'", value))?;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", verify_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, verify_usage(program)));
+        } else if path.is_none() {
+            path = Some(arg.clone());
+        } else {
+            return Err(verify_usage(program));
+        }
+    }
+    let path = path.ok_or_else(|| verify_usage(program))?;
+    let scanned = scan_for_command(Path::new(&path), &["flac"])?;
+    for reason in scanned.reasons() {
+        println!("{}", reason);
+    }
+    let files = scanned.files;
+    if files.is_empty() {
+        return Err(format!("No FLAC files found in {}", path));
+    }
+
+    let outcomes = parallel_map(&files, jobs, verify::verify);
+
+    let (mut ok, mut failed, mut unverifiable) = (0, 0, 0);
+    for (file, outcome) in files.iter().zip(outcomes) {
+        match outcome {
+            verify::Outcome::Ok => {
+                ok += 1;
+                println!("OK            {}", file.display());
+            }
+            verify::Outcome::Failed(reason) => {
+                failed += 1;
+                println!("FAIL          {}: {}", file.display(), reason);
+            }
+            verify::Outcome::Unverifiable => {
+                unverifiable += 1;
+                println!("UNVERIFIABLE  {}: it has no MD5 to check against", file.display());
+            }
+        }
+    }
+    println!("{} OK, {} failed, {} unverifiable", ok, failed, unverifiable);
+    if failed > 0 {
+        return Err(format!("{} of {} files failed", failed, files.len()));
+    }
+    Ok(())
+}
+
+fn check_usage(program: &str) -> String {
+    format!(
+        "Usage: {} check <path> [--jobs <n>] [--fail-fast]\n\nDecodes the whole of every music file at <path>, or in the directories under it, to find those that are damaged or were cut off copying them, saying how far into each the trouble starts and what's wrong. Files of a kind there's no decoder for can't be checked.\n\n  --jobs <n>   how many files to check at once (default: {}, one for each core)\n  --fail-fast  stop at the first file that fails",
+        program,
+        default_jobs()
+    )
+}
+
+/// `sdsupreme check`: decode whole music files of any kind to find those
+/// that are damaged, several at once.
+fn check_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut path = None;
+    let mut jobs = default_jobs();
+    let mut fail_fast = false;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        if arg == "--jobs" || arg == "-j" {
+            let value = rest.next().ok_or(format!("{} needs a number", arg))?;
+            jobs = value.parse().ok().filter(|&jobs| jobs > 0).ok_or(format!("Invalid number of jobs '{}'", value))?;
+        } else if arg == "--fail-fast" {
+            fail_fast = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", check_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, check_usage(program)));
+        } else if path.is_none() {
+            path = Some(arg.clone());
+        } else {
+            return Err(check_usage(program));
+        }
+    }
+    let path = path.ok_or_else(|| check_usage(program))?;
+    let scanned = scan_for_command(Path::new(&path), MUSIC_EXTENSIONS)?;
+    for reason in scanned.reasons() {
+        println!("{}", reason);
+    }
+    let files = scanned.files;
+    if files.is_empty() {
+        return Err(format!("No music files found in {}", path));
+    }
+
+    let retry = Retry::new(DEFAULT_READ_RETRIES, Duration::from_millis(DEFAULT_RETRY_DELAY_MS as u64));
+    let stopped = AtomicBool::new(false);
+    // Once one's failed with --fail-fast, the rest are passed over
+    let outcomes = parallel_map(&files, jobs, |file| {
+        if stopped.load(Ordering::SeqCst) {
+            return None;
+        }
+        let outcome = check::check(file, &retry);
+        if fail_fast && matches!(outcome, check::Outcome::Failed(..)) {
+            stopped.store(true, Ordering::SeqCst);
+        }
+        Some(outcome)
+    });
+
+    let (mut ok, mut failed, mut unsupported, mut unchecked) = (0, 0, 0, 0);
+    for (file, outcome) in files.iter().zip(outcomes) {
+        match outcome {
+            Some(check::Outcome::Ok) => {
+                ok += 1;
+                println!("OK     {}", file.display());
+            }
+            Some(check::Outcome::Failed(at, reason)) => {
+                failed += 1;
+                match at {
+                    Some(at) => println!("FAIL   {}: at {}: {}", file.display(), format_time(at.as_secs()), reason),
+                    None => println!("FAIL   {}: {}", file.display(), reason),
+                }
+            }
+            Some(check::Outcome::Unsupported(reason)) => {
+                unsupported += 1;
+                println!("SKIP   {}: {}", file.display(), reason);
+            }
+            None => unchecked += 1,
+        }
+    }
+    let mut summary = format!("{} OK, {} failed, {} couldn't be checked", ok, failed, unsupported);
+    if unchecked > 0 {
+        summary += &format!(", {} left unchecked after the first failure", unchecked);
+    }
+    println!("{}", summary);
+    if failed > 0 {
+        return Err(format!("{} of {} files failed", failed, files.len() - unchecked));
+    }
+    Ok(())
+}
+
+fn dupes_usage(program: &str) -> String {
+    format!(
+        "Usage: {} dupes <path> [--jobs <n>] [--by-file] [--delete-interactive]\n\nFinds the music files at <path>, or in the directories under it, that have the same audio, even in different kinds of file, and prints them in groups, those wasting the most room first. Every file is decoded to tell, as the same audio in different kinds of file isn't the same size; files that can't be decoded are compared byte for byte.\n\n  --jobs <n>            how many files to work on at once (default: {}, one for each core)\n  --by-file             only compare files byte for byte, and only those the same size, which is much quicker but misses the same audio in different files\n  --delete-interactive  ask which file of each group to keep, and delete the rest",
+        program,
+        default_jobs()
+    )
+}
+
+/// `sdsupreme dupes`: find music files that are the same as each other, by
+/// their audio or byte for byte, optionally deleting all but one of each.
+fn dupes_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut path = None;
+    let mut jobs = default_jobs();
+    let mut by_file = false;
+    let mut delete = false;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        if arg == "--jobs" || arg == "-j" {
+            let value = rest.next().ok_or(format!("{} needs a number", arg))?;
+            jobs = value.parse().ok().filter(|&jobs| jobs > 0).ok_or(format!("Invalid number of jobs '{}'", value))?;
+        } else if arg == "--by-file" {
+            by_file = true;
+        } else if arg == "--delete-interactive" {
+            delete = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", dupes_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, dupes_usage(program)));
+        } else if path.is_none() {
+            path = Some(arg.clone());
+        } else {
+            return Err(dupes_usage(program));
+        }
+    }
+    let path = path.ok_or_else(|| dupes_usage(program))?;
+    if delete && !io::stdin().is_terminal() {
+        return Err("--delete-interactive needs a terminal to ask which files to keep".to_string());
+    }
+
+    let scanned = scan_for_command(Path::new(&path), MUSIC_EXTENSIONS)?;
+    for reason in scanned.reasons() {
+        println!("{}", reason);
+    }
+    let mut files = Vec::new();
+    for file in scanned.files {
+        match fs::metadata(&file) {
+            Ok(metadata) => files.push((file, metadata.len())),
+            Err(e) => println!("can't read {}: {}", file.display(), e),
+        }
+    }
+    if files.is_empty() {
+        return Err(format!("No music files found in {}", path));
+    }
+    let candidates = if by_file { dupes::same_size(&files) } else { (0..files.len()).collect() };
 
-    let sd_card_path = Path::new(&args[1]);
-    if !sd_card_path.exists() {
-        eprintln!("The provided path does not exist.");
+    let retry = Retry::new(DEFAULT_READ_RETRIES, Duration::from_millis(DEFAULT_RETRY_DELAY_MS as u64));
+    let paths: Vec<PathBuf> = candidates.iter().map(|&candidate| files[candidate].0.clone()).collect();
+    let keys = parallel_map(&paths, jobs, |file| dupes::key(file, by_file, &retry));
+
+    let mut keyed = Vec::new();
+    for (&candidate, key) in candidates.iter().zip(keys) {
+        let (file, size) = &files[candidate];
+        match key {
+            Ok(key) => keyed.push((file.clone(), *size, key)),
+            Err(e) => println!("can't read {}: {}", file.display(), e),
+        }
+    }
+    let groups = dupes::group(keyed);
+    if groups.is_empty() {
+        println!("No duplicates found among {} files", files.len());
         return Ok(());
     }
+    let wasted: u64 = groups.iter().map(dupes::Group::wasted).sum();
+    for group in &groups {
+        println!("{} wasted:", format_size(group.wasted()));
+        for (number, (file, size)) in group.files.iter().enumerate() {
+            println!("  {}. {} ({})", number + 1, file.display(), format_size(*size));
+        }
+        if !delete {
+            continue;
+        }
+        print!("Keep which? (1-{}, Enter to keep them all, q to stop) ", group.files.len());
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).map_err(|e| e.to_string())?;
+        let answer = answer.trim();
+        if answer == "q" {
+            break;
+        }
+        let keep = match answer.parse::<usize>() {
+            Ok(number) if (1..=group.files.len()).contains(&number) => number - 1,
+            _ => {
+                println!("Keeping them all");
+                continue;
+            }
+        };
+        for (number, (file, _)) in group.files.iter().enumerate() {
+            if number == keep {
+                continue;
+            }
+            match fs::remove_file(file) {
+                Ok(()) => println!("Deleted {}", file.display()),
+                Err(e) => println!("Can't delete {}: {}", file.display(), e),
+            }
+        }
+    }
+    println!("{} groups of duplicates, wasting {}", groups.len(), format_size(wasted));
+    Ok(())
+}
 
-    let music_files = list_music_files(sd_card_path);
-    if music_files.is_empty() {
-        println!("No music files found in the provided path.");
+fn stats_usage(program: &str) -> String {
+    format!(
+        "Usage: {} stats [--top <count>]\n       {} stats <path> [--accurate] [--json]\n\nPrints the tracks played most, each counted once for every time it was played to the end or past halfway, with its path on the card.\n\nGiven a path, prints how much music there is there instead, or in the directories under it: how many files, how big they are and how long they last, altogether and for each kind of file, and the longest and shortest tracks. Their lengths are read from their headers, which is quick.\n\n  --top <count>  how many to print (default: {})\n  --accurate     decode every file to tell how long it lasts, which takes much longer but is right even where the headers are wrong or don't say\n  --json         print the stats of a path as JSON, for scripts",
+        program, program, DEFAULT_HISTORY_COUNT
+    )
+}
+
+/// `sdsupreme stats`: print the most played tracks, or given a path, how
+/// much music there is there.
+fn stats_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut top = None;
+    let mut path = None;
+    let mut accurate = false;
+    let mut json = false;
+    let mut rest = args.iter().skip(2);
+    while let Some(arg) = rest.next() {
+        if arg == "--top" || arg == "-n" {
+            let value = rest.next().ok_or(format!("{} needs a number", arg))?;
+            top = Some(value.parse().map_err(|_| format!("Invalid count '{}'", value))?);
+        } else if arg == "--accurate" {
+            accurate = true;
+        } else if arg == "--json" {
+            json = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", stats_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, stats_usage(program)));
+        } else if path.is_none() {
+            path = Some(arg.clone());
+        } else {
+            return Err(stats_usage(program));
+        }
+    }
+    match path {
+        Some(path) if top.is_none() => return library_stats(&path, accurate, json),
+        Some(_) => return Err(format!("--top is for the most played tracks, not the stats of a path\n{}", stats_usage(program))),
+        None if accurate || json => return Err(format!("--accurate and --json need a path to give the stats of\n{}", stats_usage(program))),
+        None => {}
+    }
+    let top = top.unwrap_or(DEFAULT_HISTORY_COUNT);
+
+    let path = plays::path().ok_or("Can't find the play counts: neither XDG_DATA_HOME nor HOME is set")?;
+    let mut entries = plays::read(&path);
+    if entries.is_empty() {
+        println!("Nothing played yet");
         return Ok(());
     }
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.file.cmp(&b.file)));
+    let width = entries[0].count.to_string().len();
+    for (rank, entry) in entries.iter().take(top).enumerate() {
+        println!("{:>3}. {:>width$}  {}", rank + 1, entry.count, entry.file, width = width);
+    }
+    Ok(())
+}
+
+/// Print how much music there is at `path`, as JSON when `json` says, with
+/// each file's length read from its headers unless `accurate` says to decode
+/// it. Files that can't be read are counted rather than stopping it.
+fn library_stats(path: &str, accurate: bool, json: bool) -> Result<(), String> {
+    let scanned = scan_for_command(Path::new(path), MUSIC_EXTENSIONS)?;
+    if !json {
+        for warning in &scanned.warnings {
+            println!("{}", warning);
+        }
+    }
+    let files = scanned.files;
 
-    println!("Found the following music files:");
-    for (index, file) in music_files.iter().enumerate() {
-        println!("{}: {}", index, file);
+    let retry = Retry::new(DEFAULT_READ_RETRIES, Duration::from_millis(DEFAULT_RETRY_DELAY_MS as u64));
+    // Each file's size, and how long it lasts if that can be told
+    let measured = parallel_map(&files, default_jobs(), |file| {
+        let size = fs::metadata(file).map(|metadata| metadata.len());
+        size.map(|size| (size, stats::length(file, accurate, &retry)))
+    });
+
+    let mut stats = stats::Stats { unreadable: scanned.unreadable.iter().map(scan::Unreadable::reason).collect(), ..Default::default() };
+    for (file, measured) in files.iter().zip(measured) {
+        match measured {
+            Ok((size, length)) => stats.add(file, music_extension(file).unwrap_or_default(), size, length),
+            Err(e) => stats.unreadable.push(format!("can't read {}: {}", file.display(), e)),
+        }
+    }
+    if json {
+        println!("{}", stats.to_json());
+        return Ok(());
+    }
+    for reason in &stats.unreadable {
+        println!("{}", reason);
+    }
+    let files = |count: usize| format!("{} {}", count, if count == 1 { "file" } else { "files" });
+    println!("{}, {}, lasting {}", files(stats.total.files), format_size(stats.total.bytes), format_time(stats.total.playtime.as_secs()));
+    if stats.unknown_length > 0 {
+        println!("(not counting how long {} last{}, which couldn't be told)", files(stats.unknown_length), if stats.unknown_length == 1 { "s" } else { "" });
+    }
+    let mut formats: Vec<(&&str, &stats::Tally)> = stats.formats.iter().collect();
+    formats.sort_by_key(|(_, tally)| std::cmp::Reverse(tally.bytes));
+    for (format, tally) in formats {
+        println!("  {:<5} {:>12} {:>10} {:>10}", format, files(tally.files), format_size(tally.bytes), format_time(tally.playtime.as_secs()));
     }
+    if let Some((file, length)) = &stats.longest {
+        println!("Longest:  {} ({})", file.display(), format_time(length.as_secs()));
+    }
+    if let Some((file, length)) = &stats.shortest {
+        println!("Shortest: {} ({})", file.display(), format_time(length.as_secs()));
+    }
+    if !stats.unreadable.is_empty() {
+        println!("{}", unreadable_note(stats.unreadable.len()));
+    }
+    Ok(())
+}
 
-    println!("Enter the number of the file you want to play:");
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
-    let selection: usize = input.trim().parse().expect("Please enter a valid number");
+fn list_usage(program: &str) -> String {
+    format!(
+        "Usage: {} list <path> [--json]\n\nPrints every music file at the path, or in the directories under it, by its absolute path, one to a line, then exits. Entries that can't be read, and links that loop or lead nowhere, are reported on stderr.\n\n  --json  print them as a JSON array for scripts instead, each file an object with these fields, which stay the same from one version to the next:\n            path         the absolute path\n            size         how big the file is, in bytes\n            extension    the kind of file, like \"flac\"\n            duration_ms  how long it lasts, from its headers, or null when they don't say\n            tags         an object with artist, title, album, track, genre and year, each null when the file doesn't have it, or null when its tags can't be read\n          A file or directory that can't be read is an object with only path and error, what went wrong. Links that loop or lead nowhere are still reported on stderr.",
+        program
+    )
+}
 
-    if selection >= music_files.len() {
-        eprintln!("Invalid selection.");
+/// `sdsupreme list`: print the music files at a path, as JSON for scripts
+/// when asked, with what can be told about each from its headers.
+fn list_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut path = None;
+    let mut json = false;
+    for arg in args.iter().skip(2) {
+        if arg == "--json" {
+            json = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", list_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, list_usage(program)));
+        } else if path.is_none() {
+            path = Some(arg.clone());
+        } else {
+            return Err(list_usage(program));
+        }
+    }
+    let path = path.ok_or_else(|| list_usage(program))?;
+    let mut scanned = scan_for_command(&listing::absolute(Path::new(&path)), MUSIC_EXTENSIONS)?;
+    let files = std::mem::take(&mut scanned.files);
+    if !json {
+        for reason in scanned.reasons() {
+            eprintln!("{}", reason);
+        }
+        for file in &files {
+            println!("{}", file.display());
+        }
         return Ok(());
     }
 
-    let selected_file = music_files[selection].clone();
-    println!("Playing {}", selected_file);
+    let listed = parallel_map(&files, default_jobs(), listing::look);
+    // Links that loop or lead nowhere aren't files to list
+    for warning in &scanned.warnings {
+        eprintln!("{}", warning);
+    }
 
-    let is_paused = Arc::new(AtomicBool::new(false));
-    let (_stream, stream_handle) = OutputStream::try_default().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-    let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?));
+    let mut entries: Vec<String> = files
+        .iter()
+        .zip(listed)
+        .map(|(file, listed)| listing::to_json(file, &listed.map_err(|e| e.to_string())))
+        .collect();
+    entries.extend(scanned.unreadable.iter().map(|unreadable| listing::to_json(&unreadable.path, &Err(unreadable.error.clone()))));
+    if entries.is_empty() {
+        println!("[]");
+    } else {
+        println!("[\n{}\n]", entries.join(",\n"));
+    }
+    Ok(())
+}
 
-    // Set up Ctrl+C handler
-    {
-        let is_paused = Arc::clone(&is_paused);
-        ctrlc::set_handler(move || {
-            let paused = is_paused.load(Ordering::SeqCst);
-            is_paused.store(!paused, Ordering::SeqCst);
-        }).expect("Error setting Ctrl-C handler");
+fn export_queue_usage(program: &str) -> String {
+    format!(
+        "Usage: {} export-queue [--force] <playlist>\n\nWrites the queue from the last time sdsupreme quit as an extended M3U playlist, with paths relative to where the playlist goes so it works wherever the card is mounted. It's written as UTF-8, so name it .m3u8. The queue can also be written while playing, with 'w'.\n\n  --force  replace the playlist if it's there already",
+        program
+    )
+}
+
+/// `sdsupreme export-queue`: write the queue that was kept when the
+/// program last quit to a playlist.
+fn export_queue_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut target = None;
+    let mut force = false;
+    for arg in args.iter().skip(2) {
+        if arg == "--force" {
+            force = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", export_queue_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, export_queue_usage(program)));
+        } else if target.is_none() {
+            target = Some(PathBuf::from(arg));
+        } else {
+            return Err(export_queue_usage(program));
+        }
     }
+    let target = target.ok_or_else(|| export_queue_usage(program))?;
 
-    let sink_clone = Arc::clone(&sink);
-    let is_paused_clone = Arc::clone(&is_paused);
+    let path = queue::path().ok_or("Can't find the queue: neither XDG_DATA_HOME nor HOME is set")?;
+    let files = queue::read(&path);
+    if files.is_empty() {
+        return Err("No queue kept yet: it's kept when sdsupreme quits after playing something".to_string());
+    }
+    export_playlist(&files, &target, force).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => format!("{} is there already; add --force to replace it", target.display()),
+        _ => format!("Can't write {}: {}", target.display(), e),
+    })?;
+    println!("Wrote {} tracks to {}", files.len(), target.display());
+    Ok(())
+}
+
+/// The files in the queue, in order.
+fn queue_files(controls: &Controls, music_files: &[PathBuf]) -> Vec<PathBuf> {
+    controls.queue.lock().unwrap().tracks().iter().map(|&track| PathBuf::from(&music_files[track])).collect()
+}
+
+/// Write `files` to the playlist at `target`, replacing one that's there
+/// only when `replace` is true.
+fn export_playlist(files: &[PathBuf], target: &Path, replace: bool) -> io::Result<()> {
+    let entries: Vec<playlist::Entry> = files.iter().map(|file| playlist::Entry::read(file)).collect();
+    playlist::write_m3u(target, &entries, replace)
+}
+
+fn scan_gain_usage(program: &str) -> String {
+    format!(
+        "Usage: {} scan-gain <path> [--force] [--dry-run]\n\nMeasures the loudness of the FLAC files at <path>, or in the directories under it, and writes ReplayGain tags to them. Each directory is taken to be an album.\n\n  --force    measure directories again even when all their files have ReplayGain tags already\n  --dry-run  print what would be written without changing any files",
+        program
+    )
+}
 
-    thread::spawn(move || {
-        play_music(selected_file, is_paused_clone, sink_clone).expect("Error playing music");
+/// `sdsupreme scan-gain`: measure the loudness of FLAC files and tag them
+/// with ReplayGain, album gain going by directory. A directory's files are
+/// all measured and tagged together, since the album gain covers them all.
+fn scan_gain_command(args: &[String]) -> Result<(), String> {
+    let program = args.first().map(String::as_str).unwrap_or("sdsupreme");
+    let mut path = None;
+    let mut force = false;
+    let mut dry_run = false;
+    for arg in args.iter().skip(2) {
+        if arg == "--force" {
+            force = true;
+        } else if arg == "--dry-run" {
+            dry_run = true;
+        } else if arg == "--help" || arg == "-h" {
+            println!("{}", scan_gain_usage(program));
+            return Ok(());
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option '{}'\n{}", arg, scan_gain_usage(program)));
+        } else if path.is_none() {
+            path = Some(arg.clone());
+        } else {
+            return Err(scan_gain_usage(program));
+        }
+    }
+    let path = path.ok_or_else(|| scan_gain_usage(program))?;
+    let scanned = scan_for_command(Path::new(&path), &["flac"])?;
+    for reason in scanned.reasons() {
+        println!("{}", reason);
+    }
+    let files = scanned.files;
+    if files.is_empty() {
+        return Err(format!("No FLAC files found in {}", path));
+    }
+    let mut albums: Vec<(&Path, Vec<&PathBuf>)> = Vec::new();
+    for file in &files {
+        let directory = file.parent().unwrap_or(Path::new(""));
+        match albums.iter_mut().find(|(album, _)| *album == directory) {
+            Some((_, tracks)) => tracks.push(file),
+            None => albums.push((directory, vec![file])),
+        }
+    }
+    let mut skipped = 0;
+    albums.retain(|(_, tracks)| {
+        let has_tags = |file: &&PathBuf| tags::read(file).is_ok_and(|tags| tags.track_gain.is_some() && tags.album_gain.is_some());
+        let tagged = !force && tracks.iter().all(has_tags);
+        if tagged {
+            skipped += tracks.len();
+        }
+        !tagged
     });
 
-    // Terminal setup for UI
-    execute!(io::stdout(), EnterAlternateScreen)?;
-    terminal::enable_raw_mode()?;
-    execute!(io::stdout(), cursor::Hide)?;
+    // Stop between files rather than partway through writing one
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = Arc::clone(&stop);
+        ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst)).map_err(|e| e.to_string())?;
+    }
+    // Every file's measured first, several at once, and then each album's
+    // tagged in turn
+    let measuring: Vec<PathBuf> = albums.iter().flat_map(|(_, tracks)| tracks.iter().map(|&file| file.clone())).collect();
+    let mut measurements = parallel_map(&measuring, default_jobs(), |file| measure(file, &stop).map_err(|e| e.to_string())).into_iter();
+    let (mut tagged, mut failed) = (0, 0);
+    for (_, tracks) in &albums {
+        let mut measured: Vec<(&PathBuf, Loudness)> = Vec::new();
+        for (&file, measurement) in tracks.iter().zip(measurements.by_ref()) {
+            match measurement {
+                Ok(Some(loudness)) => measured.push((file, loudness)),
+                Ok(None) => return Err(format!("Stopped; {} files were tagged before then", tagged)),
+                Err(e) => {
+                    println!("{}: can't decode: {}", file.display(), e);
+                    failed += 1;
+                }
+            }
+        }
 
-    // Handle key events for pausing, resuming, and exiting
-    loop {
-        if event::poll(Duration::from_millis(100))? {
-            if let event::Event::Key(key_event) = event::read()? {
-                match key_event.code {
-                    KeyCode::Char('p') => {
-                        let paused = is_paused.load(Ordering::SeqCst);
-                        is_paused.store(!paused, Ordering::SeqCst);
-                    }
-                    KeyCode::Esc => break,
-                    _ => {}
+        let all: Vec<&Loudness> = measured.iter().map(|(_, loudness)| loudness).collect();
+        let album_lufs = loudness::integrated(&all);
+        let album_peak = all.iter().map(|loudness| loudness.peak).fold(0.0, f32::max);
+        for (file, track) in &measured {
+            let (Some(track_lufs), Some(album_lufs)) = (loudness::integrated(&[track]), album_lufs) else {
+                println!("{}: too short or quiet to measure", file.display());
+                failed += 1;
+                continue;
+            };
+            let (track_gain, album_gain) = (loudness::gain(track_lufs), loudness::gain(album_lufs));
+            let summary = format!(
+                "{}: {:.1} LUFS, track gain {:+.2} dB, peak {:.6}; album gain {:+.2} dB, peak {:.6}",
+                file.display(), track_lufs, track_gain, track.peak, album_gain, album_peak
+            );
+            if dry_run {
+                println!("{}", summary);
+                continue;
+            }
+            let fields = [
+                ("REPLAYGAIN_TRACK_GAIN", format!("{:+.2} dB", track_gain)),
+                ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", track.peak)),
+                ("REPLAYGAIN_ALBUM_GAIN", format!("{:+.2} dB", album_gain)),
+                ("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", album_peak)),
+            ];
+            match tags::write_flac_comments(file, &fields) {
+                Ok(()) => {
+                    println!("{}", summary);
+                    tagged += 1;
+                }
+                Err(e) => {
+                    println!("{}: can't write tags: {}", file.display(), e);
+                    failed += 1;
                 }
             }
         }
     }
 
-    // Cleanup
-    terminal::disable_raw_mode()?;
-    execute!(io::stdout(), LeaveAlternateScreen)?;
-    execute!(io::stdout(), cursor::Show)?;
-    println!("\nExiting...");
+    let done = if dry_run { "Measured" } else { "Tagged" };
+    let count = if dry_run { files.len() - skipped - failed } else { tagged };
+    println!("{} {} files, skipping {} that already had ReplayGain tags", done, count, skipped);
+    if !scanned.unreadable.is_empty() {
+        println!("{}", unreadable_note(scanned.unreadable.len()));
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files failed", failed, files.len()));
+    }
     Ok(())
 }
+
+/// Decode the file at `path` and measure its loudness, giving up with None
+/// once `stop` is set.
+fn measure(path: &Path, stop: &AtomicBool) -> Result<Option<Loudness>, Box<dyn std::error::Error>> {
+    let retry = Retry::new(DEFAULT_READ_RETRIES, Duration::from_millis(DEFAULT_RETRY_DELAY_MS as u64));
+    let source = open_source(path, None, &retry, &Failure::default())?;
+    let mut meter = Meter::new(source.sample_rate(), source.channels());
+    for (index, sample) in source.enumerate() {
+        if index % 65536 == 0 && stop.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        meter.add(sample);
+    }
+    Ok(Some(meter.finish()))
+}
+
+/// Where a setting's value came from.
+#[derive(Clone, Copy)]
+enum Origin {
+    CommandLine,
+    ConfigFile,
+    Default,
+}
+
+impl Origin {
+    fn label(self) -> &'static str {
+        match self {
+            Origin::CommandLine => "command line",
+            Origin::ConfigFile => "config file",
+            Origin::Default => "default",
+        }
+    }
+}
+
+/// The command line's value if it gave one, then the config file's, then
+/// `default`, with where it came from.
+fn pick<T>(command_line: Option<T>, config_file: Option<T>, default: T) -> (T, Origin) {
+    match (command_line, config_file) {
+        (Some(value), _) => (value, Origin::CommandLine),
+        (None, Some(value)) => (value, Origin::ConfigFile),
+        (None, None) => (default, Origin::Default),
+    }
+}
+
+/// The settings in effect once the command line and config file are combined.
+struct Settings {
+    paths: Option<(Vec<String>, Origin)>,
+    exclude: (Vec<String>, Origin),
+    skip_nomedia: (bool, Origin),
+    hidden: (bool, Origin),
+    follow_symlinks: (bool, Origin),
+    shuffle: (bool, Origin),
+    volume: (u32, Origin),
+    bar_style: (BarStyle, Origin),
+    theme: (Theme, Origin),
+    sort: (SortKey, Origin),
+    cover_size: (u16, Origin),
+    replaygain: (replaygain::Mode, Origin),
+    replaygain_preamp: (f32, Origin),
+    replaygain_fallback: (f32, Origin),
+    fade_ms: (u32, Origin),
+    keep_speed: (bool, Origin),
+    prefetch: (bool, Origin),
+    /// In megabytes.
+    prefetch_limit: (u64, Origin),
+    read_retries: (u32, Origin),
+    retry_delay_ms: (u32, Origin),
+    local_cache: (Option<PathBuf>, Origin),
+    /// In megabytes.
+    local_cache_size: (u64, Origin),
+    mono: (bool, Origin),
+    balance: (i32, Origin),
+    skip_silence: (bool, Origin),
+    silence_threshold: (f32, Origin),
+    silence_min: (u32, Origin),
+    resume: (bool, Origin),
+    play_counts: (bool, Origin),
+    write_tags: (bool, Origin),
+    status_file: (Option<PathBuf>, Origin),
+    /// In seconds.
+    status_interval: (u32, Origin),
+    now_playing_file: (Option<PathBuf>, Origin),
+    now_playing_format: (Template, Origin),
+    #[cfg(feature = "notify")]
+    notify: (bool, Origin),
+}
+
+impl Settings {
+    fn new(options: &Options, config: &config::Config) -> Settings {
+        let paths = match &config.music_path {
+            _ if !options.paths.is_empty() => Some((options.paths.clone(), Origin::CommandLine)),
+            Some(paths) => Some((paths.clone(), Origin::ConfigFile)),
+            None => None,
+        };
+        Settings {
+            paths,
+            exclude: pick(options.exclude.clone(), config.exclude.clone(), Vec::new()),
+            skip_nomedia: pick(options.skip_nomedia, config.skip_nomedia, true),
+            hidden: pick(options.hidden, config.hidden, false),
+            follow_symlinks: pick(options.follow_symlinks, config.follow_symlinks, false),
+            shuffle: pick(options.shuffle, config.shuffle, false),
+            volume: pick(options.volume, config.default_volume, 100),
+            bar_style: pick(options.bar_style, config.bar_style, BarStyle::Ascii),
+            theme: pick(options.theme, config.theme, default_theme()),
+            sort: pick(options.sort, config.sort, SortKey::Name),
+            cover_size: pick(options.cover_size, config.cover_size, DEFAULT_COVER_SIZE),
+            replaygain: pick(options.replaygain, config.replaygain, replaygain::Mode::Off),
+            replaygain_preamp: pick(options.replaygain_preamp, config.replaygain_preamp, 0.0),
+            replaygain_fallback: pick(options.replaygain_fallback, config.replaygain_fallback, -6.0),
+            fade_ms: pick(options.fade_ms, config.fade_ms, DEFAULT_FADE_MS),
+            keep_speed: pick(options.keep_speed, config.keep_speed, false),
+            prefetch: pick(options.prefetch, config.prefetch, false),
+            prefetch_limit: pick(options.prefetch_limit, config.prefetch_limit, DEFAULT_PREFETCH_MB),
+            read_retries: pick(options.read_retries, config.read_retries, DEFAULT_READ_RETRIES),
+            retry_delay_ms: pick(options.retry_delay_ms, config.retry_delay_ms, DEFAULT_RETRY_DELAY_MS),
+            local_cache: pick(options.local_cache.clone(), config.local_cache.clone(), None),
+            local_cache_size: pick(options.local_cache_size, config.local_cache_size, DEFAULT_LOCAL_CACHE_MB),
+            mono: pick(options.mono, config.mono, false),
+            balance: pick(options.balance, config.balance, 0),
+            skip_silence: pick(options.skip_silence, config.skip_silence, false),
+            silence_threshold: pick(options.silence_threshold, config.silence_threshold, dsp::DEFAULT_SILENCE_DB),
+            silence_min: pick(options.silence_min, config.silence_min, dsp::DEFAULT_SILENCE_SECONDS),
+            resume: pick(options.resume, config.resume, false),
+            play_counts: pick(options.play_counts, config.play_counts, false),
+            write_tags: pick(options.write_tags, config.write_tags, false),
+            status_file: pick(options.status_file.clone().map(Some), config.status_file.clone().map(Some), None),
+            status_interval: pick(options.status_interval, config.status_interval, DEFAULT_STATUS_INTERVAL),
+            now_playing_file: pick(options.now_playing_file.clone().map(Some), config.now_playing_file.clone().map(Some), None),
+            now_playing_format: pick(options.now_playing_format.clone(), config.now_playing_format.clone(), Template::default()),
+            #[cfg(feature = "notify")]
+            notify: pick(options.notify, config.notify, false),
+        }
+    }
+
+    /// The settings as a config file, noting where each value came from.
+    fn config_lines(&self, config: &config::Config) -> Vec<String> {
+        let file = match (&config.path, config::default_path()) {
+            (Some(path), _) => format!("# Read from {}", path.display()),
+            (None, Some(path)) => format!("# No config file at {}", path.display()),
+            (None, None) => "# No config file".to_string(),
+        };
+        let setting = |name: &str, value: String, source: Origin| format!("{} = {}  # {}", name, value, source.label());
+        let mut lines = vec![file];
+        lines.push(match &self.paths {
+            Some((paths, source)) if paths.len() == 1 => setting("music_path", format!("{:?}", paths[0]), *source),
+            Some((paths, source)) => {
+                let quoted: Vec<String> = paths.iter().map(|path| format!("{:?}", path)).collect();
+                setting("music_path", format!("[{}]", quoted.join(", ")), *source)
+            }
+            None => "# music_path is not set".to_string(),
+        });
+        let patterns: Vec<String> = self.exclude.0.iter().map(|pattern| format!("{:?}", pattern)).collect();
+        lines.push(setting("exclude", format!("[{}]", patterns.join(", ")), self.exclude.1));
+        lines.push(setting("skip_nomedia", self.skip_nomedia.0.to_string(), self.skip_nomedia.1));
+        lines.push(setting("hidden", self.hidden.0.to_string(), self.hidden.1));
+        lines.push(setting("follow_symlinks", self.follow_symlinks.0.to_string(), self.follow_symlinks.1));
+        lines.push(setting("default_volume", self.volume.0.to_string(), self.volume.1));
+        lines.push(setting("shuffle", self.shuffle.0.to_string(), self.shuffle.1));
+        lines.push(setting("bar_style", format!("{:?}", self.bar_style.0.name()), self.bar_style.1));
+        lines.push(setting("theme", format!("{:?}", self.theme.0.name), self.theme.1));
+        lines.push(setting("sort", format!("{:?}", self.sort.0.name()), self.sort.1));
+        lines.push(setting("cover_size", self.cover_size.0.to_string(), self.cover_size.1));
+        lines.push(setting("replaygain", format!("{:?}", self.replaygain.0.name()), self.replaygain.1));
+        lines.push(setting("replaygain_preamp", format!("{:?}", self.replaygain_preamp.0), self.replaygain_preamp.1));
+        lines.push(setting("replaygain_fallback", format!("{:?}", self.replaygain_fallback.0), self.replaygain_fallback.1));
+        lines.push(setting("fade_ms", self.fade_ms.0.to_string(), self.fade_ms.1));
+        lines.push(setting("keep_speed", self.keep_speed.0.to_string(), self.keep_speed.1));
+        lines.push(setting("prefetch", self.prefetch.0.to_string(), self.prefetch.1));
+        lines.push(setting("prefetch_limit", self.prefetch_limit.0.to_string(), self.prefetch_limit.1));
+        lines.push(setting("read_retries", self.read_retries.0.to_string(), self.read_retries.1));
+        lines.push(setting("retry_delay_ms", self.retry_delay_ms.0.to_string(), self.retry_delay_ms.1));
+        let local_cache = self.local_cache.0.as_ref().map_or("false".to_string(), |dir| format!("{:?}", dir.display().to_string()));
+        lines.push(setting("local_cache", local_cache, self.local_cache.1));
+        lines.push(setting("local_cache_size", self.local_cache_size.0.to_string(), self.local_cache_size.1));
+        lines.push(setting("mono", self.mono.0.to_string(), self.mono.1));
+        lines.push(setting("balance", self.balance.0.to_string(), self.balance.1));
+        lines.push(setting("skip_silence", self.skip_silence.0.to_string(), self.skip_silence.1));
+        lines.push(setting("silence_threshold", format!("{:?}", self.silence_threshold.0), self.silence_threshold.1));
+        lines.push(setting("silence_min", self.silence_min.0.to_string(), self.silence_min.1));
+        lines.push(setting("resume", self.resume.0.to_string(), self.resume.1));
+        lines.push(setting("play_counts", self.play_counts.0.to_string(), self.play_counts.1));
+        lines.push(setting("write_tags", self.write_tags.0.to_string(), self.write_tags.1));
+        lines.push(match &self.status_file.0 {
+            Some(path) => setting("status_file", format!("{:?}", path.display().to_string()), self.status_file.1),
+            None => "# status_file is not set".to_string(),
+        });
+        lines.push(setting("status_interval", self.status_interval.0.to_string(), self.status_interval.1));
+        lines.push(match &self.now_playing_file.0 {
+            Some(path) => setting("now_playing_file", format!("{:?}", path.display().to_string()), self.now_playing_file.1),
+            None => "# now_playing_file is not set".to_string(),
+        });
+        lines.push(setting("now_playing_format", format!("{:?}", self.now_playing_format.0.text()), self.now_playing_format.1));
+        #[cfg(feature = "notify")]
+        lines.push(setting("notify", self.notify.0.to_string(), self.notify.1));
+        lines.push(String::new());
+        lines.extend(config.keymap.config_lines());
+        lines
+    }
+}
+
+/// The theme used unless one is chosen: no color when the NO_COLOR
+/// convention asks for it or the output isn't a terminal, dark otherwise.
+fn default_theme() -> Theme {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    if no_color || !io::stdout().is_terminal() {
+        theme::NO_COLOR
+    } else {
+        theme::DARK
+    }
+}
+
+/// Removable media with music on it, for when no path is given: the only
+/// one there is, with a note saying so, or the one picked from a list of
+/// them. Gives None, having said why, when there's none to play.
+fn pick_media(program: &str, extensions: &HashSet<&'static str>) -> Option<(String, Option<String>)> {
+    let extensions = extensions.clone();
+    let found = mounts::with_music(mounts::candidates(), move |path| music_extension(path).is_some_and(|ext| extensions.contains(ext)));
+    let several = match found.as_slice() {
+        [] => {
+            eprintln!("{}", usage(program));
+            eprintln!("\nNo path was given, and there's no removable media with music on it to play instead.");
+            return None;
+        }
+        [only] => {
+            let path = only.to_string_lossy().into_owned();
+            let note = format!("Playing from {}, the only removable media with music on it", path);
+            return Some((path, Some(note)));
+        }
+        several => several,
+    };
+    println!("No path was given. Removable media with music on it:");
+    for (number, path) in several.iter().enumerate() {
+        println!("  {}. {}", number + 1, path.display());
+    }
+    if !io::stdin().is_terminal() {
+        println!("Give the one to play as the path.");
+        return None;
+    }
+    print!("Which one? (1-{}) ", several.len());
+    io::stdout().flush().ok()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok()?;
+    match answer.trim().parse::<usize>() {
+        Ok(number) if (1..=several.len()).contains(&number) => Some((several[number - 1].to_string_lossy().into_owned(), None)),
+        _ => {
+            println!("That isn't one of them.");
+            None
+        }
+    }
+}
+
+/// The music found at the paths given, as one list.
+struct Found {
+    files: Vec<PathBuf>,
+    /// The name of each file from a playlist, for those without tags.
+    names: Vec<Option<String>>,
+    /// Which of `roots` each file came from.
+    sources: Vec<usize>,
+    /// The directories the files were found in: each one given, or the one
+    /// a playlist given is in.
+    roots: Vec<PathBuf>,
+    /// How many tracks the playlists list that aren't there.
+    missing: usize,
+    /// How many directories were too deep to look in.
+    too_deep: usize,
+    /// How many entries in the directories couldn't be read.
+    unreadable: usize,
+    /// The index of each directory looked through, with which of `roots`
+    /// it's for.
+    indexes: Vec<(usize, Index)>,
+    /// A single playlist was given, so the list keeps its order.
+    playlist_order: bool,
+}
+
+/// Look for music at each of `paths`: every music file under a directory,
+/// or the tracks a playlist lists. Paths that aren't there, or can't be
+/// read, are left out with a warning. A directory inside another one given
+/// is only looked through once, outermost first. Following links, a file
+/// reached more than one way is only listed once.
+fn find_music(paths: &[String], extensions: &HashSet<&'static str>, exclude: &Exclude, follow_links: bool, cache: Option<&Path>, rescan: bool) -> Found {
+    let mut found = Found { files: Vec::new(), names: Vec::new(), sources: Vec::new(), roots: Vec::new(), missing: 0, too_deep: 0, unreadable: 0, indexes: Vec::new(), playlist_order: false };
+    let mut given: Vec<(&String, PathBuf)> = Vec::new();
+    for path in paths {
+        if http::is_url(path) {
+            eprintln!("Warning: {} is left out; a URL can only be played on its own", path);
+            continue;
+        }
+        match fs::canonicalize(path) {
+            Ok(full) => given.push((path, full)),
+            Err(_) => eprintln!("Warning: {} does not exist, so it's left out", path),
+        }
+    }
+    let is_playlist = |path: &Path| playlist::is_playlist(path) && path.is_file();
+    found.playlist_order = paths.len() == 1 && given.len() == 1 && is_playlist(&given[0].1);
+    // Directories outermost first, so any inside them can be skipped, then
+    // playlists in the order given
+    given.sort_by_key(|(_, full)| if is_playlist(full) { usize::MAX } else { full.components().count() });
+    let mut walked: Vec<(PathBuf, &String)> = Vec::new();
+    let mut seen = HashSet::new();
+    for (path, full) in given {
+        if is_playlist(&full) {
+            let listed = match playlist::read(Path::new(path)) {
+                Ok(listed) => listed,
+                Err(e) => {
+                    eprintln!("Warning: can't read {}, so it's left out: {}", path, e);
+                    continue;
+                }
+            };
+            for file in &listed.missing {
+                eprintln!("Warning: {} in {} isn't there", file, path);
+            }
+            found.missing += listed.missing.len();
+            // Paths from a playlist go by where it is
+            let root = match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            let source = found.roots.iter().position(|known| *known == root).unwrap_or_else(|| {
+                found.roots.push(root);
+                found.roots.len() - 1
+            });
+            for entry in listed.entries.into_iter().filter(|entry| music_extension(&entry.path).is_some_and(|ext| extensions.contains(ext))) {
+                found.files.push(entry.path);
+                found.names.push(entry.title);
+                found.sources.push(source);
+            }
+            continue;
+        }
+        if let Some((outer_full, outer)) = walked.iter().find(|(outer, _)| full.starts_with(outer)) {
+            if *outer_full == full {
+                eprintln!("Warning: {} is given more than once", path);
+            } else {
+                eprintln!("Warning: {} is already looked through as part of {}", path, outer);
+            }
+            continue;
+        }
+        // A music file given on its own has nothing in it to keep track of
+        let mut index = full.is_dir().then(|| Index::load(Path::new(path), cache, rescan));
+        walked.push((full, path));
+        found.roots.push(PathBuf::from(path));
+        let started = SystemTime::now();
+        let mut scanned = list_music_files(Path::new(path), extensions, exclude, follow_links, index.as_ref(), None);
+        if let Some(index) = &mut index {
+            index.set_directories(mem::take(&mut scanned.directories), started);
+        }
+        found.too_deep += scanned.too_deep;
+        for reason in scanned.reasons() {
+            eprintln!("Warning: {}", reason);
+        }
+        found.unreadable += scanned.unreadable.len();
+        for file in scanned.files {
+            // Only through a link can the same file be reached more than one
+            // way, and finding where each really is takes a while on a card
+            if !follow_links || seen.insert(fs::canonicalize(&file).unwrap_or_else(|_| PathBuf::from(&file))) {
+                found.files.push(file);
+                found.names.push(None);
+                found.sources.push(found.roots.len() - 1);
+            }
+        }
+        // Written now as well as when quitting, in case that doesn't go well
+        if let Some(index) = index {
+            let source = found.roots.len() - 1;
+            let _ = index.save(&indexed_files(&found.files, &found.sources, &found.roots, source));
+            found.indexes.push((source, index));
+        }
+    }
+    found
+}
+
+/// The paths of those of `files` from `roots[source]`, as the index for
+/// it has them.
+fn indexed_files(files: &[PathBuf], sources: &[usize], roots: &[PathBuf], source: usize) -> Vec<String> {
+    files
+        .iter()
+        .zip(sources)
+        .filter(|(_, &from)| from == source)
+        .filter_map(|(file, _)| Some(file.strip_prefix(&roots[source]).ok()?.to_str()?.to_string()))
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RepeatMode {
+    Off,
+    One,
+    All,
+}
+
+impl RepeatMode {
+    fn from_u8(value: u8) -> RepeatMode {
+        match value {
+            1 => RepeatMode::One,
+            2 => RepeatMode::All,
+            _ => RepeatMode::Off,
+        }
+    }
+
+    fn next(self) -> RepeatMode {
+        match self {
+            RepeatMode::Off => RepeatMode::One,
+            RepeatMode::One => RepeatMode::All,
+            RepeatMode::All => RepeatMode::Off,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "off",
+            RepeatMode::One => "one",
+            RepeatMode::All => "all",
+        }
+    }
+}
+
+/// Small xorshift generator; shuffle doesn't need anything stronger.
+struct Rng(u64);
+
+impl Rng {
+    fn from_time() -> Rng {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Rng(nanos | 1)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Pick a random track from `tracks`, avoiding the most recently played ones
+/// where possible.
+fn pick_shuffled(tracks: &[usize], recent: &VecDeque<usize>, rng: &mut Rng) -> usize {
+    let candidates: Vec<usize> = tracks.iter().copied().filter(|index| !recent.contains(index)).collect();
+    if candidates.is_empty() {
+        return tracks[rng.below(tracks.len())];
+    }
+    candidates[rng.below(candidates.len())]
+}
+
+/// What plays after the current track.
+struct QueuePosition {
+    track: usize,
+    /// Where shuffle goes next, picked up front so it can be shown.
+    shuffled_next: usize,
+}
+
+impl QueuePosition {
+    /// Where the entry that follows this one is in `queue`, or None when
+    /// the queue ends here. Repeat-one is left to the caller, since skipping
+    /// still moves on. The queue is asked afresh each time, so whatever's
+    /// been edited in it since the track started counts.
+    fn following(&self, queue: &Queue, repeat_all: bool, shuffle: bool) -> Option<usize> {
+        // Unless the track shuffle picked has been taken out meanwhile
+        if let Some(position) = queue.find(self.shuffled_next).filter(|_| shuffle) {
+            return Some(position);
+        }
+        match queue.after() {
+            Some(next) => Some(next),
+            None if repeat_all && queue.len() > 0 => Some(0),
+            None => None,
+        }
+    }
+
+    /// The track that plays next if this one finishes on its own.
+    fn up_next(&self, controls: &Controls) -> Option<usize> {
+        let repeat_mode = RepeatMode::from_u8(controls.repeat.load(Ordering::SeqCst));
+        if repeat_mode == RepeatMode::One {
+            return Some(self.track);
+        }
+        let queue = controls.queue.lock().unwrap();
+        let following = self.following(&queue, repeat_mode == RepeatMode::All, controls.shuffle.load(Ordering::SeqCst));
+        following.and_then(|position| queue.get(position))
+    }
+}
+
+/// What the playback thread is doing, published for the UI thread to draw.
+#[derive(Clone, Default)]
+struct PlayerStatus {
+    /// Index of the current track in the list, None between queues.
+    track: Option<usize>,
+    position: Duration,
+    /// Zero when the length is unknown.
+    total: Duration,
+    up_next: Option<usize>,
+    /// What the current track is being decoded from, once it's open.
+    stream: Option<StreamInfo>,
+    /// A track streamed over HTTP is waiting for more of it to arrive.
+    buffering: bool,
+    /// What the track's waiting on before it plays, like being read into
+    /// memory, and how many bytes of it are done so far, of how many.
+    waiting: Option<(&'static str, u64, u64)>,
+    /// Where the A-B loop starts and ends, as far as they've been marked.
+    loop_start: Option<Duration>,
+    loop_end: Option<Duration>,
+    /// Where the track's bookmarks are, in order.
+    bookmarks: Vec<Duration>,
+    /// Latest notice for the user, such as a skipped track or a volume change.
+    message: Option<String>,
+    /// Why the queue stopped of itself, when none of it would play, for the
+    /// list to say once it's back.
+    failed: Option<String>,
+}
+
+/// The format of the track being played, as decoded.
+#[derive(Clone)]
+struct StreamInfo {
+    format: &'static str,
+    sample_rate: u32,
+    channels: u16,
+    /// None for lossy formats, which don't have one.
+    bits_per_sample: Option<u32>,
+    /// Average over the whole file; None when the length is unknown.
+    kbps: Option<u64>,
+}
+
+impl StreamInfo {
+    fn new(path: &Path, source: &dyn Source<Item = i16>, duration: Duration) -> StreamInfo {
+        let format = match music_extension(path) {
+            Some("flac") => "FLAC",
+            Some("mp3") => "MP3",
+            Some("ogg") => "Ogg Vorbis",
+            Some("wav") => "WAV",
+            Some("m4a") => "ALAC",
+            Some("aiff" | "aif") => "AIFF",
+            _ => "Audio",
+        };
+        let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        StreamInfo {
+            format,
+            sample_rate: source.sample_rate(),
+            channels: source.channels(),
+            bits_per_sample: probe::bits_per_sample(path),
+            kbps: (duration > Duration::ZERO && size > 0).then(|| (size as f64 * 8.0 / 1000.0 / duration.as_secs_f64()).round() as u64),
+        }
+    }
+
+    /// Like `FLAC 96 kHz / 24-bit / stereo / 2,835 kbps`, leaving out
+    /// whatever isn't known.
+    fn label(&self) -> String {
+        let mut fields = Vec::new();
+        if self.sample_rate > 0 {
+            // 44100 shows as 44.1, but 48000 as just 48
+            let khz = format!("{:.1}", self.sample_rate as f64 / 1000.0);
+            fields.push(format!("{} kHz", khz.trim_end_matches(".0")));
+        }
+        if let Some(bits) = self.bits_per_sample {
+            fields.push(format!("{}-bit", bits));
+        }
+        fields.push(match self.channels {
+            1 => "mono".to_string(),
+            2 => "stereo".to_string(),
+            channels => format!("{} channels", channels),
+        });
+        if let Some(kbps) = self.kbps {
+            fields.push(format!("{} kbps", thousands(kbps)));
+        }
+        format!("{} {}", self.format, fields.join(" / "))
+    }
+}
+
+/// `n` with commas between each group of three digits.
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Playback settings shared between the key-event loop and the playback thread.
+struct Controls {
+    is_paused: AtomicBool,
+    /// Set when the program is exiting.
+    shutdown: AtomicBool,
+    /// Whether a queue is playing; cleared by the playback thread when it ends.
+    is_playing: AtomicBool,
+    shuffle: AtomicBool,
+    repeat: AtomicU8,
+    /// Volume in percent, applied to every track's sink.
+    volume: AtomicU32,
+    /// Muting leaves `volume` alone so it can be restored.
+    muted: AtomicBool,
+    replaygain: ReplayGain,
+    /// What the current track's ReplayGain multiplies the volume by, as the
+    /// bits of an f32.
+    track_gain: AtomicU32,
+    /// How long pausing, resuming and stopping fade for.
+    fade: Duration,
+    /// How far faded in the sink is, from 0 for silent to 1, as the bits of
+    /// an f32. It's 0 while paused.
+    fade_level: AtomicU32,
+    /// Playback speed in percent. Pitch goes with it. Shared with the audio
+    /// thread, which changes to it straight away.
+    speed: Arc<AtomicU32>,
+    /// Don't go back to normal speed when the track changes.
+    keep_speed: bool,
+    /// With --prefetch, the biggest track read into memory before it plays,
+    /// in bytes.
+    prefetch_limit: Option<u64>,
+    /// How reads from the card that fail are tried again.
+    retry: Retry,
+    /// Where tracks are copied to play from, with --local-cache.
+    local_cache: Option<LocalCache>,
+    /// Shared with the audio thread, which applies them as it plays.
+    effects: Arc<dsp::Effects>,
+    /// What the audio thread has just played, for the spectrum.
+    tap: Arc<dsp::Tap>,
+    /// The A-B loop in the track playing, which the audio thread keeps in
+    /// memory to go back to.
+    ab_loop: Arc<abloop::Points>,
+    /// When the sleep timer runs out and the program quits, whether or not
+    /// it's paused.
+    sleep_at: Mutex<Option<Instant>>,
+    /// Where long tracks were stopped, when there's somewhere to keep it.
+    positions: Option<Positions>,
+    /// Start them from there without asking.
+    resume: bool,
+    /// The log of what's been played, when there's somewhere to keep it.
+    history: Option<PathBuf>,
+    /// How many times each track has been played, likewise.
+    plays: Option<PlayCounts>,
+    /// Goes up each time a play is counted, so the list knows to read the
+    /// counts again.
+    plays_counted: AtomicU32,
+    /// Favorites and star ratings, likewise.
+    ratings: Option<Ratings>,
+    /// Write star ratings to the tags of the files too.
+    write_tags: bool,
+    /// The tracks the queue plays, in order: the whole list, or an album
+    /// picked in the library, as edited since. The playback thread looks
+    /// at it again each time a track ends.
+    queue: Mutex<Queue>,
+    /// The file each track is, which more are added to as they're found.
+    files: Mutex<Vec<PathBuf>>,
+    status: Mutex<PlayerStatus>,
+}
+
+impl Controls {
+    /// The volume the sink should actually play at.
+    fn sink_volume(&self) -> f32 {
+        if self.muted.load(Ordering::SeqCst) {
+            0.0
+        } else {
+            self.volume.load(Ordering::SeqCst) as f32 / 100.0
+                * f32::from_bits(self.track_gain.load(Ordering::SeqCst))
+                * f32::from_bits(self.fade_level.load(Ordering::SeqCst))
+                * sleep::fade_level(*self.sleep_at.lock().unwrap())
+        }
+    }
+
+    fn set_message(&self, message: String) {
+        self.status.lock().unwrap().message = Some(message);
+    }
+
+    fn volume_label(&self) -> String {
+        if self.muted.load(Ordering::SeqCst) {
+            "MUTED".to_string()
+        } else {
+            format!("{}%", self.volume.load(Ordering::SeqCst))
+        }
+    }
+}
+
+/// What the playback thread tells the UI, as it happens.
+enum PlayerEvent {
+    /// The file playing couldn't be read any more, as when the card it's on
+    /// has been taken out, so the queue's been stopped.
+    Unreadable(PathBuf),
+}
+
+/// Requests from the UI to the playback thread.
+enum PlayerCommand {
+    /// Start playing the queue from the entry at this position.
+    Play(usize),
+    /// Stop the queue and go idle.
+    Stop,
+    TogglePause,
+    /// Seek back to the start of the current track.
+    Restart,
+    Next,
+    Previous,
+    /// Jump by this many seconds within the current track.
+    Seek(i64),
+    /// Jump to this fraction (0.0 to 1.0) of the way through the current track.
+    SeekFraction(f64),
+    /// Play a step faster, or slower when false.
+    ChangeSpeed(bool),
+    /// Mark where the A-B loop starts, at the current position.
+    LoopStart,
+    /// Mark where it ends, after which playback goes round between the two.
+    LoopEnd,
+    ClearLoop,
+    /// Seek to where the track was stopped last time.
+    ResumeSaved,
+    /// Add a bookmark at the current position.
+    Bookmark,
+    /// Seek to the next bookmark, or the one before.
+    NextBookmark,
+    PreviousBookmark,
+    /// Seek to the bookmark at this index.
+    JumpToBookmark(usize),
+    DeleteBookmark(usize),
+    /// Stop playback and end the playback thread.
+    Quit,
+}
+
+/// Save where a track that's stopped playing got to, or forget its old
+/// position once it's been played to the end.
+fn remember_position(controls: &Controls, path: &Path, end: &TrackEnd) {
+    let Some(positions) = &controls.positions else {
+        return;
+    };
+    let (position, total) = {
+        let status = controls.status.lock().unwrap();
+        (status.position, status.total)
+    };
+    // Losing a position isn't worth interrupting the music for
+    let _ = match end {
+        TrackEnd::Finished => positions.forget(path),
+        _ => positions.set(path, position, total),
+    };
+}
+
+/// Add a track that's stopped playing to the history, if it was played to
+/// the end or past halfway, and count the play unless `count` is false.
+fn log_play(controls: &Controls, path: &Path, end: &TrackEnd, count: bool) {
+    let (position, total) = {
+        let status = controls.status.lock().unwrap();
+        (status.position, status.total)
+    };
+    let Some(entry) = history::Entry::new(path, position, total, matches!(end, TrackEnd::Finished)) else {
+        return;
+    };
+    if let Some(history) = &controls.history {
+        let _ = history::append(history, &entry);
+    }
+    if let Some(plays) = controls.plays.as_ref().filter(|_| count) {
+        if plays.add(path).is_ok() {
+            controls.plays_counted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Why `play_music` returned.
+enum TrackEnd {
+    Finished,
+    Next,
+    Previous,
+    Restart,
+    Stopped,
+    Quit,
+    /// Play the entry at this position next, picked from the list or the
+    /// queue.
+    Jump(usize),
+}
+
+struct Playback<'a> {
+    controls: &'a Controls,
+    commands: &'a Receiver<PlayerCommand>,
+    sink: &'a Mutex<Sink>,
+    stream_handle: &'a OutputStreamHandle,
+    events: &'a mpsc::Sender<PlayerEvent>,
+    /// Any error reading the file playing.
+    failure: Failure,
+}
+
+/// Stop the whole queue, not just the current track, and wait for the
+/// playback thread to finish. The flag covers a playback thread that's busy
+/// opening a file; the command wakes one that's waiting. Joining makes sure
+/// nothing is left playing before we exit.
+fn stop_player(controls: &Controls, commands: &mpsc::Sender<PlayerCommand>, sink: &Mutex<Sink>, player: thread::JoinHandle<()>) {
+    controls.shutdown.store(true, Ordering::SeqCst);
+    let _ = commands.send(PlayerCommand::Quit);
+    sink.lock().unwrap().stop();
+    let _ = player.join();
+}
+
+/// Body of the playback thread: idle until told to play, then run the queue.
+/// Commands that only make sense while playing are ignored when idle.
+fn run_player(playback: Playback) {
+    loop {
+        match playback.commands.recv() {
+            Ok(PlayerCommand::Play(start)) => {
+                let end = play_queue(start, &playback);
+                playback.controls.status.lock().unwrap().track = None;
+                playback.controls.is_playing.store(false, Ordering::SeqCst);
+                // Stopping while paused shouldn't leave the next queue paused
+                playback.controls.is_paused.store(false, Ordering::SeqCst);
+                playback.controls.fade_level.store(1.0f32.to_bits(), Ordering::SeqCst);
+                if let TrackEnd::Quit = end {
+                    return;
+                }
+            }
+            Ok(PlayerCommand::Quit) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+fn play_queue(start: usize, playback: &Playback) -> TrackEnd {
+    let controls = playback.controls;
+    let mut rng = Rng::from_time();
+    let mut recent = VecDeque::new();
+    let mut next = start;
+    let mut failures = 0;
+    // Going round a track on repeat-one counts as playing it once
+    let mut repeating = false;
+
+    loop {
+        // The queue can be swapped or edited while a track plays, so the
+        // entry is looked up afresh each time
+        let (index, queue) = {
+            let mut queue = controls.queue.lock().unwrap();
+            let Some(index) = queue.get(next).filter(|&index| index < controls.files.lock().unwrap().len()) else {
+                break;
+            };
+            queue.play(next);
+            (index, queue.tracks().to_vec())
+        };
+        let file_path = controls.files.lock().unwrap()[index].clone();
+        // Never hold back so many tracks that there's nothing left to pick
+        let history_len = SHUFFLE_HISTORY.min(queue.len() / 2);
+        let mut lookahead = recent.clone();
+        lookahead.push_back(index);
+        if lookahead.len() > history_len {
+            lookahead.pop_front();
+        }
+        let position = QueuePosition {
+            track: index,
+            shuffled_next: pick_shuffled(&queue, &lookahead, &mut rng),
+        };
+        {
+            let mut status = controls.status.lock().unwrap();
+            status.track = Some(index);
+            status.position = Duration::ZERO;
+            status.total = Duration::ZERO;
+            status.stream = None;
+            // Until the server answers
+            status.buffering = file_path.to_str().is_some_and(http::is_url);
+            status.loop_start = None;
+            status.loop_end = None;
+            status.bookmarks = Vec::new();
+        }
+        if let Some(cache) = &controls.local_cache {
+            // This track first, then the one up next and those after it
+            let up_next = position.up_next(controls);
+            let files = controls.files.lock().unwrap();
+            let mut wanted: Vec<PathBuf> = Vec::new();
+            for track in [index].into_iter().chain(up_next).chain(queue.iter().skip(next + 1).copied()) {
+                if wanted.len() > LOCAL_CACHE_AHEAD {
+                    break;
+                }
+                match files.get(track) {
+                    Some(file) if !wanted.contains(file) && !file.to_str().is_some_and(http::is_url) => wanted.push(file.clone()),
+                    _ => {}
+                }
+            }
+            cache.want(wanted);
+        }
+        let end = match play_music(&file_path, &position, playback) {
+            Ok(end) => {
+                failures = 0;
+                // Streams always start from the beginning
+                if !file_path.to_str().is_some_and(http::is_url) {
+                    remember_position(controls, &file_path, &end);
+                }
+                log_play(controls, &file_path, &end, !repeating);
+                end
+            }
+            // Going on to the next track would only fail the same way
+            Err(_) if reader::unreadable(&file_path) => {
+                let _ = playback.events.send(PlayerEvent::Unreadable(file_path));
+                return TrackEnd::Stopped;
+            }
+            Err(e) => {
+                controls.set_message(format!("Skipping track {}: {}", index + 1, e));
+                failures += 1;
+                // Don't spin forever when repeating a queue where nothing plays
+                if failures >= queue.len() {
+                    controls.status.lock().unwrap().failed = Some(format!("Couldn't play {}: {}", track_name(&file_path), e));
+                    return TrackEnd::Stopped;
+                }
+                TrackEnd::Next
+            }
+        };
+
+        // Repeat and shuffle are checked at each transition, so changing
+        // them never restarts the current track
+        let repeat_mode = RepeatMode::from_u8(controls.repeat.load(Ordering::SeqCst));
+        let shuffle = controls.shuffle.load(Ordering::SeqCst);
+        repeating = matches!(end, TrackEnd::Finished) && repeat_mode == RepeatMode::One;
+        let queue = controls.queue.lock().unwrap();
+        // Playing it again goes on to the next entry if it's been taken out
+        let again = queue.current().or_else(|| position.following(&queue, repeat_mode == RepeatMode::All, false));
+        let following = match end {
+            TrackEnd::Stopped | TrackEnd::Quit => return end,
+            TrackEnd::Restart => again,
+            TrackEnd::Jump(position) => {
+                recent = lookahead;
+                Some(position)
+            }
+            TrackEnd::Finished if repeat_mode == RepeatMode::One => again,
+            TrackEnd::Previous => match recent.back().copied().and_then(|previous| queue.find(previous)) {
+                // In shuffle mode "previous" means the last track actually played
+                Some(previous) if shuffle => {
+                    recent.pop_back();
+                    Some(previous)
+                }
+                _ => match queue.before() {
+                    Some(previous) => Some(previous),
+                    None if repeat_mode == RepeatMode::All && queue.len() > 0 => Some(queue.len() - 1),
+                    None => again,
+                },
+            },
+            TrackEnd::Finished | TrackEnd::Next => {
+                recent = lookahead;
+                position.following(&queue, repeat_mode == RepeatMode::All, shuffle)
+            }
+        };
+        match following {
+            Some(following) => next = following,
+            None => break,
+        }
+    }
+    TrackEnd::Stopped
+}
+
+/// Open the file at `path` to decode, trying again as `retry` says when
+/// reading it fails and noting the error in `failure` when it still does, or
+/// decode it from memory when it's been `loaded`.
+fn open_source(path: &Path, loaded: Option<&Loaded>, retry: &Retry, failure: &Failure) -> Result<Box<dyn Source<Item = i16> + Send>, Box<dyn std::error::Error>> {
+    if let Some(loaded) = loaded {
+        return decode(Cursor::new(loaded.clone()), music_extension(path), failure);
+    }
+    probe::check_decodable(path)?;
+    let file = Retrying::new(fs::File::open(path)?, retry.clone());
+    decode(BufReader::new(Noting::new(file, failure.clone())), music_extension(path), failure)
+}
+
+/// How reading a track into memory before playing it went.
+enum Prefetched {
+    Loaded(Loaded),
+    /// Bigger than the limit, so it's played from the card as usual.
+    TooBig,
+    /// Told to go to another track or stop before it was done.
+    Cancelled(TrackEnd),
+}
+
+/// Read the file at `path` into memory on a thread of its own, unless it's
+/// bigger than `limit` bytes, saying how far it's got once it's taken a
+/// while. Changing track or stopping meanwhile gives up on it straight away.
+fn prefetch(path: &Path, limit: u64, playback: &Playback) -> Result<Prefetched, Box<dyn std::error::Error>> {
+    let controls = playback.controls;
+    let length = fs::metadata(path)?.len();
+    if length > limit {
+        return Ok(Prefetched::TooBig);
+    }
+    let read = Arc::new(AtomicU64::new(0));
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (done, loading) = mpsc::channel();
+    {
+        let (path, retry, read, cancel) = (path.to_path_buf(), controls.retry.clone(), Arc::clone(&read), Arc::clone(&cancel));
+        thread::spawn(move || {
+            let _ = done.send(reader::load(&path, length, &retry, &read, &cancel));
+        });
+    }
+    let started = Instant::now();
+    loop {
+        match loading.recv_timeout(Duration::from_millis(50)) {
+            Ok(loaded) => return Ok(loaded?.map_or(Prefetched::Cancelled(TrackEnd::Stopped), Prefetched::Loaded)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err("the file stopped being read".into()),
+        }
+        if started.elapsed() >= WAIT_QUIET_TIME {
+            controls.status.lock().unwrap().waiting = Some(("Buffering", read.load(Ordering::SeqCst), length));
+        }
+        if let Some(end) = interrupted(playback) {
+            cancel.store(true, Ordering::SeqCst);
+            return Ok(Prefetched::Cancelled(end));
+        }
+    }
+}
+
+/// Where a track that's copied to the local cache plays from.
+enum Copied {
+    Local(PathBuf),
+    /// It couldn't be copied, for this reason, so it's played from the card.
+    Card(String),
+    /// Told to go to another track or stop before it was done.
+    Cancelled(TrackEnd),
+}
+
+/// Wait for the copy of `path` in `cache`, saying how far it's got once it's
+/// taken a while. Changing track or stopping meanwhile stops waiting.
+fn local_copy(path: &Path, cache: &LocalCache, playback: &Playback) -> Copied {
+    let started = Instant::now();
+    loop {
+        match cache.state(path) {
+            Some(local::State::Copied(copy)) => return Copied::Local(copy),
+            Some(local::State::Failed(reason)) => return Copied::Card(reason),
+            Some(local::State::Copying(copied, length)) if started.elapsed() >= WAIT_QUIET_TIME => {
+                playback.controls.status.lock().unwrap().waiting = Some(("Copying to the local disk", copied, length));
+            }
+            _ => {}
+        }
+        if let Some(end) = interrupted(playback) {
+            return Copied::Cancelled(end);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// What's been asked for, while a track waits to start, that means not
+/// playing it after all. Pausing meanwhile has it start paused.
+fn interrupted(playback: &Playback) -> Option<TrackEnd> {
+    let controls = playback.controls;
+    if controls.shutdown.load(Ordering::SeqCst) {
+        return Some(TrackEnd::Quit);
+    }
+    for command in playback.commands.try_iter() {
+        match command {
+            PlayerCommand::Quit => return Some(TrackEnd::Quit),
+            PlayerCommand::Stop => return Some(TrackEnd::Stopped),
+            PlayerCommand::Play(index) => return Some(TrackEnd::Jump(index)),
+            PlayerCommand::Next => return Some(TrackEnd::Next),
+            PlayerCommand::Previous => return Some(TrackEnd::Previous),
+            // Nothing's playing yet to fade
+            PlayerCommand::TogglePause => {
+                let paused = !controls.is_paused.load(Ordering::SeqCst);
+                controls.is_paused.store(paused, Ordering::SeqCst);
+                controls.fade_level.store(if paused { 0.0f32 } else { 1.0 }.to_bits(), Ordering::SeqCst);
+            }
+            // Seeking and the like wait for it to start
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The decoder for a file with `extension`, reading it from `reader`.
+fn decode<R: Read + Seek + Send + Sync + 'static>(reader: R, extension: Option<&str>, failure: &Failure) -> Result<Box<dyn Source<Item = i16> + Send>, Box<dyn std::error::Error>> {
+    match extension {
+        Some("aiff" | "aif") => Ok(Box::new(aiff::AiffDecoder::new(reader)?)),
+        Some("m4a") => Ok(Box::new(alac::AlacDecoder::new(reader, failure.clone())?)),
+        _ => Ok(Box::new(Decoder::new(reader)?)),
+    }
+}
+
+/// Start streaming `url`, decoding it ahead of playing. Gives the duration
+/// too, when it can be worked out from the length and the first headers,
+/// for formats the decoder can't tell it for.
+fn open_stream(url: &str) -> Result<(http::Prefetch, Option<Duration>), Box<dyn std::error::Error>> {
+    let body = http::get(url)?;
+    let extension = music_extension(Path::new(http::file_part(url)));
+    // A dropped connection looks like a file that's been cut short
+    let decoded = match (decode(BufReader::new(body.reopen()), extension, &Failure::default()), body.error()) {
+        (Err(_), Some(error)) => return Err(error.into()),
+        (decoded, _) => decoded?,
+    };
+    let estimate = match (extension, body.length()) {
+        (Some(extension), Some(length)) => probe::estimate_stream_duration(extension, &body.start_bytes(64 * 1024), length),
+        _ => None,
+    };
+    Ok((http::Prefetch::new(decoded, &body), estimate))
+}
+
+/// A track that's started playing.
+struct Started {
+    /// Zero when it's unknown.
+    duration: Duration,
+    stream: StreamInfo,
+    /// How the download's getting on, for a track streamed over HTTP.
+    feed: Option<Arc<http::Feed>>,
+}
+
+/// Open `path`, or what's been `loaded` of it, skip ahead to `offset` and
+/// start it on a fresh sink.
+fn start_playback(path: &Path, loaded: Option<&Loaded>, offset: Duration, playback: &Playback) -> Result<Started, Box<dyn std::error::Error>> {
+    let (mut source, feed, estimate) = match path.to_str().filter(|path| http::is_url(path)) {
+        Some(url) => {
+            let (prefetch, estimate) = open_stream(url)?;
+            let feed = prefetch.feed();
+            (Box::new(prefetch) as Box<dyn Source<Item = i16> + Send>, Some(feed), estimate)
+        }
+        None => {
+            // What's in memory says as much as the headers on the card
+            let estimate = loaded.and_then(|loaded| {
+                let bytes = loaded.as_ref();
+                probe::estimate_stream_duration(music_extension(path)?, &bytes[..bytes.len().min(64 * 1024)], bytes.len() as u64)
+            });
+            (open_source(path, loaded, &playback.controls.retry, &playback.failure)?, None, estimate)
+        }
+    };
+    let duration = source
+        .total_duration()
+        .or(estimate)
+        .or_else(|| probe::estimate_duration(path))
+        .unwrap_or(Duration::new(0, 0));
+    // The format goes by the extension, which comes before any query in a URL
+    let named = path.to_str().filter(|path| http::is_url(path)).map_or(path, |url| Path::new(http::file_part(url)));
+    let stream = StreamInfo::new(named, source.as_ref(), duration);
+
+    // None of the decoders can seek, so decode and discard up to the offset
+    // here rather than lazily on the audio thread, which would glitch.
+    // Going back to the start, it's only opened again.
+    let samples = abloop::samples_at(offset, &source);
+    if offset > Duration::ZERO {
+        for _ in 0..samples {
+            if source.next().is_none() {
+                break;
+            }
+        }
+    }
+
+    // A stopped rodio sink can't be reused, so every start gets a fresh one.
+    // Replacing the old sink drops whatever was still queued on it. Volume and
+    // pause state are applied under the lock so a concurrent change isn't lost.
+    let new_sink = Sink::try_new(playback.stream_handle)?;
+    let mut sink = playback.sink.lock().unwrap();
+    new_sink.set_volume(playback.controls.sink_volume());
+    if playback.controls.is_paused.load(Ordering::SeqCst) {
+        new_sink.pause();
+    }
+    playback.controls.tap.clear();
+    playback.controls.effects.skipped_us.store(0, Ordering::SeqCst);
+    let replay = abloop::Replay::new(source, samples, Arc::clone(&playback.controls.ab_loop));
+    let processed = dsp::Process::new(replay, Arc::clone(&playback.controls.effects), Arc::clone(&playback.controls.tap));
+    new_sink.append(dsp::Varispeed::new(processed, Arc::clone(&playback.controls.speed)));
+    *sink = new_sink;
+    Ok(Started { duration, stream, feed })
+}
+
+/// Ramp the sink's volume from where it is to `to`, from 0 for silent to 1
+/// for the full volume, over the fade time. The volume is looked up again at
+/// every step, so changing it meanwhile isn't lost.
+fn fade(controls: &Controls, sink: &Mutex<Sink>, to: f32) {
+    let from = f32::from_bits(controls.fade_level.load(Ordering::SeqCst));
+    if from == to {
+        return;
+    }
+    let steps = (controls.fade.as_millis() / FADE_STEP.as_millis()).max(1) as u32;
+    for step in 1..=steps {
+        let level = from + (to - from) * step as f32 / steps as f32;
+        controls.fade_level.store(level.to_bits(), Ordering::SeqCst);
+        sink.lock().unwrap().set_volume(controls.sink_volume());
+        if step < steps {
+            thread::sleep(FADE_STEP);
+        }
+    }
+}
+
+/// Playback clock that only advances while the track is actually playing,
+/// and counts time in the track rather than on the wall, so it runs faster
+/// or slower with the speed.
+struct Stopwatch {
+    accumulated: Duration,
+    running_since: Option<Instant>,
+    /// In percent.
+    speed: u32,
+}
+
+impl Stopwatch {
+    fn new(running: bool, speed: u32) -> Stopwatch {
+        Stopwatch {
+            accumulated: Duration::ZERO,
+            running_since: running.then(Instant::now),
+            speed,
+        }
+    }
+
+    /// How far into the track the time since `since` goes.
+    fn played_since(&self, since: Instant) -> Duration {
+        since.elapsed() * self.speed / 100
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.accumulated + self.running_since.map_or(Duration::ZERO, |since| self.played_since(since))
+    }
+
+    fn pause(&mut self) {
+        if let Some(since) = self.running_since.take() {
+            self.accumulated += self.played_since(since);
+        }
+    }
+
+    /// Count at `speed` from now on.
+    fn set_speed(&mut self, speed: u32) {
+        self.accumulated = self.elapsed();
+        if self.running_since.is_some() {
+            self.running_since = Some(Instant::now());
+        }
+        self.speed = speed;
+    }
+
+    fn resume(&mut self) {
+        self.running_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Jump to `position`, e.g. after a seek, keeping the running state.
+    fn set(&mut self, position: Duration) {
+        self.accumulated = position;
+        if self.running_since.is_some() {
+            self.running_since = Some(Instant::now());
+        }
+    }
+}
+
+fn play_music(path: &Path, queue: &QueuePosition, playback: &Playback) -> Result<TrackEnd, Box<dyn std::error::Error>> {
+    let controls = playback.controls;
+    let sink = playback.sink;
+    let is_stream = path.to_str().is_some_and(http::is_url);
+    // Seeking starts the track over, so the gain is only worked out here
+    let gain = controls.replaygain.factor(&tags::read(path).unwrap_or_default());
+    controls.track_gain.store(gain.to_bits(), Ordering::SeqCst);
+    if !controls.keep_speed {
+        controls.speed.store(100, Ordering::SeqCst);
+    }
+    let saved = controls.positions.as_ref().filter(|_| !is_stream).and_then(|positions| positions.get(path));
+    let start = saved.filter(|_| controls.resume).unwrap_or(Duration::ZERO);
+    playback.failure.take();
+    // From the copy on the local disk, once it's been made
+    let source = match controls.local_cache.as_ref().filter(|_| !is_stream) {
+        Some(cache) => {
+            let copied = local_copy(path, cache, playback);
+            controls.status.lock().unwrap().waiting = None;
+            match copied {
+                Copied::Local(copy) => copy,
+                Copied::Card(reason) => {
+                    controls.set_message(format!("Playing it from the card, as it couldn't be copied: {}", reason));
+                    path.to_path_buf()
+                }
+                Copied::Cancelled(end) => return Ok(end),
+            }
+        }
+        None => path.to_path_buf(),
+    };
+    // Read into memory first, when it's small enough, so playing it doesn't
+    // need the card
+    let loaded = match controls.prefetch_limit.filter(|_| !is_stream) {
+        Some(limit) => {
+            probe::check_decodable(&source)?;
+            let prefetched = prefetch(&source, limit, playback);
+            controls.status.lock().unwrap().waiting = None;
+            match prefetched? {
+                Prefetched::Loaded(loaded) => Some(loaded),
+                Prefetched::TooBig => None,
+                Prefetched::Cancelled(end) => return Ok(end),
+            }
+        }
+        None => None,
+    };
+    controls.ab_loop.set(None, None);
+    let Started { duration, stream, mut feed } = start_playback(&source, loaded.as_ref(), start, playback)?;
+    {
+        let mut status = controls.status.lock().unwrap();
+        status.total = duration;
+        status.stream = Some(stream);
+        status.buffering = false;
+    }
+    // The clock stops while a stream waits for more of it
+    let mut buffering = false;
+    let mut clock = Stopwatch::new(!controls.is_paused.load(Ordering::SeqCst), controls.speed.load(Ordering::SeqCst));
+    clock.set(start);
+    // Offered until it's taken, when it wasn't already
+    let mut saved = match saved {
+        Some(at) if controls.resume => {
+            controls.set_message(format!("Resumed from {}", format_time(at.as_secs())));
+            None
+        }
+        Some(at) => {
+            controls.set_message(format!("Stopped at {} last time; press R to carry on from there", format_time(at.as_secs())));
+            Some(at)
+        }
+        None => None,
+    };
+    let save_bookmarks = |bookmarks: &[Duration]| {
+        if let Some(positions) = &controls.positions {
+            let _ = positions.set_bookmarks(path, bookmarks);
+        }
+    };
+    let mut bookmarks = controls.positions.as_ref().map(|positions| positions.bookmarks(path)).unwrap_or_default();
+    // Left behind when the file was replaced with a shorter one
+    let before = bookmarks.len();
+    bookmarks.retain(|&at| duration.is_zero() || at < duration);
+    let past_end = before - bookmarks.len();
+    if past_end > 0 {
+        save_bookmarks(&bookmarks);
+        controls.set_message(format!(
+            "Dropped {} {} past the end of the track",
+            past_end,
+            if past_end == 1 { "bookmark" } else { "bookmarks" }
+        ));
+    }
+    let (mut loop_start, mut loop_end): (Option<Duration>, Option<Duration>) = (None, None);
+    // Where the last tick left off, so the loop only goes back when playing
+    // reaches its end, not when seeking past it
+    let mut last_position = start;
+
+    // Handle pausing, resuming, track changes, seeking and progress bar
+    loop {
+        if controls.shutdown.load(Ordering::SeqCst) {
+            fade(controls, sink, 0.0);
+            sink.lock().unwrap().stop();
+            return Ok(TrackEnd::Quit);
+        }
+
+        // Wait for the next command, waking up at least every 100ms to redraw.
+        // Everything already queued is drained so a burst of seek keypresses
+        // becomes a single seek.
+        let mut commands: Vec<PlayerCommand> = match playback.commands.recv_timeout(Duration::from_millis(100)) {
+            Ok(command) => vec![command],
+            Err(RecvTimeoutError::Timeout) => Vec::new(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(TrackEnd::Quit),
+        };
+        commands.extend(playback.commands.try_iter());
+
+        // Absolute position to seek to, if any of the commands asked for one
+        let mut seek_to: Option<f64> = None;
+        for command in commands {
+            let end = match command {
+                PlayerCommand::Seek(seconds) => {
+                    let from = seek_to.unwrap_or_else(|| clock.elapsed().as_secs_f64());
+                    seek_to = Some((from + seconds as f64).max(0.0));
+                    continue;
+                }
+                PlayerCommand::Restart => {
+                    seek_to = Some(0.0);
+                    continue;
+                }
+                // Without a known duration there's nothing to take a fraction of
+                PlayerCommand::SeekFraction(fraction) if duration > Duration::ZERO => {
+                    seek_to = Some(duration.as_secs_f64() * fraction.clamp(0.0, 1.0));
+                    continue;
+                }
+                PlayerCommand::SeekFraction(_) => continue,
+                PlayerCommand::ChangeSpeed(faster) => {
+                    let current = controls.speed.load(Ordering::SeqCst);
+                    let speed = if faster {
+                        (current + SPEED_STEP).min(MAX_SPEED)
+                    } else {
+                        current.saturating_sub(SPEED_STEP).max(MIN_SPEED)
+                    };
+                    controls.speed.store(speed, Ordering::SeqCst);
+                    controls.set_message(format!("Speed {}", speed_label(speed)));
+                    clock.set_speed(speed);
+                    continue;
+                }
+                PlayerCommand::LoopStart if is_stream => {
+                    controls.set_message("Streams can't loop yet".to_string());
+                    continue;
+                }
+                PlayerCommand::LoopStart => {
+                    let at = clock.elapsed();
+                    if duration > Duration::ZERO && at >= duration {
+                        controls.set_message("Point A has to be within the track".to_string());
+                    } else {
+                        // Starting a new loop drops the old one's end
+                        loop_start = Some(at);
+                        loop_end = None;
+                        controls.set_message(format!("Loop from {}; mark point B to start looping", format_time(at.as_secs())));
+                    }
+                    continue;
+                }
+                PlayerCommand::LoopEnd => {
+                    let at = clock.elapsed();
+                    let message = match loop_start {
+                        None => "Mark point A first".to_string(),
+                        Some(start) if at <= start => format!("Point B has to come after point A, at {}", format_time(start.as_secs())),
+                        Some(_) if duration > Duration::ZERO && at > duration => "Point B has to be within the track".to_string(),
+                        Some(start) => {
+                            loop_end = Some(at);
+                            format!("Looping {}-{}", format_time(start.as_secs()), format_time(at.as_secs()))
+                        }
+                    };
+                    controls.set_message(message);
+                    continue;
+                }
+                PlayerCommand::ResumeSaved => {
+                    if let Some(at) = saved.take() {
+                        seek_to = Some(at.as_secs_f64());
+                        controls.set_message(format!("Resumed from {}", format_time(at.as_secs())));
+                    }
+                    continue;
+                }
+                PlayerCommand::Bookmark => {
+                    let at = clock.elapsed();
+                    // Marking the same place twice only keeps one
+                    let index = match bookmarks.iter().position(|mark| mark.abs_diff(at) < Duration::from_secs(1)) {
+                        Some(index) => index,
+                        None => {
+                            let index = bookmarks.partition_point(|&mark| mark < at);
+                            bookmarks.insert(index, at);
+                            save_bookmarks(&bookmarks);
+                            index
+                        }
+                    };
+                    controls.set_message(bookmark_label(&bookmarks, index));
+                    continue;
+                }
+                PlayerCommand::NextBookmark | PlayerCommand::PreviousBookmark => {
+                    let from = seek_to.map_or(clock.elapsed(), Duration::from_secs_f64);
+                    let next = matches!(command, PlayerCommand::NextBookmark);
+                    // Like "previous" for tracks, going back skips the one
+                    // just passed for the first few seconds
+                    let found = if next {
+                        bookmarks.iter().position(|&mark| mark > from)
+                    } else {
+                        bookmarks.iter().rposition(|&mark| mark + Duration::from_secs(3) < from)
+                    };
+                    match found {
+                        Some(index) => {
+                            seek_to = Some(bookmarks[index].as_secs_f64());
+                            controls.set_message(bookmark_label(&bookmarks, index));
+                        }
+                        None if bookmarks.is_empty() => controls.set_message("No bookmarks; press B to add one".to_string()),
+                        None => controls.set_message(format!("No bookmark {} here", if next { "after" } else { "before" })),
+                    }
+                    continue;
+                }
+                PlayerCommand::JumpToBookmark(index) => {
+                    if let Some(&at) = bookmarks.get(index) {
+                        seek_to = Some(at.as_secs_f64());
+                        controls.set_message(bookmark_label(&bookmarks, index));
+                    }
+                    continue;
+                }
+                PlayerCommand::DeleteBookmark(index) => {
+                    if index < bookmarks.len() {
+                        let at = bookmarks.remove(index);
+                        save_bookmarks(&bookmarks);
+                        controls.set_message(format!("Deleted the bookmark at {}", format_time(at.as_secs())));
+                    }
+                    continue;
+                }
+                PlayerCommand::ClearLoop => {
+                    if loop_start.is_some() {
+                        controls.set_message("Loop cleared".to_string());
+                    }
+                    (loop_start, loop_end) = (None, None);
+                    continue;
+                }
+                PlayerCommand::TogglePause => {
+                    let paused = !controls.is_paused.load(Ordering::SeqCst);
+                    controls.is_paused.store(paused, Ordering::SeqCst);
+                    // The clock keeps going while fading out, since that's
+                    // still playing
+                    if paused {
+                        fade(controls, sink, 0.0);
+                        sink.lock().unwrap().pause();
+                        clock.pause();
+                    } else {
+                        sink.lock().unwrap().play();
+                        if !buffering {
+                            clock.resume();
+                        }
+                        fade(controls, sink, 1.0);
+                    }
+                    continue;
+                }
+                PlayerCommand::Quit => TrackEnd::Quit,
+                PlayerCommand::Stop => TrackEnd::Stopped,
+                // Picking a track from the list while playing carries on from there
+                PlayerCommand::Play(index) => TrackEnd::Jump(index),
+                PlayerCommand::Next => TrackEnd::Next,
+                // Like most players, "previous" past the first few seconds restarts the track
+                PlayerCommand::Previous if clock.elapsed() > Duration::from_secs(3) => TrackEnd::Restart,
+                PlayerCommand::Previous => TrackEnd::Previous,
+            };
+            // Changing track cuts straight to the next one
+            if matches!(end, TrackEnd::Quit | TrackEnd::Stopped) {
+                fade(controls, sink, 0.0);
+            }
+            sink.lock().unwrap().stop();
+            return Ok(end);
+        }
+        // Seeking would mean downloading it again from there, which isn't
+        // done yet, though going back to the start is
+        if is_stream && seek_to.is_some_and(|target| target > 0.0) {
+            controls.set_message("Streams can't seek yet".to_string());
+            seek_to = None;
+        }
+        if let Some(target) = seek_to {
+            if duration > Duration::ZERO && target >= duration.as_secs_f64() {
+                sink.lock().unwrap().stop();
+                return Ok(TrackEnd::Next);
+            }
+            let target = Duration::from_secs_f64(target);
+            let seeking = (target > SLOW_SEEK).then(|| format!("Seeking to {}, decoding the track up to there…", format_time(target.as_secs())));
+            if let Some(seeking) = &seeking {
+                controls.set_message(seeking.clone());
+            }
+            feed = start_playback(&source, loaded.as_ref(), target, playback)?.feed;
+            // Unless something else has been said meanwhile
+            if seeking.is_some() {
+                let mut status = controls.status.lock().unwrap();
+                if status.message == seeking {
+                    status.message = None;
+                }
+            }
+            clock.set(target);
+            last_position = target;
+        }
+
+        if let Some(feed) = &feed {
+            // What had arrived before the connection dropped is played first
+            if let Some(error) = feed.error().filter(|_| sink.lock().unwrap().empty()) {
+                return Err(error.into());
+            }
+            if feed.stalled() != buffering {
+                buffering = feed.stalled();
+                if buffering {
+                    clock.pause();
+                } else if !controls.is_paused.load(Ordering::SeqCst) {
+                    clock.resume();
+                }
+                controls.status.lock().unwrap().buffering = buffering;
+            }
+        }
+
+        // The audio thread has already read past it, so the clock catches up
+        let skipped = Duration::from_micros(controls.effects.skipped_us.swap(0, Ordering::SeqCst));
+        if skipped > Duration::ZERO {
+            clock.set(clock.elapsed() + skipped);
+            controls.set_message(format!("Skipped {} of silence", format_time(skipped.as_secs())));
+        }
+
+        // Checked every tick, so going back to A is never more than one
+        // tick late. Once the audio thread has the loop in memory it goes
+        // back by itself, and only the clock needs to.
+        controls.ab_loop.set(loop_start, loop_end);
+        if let (Some(start), Some(end)) = (loop_start, loop_end) {
+            if last_position < end && clock.elapsed() >= end {
+                if !controls.ab_loop.kept() {
+                    start_playback(&source, loaded.as_ref(), start, playback)?;
+                }
+                clock.set(start);
+            }
+        }
+        last_position = clock.elapsed();
+
+        // Display progress bar. The clock is frozen while paused, so both the
+        // bar and the end check follow what has actually been played.
+        {
+            // Worked out first so the queue and status are never locked together
+            let up_next = queue.up_next(controls);
+            let mut status = controls.status.lock().unwrap();
+            status.position = clock.elapsed().min(duration);
+            status.up_next = up_next;
+            status.loop_start = loop_start;
+            status.loop_end = loop_end;
+            status.bookmarks.clone_from(&bookmarks);
+        }
+
+        // Rather than playing on in silence to the end once it stops
+        if let Some(e) = playback.failure.take() {
+            sink.lock().unwrap().stop();
+            return Err(Box::new(e));
+        }
+
+        // Compare the exact durations: whole seconds would cut off the last
+        // fraction of a second of every track. The sink running out of audio
+        // ends it too, as when the length is unknown, or the headers said it
+        // was longer than it is.
+        let finished = (duration > Duration::ZERO && clock.elapsed() >= duration) || sink.lock().unwrap().empty();
+        if finished && !controls.is_paused.load(Ordering::SeqCst) {
+            return Ok(TrackEnd::Finished);
+        }
+    }
+}
+
+/// Which of `bookmarks` the one at `index` is, and where, like
+/// `Bookmark 2 of 3 at 01:20`.
+fn bookmark_label(bookmarks: &[Duration], index: usize) -> String {
+    format!("Bookmark {} of {} at {}", index + 1, bookmarks.len(), format_time(bookmarks[index].as_secs()))
+}
+
+/// Render a bar `width` characters wide with `progress` (0.0 to 1.0) filled,
+/// as the filled part and the rest, so they can be colored apart.
+fn progress_bar(progress: f64, width: usize, style: BarStyle) -> (String, String) {
+    let progress = progress.clamp(0.0, 1.0);
+    match style {
+        BarStyle::Ascii => {
+            let filled_length = ((width as f64 * progress) as usize).min(width);
+            ("=".repeat(filled_length), "-".repeat(width - filled_length))
+        }
+        BarStyle::Unicode => {
+            const PARTIAL: [char; 7] = ['▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+            let eighths = ((width * 8) as f64 * progress) as usize;
+            let full = eighths / 8;
+            let mut bar = "█".repeat(full);
+            let mut used = full;
+            if let Some(&partial) = PARTIAL.get((eighths % 8).wrapping_sub(1)) {
+                bar.push(partial);
+                used += 1;
+            }
+            (bar, " ".repeat(width - used))
+        }
+    }
+}
+
+/// The level meter as a bar for each channel, with the held peak marked by
+/// `|` and given in dBFS after it. Left out when there's no room for a bar.
+fn meter_lines(meter: &LevelMeter, display: &DisplayOptions, columns: usize) -> Vec<Vec<Span>> {
+    let label_width = " -48.0 dBFS".len();
+    let width = columns.saturating_sub("L []".len() + label_width);
+    if width < MIN_BAR_WIDTH {
+        return Vec::new();
+    }
+    let fraction = |db: f32| ((db - meter::FLOOR_DB) / -meter::FLOOR_DB) as f64;
+    let mut lines = Vec::new();
+    for (name, (level, held)) in ["L", "R"].into_iter().zip(meter.levels()) {
+        let (filled, empty) = progress_bar(fraction(level), width, display.bar_style);
+        let hold_at = ((fraction(held).clamp(0.0, 1.0) * width as f64) as usize).min(width - 1);
+        let mut cells: Vec<(char, Color)> = filled
+            .chars()
+            .map(|c| (c, display.theme.bar_filled))
+            .chain(empty.chars().map(|c| (c, display.theme.bar_empty)))
+            .collect();
+        if held > meter::FLOOR_DB {
+            cells[hold_at] = ('|', Color::Reset);
+        }
+        let mut line = vec![(format!("{} [", name), Color::Reset)];
+        line.extend(color_runs(cells));
+        let label = if held > 0.0 {
+            (format!("]  CLIP {:+.1}", held), display.theme.paused)
+        } else if held > meter::FLOOR_DB {
+            (format!("] {:5.1} dBFS", held), Color::Reset)
+        } else {
+            ("]    -inf".to_string(), Color::Reset)
+        };
+        line.push(label);
+        lines.push(line);
+    }
+    lines
+}
+
+/// A number of bytes, in whichever unit suits it, like "12.3 MB".
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format a time as `MM:SS`, or `H:MM:SS` once it reaches an hour.
+fn format_time(seconds: u64) -> String {
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+/// The progress bar and time for a track of known length, `columns` wide.
+fn progress_line(status: &PlayerStatus, display: &DisplayOptions, columns: usize) -> Vec<Span> {
+    let position = status.position.min(status.total);
+    let progress = position.as_secs_f64() / status.total.as_secs_f64();
+    let time = progress_time(status, display);
+    let bar_width = bar_width(columns, &time);
+    if bar_width >= MIN_BAR_WIDTH {
+        let (filled, empty) = progress_bar(progress, bar_width, display.bar_style);
+        let mut line = vec![("[".to_string(), Color::Reset)];
+        line.extend(bar_marks(status, filled, empty, display, bar_width));
+        line.push((format!("] {}", time), Color::Reset));
+        line
+    } else {
+        plain(time)
+    }
+}
+
+/// The bar's filled and empty parts, with bookmarks marked by `|` and the
+/// A-B loop's points by `A` and `B` in the columns they fall in.
+fn bar_marks(status: &PlayerStatus, filled: String, empty: String, display: &DisplayOptions, width: usize) -> Vec<Span> {
+    let mut cells: Vec<(char, Color)> = filled
+        .chars()
+        .map(|c| (c, display.theme.bar_filled))
+        .chain(empty.chars().map(|c| (c, display.theme.bar_empty)))
+        .collect();
+    // The loop's points go last, so they show over a bookmark in the same place
+    let bookmarks = status.bookmarks.iter().map(|&at| ('|', Some(at)));
+    for (mark, at) in bookmarks.chain([('A', status.loop_start), ('B', status.loop_end)]) {
+        if let Some(at) = at {
+            let column = (at.as_secs_f64() / status.total.as_secs_f64() * width as f64) as usize;
+            if let Some(cell) = cells.get_mut(column.min(width.saturating_sub(1))) {
+                *cell = (mark, Color::Reset);
+            }
+        }
+    }
+    color_runs(cells)
+}
+
+/// Colored characters as spans, with runs of the same color put back
+/// together.
+fn color_runs(cells: Vec<(char, Color)>) -> Vec<Span> {
+    let mut spans: Vec<Span> = Vec::new();
+    for (c, color) in cells {
+        match spans.last_mut() {
+            Some((text, last)) if *last == color => text.push(c),
+            _ => spans.push((c.to_string(), color)),
+        }
+    }
+    spans
+}
+
+/// The time shown after the progress bar.
+fn progress_time(status: &PlayerStatus, display: &DisplayOptions) -> String {
+    let position = status.position.min(status.total).as_secs();
+    let total = status.total.as_secs();
+    if display.show_remaining {
+        format!("-{}/{}", format_time(total - position), format_time(total))
+    } else {
+        format!("{}/{}", format_time(position), format_time(total))
+    }
+}
+
+/// Width of the bar itself, leaving room for the brackets, the space before
+/// `time`, and the last column free so the line never wraps.
+fn bar_width(columns: usize, time: &str) -> usize {
+    columns.saturating_sub(time.len() + 4)
+}
+
+/// Where a click on `column` of the progress bar's row points within the
+/// track, as a fraction, or None if it missed the bar.
+fn bar_click_fraction(status: &PlayerStatus, display: &DisplayOptions, columns: usize, column: usize) -> Option<f64> {
+    if status.total == Duration::ZERO {
+        return None;
+    }
+    let width = bar_width(columns, &progress_time(status, display));
+    // The bar starts after the opening bracket in column 0
+    if width < MIN_BAR_WIDTH || column == 0 || column > width {
+        return None;
+    }
+    Some((column - 1) as f64 / width as f64)
+}
+
+/// File name of a track without its directory, for compact display.
+fn track_name(path: &Path) -> Cow<'_, str> {
+    path.file_name().map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy())
+}
+
+/// What a track is shown as: `Artist – Title` from its tags, or its file
+/// name when it has none.
+fn display_name(music_files: &[PathBuf], tags: &TagCache, index: usize) -> String {
+    tags.get(index, &music_files[index])
+        .display_name()
+        .unwrap_or_else(|| track_name(&music_files[index]).to_string())
+}
+
+/// Shorten `text` to at most `width` characters, marking the cut with an ellipsis.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut short: String = text.chars().take(width.saturating_sub(1)).collect();
+    short.push('…');
+    short
+}
+
+/// A piece of a line and the color of its text.
+type Span = (String, Color);
+
+fn plain(text: String) -> Vec<Span> {
+    vec![(text, Color::Reset)]
+}
+
+/// A path shortened by `short_path`, with its directories dimmed, so the
+/// file name stands out.
+fn path_spans(path: &Path, root: &Path, width: usize, theme: &Theme) -> Vec<Span> {
+    let (directory, name) = short_path(path, root, width);
+    vec![(directory, theme.directory), (name, Color::Reset)]
+}
+
+/// Where `path` is, from the `root` it was found in, or all of it when it
+/// isn't in there, like a playlist's track: the directories leading to it,
+/// then its name. When they don't fit in `width` characters, the
+/// directories in the middle make way for an ellipsis, keeping the first
+/// and as many of the last as fit. The name's always kept whole.
+fn short_path(path: &Path, root: &Path, width: usize) -> (String, String) {
+    let relative = path.strip_prefix(root).ok().filter(|relative| !relative.as_os_str().is_empty()).unwrap_or(path);
+    let (text, name) = (relative.to_string_lossy(), track_name(relative).into_owned());
+    let directory = text.strip_suffix(name.as_str()).unwrap_or("");
+    let room = width.saturating_sub(name.chars().count());
+    if directory.chars().count() <= room {
+        return (directory.to_string(), name);
+    }
+    // Each directory with the separator after it, which the first is all of
+    // for an absolute path
+    let parts: Vec<&str> = directory.split_inclusive(std::path::MAIN_SEPARATOR).collect();
+    let ellipsis = format!("…{}", std::path::MAIN_SEPARATOR);
+    let first = parts.first().copied().unwrap_or("");
+    let mut kept = if first.chars().count() + ellipsis.chars().count() <= room { format!("{}{}", first, ellipsis) } else { ellipsis.clone() };
+    let mut last = String::new();
+    for part in parts.iter().skip(1).rev() {
+        if kept.chars().count() + last.chars().count() + part.chars().count() > room {
+            break;
+        }
+        last.insert_str(0, part);
+    }
+    kept += &last;
+    (kept, name)
+}
+
+/// Print `text` in `color`, without any escape codes for `Color::Reset`.
+fn print_colored(stdout: &mut io::Stdout, text: &str, color: Color) -> io::Result<()> {
+    if color == Color::Reset {
+        print!("{}", text);
+        Ok(())
+    } else {
+        execute!(stdout, SetForegroundColor(color), Print(text), SetForegroundColor(Color::Reset))
+    }
+}
+
+/// Print `spans` as one line, each in its own color, cut off with an
+/// ellipsis after `width` characters in all. Returns the characters printed.
+fn print_spans(stdout: &mut io::Stdout, spans: &[Span], width: usize) -> io::Result<usize> {
+    let text: String = spans.iter().map(|(text, _)| text.as_str()).collect();
+    let short = truncate(&text, width);
+    let mut chars = short.chars();
+    for (text, color) in spans {
+        let part: String = chars.by_ref().take(text.chars().count()).collect();
+        print_colored(stdout, &part, *color)?;
+    }
+    Ok(short.chars().count())
+}
+
+/// How the views are drawn, as chosen on the command line and with keys.
+struct DisplayOptions {
+    bar_style: BarStyle,
+    theme: Theme,
+    /// What the list is sorted by, for the footer; None while it's in the
+    /// order of the playlist it came from.
+    sort: Option<SortKey>,
+    /// The tags the list is narrowed to.
+    filter: Filter,
+    /// Which tracks the list shows.
+    listing: Listing,
+    /// Show how many times each track has been played in the list.
+    show_play_counts: bool,
+    /// Show how big each file is and when it last changed in the list.
+    show_size: bool,
+    show_date: bool,
+    /// The counts, read when they're shown, and how many plays had been
+    /// counted when they were read.
+    play_counts: Vec<u32>,
+    play_counts_read: Option<u32>,
+    /// Each track's rating, read at the start.
+    ratings: Vec<Rating>,
+    /// How long each track lasts, as it's read.
+    lengths: Lengths,
+    /// Show the time left instead of the time played.
+    show_remaining: bool,
+    /// Show the lyrics pane, for tracks with an `.lrc` file.
+    show_lyrics: bool,
+    show_spectrum: bool,
+    show_meter: bool,
+    /// The sleep timer being typed, and what was wrong with it when last
+    /// set.
+    sleep_prompt: Option<(String, Option<String>)>,
+    /// Where to write the queue as a playlist as it's typed, and what was
+    /// wrong with it when last tried.
+    export_prompt: Option<(String, Option<String>)>,
+    /// The bookmark highlighted in the list of them, while it's open.
+    bookmark_cursor: Option<usize>,
+    /// Where the entry highlighted in the queue is, while it's shown.
+    queue_cursor: Option<usize>,
+    /// What the list's footer says instead for a while, and since when.
+    notice: Option<(String, Instant)>,
+    /// What each place tracks were found is called in the list, when there's
+    /// more than one, and which of them each track came from.
+    places: Vec<String>,
+    sources: Vec<usize>,
+    /// Where each of those places is, for showing paths from there.
+    roots: Vec<PathBuf>,
+    /// How many directories were too deep to look in, so the list is known
+    /// to leave some out.
+    too_deep: usize,
+    /// Which of the tracks found have gone since, which aren't counted.
+    vanished: Vec<bool>,
+    /// The file that stopped playing when it couldn't be read any more, and
+    /// whether it can be again, until it's looked for again or something
+    /// else is played.
+    unreadable: Option<(PathBuf, bool)>,
+}
+
+impl DisplayOptions {
+    /// The notice for the list's footer: that a file couldn't be read, for
+    /// as long as that's the case, or else the last one, unless it's been up
+    /// long enough.
+    fn notice(&self) -> Option<String> {
+        if let Some((path, back)) = &self.unreadable {
+            return Some(match back {
+                false => format!("Device removed or unreadable: {}  F5: look again  q: quit", path.display()),
+                true => format!("{} can be read again  F5: look through it again  q: quit", path.display()),
+            });
+        }
+        self.notice.as_ref().filter(|(_, since)| since.elapsed() < NOTICE_TIME).map(|(notice, _)| notice.clone())
+    }
+}
+
+/// Which tracks the file list shows.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Listing {
+    /// Every file, or the ones the filter keeps, as sorted.
+    All,
+    /// What's been played lately, most recent first.
+    Recent,
+    /// What's been played most, most first.
+    MostPlayed,
+    /// The favorites of what `All` shows.
+    Favorites,
+}
+
+/// What's drawn from the samples being played, kept from one redraw to the
+/// next so it can fall back smoothly.
+#[derive(Default)]
+struct Visuals {
+    spectrum: Spectrum,
+    meter: LevelMeter,
+}
+
+/// The queue as listed in the playing view: the entry playing and the ones
+/// after it, scrolled to keep the one at `cursor` in view.
+fn queue_lines(music_files: &[PathBuf], tags: &TagCache, queue: &Queue, cursor: usize) -> Vec<Vec<Span>> {
+    let top = queue.first_shown();
+    if top >= queue.len() {
+        return vec![plain("Nothing else in the queue  Esc: close".to_string())];
+    }
+    let cursor = cursor.clamp(top, queue.len() - 1);
+    let first = top.max((cursor + 1).saturating_sub(QUEUE_ROWS));
+    let mut lines = vec![plain(format!("Queue: {} to come", queue.len() - queue.upcoming()))];
+    for position in first..queue.len().min(first + QUEUE_ROWS) {
+        let mark = if position == cursor { '>' } else { ' ' };
+        let playing = if queue.current() == Some(position) { "  (playing)" } else { "" };
+        let name = queue.get(position).map_or(String::new(), |track| display_name(music_files, tags, track));
+        lines.push(plain(format!("{} {}. {}{}", mark, position + 1, name, playing)));
+    }
+    lines.push(plain("Enter: play  d: take out  J/K: move down/up  c: clear what's to come  Esc: close".to_string()));
+    lines
+}
+
+/// Draw the playing view from the shared state. Every line is rewritten in
+/// place and cleared to the end, so redrawing on each tick doesn't flicker
+/// and nothing is left over from a longer line or a wider terminal.
+///
+/// Room is left in the bottom right corner for cover art `art` cells in
+/// size, if it fits below the text, and where its top left corner goes is
+/// returned. Any `lyrics` fill the rows below the text, beside the art, and
+/// the spectrum and level meter go under the progress bar.
+fn draw_playing(
+    music_files: &[PathBuf],
+    tags: &TagCache,
+    controls: &Controls,
+    display: &DisplayOptions,
+    art: Option<(u16, u16)>,
+    lyrics: Option<&Lyrics>,
+    visuals: &mut Visuals,
+) -> io::Result<Option<(u16, u16)>> {
+    let status = controls.status.lock().unwrap().clone();
+    let columns = terminal::size().map_or(80, |(columns, _)| columns as usize);
+
+    let mut lines = Vec::new();
+    match status.track {
+        Some(index) => {
+            let (position, queue_len) = {
+                let queue = controls.queue.lock().unwrap();
+                (queue.current().unwrap_or(0), queue.len())
+            };
+            lines.push(plain(format!("Playing {}: {}", position + 1, display_name(music_files, tags, index))));
+            let root = display.sources.get(index).and_then(|&source| display.roots.get(source)).map_or(Path::new(""), PathBuf::as_path);
+            lines.push(path_spans(&music_files[index], root, columns.saturating_sub(1), &display.theme));
+            // Always on PROGRESS_ROW, which mouse clicks rely on
+            if status.total > Duration::ZERO {
+                lines.push(progress_line(&status, display, columns));
+            } else {
+                lines.push(plain(format_time(status.position.as_secs())));
+            }
+            match (&status.stream, status.waiting) {
+                (Some(stream), _) if status.buffering => lines.push(plain(format!("{}  Buffering...", stream.label()))),
+                (Some(stream), _) => lines.push(plain(stream.label())),
+                (None, Some((waiting, done, length))) => lines.push(plain(format!("{}... {}%", waiting, done * 100 / length.max(1)))),
+                (None, None) if status.buffering => lines.push(plain("Buffering...".to_string())),
+                (None, None) => {}
+            }
+            let paused = controls.is_paused.load(Ordering::SeqCst);
+            if display.show_spectrum {
+                let (frames, sample_rate) = if paused { (Vec::new(), 0) } else { controls.tap.latest(spectrum::WINDOW) };
+                let spectrum = &mut visuals.spectrum;
+                spectrum.update(&frames, sample_rate, columns.saturating_sub(1));
+                lines.extend(spectrum.lines(display.bar_style).into_iter().map(|line| vec![(line, display.theme.bar_filled)]));
+            }
+            if display.show_meter {
+                // What reaches the speakers, after the volume, so a boost
+                // over 100% shows up as clipping
+                let volume = controls.sink_volume();
+                let peaks = if paused { [0.0; 2] } else { controls.tap.take_peaks().map(|peak| peak * volume) };
+                visuals.meter.update(peaks);
+                lines.extend(meter_lines(&visuals.meter, display, columns.saturating_sub(1)));
+            }
+            lines.push(plain(format!("Track {}/{}", position + 1, queue_len)));
+            if let Some(album) = &tags.get(index, &music_files[index]).album {
+                lines.push(plain(format!("Album: {}", album)));
+            }
+            let up_next = status.up_next.map_or("end of queue".to_string(), |next| display_name(music_files, tags, next));
+            lines.push(plain(format!("Up next: {}", up_next)));
+        }
+        None => lines.push(plain("Starting...".to_string())),
+    }
+    lines.push(Vec::new());
+    match &display.sleep_prompt {
+        Some((text, Some(error))) => lines.push(plain(format!("sleep in: {}  ({})  Esc: cancel", text, error))),
+        Some((text, None)) => lines.push(plain(format!("sleep in: {}  like 45m, +15m to add or off  Enter: set  Esc: cancel", text))),
+        None => match &display.export_prompt {
+            Some((text, Some(error))) => lines.push(plain(format!("write queue to: {}  ({})  Esc: cancel", text, error))),
+            Some((text, None)) => lines.push(plain(format!("write queue to: {}  like road-trip.m3u8  Enter: write  Esc: cancel", text))),
+            None => lines.push(plain(status.message.unwrap_or_default())),
+        },
+    }
+    if let Some(cursor) = display.bookmark_cursor {
+        if status.bookmarks.is_empty() {
+            lines.push(plain("No bookmarks; press B to add one  Esc: close".to_string()));
+        }
+        for (index, at) in status.bookmarks.iter().enumerate() {
+            let mark = if index == cursor.min(status.bookmarks.len() - 1) { '>' } else { ' ' };
+            lines.push(plain(format!("{} {}. {}", mark, index + 1, format_time(at.as_secs()))));
+        }
+        if !status.bookmarks.is_empty() {
+            lines.push(plain("Enter: jump  d: delete  Esc: close".to_string()));
+        }
+    }
+    if let Some(cursor) = display.queue_cursor {
+        lines.extend(queue_lines(music_files, tags, &controls.queue.lock().unwrap(), cursor));
+    }
+
+    let mut stdout = io::stdout();
+    for (row, line) in lines.iter().enumerate() {
+        execute!(stdout, cursor::MoveTo(0, row as u16))?;
+        print_spans(&mut stdout, line, columns.saturating_sub(1))?;
+        execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    }
+
+    // The status line sits on the bottom row, below everything else
+    let rows = terminal::size().map_or(24, |(_, rows)| rows);
+    // The art sits just above the status line, a column in from the edge
+    let art_at = art.and_then(|(art_columns, art_rows)| {
+        let left = (columns as u16).checked_sub(art_columns + 1)?;
+        let top = rows.checked_sub(art_rows + 1).filter(|&top| top as usize >= lines.len())?;
+        Some((left, top))
+    });
+    // The lyrics start after a blank row, scrolled to keep the line being
+    // sung in the middle once there are more than fit. Following the
+    // position means they keep up with seeks too.
+    let lyrics_top = lines.len() + 1;
+    let lyrics_rows = (rows as usize).saturating_sub(1).saturating_sub(lyrics_top);
+    let current = lyrics.and_then(|lyrics| lyrics.current(status.position));
+    let first = match (lyrics, current) {
+        (Some(lyrics), Some(current)) => current.saturating_sub(lyrics_rows / 2).min(lyrics.lines.len().saturating_sub(lyrics_rows)),
+        _ => 0,
+    };
+    // Clearing the rest of the screen would wipe the art, so its rows are
+    // only cleared up to it
+    for row in lines.len() as u16..rows.saturating_sub(1) {
+        execute!(stdout, cursor::MoveTo(0, row))?;
+        let beside_art = art_at.filter(|&(_, top)| row >= top).map(|(left, _)| left as usize);
+        let width = beside_art.map_or(columns, |left| left.saturating_sub(1)).saturating_sub(1);
+        let index = (row as usize).checked_sub(lyrics_top).map(|offset| first + offset);
+        let mut printed = 0;
+        if let Some(text) = index.and_then(|index| lyrics?.lines.get(index)) {
+            let text = truncate(text, width);
+            printed = text.chars().count();
+            if index == current {
+                highlight(&mut stdout, display.theme.selection, display.theme.selection_text)?;
+                print!("{}", text);
+                execute!(stdout, SetAttribute(Attribute::Reset))?;
+            } else {
+                print!("{}", text);
+            }
+        }
+        match beside_art {
+            Some(left) => print!("{}", " ".repeat(left.saturating_sub(printed))),
+            None => execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?,
+        }
+    }
+    // Taken off the card, it plays on for as long as what's been read lasts
+    let track = status.track.map(|index| match display.vanished.get(index) {
+        Some(true) => format!("{} (gone from the card)", display_name(music_files, tags, index)),
+        _ => display_name(music_files, tags, index),
+    });
+    let width = columns.saturating_sub(1);
+    execute!(stdout, cursor::MoveTo(0, rows.saturating_sub(1)))?;
+    highlight(&mut stdout, display.theme.status, display.theme.status_text)?;
+    let rating = status.track.and_then(|index| display.ratings.get(index).copied()).unwrap_or_default();
+    let printed = print_spans(&mut stdout, &status_line(controls, track.as_deref(), rating, &display.theme), width)?;
+    print!("{}", " ".repeat(width.saturating_sub(printed)));
+    execute!(stdout, SetAttribute(Attribute::Reset))?;
+    stdout.flush()?;
+    Ok(art_at)
+}
+
+/// Start drawing in `background` and `text`, or in reverse video when the
+/// theme has no color for it. `SetAttribute(Attribute::Reset)` ends either.
+fn highlight(stdout: &mut io::Stdout, background: Color, text: Color) -> io::Result<()> {
+    if background == Color::Reset {
+        execute!(stdout, SetAttribute(Attribute::Reverse))
+    } else {
+        execute!(stdout, SetBackgroundColor(background), SetForegroundColor(text))
+    }
+}
+
+/// One-line summary of the playback state: pause, volume, repeat, shuffle
+/// and the current file.
+fn status_line(controls: &Controls, track: Option<&str>, rating: Rating, theme: &Theme) -> Vec<Span> {
+    let paused = controls.is_paused.load(Ordering::SeqCst);
+    let state = if paused {
+        (" PAUSED".to_string(), theme.paused)
+    } else {
+        (" PLAYING".to_string(), theme.status_text)
+    };
+    let repeat_mode = RepeatMode::from_u8(controls.repeat.load(Ordering::SeqCst));
+    let shuffle = if controls.shuffle.load(Ordering::SeqCst) { "on" } else { "off" };
+    // Only shown when it's been changed
+    let mut changed = match controls.speed.load(Ordering::SeqCst) {
+        100 => String::new(),
+        speed => format!(" | Speed: {}", speed_label(speed)),
+    };
+    if controls.effects.mono.load(Ordering::SeqCst) {
+        changed.push_str(" | Mono");
+    }
+    if let Some(deadline) = *controls.sleep_at.lock().unwrap() {
+        // Rounded up, so it doesn't show 00:00 for the last second
+        let left = deadline.saturating_duration_since(Instant::now()) + Duration::from_millis(999);
+        changed.push_str(&format!(" | Sleep: {}", format_time(left.as_secs())));
+    }
+    for (name, tone) in [("Bass", &controls.effects.bass), ("Treble", &controls.effects.treble)] {
+        let db = tone.load(Ordering::SeqCst);
+        if db != 0 {
+            changed.push_str(&format!(" | {}: {}", name, tone_label(db)));
+        }
+    }
+    if rating.favorite {
+        changed.push_str(" | Favorite");
+    }
+    if rating.stars > 0 {
+        changed.push_str(&format!(" | {}", stars_label(rating.stars)));
+    }
+    let rest = format!(
+        " | Volume: {} | Repeat: {} | Shuffle: {}{} | {}",
+        controls.volume_label(),
+        repeat_mode.label(),
+        shuffle,
+        changed,
+        track.unwrap_or("-")
+    );
+    vec![state, (rest, theme.status_text)]
+}
+
+/// A star rating like `3/5 stars`.
+fn stars_label(stars: u8) -> String {
+    format!("{}/{} {}", stars, ratings::MAX_STARS, if stars == 1 { "star" } else { "stars" })
+}
+
+/// Save `file`'s new `rating`, changed from `old`, and with --write-tags
+/// write its stars to the file's tags. Says how it went.
+fn rate(controls: &Controls, file: &Path, old: Rating, rating: Rating) -> String {
+    let Some(ratings) = &controls.ratings else {
+        return "Can't save ratings: neither XDG_DATA_HOME nor HOME is set".to_string();
+    };
+    let mut message = match (old.favorite, rating.favorite) {
+        (false, true) => "Added to favorites".to_string(),
+        (true, false) => "Removed from favorites".to_string(),
+        _ if rating.stars == 0 => "Rating cleared".to_string(),
+        _ => format!("Rated {}", stars_label(rating.stars)),
+    };
+    if controls.write_tags && rating.stars != old.stars {
+        if let Err(e) = write_rating(controls, file, rating.stars) {
+            message = format!("{}, but can't write it to the tags: {}", message, e);
+        }
+    }
+    if let Err(e) = ratings.set(file, rating) {
+        message = format!("Can't save the rating: {}", e);
+    }
+    message
+}
+
+/// Write `stars` to the RATING tag of the FLAC file at `file`, as a
+/// percentage like other players, or remove it for none. Writing the tags
+/// changes the file's size, so its play count is moved over to the new one.
+fn write_rating(controls: &Controls, file: &Path, stars: u8) -> Result<(), String> {
+    if music_extension(file) != Some("flac") {
+        return Err("only FLAC files can be tagged".to_string());
+    }
+    let old_size = fs::metadata(file).map_err(|e| e.to_string())?.len();
+    let value = if stars == 0 { String::new() } else { (stars as u32 * 100 / ratings::MAX_STARS as u32).to_string() };
+    tags::write_flac_comments(file, &[("RATING", value)]).map_err(|e| e.to_string())?;
+    if let Some(plays) = &controls.plays {
+        let _ = plays.resized(file, old_size);
+    }
+    Ok(())
+}
+
+/// A speed in percent as a rate, like `1.25x`.
+fn speed_label(speed: u32) -> String {
+    format!("{}.{:02}x", speed / 100, speed % 100)
+}
+
+/// "center", or how far to one side, like "30 left".
+fn balance_label(balance: i32) -> String {
+    match balance {
+        0 => "center".to_string(),
+        1.. => format!("{} right", balance),
+        _ => format!("{} left", -balance),
+    }
+}
+
+/// Step the bass or treble in `tone` up or down a dB, returning where it
+/// ends up. The audio thread picks it up from there.
+fn change_tone(tone: &AtomicI32, up: bool) -> i32 {
+    let step = if up { 1 } else { -1 };
+    let db = (tone.load(Ordering::SeqCst) + step).clamp(-dsp::MAX_TONE_DB, dsp::MAX_TONE_DB);
+    tone.store(db, Ordering::SeqCst);
+    db
+}
+
+/// A bass or treble setting, like "+3 dB".
+fn tone_label(db: i32) -> String {
+    match db {
+        0 => "0 dB".to_string(),
+        _ => format!("{:+} dB", db),
+    }
+}
+
+/// Step the volume up or down, applying it to the playing sink.
+fn change_volume(controls: &Controls, sink: &Mutex<Sink>, up: bool) {
+    // Adjusting the volume while muted unmutes first
+    controls.muted.store(false, Ordering::SeqCst);
+    let current = controls.volume.load(Ordering::SeqCst);
+    let volume = if up {
+        (current + VOLUME_STEP).min(MAX_VOLUME)
+    } else {
+        current.saturating_sub(VOLUME_STEP)
+    };
+    controls.volume.store(volume, Ordering::SeqCst);
+    sink.lock().unwrap().set_volume(controls.sink_volume());
+}
+
+/// Draw `lines` in a box in the middle of the screen. Lines that don't fit
+/// are cut off, and the box shrinks to fit small terminals.
+fn draw_help(lines: &[String]) -> io::Result<()> {
+    let (columns, rows) = terminal::size().map_or((80, 24), |(columns, rows)| (columns as usize, rows as usize));
+    let longest = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+    // A border and a space of padding on each side
+    let width = (longest + 4).min(columns);
+    let height = (lines.len() + 2).min(rows);
+    if width < 5 || height < 3 {
+        return Ok(());
+    }
+    let left = (columns - width) / 2;
+    let top = (rows - height) / 2;
+    let inner = width - 4;
+
+    let mut stdout = io::stdout();
+    let border = format!("+{}+", "-".repeat(width - 2));
+    execute!(stdout, cursor::MoveTo(left as u16, top as u16))?;
+    print!("{}", border);
+    for row in 0..height - 2 {
+        let mut line = lines[row].clone();
+        if row == height - 3 && lines.len() > height - 2 {
+            // Mark that the list goes on past the bottom of the box
+            line = "...".to_string();
+        }
+        execute!(stdout, cursor::MoveTo(left as u16, (top + 1 + row) as u16))?;
+        print!("| {:inner$} |", truncate(&line, inner), inner = inner);
+    }
+    execute!(stdout, cursor::MoveTo(left as u16, (top + height - 1) as u16))?;
+    print!("{}", border);
+    stdout.flush()
+}
+
+/// Rows available for entries in the track list, below the header and
+/// above the blank line and key hints.
+fn list_height() -> usize {
+    let rows = terminal::size().map_or(24, |(_, rows)| rows as usize);
+    rows.saturating_sub(3).max(1)
+}
+
+/// A line being typed at the bottom of the track list.
+enum Prompt<'a> {
+    Search(&'a str),
+    /// The filter, and what was wrong with it when last applied.
+    Filter(&'a str, Option<&'a str>),
+}
+
+/// What each of `roots` is called in the list: the last part of its path,
+/// or the whole of it when that doesn't tell them apart.
+fn place_names(roots: &[PathBuf]) -> Vec<String> {
+    let last = |root: &PathBuf| root.file_name().map_or(root.to_string_lossy(), |name| name.to_string_lossy()).into_owned();
+    let names: Vec<String> = roots.iter().map(last).collect();
+    if names.iter().collect::<HashSet<_>>().len() == names.len() {
+        names
+    } else {
+        roots.iter().map(|root| root.to_string_lossy().into_owned()).collect()
+    }
+}
+
+/// What the header says about directories left out for being past
+/// --max-depth, if any were, so the list isn't taken to be all there is.
+fn too_deep_note(too_deep: usize) -> String {
+    match too_deep {
+        0 => String::new(),
+        1 => " (1 directory past --max-depth not looked in)".to_string(),
+        n => format!(" ({} directories past --max-depth not looked in)", n),
+    }
+}
+
+/// What's said when the list changes as music's put on the card or taken off.
+fn changed_note(added: usize, gone: usize) -> String {
+    let tracks = |n: usize| format!("{} {}", n, if n == 1 { "track" } else { "tracks" });
+    match (added, gone) {
+        (_, 0) => format!("Found {} more", tracks(added)),
+        (0, _) => format!("{} gone from the list", tracks(gone)),
+        _ => format!("Found {} more, and {} gone from the list", tracks(added), tracks(gone)),
+    }
+}
+
+/// Draw the visible part of the track list, highlighting the cursor and
+/// marking the track that's playing, with how long each track lasts down
+/// the right once that's been read. Like the playing view, rows are
+/// rewritten in place so redrawing on every tick doesn't flicker.
+fn draw_file_list(
+    music_files: &[PathBuf],
+    tags: &TagCache,
+    order: &Order,
+    list: &TrackList,
+    playing: Option<usize>,
+    prompt: Option<Prompt>,
+    display: &DisplayOptions,
+) -> io::Result<()> {
+    let theme = &display.theme;
+    let (columns, rows) = terminal::size().map_or((80, 24), |(columns, rows)| (columns as usize, rows));
+    let height = list_height();
+    let width = columns.saturating_sub(1);
+    let mut stdout = io::stdout();
+
+    execute!(stdout, cursor::MoveTo(0, 0))?;
+    let total = display.vanished.iter().filter(|&&vanished| !vanished).count();
+    let header = if display.listing == Listing::Recent {
+        format!("{} music files played recently:", order.tracks().len())
+    } else if display.listing == Listing::MostPlayed {
+        format!("{} music files played, most played first:", order.tracks().len())
+    } else if display.listing == Listing::Favorites {
+        format!("{} favorites of {} music files{}:", order.tracks().len(), total, if display.filter.is_empty() { String::new() } else { format!(" matching {}", display.filter.text()) })
+    } else if display.filter.is_empty() && display.places.len() > 1 {
+        format!("Found {} music files in {} places{}:", total, display.places.len(), too_deep_note(display.too_deep))
+    } else if display.filter.is_empty() {
+        format!("Found {} music files{}:", total, too_deep_note(display.too_deep))
+    } else {
+        format!("{} of {} music files match {}:", order.tracks().len(), total, display.filter.text())
+    };
+    print!("{}", truncate(&header, width));
+    execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    let visible = list.visible(height);
+    // Down the right, each as wide as the widest shown so hour-long tracks
+    // line up with the rest. Those that don't leave room for enough of the
+    // names go, the last first.
+    let mut columns: Vec<Vec<String>> = vec![visible
+        .iter()
+        .map(|&index| match display.lengths.get(index) {
+            Some(Some(length)) => format_time(length.as_secs()),
+            Some(None) => "--:--".to_string(),
+            None => String::new(),
+        })
+        .collect()];
+    if display.show_size {
+        columns.push(visible.iter().map(|&index| display.lengths.stamp(index).map_or_else(String::new, |(size, _)| format!("{:.1} MiB", size as f64 / (1024.0 * 1024.0)))).collect());
+    }
+    if display.show_date {
+        columns.push(visible.iter().map(|&index| display.lengths.stamp(index).map_or_else(String::new, |(_, modified)| history::format_date((modified / 1_000_000_000) as u64))).collect());
+    }
+    let mut widths: Vec<usize> = columns.iter().map(|column| column.iter().map(|cell| cell.chars().count()).max().unwrap_or(0).max(5)).collect();
+    while width.saturating_sub(widths.iter().map(|width| width + 2).sum()) < MIN_NAME_WIDTH && widths.pop().is_some() {
+        columns.pop();
+    }
+    for row in 0..height {
+        execute!(stdout, cursor::MoveTo(0, row as u16 + 1))?;
+        if let Some(&index) = visible.get(row) {
+            let file = &music_files[index];
+            let extension = music_extension(file).unwrap_or("");
+            let mark = if playing == Some(index) { '*' } else { ' ' };
+            let mut name = display_name(music_files, tags, index);
+            if display.places.len() > 1 {
+                name = format!("({}) {}", display.places[display.sources[index]], name);
+            }
+            let plays = match display.play_counts.get(index) {
+                _ if !display.show_play_counts && display.listing != Listing::MostPlayed => String::new(),
+                Some(&count) if count > 0 => format!("{:>4}  ", count),
+                _ => " ".repeat(6),
+            };
+            let entry = format!("{} {}: [{}] {}{}", mark, order.position(index).unwrap_or(index), extension.to_uppercase(), plays, name);
+            let mut line = truncate(&entry, width.saturating_sub(widths.iter().map(|width| width + 2).sum()));
+            if !columns.is_empty() {
+                line = format!("{:left$}", line, left = width.saturating_sub(widths.iter().map(|width| width + 2).sum()));
+                for (column, &column_width) in columns.iter().zip(&widths) {
+                    line += &format!("  {:>column_width$}", column[row], column_width = column_width);
+                }
+            }
+            let line = truncate(&line, width);
+            if list.offset + row == list.cursor {
+                highlight(&mut stdout, theme.selection, theme.selection_text)?;
+                print!("{:width$}", line, width = width);
+                execute!(stdout, SetAttribute(Attribute::Reset))?;
+            } else {
+                print!("{}", line);
+            }
+        }
+        execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    }
+    let (page, pages) = list.page(height);
+    execute!(stdout, cursor::MoveTo(0, rows.saturating_sub(1)))?;
+    let footer = match prompt {
+        Some(Prompt::Filter(text, Some(error))) => format!("filter: {}  ({})  Esc: cancel", text, error),
+        Some(Prompt::Filter(text, None)) => format!("filter: {}  Enter: apply, empty to clear  Esc: cancel", text),
+        Some(Prompt::Search(query)) if list.is_empty() => format!("/{}  (no matches)  Esc: cancel", query),
+        Some(Prompt::Search(query)) => format!("/{}  ({} matching)  Enter: play  Esc: cancel", query, list.len()),
+        None => match display.notice() {
+            Some(notice) => notice,
+            None => format!(
+                "page {}/{}  {}  Enter: play  /: search  Tab: now playing  ?: help  q: quit",
+                page,
+                pages,
+                match display.listing {
+                    Listing::All => sort_label(display.sort),
+                    Listing::Favorites => format!("favorites {}", sort_label(display.sort)),
+                    Listing::Recent => "recently played".to_string(),
+                    Listing::MostPlayed => "most played".to_string(),
+                }
+            ),
+        },
+    };
+    print!("{}", truncate(&footer, width));
+    execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    stdout.flush()
+}
+
+/// How the list's sorted, like `sorted by name`.
+fn sort_label(sort: Option<SortKey>) -> String {
+    match sort {
+        Some(sort) => sort.label().to_string(),
+        None => "in playlist order".to_string(),
+    }
+}
+
+/// Draw the level of the library that's open, like the file list.
+fn draw_library(music_files: &[PathBuf], tags: &TagCache, browser: &Browser, playing: Option<usize>, display: &DisplayOptions) -> io::Result<()> {
+    let (columns, rows) = terminal::size().map_or((80, 24), |(columns, rows)| (columns as usize, rows));
+    let height = list_height();
+    let width = columns.saturating_sub(1);
+    let mut stdout = io::stdout();
+
+    execute!(stdout, cursor::MoveTo(0, 0))?;
+    print!("{}", truncate(&browser.title(), width));
+    execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    let visible = browser.rows.visible(height);
+    for row in 0..height {
+        execute!(stdout, cursor::MoveTo(0, row as u16 + 1))?;
+        if let Some(&entry) = visible.get(row) {
+            let mark = if browser.showing_tracks() && playing == Some(entry) { '*' } else { ' ' };
+            let label = browser.label(entry, |track| display_name(music_files, tags, track));
+            let line = truncate(&format!("{} {}", mark, label), width);
+            if browser.rows.offset + row == browser.rows.cursor {
+                highlight(&mut stdout, display.theme.selection, display.theme.selection_text)?;
+                print!("{:width$}", line, width = width);
+                execute!(stdout, SetAttribute(Attribute::Reset))?;
+            } else {
+                print!("{}", line);
+            }
+        }
+        execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    }
+    let (page, pages) = browser.rows.page(height);
+    execute!(stdout, cursor::MoveTo(0, rows.saturating_sub(1)))?;
+    let footer = match display.notice() {
+        Some(notice) => notice,
+        None => format!(
+            "page {}/{}  Enter: {}  Backspace: back  Ctrl-b: all files  ?: help  q: quit",
+            page,
+            pages,
+            if browser.showing_tracks() { "play album" } else { "open" }
+        ),
+    };
+    print!("{}", truncate(&footer, width));
+    execute!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
+    stdout.flush()
+}
+
+/// The rows that keys and the mouse move through in `view`: the library's
+/// while it's open, the file list's otherwise.
+fn active_rows<'a>(view: View, list: &'a mut TrackList, browser: &'a mut Option<Browser>) -> &'a mut TrackList {
+    match (view, browser) {
+        (View::Library, Some(browser)) => &mut browser.rows,
+        _ => list,
+    }
+}
+
+/// The tracks of `order` that `filter` keeps, in the same order.
+fn filtered(music_files: &[PathBuf], tags: &TagCache, order: &Order, filter: &Filter) -> Order {
+    if filter.is_empty() {
+        return order.clone();
+    }
+    Order::new(order.tracks().iter().copied().filter(|&track| filter.matches(tags.get(track, &music_files[track]))).collect())
+}
+
+/// What the list shows of `order`, unless it's showing what's been played:
+/// the tracks the filter keeps, or just the favorites among them.
+fn listed(music_files: &[PathBuf], tags: &TagCache, order: &Order, display: &DisplayOptions) -> Order {
+    let shown = filtered(music_files, tags, order, &display.filter);
+    if display.listing != Listing::Favorites {
+        return shown;
+    }
+    Order::new(shown.tracks().iter().copied().filter(|&track| display.ratings.get(track).is_some_and(|rating| rating.favorite)).collect())
+}
+
+/// The tracks in `history` that are still in `music_files`, most recently
+/// played first, each only once. The history has full paths, so the files
+/// are matched by their path under the full path of the one of `roots` each
+/// came from.
+fn recently_played(history: &[history::Entry], roots: &[PathBuf], sources: &[usize], search_names: &[String]) -> Order {
+    let roots: Vec<PathBuf> = roots.iter().map(|root| fs::canonicalize(root).unwrap_or_else(|_| root.clone())).collect();
+    let tracks: HashMap<PathBuf, usize> =
+        search_names.iter().enumerate().map(|(track, name)| (roots[sources[track]].join(name), track)).collect();
+    let mut seen = HashSet::new();
+    Order::new(
+        history
+            .iter()
+            .rev()
+            .filter_map(|entry| tracks.get(Path::new(&entry.path)).copied())
+            .filter(|&track| seen.insert(track))
+            .collect(),
+    )
+}
+
+/// The tracks of `order` that have been played, going by `counts`, most
+/// played first.
+fn most_played(order: &Order, counts: &[u32]) -> Order {
+    let count = |track: usize| counts.get(track).copied().unwrap_or(0);
+    let mut tracks: Vec<usize> = order.tracks().iter().copied().filter(|&track| count(track) > 0).collect();
+    tracks.sort_by_key(|&track| Reverse(count(track)));
+    Order::new(tracks)
+}
+
+/// Read the play counts again if they're needed and a play has been counted
+/// since they were last read.
+fn refresh_play_counts(controls: &Controls, music_files: &[PathBuf], display: &mut DisplayOptions) {
+    let counted = controls.plays_counted.load(Ordering::SeqCst);
+    let needed = display.show_play_counts || display.listing == Listing::MostPlayed;
+    if !needed || display.play_counts_read == Some(counted) {
+        return;
+    }
+    if let Some(plays) = &controls.plays {
+        display.play_counts = plays.counts(music_files);
+    }
+    display.play_counts_read = Some(counted);
+}
+
+/// The rows of the track list: the tracks shown, narrowed further by the
+/// search if one is being typed.
+fn list_entries(shown: &Order, search: Option<&str>, search_names: &[String]) -> Vec<usize> {
+    match search {
+        Some(query) => tracklist::filter(search_names, shown.tracks(), query),
+        None => shown.tracks().to_vec(),
+    }
+}
+
+/// Say in the status file what's playing, if anything, with its title and
+/// artist from its tags, or its file name when it hasn't got a title.
+fn write_status(status_file: &mut StatusFile, controls: &Controls, music_files: &[PathBuf], tags: &TagCache) {
+    let (track, elapsed, total) = {
+        let status = controls.status.lock().unwrap();
+        (status.track, status.position, status.total)
+    };
+    let volume = controls.volume.load(Ordering::SeqCst);
+    let muted = controls.muted.load(Ordering::SeqCst);
+    let Some(index) = track.filter(|&index| index < music_files.len()) else {
+        status_file.update(None, volume, muted);
+        return;
+    };
+    let path = &music_files[index];
+    let found = tags.get(index, path);
+    let name = track_name(path);
+    let playing = status::Playing {
+        path,
+        title: Some(found.title.as_deref().unwrap_or(&name)),
+        artist: found.artist.as_deref(),
+        elapsed,
+        duration: (!total.is_zero()).then_some(total),
+        paused: controls.is_paused.load(Ordering::SeqCst),
+    };
+    status_file.update(Some(&playing), volume, muted);
+}
+
+/// Say in the now playing file what's playing, with what the tags don't
+/// say filled in, or nothing when nothing is.
+fn write_now_playing(now_playing_file: &mut NowPlayingFile, controls: &Controls, music_files: &[PathBuf], tags: &TagCache) {
+    let (track, elapsed, total) = {
+        let status = controls.status.lock().unwrap();
+        (status.track, status.position, status.total)
+    };
+    let Some(index) = track.filter(|&index| index < music_files.len()) else {
+        now_playing_file.update(String::new());
+        return;
+    };
+    let path = &music_files[index];
+    let found = tags.get(index, path);
+    let text = now_playing_file.template.render(|field| now_playing_field(field, path, found, elapsed, total));
+    now_playing_file.update(text);
+}
+
+/// What `field` says of the track at `path`, with `tags`, `elapsed` into it
+/// of `total`, which is zero when it's unknown. What the tags don't say is
+/// filled in, so the file never has a gap in it.
+fn now_playing_field(field: nowplaying::Field, path: &Path, tags: &Tags, elapsed: Duration, total: Duration) -> String {
+    match field {
+        nowplaying::Field::Artist => tags.artist.clone().unwrap_or_else(|| "Unknown artist".to_string()),
+        nowplaying::Field::Title => tags.title.clone().unwrap_or_else(|| track_name(path).into_owned()),
+        nowplaying::Field::Album => tags.album.clone().unwrap_or_else(|| "Unknown album".to_string()),
+        nowplaying::Field::Elapsed => format_time(elapsed.as_secs()),
+        nowplaying::Field::Duration if total.is_zero() => "--:--".to_string(),
+        nowplaying::Field::Duration => format_time(total.as_secs()),
+        nowplaying::Field::Filename => track_name(path).into_owned(),
+    }
+}
+
+/// Say what's taking a while on the bottom line, until the next redraw.
+fn show_busy(message: &str) -> io::Result<()> {
+    let rows = terminal::size().map_or(24, |(_, rows)| rows);
+    execute!(io::stdout(), cursor::MoveTo(0, rows.saturating_sub(1)), terminal::Clear(ClearType::CurrentLine))?;
+    print!("{}", message);
+    io::stdout().flush()
+}
+
+/// Keep the tags and lengths read of `music_files` in the index of the path
+/// each was found in, `sources` saying which, and write them, leaving out
+/// the tracks that have `vanished`.
+fn save_indexes(indexes: &mut [(usize, Index)], roots: &[PathBuf], music_files: &[PathBuf], sources: &[usize], vanished: &[bool], tags: &TagCache, lengths: &Lengths) {
+    for (source, index) in indexes {
+        let mut files = Vec::new();
+        for (track, file) in music_files.iter().enumerate().filter(|&(track, _)| sources[track] == *source && !vanished[track]) {
+            let Some(relative) = file.strip_prefix(&roots[*source]).ok().and_then(Path::to_str) else {
+                continue;
+            };
+            if let (Some(read), Some((size, modified))) = (tags.read_so_far(track), index::stamp(file)) {
+                index.set_tags(relative.to_string(), index::Stamped { size, modified, tags: read.clone() });
+            }
+            if let Some(length) = lengths.read_so_far(track) {
+                index.set_length(relative.to_string(), length);
+            }
+            files.push(relative.to_string());
+        }
+        let _ = index.save(&files);
+    }
+}
+
+/// Make `tracks` the queue and start playing it from `track`.
+fn start_queue(controls: &Controls, commands: &mpsc::Sender<PlayerCommand>, tracks: &[usize], track: usize) {
+    let queue = Queue::new(tracks.to_vec());
+    let position = queue.find(track).unwrap_or(0);
+    *controls.queue.lock().unwrap() = queue;
+    // Set before sending so the main loop doesn't send us straight back
+    controls.is_playing.store(true, Ordering::SeqCst);
+    controls.status.lock().unwrap().message = None;
+    let _ = commands.send(PlayerCommand::Play(position));
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().collect();
+    let command: Option<Command> = match args.get(1).map(String::as_str) {
+        Some("scan-gain") => Some(scan_gain_command),
+        Some("verify") => Some(verify_command),
+        Some("check") => Some(check_command),
+        Some("dupes") => Some(dupes_command),
+        Some("history") => Some(history_command),
+        Some("export-queue") => Some(export_queue_command),
+        Some("list") => Some(list_command),
+        Some("stats") => Some(stats_command),
+        Some("cover") => Some(cover_command),
+        _ => None,
+    };
+    if let Some(command) = command {
+        if let Err(message) = command(&args) {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
+
+    let config = match config::load(options.config.as_deref().map(Path::new)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("Error in config file: {}", message);
+            process::exit(1);
+        }
+    };
+    let settings = Settings::new(&options, &config);
+    if options.print_config {
+        for line in settings.config_lines(&config) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    let keymap = config.keymap;
+    for warning in keymap.conflicts() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    let exclude = match Exclude::new(&settings.exclude.0) {
+        Ok(mut exclude) => {
+            exclude.nomedia = settings.skip_nomedia.0;
+            exclude.hidden = !settings.hidden.0;
+            exclude.max_depth = options.max_depth;
+            exclude
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    };
+    // Without a path, look for an SD card or the like that's plugged in
+    let detected: Vec<String>;
+    let (paths, detected_note) = match &settings.paths {
+        Some((paths, _)) => (paths, None),
+        None => match pick_media(args.first().map(String::as_str).unwrap_or("sdsupreme"), &options.extensions) {
+            Some((path, note)) => {
+                detected = vec![path];
+                (&detected, note)
+            }
+            None => process::exit(1),
+        },
+    };
+    // A music file on its own plays straight away, by itself or with the
+    // ones after it in its directory, and so does a URL to stream
+    let url = match paths.as_slice() {
+        [path] if http::is_url(path) => Some(path),
+        _ => None,
+    };
+    let single_file = match paths.as_slice() {
+        [path] if url.is_some() || (Path::new(path).is_file() && !playlist::is_playlist(Path::new(path))) => Some(PathBuf::from(path)),
+        _ => None,
+    };
+    if options.and_following && (single_file.is_none() || url.is_some()) {
+        eprintln!("--and-following needs a single music file as the path.");
+        process::exit(1);
+    }
+    if let Some(file) = &single_file {
+        // A URL without an extension can still be played, going by what it sends
+        let extension = music_extension(url.map_or(file, |url| Path::new(http::file_part(url))));
+        let whitelisted = match extension {
+            Some(extension) => options.extensions.contains(extension),
+            None => url.is_some(),
+        };
+        if !whitelisted {
+            let mut extensions: Vec<&str> = options.extensions.iter().copied().collect();
+            extensions.sort_unstable();
+            eprintln!("{} isn't a music file that can be played; the extensions played are {}.", file.display(), extensions.join(", "));
+            process::exit(1);
+        }
+    }
+    let dir = single_file.as_deref().and_then(Path::parent).filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let Found { files: mut music_files, names, mut sources, roots, missing, too_deep, unreadable, indexes, playlist_order } = match &single_file {
+        // Nothing's kept by where it's found for a stream
+        Some(_) if url.is_some() => Found { files: paths.iter().map(PathBuf::from).collect(), names: vec![None], sources: vec![0], roots: vec![PathBuf::new()], missing: 0, too_deep: 0, unreadable: 0, indexes: Vec::new(), playlist_order: true },
+        Some(_) if options.and_following => {
+            // Only the files in the directory itself follow it, not those
+            // in directories inside it
+            let files: Vec<PathBuf> = list_music_files(dir, &options.extensions, &exclude, settings.follow_symlinks.0, None, None).files.into_iter().filter(|file| file.parent() == Some(dir)).collect();
+            Found { names: vec![None; files.len()], sources: vec![0; files.len()], files, roots: vec![dir.to_path_buf()], missing: 0, too_deep: 0, unreadable: 0, indexes: Vec::new(), playlist_order: false }
+        }
+        _ => find_music(paths, &options.extensions, &exclude, settings.follow_symlinks.0, config::cache_dir().as_deref(), options.rescan),
+    };
+    if roots.is_empty() {
+        eprintln!("None of the provided paths exist.");
+        process::exit(1);
+    }
+    if music_files.is_empty() {
+        match unreadable {
+            0 => println!("No music files found in the provided paths."),
+            _ => println!("No music files found in the provided paths, though {}.", unreadable_note(unreadable)),
+        }
+        return Ok(());
+    }
+
+    let indexed = music_files
+        .iter()
+        .zip(&sources)
+        .map(|(file, source)| {
+            let (_, index) = indexes.iter().find(|(indexed, _)| indexed == source)?;
+            index.tags(file.strip_prefix(&roots[*source]).ok()?.to_str()?).cloned()
+        })
+        .collect();
+    let mut tags = TagCache::new(names, indexed);
+    let indexed = music_files
+        .iter()
+        .zip(&sources)
+        .map(|(file, source)| {
+            let (_, index) = indexes.iter().find(|(indexed, _)| indexed == source)?;
+            index.length(file.strip_prefix(&roots[*source]).ok()?.to_str()?).cloned()
+        })
+        .collect();
+    let lengths = Lengths::new(&music_files, indexed);
+    // Shared with the watcher, which keeps them up to date
+    let indexes = Arc::new(Mutex::new(indexes));
+    // Tracks whose files have gone since they were found. They keep their
+    // place, so every other track is still the same one, but aren't listed.
+    let mut vanished = vec![false; music_files.len()];
+    // Every track in the list's order, and the tracks the filter keeps in
+    // that order, which is what a queue started from the list plays
+    let mut order = if playlist_order { Order::new((0..music_files.len()).collect()) } else { Order::sorted(&music_files, &tags, settings.sort.0) };
+    let mut shown = order.clone();
+    let mut list = TrackList::new(shown.tracks().to_vec());
+    let retry = Retry::new(settings.read_retries.0, Duration::from_millis(settings.retry_delay_ms.0 as u64));
+    // With nowhere to copy them, tracks are played from the card after all
+    let local_cache = settings.local_cache.0.as_ref().and_then(|parent| match LocalCache::new(parent, settings.local_cache_size.0 * 1024 * 1024, retry.clone()) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            eprintln!("Warning: can't make the local cache in {}, so tracks are played from where they are: {}", parent.display(), e);
+            None
+        }
+    });
+    let controls = Arc::new(Controls {
+        is_paused: AtomicBool::new(false),
+        shutdown: AtomicBool::new(false),
+        is_playing: AtomicBool::new(false),
+        shuffle: AtomicBool::new(settings.shuffle.0),
+        repeat: AtomicU8::new(RepeatMode::Off as u8),
+        volume: AtomicU32::new(settings.volume.0),
+        muted: AtomicBool::new(false),
+        replaygain: ReplayGain {
+            mode: settings.replaygain.0,
+            preamp: settings.replaygain_preamp.0,
+            fallback: settings.replaygain_fallback.0,
+        },
+        track_gain: AtomicU32::new(1.0f32.to_bits()),
+        fade: Duration::from_millis(settings.fade_ms.0 as u64),
+        fade_level: AtomicU32::new(1.0f32.to_bits()),
+        speed: Arc::new(AtomicU32::new(100)),
+        keep_speed: settings.keep_speed.0,
+        prefetch_limit: settings.prefetch.0.then_some(settings.prefetch_limit.0 * 1024 * 1024),
+        retry,
+        local_cache,
+        effects: Arc::new(dsp::Effects {
+            mono: AtomicBool::new(settings.mono.0),
+            balance: AtomicI32::new(settings.balance.0),
+            skip_silence: settings.skip_silence.0.then(|| dsp::Silence::new(settings.silence_threshold.0, settings.silence_min.0)),
+            ..Default::default()
+        }),
+        tap: Arc::default(),
+        ab_loop: Arc::default(),
+        sleep_at: Mutex::new(options.sleep.map(|sleep| Instant::now() + sleep)),
+        positions: Positions::new(),
+        resume: settings.resume.0,
+        history: history::path(),
+        plays: PlayCounts::new(&roots),
+        plays_counted: AtomicU32::new(0),
+        ratings: Ratings::new(&roots),
+        write_tags: settings.write_tags.0,
+        queue: Mutex::new(Queue::new(order.tracks().to_vec())),
+        files: Mutex::new(music_files.clone()),
+        status: Mutex::new(PlayerStatus::default()),
+    });
+    let (_stream, stream_handle) = OutputStream::try_default().map_err(io::Error::other)?;
+    let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle).map_err(io::Error::other)?));
+    let (command_tx, command_rx) = mpsc::channel();
+    let (event_tx, events) = mpsc::channel();
+
+    // Set up Ctrl+C handler
+    {
+        let command_tx = command_tx.clone();
+        ctrlc::set_handler(move || {
+            let _ = command_tx.send(PlayerCommand::TogglePause);
+        }).expect("Error setting Ctrl-C handler");
+    }
+
+    let sink_clone = Arc::clone(&sink);
+    let controls_clone = Arc::clone(&controls);
+
+    let player = thread::spawn(move || {
+        let playback = Playback {
+            controls: &controls_clone,
+            commands: &command_rx,
+            sink: &sink_clone,
+            stream_handle: &stream_handle,
+            events: &event_tx,
+            failure: Failure::default(),
+        };
+        run_player(playback);
+    });
+
+    // Terminal setup for UI
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    terminal::enable_raw_mode()?;
+    execute!(io::stdout(), cursor::Hide, EnableMouseCapture)?;
+
+    let mut display = DisplayOptions {
+        bar_style: settings.bar_style.0,
+        theme: settings.theme.0,
+        sort: (!playlist_order).then_some(settings.sort.0),
+        filter: Filter::default(),
+        listing: Listing::All,
+        show_play_counts: settings.play_counts.0,
+        show_size: false,
+        show_date: false,
+        play_counts: Vec::new(),
+        play_counts_read: None,
+        ratings: match &controls.ratings {
+            Some(ratings) => ratings.all(&music_files),
+            None => vec![Rating::default(); music_files.len()],
+        },
+        lengths,
+        show_remaining: false,
+        show_lyrics: true,
+        show_spectrum: true,
+        show_meter: false,
+        sleep_prompt: None,
+        export_prompt: None,
+        bookmark_cursor: None,
+        queue_cursor: None,
+        // The warnings are gone under the player, but still there when it quits
+        notice: (missing > 0)
+            .then(|| format!("Left out {} playlist {} that aren't there", missing, if missing == 1 { "track" } else { "tracks" }))
+            .or_else(|| (unreadable > 0).then(|| unreadable_note(unreadable)))
+            .or(detected_note)
+            .map(|notice| (notice, Instant::now())),
+        places: if roots.len() > 1 { place_names(&roots) } else { Vec::new() },
+        sources: sources.clone(),
+        roots: roots.clone(),
+        too_deep,
+        vanished: vec![false; music_files.len()],
+        unreadable: None,
+    };
+    // When the file that couldn't be read was last looked at
+    let mut unreadable_checked = Instant::now();
+    let mut view = View::List;
+    if let Some(file) = &single_file {
+        let full = |file: &Path| fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+        let file = full(file);
+        if let Some(track) = music_files.iter().position(|found| full(found) == file) {
+            let start = order.position(track).unwrap_or(0);
+            start_queue(&controls, &command_tx, &order.tracks()[start..], track);
+            view = View::Playing;
+        }
+    }
+    // Where leaving the playing view goes back to, the file list or the library
+    let mut list_view = View::List;
+    // Built the first time the library is opened
+    let mut browser: Option<Browser> = None;
+    let mut cover = match (settings.cover_size.0, cover::detect()) {
+        (0, _) | (_, None) => None,
+        (columns, Some(protocol)) => Some(CoverArt::new(protocol, columns)),
+    };
+    let mut lyrics = Sidecar::default();
+    let mut visuals = Visuals::default();
+    // The first key of a possible two-key binding, and when it was pressed
+    let mut pending_key: Option<(char, Instant)> = None;
+    let mut help_open = false;
+    // The search being typed, while the list is filtered
+    let mut search: Option<String> = None;
+    // The tag filter being typed, and what was wrong with it when last applied
+    let mut filter_prompt: Option<(String, Option<String>)> = None;
+    // Search within the library rather than the path leading to it, which
+    // every file from the same place shares
+    let mut search_names: Vec<String> = music_files
+        .iter()
+        .zip(&sources)
+        .map(|(file, &source)| file.strip_prefix(&roots[source]).unwrap_or(file).to_string_lossy().into_owned())
+        .collect();
+    // Jumping by letter goes by file name; the directories all start the same
+    let mut file_names: Vec<String> = music_files.iter().map(|file| track_name(file).to_string()).collect();
+    // Directories looked through are watched for music being added or
+    // taken away, and the list kept up to date
+    let walked = !indexes.lock().unwrap().is_empty();
+    let watcher = walked.then(|| Watcher::start(&roots, Arc::clone(&indexes), options.extensions.clone(), exclude.clone(), settings.follow_symlinks.0));
+    // Whether the library's been built from a list that's changed since
+    let mut library_changed = false;
+    let mut status_file = settings.status_file.0.clone().map(|path| StatusFile::new(path, Duration::from_secs(u64::from(settings.status_interval.0))));
+    let mut now_playing_file = settings.now_playing_file.0.clone().map(|path| NowPlayingFile::new(path, settings.now_playing_format.0.clone()));
+    #[cfg(feature = "notify")]
+    let mut notifier = settings.notify.0.then(Notifier::default);
+    execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+
+    // Handle key events for picking a track, controlling playback and exiting
+    loop {
+        if player.is_finished() {
+            break;
+        }
+        let sleep_at = *controls.sleep_at.lock().unwrap();
+        if let Some(deadline) = sleep_at {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            // The fade is worked out from the time left
+            if left <= sleep::FADE {
+                sink.lock().unwrap().set_volume(controls.sink_volume());
+            }
+        }
+        // Back to the list once the queue stops, whether it ran out or was stopped
+        if let Some(failed) = controls.status.lock().unwrap().failed.take() {
+            display.notice = Some((failed, Instant::now()));
+        }
+        if let Some(status_file) = status_file.as_mut() {
+            write_status(status_file, &controls, &music_files, &tags);
+        }
+        if let Some(now_playing_file) = now_playing_file.as_mut() {
+            write_now_playing(now_playing_file, &controls, &music_files, &tags);
+        }
+        #[cfg(feature = "notify")]
+        if let Some(notifier) = notifier.as_mut() {
+            let track = controls.status.lock().unwrap().track;
+            if let Some(index) = notifier.playing(track).filter(|&index| index < music_files.len()) {
+                let path = &music_files[index];
+                let found = tags.get(index, path);
+                notify::track_started(path.clone(), found.title.clone().unwrap_or_else(|| track_name(path).into_owned()), found.artist.clone());
+            }
+        }
+        if let Ok(PlayerEvent::Unreadable(path)) = events.try_recv() {
+            display.unreadable = Some((path, false));
+        }
+        // Once a second, so a card that's stopped answering can't hold up the UI
+        if let Some((path, back)) = display.unreadable.as_mut().filter(|_| unreadable_checked.elapsed() >= Duration::from_secs(1)) {
+            *back = !reader::unreadable(path);
+            unreadable_checked = Instant::now();
+        }
+        if let Some(found) = watcher.as_ref().and_then(Watcher::rescanning) {
+            display.notice = Some((format!("Looking for music again: {} found so far...", found), Instant::now()));
+        }
+        while let Some(change) = watcher.as_ref().and_then(|watcher| watcher.changes.try_recv().ok()) {
+            let (source, files, asked) = match change {
+                Change::Found { source, files, asked } => (source, files, asked),
+                // Its tracks stay listed, in case it's only been unmounted for
+                // a moment, and any that are played will say they're not there
+                Change::Gone { source } => {
+                    display.notice = Some((format!("{} can't be read any more; has the card been taken out?", roots[source].display()), Instant::now()));
+                    continue;
+                }
+            };
+            let (mut added, mut gone) = (0, 0);
+            let found: HashSet<&PathBuf> = files.iter().collect();
+            for track in (0..music_files.len()).filter(|&track| sources[track] == source) {
+                let now_vanished = !found.contains(&music_files[track]);
+                match (vanished[track], now_vanished) {
+                    (false, true) => gone += 1,
+                    (true, false) => added += 1,
+                    _ => {}
+                }
+                vanished[track] = now_vanished;
+            }
+            let known: HashSet<PathBuf> = music_files.iter().zip(&sources).filter(|(_, &from)| from == source).map(|(file, _)| file.clone()).collect();
+            for file in files.into_iter().filter(|file| !known.contains(file)) {
+                search_names.push(file.strip_prefix(&roots[source]).unwrap_or(&file).to_string_lossy().into_owned());
+                file_names.push(track_name(&file).to_string());
+                tags.push(None);
+                display.lengths.push(file.clone());
+                sources.push(source);
+                display.sources.push(source);
+                vanished.push(false);
+                controls.files.lock().unwrap().push(file.clone());
+                music_files.push(file);
+                added += 1;
+            }
+            if added == 0 && gone == 0 {
+                if asked {
+                    display.notice = Some(("Nothing's changed".to_string(), Instant::now()));
+                }
+                continue;
+            }
+            display.ratings = match &controls.ratings {
+                Some(ratings) => ratings.all(&music_files),
+                None => vec![Rating::default(); music_files.len()],
+            };
+            display.vanished.clone_from(&vanished);
+            display.play_counts_read = None;
+            refresh_play_counts(&controls, &music_files, &mut display);
+            order = match display.sort {
+                Some(sort) => Order::sorted(&music_files, &tags, sort).retain(|track| !vanished[track]),
+                None => order.retain(|track| !vanished[track]),
+            };
+            let listing = match display.listing {
+                Listing::All | Listing::Favorites => listed(&music_files, &tags, &order, &display),
+                Listing::Recent | Listing::MostPlayed => shown.retain(|track| !vanished[track]),
+            };
+            let previous = mem::replace(&mut shown, listing);
+            // The cursor stays on the same file, or where it was if that's gone
+            let (selected, cursor) = (list.selected(), list.cursor);
+            list.set_entries(list_entries(&shown, search.as_deref(), &search_names));
+            match selected {
+                Some(track) if !vanished[track] => list.select_track(track, list_height()),
+                _ => list.select(cursor, list_height()),
+            }
+            // A queue started from the list follows it, as with sorting,
+            // unless what's playing has gone from it
+            let playing = controls.status.lock().unwrap().track;
+            let mut queue = controls.queue.lock().unwrap();
+            if queue.tracks() == previous.tracks() && playing.is_none_or(|track| !vanished[track]) {
+                *queue = Queue::new(shown.tracks().to_vec());
+                if let Some(position) = playing.and_then(|track| shown.position(track)) {
+                    queue.play(position);
+                }
+            }
+            // The library's built again when it's next opened
+            if list_view == View::Library {
+                library_changed = true;
+            } else {
+                browser = None;
+            }
+            display.notice = Some((changed_note(added, gone), Instant::now()));
+        }
+        if matches!(view, View::Playing) && !controls.is_playing.load(Ordering::SeqCst) {
+            view = list_view;
+            execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+        }
+        // Only the playing view shows the art, and the help overlay hides it
+        if help_open || view != View::Playing {
+            if let Some(cover) = cover.as_mut() {
+                cover.hide()?;
+            }
+        }
+        match view {
+            // The view stays as it was under the overlay
+            _ if help_open => draw_help(&keymap.help_lines())?,
+            View::List => {
+                refresh_play_counts(&controls, &music_files, &mut display);
+                let playing = controls.status.lock().unwrap().track;
+                let prompt = match (&filter_prompt, &search) {
+                    (Some((text, error)), _) => Some(Prompt::Filter(text, error.as_deref())),
+                    (None, Some(query)) => Some(Prompt::Search(query)),
+                    (None, None) => None,
+                };
+                display.lengths.show_first(list.visible(list_height()));
+                draw_file_list(&music_files, &tags, &shown, &list, playing, prompt, &display)?
+            }
+            View::Library => {
+                let playing = controls.status.lock().unwrap().track;
+                if let Some(browser) = &browser {
+                    draw_library(&music_files, &tags, browser, playing, &display)?
+                }
+            }
+            View::Playing => {
+                let art = match cover.as_mut() {
+                    Some(cover) => cover.size(controls.status.lock().unwrap().track, &music_files)?,
+                    None => None,
+                };
+                let track = controls.status.lock().unwrap().track;
+                let lyrics = if display.show_lyrics { lyrics.get(track, &music_files) } else { None };
+                let art_at = draw_playing(&music_files, &tags, &controls, &display, art, lyrics, &mut visuals)?;
+                if let Some(cover) = cover.as_mut() {
+                    cover.draw(art_at)?;
+                }
+            }
+        }
+        if !event::poll(Duration::from_millis(100))? {
+            continue;
+        }
+        let key_event = match event::read()? {
+            event::Event::Key(key_event) => key_event,
+            // The wheel scrolls the list, leaving the cursor where it is, and
+            // changes the volume while playing
+            event::Event::Mouse(mouse) if matches!(mouse.kind, MouseEventKind::ScrollUp | MouseEventKind::ScrollDown) => {
+                let up = mouse.kind == MouseEventKind::ScrollUp;
+                match view {
+                    View::List | View::Library => {
+                        active_rows(view, &mut list, &mut browser).scroll_by(if up { -WHEEL_ROWS } else { WHEEL_ROWS }, list_height())
+                    }
+                    View::Playing => change_volume(&controls, &sink, up),
+                }
+                continue;
+            }
+            event::Event::Mouse(mouse) if mouse.kind == MouseEventKind::Down(MouseButton::Left) => match view {
+                View::List | View::Library => {
+                    let height = list_height();
+                    let rows = active_rows(view, &mut list, &mut browser);
+                    // Entries start on the row below the header
+                    let row = (mouse.row as usize)
+                        .checked_sub(1)
+                        .filter(|&row| row < rows.visible(height).len())
+                        .map(|row| rows.offset + row);
+                    match row {
+                        // Clicking the highlighted entry plays it, just like Enter
+                        Some(row) if row == rows.cursor => KeyEvent::from(KeyCode::Enter),
+                        Some(row) => {
+                            rows.select(row, height);
+                            continue;
+                        }
+                        None => continue,
+                    }
+                }
+                View::Playing => {
+                    if mouse.row == PROGRESS_ROW {
+                        let columns = terminal::size().map_or(80, |(columns, _)| columns as usize);
+                        let status = controls.status.lock().unwrap().clone();
+                        if let Some(fraction) = bar_click_fraction(&status, &display, columns, mouse.column as usize) {
+                            let _ = command_tx.send(PlayerCommand::SeekFraction(fraction));
+                        }
+                    }
+                    continue;
+                }
+            },
+            // A shorter terminal can leave the cursor below the viewport;
+            // everything else is picked up by the redraw at the top of the loop
+            event::Event::Resize(_, _) => {
+                active_rows(view, &mut list, &mut browser).scroll_to_cursor(list_height());
+                // The art moves with the corner, so it's drawn again from scratch
+                if let Some(cover) = cover.as_mut() {
+                    cover.hide()?;
+                    execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        // Any key closes the help overlay, and does nothing else
+        if help_open {
+            help_open = false;
+            execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+            continue;
+        }
+        let height = list_height();
+        if let (View::Playing, Some(cursor)) = (view, display.bookmark_cursor.as_mut()) {
+            // The list takes every key until it's closed
+            let count = controls.status.lock().unwrap().bookmarks.len();
+            // Deleting the last one, or the track changing, can leave it past the end
+            *cursor = (*cursor).min(count.saturating_sub(1));
+            match key_event.code {
+                KeyCode::Up | KeyCode::Char('k') => *cursor = cursor.saturating_sub(1),
+                KeyCode::Down | KeyCode::Char('j') => *cursor = (*cursor + 1).min(count.saturating_sub(1)),
+                KeyCode::Enter if *cursor < count => {
+                    let _ = command_tx.send(PlayerCommand::JumpToBookmark(*cursor));
+                    display.bookmark_cursor = None;
+                }
+                KeyCode::Char('d') | KeyCode::Delete if *cursor < count => {
+                    let _ = command_tx.send(PlayerCommand::DeleteBookmark(*cursor));
+                }
+                KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('\'') => display.bookmark_cursor = None,
+                _ => {}
+            }
+            continue;
+        }
+        if let (View::Playing, Some(cursor)) = (view, display.queue_cursor.as_mut()) {
+            // Like the bookmarks, the queue takes every key until it's
+            // closed. Edits are made under the lock, so the playback thread
+            // always goes on to what the queue says at the time.
+            let mut queue = controls.queue.lock().unwrap();
+            // Tracks ending, or the last entry going, can leave it out of range
+            *cursor = (*cursor).clamp(queue.first_shown(), queue.len().saturating_sub(1));
+            let upcoming = queue.upcoming();
+            match key_event.code {
+                KeyCode::Up | KeyCode::Char('k') => *cursor = cursor.saturating_sub(1).max(queue.first_shown()),
+                KeyCode::Down | KeyCode::Char('j') => *cursor = (*cursor + 1).min(queue.len().saturating_sub(1)),
+                KeyCode::Enter if *cursor < queue.len() => {
+                    let _ = command_tx.send(PlayerCommand::Play(*cursor));
+                    display.queue_cursor = None;
+                }
+                KeyCode::Char('d') | KeyCode::Delete => {
+                    let was_playing = queue.remove(*cursor);
+                    // Taking out the entry playing skips to the one after it
+                    if was_playing {
+                        let _ = command_tx.send(PlayerCommand::Next);
+                    }
+                }
+                // Only what's to come moves, so it never goes above the entry playing
+                KeyCode::Char('J') if *cursor >= upcoming => {
+                    if let Some(moved) = queue.shift(*cursor, true) {
+                        *cursor = moved;
+                    }
+                }
+                KeyCode::Char('K') if *cursor > upcoming => {
+                    if let Some(moved) = queue.shift(*cursor, false) {
+                        *cursor = moved;
+                    }
+                }
+                KeyCode::Char('c') => queue.clear_upcoming(),
+                KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('u') => display.queue_cursor = None,
+                _ => {}
+            }
+            continue;
+        }
+        if let (View::Playing, Some((text, error))) = (view, display.sleep_prompt.as_mut()) {
+            // Like the filter prompt, this takes every key until it's done
+            match key_event.code {
+                KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Esc => display.sleep_prompt = None,
+                KeyCode::Enter => match sleep::parse_change(text) {
+                    Ok(change) => {
+                        let deadline = {
+                            let mut sleep_at = controls.sleep_at.lock().unwrap();
+                            *sleep_at = sleep::apply(*sleep_at, change);
+                            *sleep_at
+                        };
+                        // Cancelling mid-fade brings the volume straight back
+                        sink.lock().unwrap().set_volume(controls.sink_volume());
+                        controls.set_message(match deadline {
+                            Some(deadline) => format!("Sleeping in {}", format_time(deadline.saturating_duration_since(Instant::now()).as_secs())),
+                            None => "Sleep timer off".to_string(),
+                        });
+                        display.sleep_prompt = None;
+                    }
+                    Err(message) => {
+                        *error = Some(message);
+                        continue;
+                    }
+                },
+                _ => {}
+            }
+            if let Some((_, error)) = display.sleep_prompt.as_mut() {
+                *error = None;
+            }
+            continue;
+        }
+        if let (View::Playing, Some((text, error))) = (view, display.export_prompt.as_mut()) {
+            match key_event.code {
+                KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Esc => display.export_prompt = None,
+                KeyCode::Enter if text.trim().is_empty() => {
+                    *error = Some("type a file name".to_string());
+                    continue;
+                }
+                KeyCode::Enter => {
+                    let target = PathBuf::from(text.trim());
+                    let files = queue_files(&controls, &music_files);
+                    // Every track's length is read from its file
+                    show_busy("Writing playlist...")?;
+                    // An existing playlist is never replaced from here; that
+                    // takes `export-queue --force`
+                    match export_playlist(&files, &target, false) {
+                        Ok(()) => {
+                            controls.set_message(format!("Wrote {} tracks to {}", files.len(), target.display()));
+                            display.export_prompt = None;
+                        }
+                        Err(e) => {
+                            *error = Some(match e.kind() {
+                                io::ErrorKind::AlreadyExists => "there already; pick another name".to_string(),
+                                _ => e.to_string(),
+                            });
+                            continue;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if let Some((_, error)) = display.export_prompt.as_mut() {
+                *error = None;
+            }
+            continue;
+        }
+        if let (View::List, Some((text, error))) = (view, filter_prompt.as_mut()) {
+            // The prompt takes every key until it's applied or cancelled
+            match key_event.code {
+                KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => text.push(c),
+                KeyCode::Backspace => {
+                    text.pop();
+                }
+                KeyCode::Esc => filter_prompt = None,
+                KeyCode::Enter => match filter::parse(text) {
+                    Ok(filter) => {
+                        if !filter.is_empty() {
+                            show_busy("Reading tags...")?;
+                        }
+                        display.filter = filter;
+                        if display.listing != Listing::Favorites {
+                            display.listing = Listing::All;
+                        }
+                        filter_prompt = None;
+                        shown = listed(&music_files, &tags, &order, &display);
+                        let selected = list.selected();
+                        list.set_entries(list_entries(&shown, search.as_deref(), &search_names));
+                        if let Some(track) = selected {
+                            list.select_track(track, height);
+                        }
+                    }
+                    Err(message) => {
+                        *error = Some(message);
+                        continue;
+                    }
+                },
+                _ => {}
+            }
+            if let Some((_, error)) = filter_prompt.as_mut() {
+                // Typing again hides the last complaint
+                *error = None;
+            }
+            continue;
+        }
+        if let (View::List, Some(query)) = (view, search.as_mut()) {
+            // Typing edits the search; navigation and Enter work as usual
+            let edited = match key_event.code {
+                KeyCode::Char(c) if !key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                    query.push(c);
+                    true
+                }
+                KeyCode::Backspace => query.pop().is_some(),
+                KeyCode::Esc => {
+                    search = None;
+                    list.set_entries(shown.tracks().to_vec());
+                    continue;
+                }
+                _ => false,
+            };
+            if edited {
+                list.set_entries(tracklist::filter(&search_names, shown.tracks(), query));
+                continue;
+            }
+        }
+
+        // A key counts towards a two-key binding like `gg` only if it follows
+        // the first press closely enough
+        let previous = pending_key.take().filter(|(_, since)| since.elapsed() < PENDING_KEY_TIMEOUT).map(|(c, _)| c);
+        let action = keymap.lookup(view, &key_event, previous);
+        if let (None, KeyCode::Char(c)) = (action, key_event.code) {
+            if keymap.starts_twice(view, c) {
+                pending_key = Some((c, Instant::now()));
+            }
+        }
+        let Some(action) = action else {
+            continue;
+        };
+        // Queueing only means something while playing; otherwise the letters
+        // jump as usual
+        let action = match action {
+            Action::Enqueue(_) if !controls.is_playing.load(Ordering::SeqCst) => Action::JumpToInitial,
+            action => action,
+        };
+        let rows = active_rows(view, &mut list, &mut browser);
+        match action {
+            Action::MoveUp => rows.move_by(-1, height),
+            Action::MoveDown => rows.move_by(1, height),
+            Action::HalfPageUp => rows.move_by(-(height as isize / 2), height),
+            Action::HalfPageDown => rows.move_by(height as isize / 2, height),
+            Action::PageUp => rows.turn_page(-1, height),
+            Action::PageDown => rows.turn_page(1, height),
+            Action::Top => rows.select(0, height),
+            Action::Bottom => rows.select(usize::MAX, height),
+            Action::Search => search = Some(String::new()),
+            // Start from the filter that's applied, to change it or clear it
+            Action::Filter => filter_prompt = Some((display.filter.text().to_string(), None)),
+            Action::JumpToInitial => {
+                if let KeyCode::Char(c) = key_event.code {
+                    match (view, browser.as_mut()) {
+                        (View::Library, Some(browser)) => browser.jump_to_initial(c, height),
+                        _ => list.jump_to_initial(c, &file_names, height),
+                    }
+                }
+            }
+            Action::Enqueue(next) => {
+                let selected = match (view, browser.as_ref()) {
+                    (View::Library, Some(browser)) => browser.selected_track(),
+                    _ => list.selected(),
+                };
+                let Some(track) = selected else {
+                    continue;
+                };
+                // The track playing carries on; the queue is read again when it ends
+                {
+                    let mut queue = controls.queue.lock().unwrap();
+                    if next {
+                        queue.insert_next(track);
+                    } else {
+                        queue.push(track);
+                    }
+                }
+                let name = display_name(&music_files, &tags, track);
+                display.notice = Some((if next { format!("Playing next: {}", name) } else { format!("Queued: {}", name) }, Instant::now()));
+            }
+            Action::CycleColumns => {
+                (display.show_size, display.show_date) = match (display.show_size, display.show_date) {
+                    (false, false) => (true, false),
+                    (true, false) => (false, true),
+                    (false, true) => (true, true),
+                    (true, true) => (false, false),
+                };
+            }
+            Action::CycleSort => {
+                // Out of a playlist's order, the sort starts from the one set
+                let sort = display.sort.map_or(settings.sort.0, SortKey::next);
+                display.sort = Some(sort);
+                if display.listing != Listing::Favorites {
+                    display.listing = Listing::All;
+                }
+                order = Order::sorted(&music_files, &tags, sort).retain(|track| !vanished[track]);
+                let previous = mem::replace(&mut shown, listed(&music_files, &tags, &order, &display));
+                // The cursor stays on the same file, wherever it's moved to
+                let selected = list.selected();
+                list.set_entries(list_entries(&shown, search.as_deref(), &search_names));
+                if let Some(track) = selected {
+                    list.select_track(track, height);
+                }
+                // A queue started from the list follows it; an album, or a
+                // queue that's been edited, keeps its own order
+                let playing = controls.status.lock().unwrap().track;
+                let mut queue = controls.queue.lock().unwrap();
+                if queue.tracks() == previous.tracks() {
+                    *queue = Queue::new(shown.tracks().to_vec());
+                    if let Some(position) = playing.and_then(|track| shown.position(track)) {
+                        queue.play(position);
+                    }
+                }
+            }
+            Action::Play => {
+                let Some(track) = list.selected() else {
+                    continue;
+                };
+                // Leave the filtered view, keeping the cursor on what was picked
+                if search.take().is_some() {
+                    list.set_entries(shown.tracks().to_vec());
+                    list.select_track(track, height);
+                }
+                display.unreadable = None;
+                start_queue(&controls, &command_tx, shown.tracks(), track);
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+                view = View::Playing;
+            }
+            Action::ShowRecent | Action::ShowMostPlayed | Action::ShowFavorites => {
+                let listing = match action {
+                    Action::ShowRecent => Listing::Recent,
+                    Action::ShowMostPlayed => Listing::MostPlayed,
+                    _ => Listing::Favorites,
+                };
+                display.listing = if display.listing == listing { Listing::All } else { listing };
+                refresh_play_counts(&controls, &music_files, &mut display);
+                shown = match (display.listing, &controls.history) {
+                    (Listing::All | Listing::Favorites, _) => listed(&music_files, &tags, &order, &display),
+                    (Listing::Recent, Some(path)) => recently_played(&history::read(path), &roots, &sources, &search_names),
+                    (Listing::Recent, None) => Order::new(Vec::new()),
+                    (Listing::MostPlayed, _) => most_played(&order, &display.play_counts),
+                };
+                search = None;
+                list.set_entries(shown.tracks().to_vec());
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+            }
+            Action::Refresh => {
+                display.unreadable = None;
+                let notice = match &watcher {
+                    Some(watcher) => {
+                        watcher.refresh();
+                        "Looking through everything again..."
+                    }
+                    None => "Only directories are looked through again, not playlists or files played on their own",
+                };
+                display.notice = Some((notice.to_string(), Instant::now()));
+            }
+            Action::ForgetIndex => {
+                let mut indexes = indexes.lock().unwrap();
+                let forgotten = indexes.iter_mut().map(|(_, index)| index.forget()).collect::<io::Result<Vec<()>>>();
+                let notice = match forgotten {
+                    _ if indexes.is_empty() => "There's no index for what's listed".to_string(),
+                    Ok(_) => "Forgot the index; everything's looked through again next time".to_string(),
+                    Err(e) => format!("Couldn't forget the index: {}", e),
+                };
+                display.notice = Some((notice, Instant::now()));
+            }
+            Action::ShowLibrary => {
+                if browser.is_none() || library_changed {
+                    // Reading every file's tags can take a while on a big card
+                    show_busy("Reading tags...")?;
+                    browser = Some(Browser::new(library::build(&music_files, &tags, order.tracks())));
+                    library_changed = false;
+                }
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+                view = View::Library;
+                list_view = View::Library;
+            }
+            Action::ShowFiles => {
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+                view = View::List;
+                list_view = View::List;
+            }
+            Action::Open => {
+                let Some(browser) = browser.as_mut() else {
+                    continue;
+                };
+                if let Some((tracks, track)) = browser.open() {
+                    display.unreadable = None;
+                    start_queue(&controls, &command_tx, &tracks, track);
+                    view = View::Playing;
+                }
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+            }
+            Action::Back => {
+                if !browser.as_mut().is_some_and(Browser::back) {
+                    view = View::List;
+                    list_view = View::List;
+                }
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+            }
+            Action::ShowPlaying => {
+                if controls.is_playing.load(Ordering::SeqCst) {
+                    execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+                    view = View::Playing;
+                }
+            }
+            Action::TogglePause => {
+                let _ = command_tx.send(PlayerCommand::TogglePause);
+            }
+            Action::Next => {
+                let _ = command_tx.send(PlayerCommand::Next);
+            }
+            Action::Previous => {
+                let _ = command_tx.send(PlayerCommand::Previous);
+            }
+            Action::Restart => {
+                let _ = command_tx.send(PlayerCommand::Restart);
+            }
+            Action::SeekToDigit => {
+                // Only bound to digits
+                if let KeyCode::Char(c) = key_event.code {
+                    let tenths = c.to_digit(10).unwrap_or(0);
+                    let _ = command_tx.send(PlayerCommand::SeekFraction(tenths as f64 / 10.0));
+                }
+            }
+            Action::Seek(seconds) => {
+                let _ = command_tx.send(PlayerCommand::Seek(seconds));
+            }
+            Action::Faster => {
+                let _ = command_tx.send(PlayerCommand::ChangeSpeed(true));
+            }
+            Action::Slower => {
+                let _ = command_tx.send(PlayerCommand::ChangeSpeed(false));
+            }
+            Action::LoopStart => {
+                let _ = command_tx.send(PlayerCommand::LoopStart);
+            }
+            Action::LoopEnd => {
+                let _ = command_tx.send(PlayerCommand::LoopEnd);
+            }
+            Action::ClearLoop => {
+                let _ = command_tx.send(PlayerCommand::ClearLoop);
+            }
+            Action::ResumeSaved => {
+                let _ = command_tx.send(PlayerCommand::ResumeSaved);
+            }
+            Action::Bookmark => {
+                let _ = command_tx.send(PlayerCommand::Bookmark);
+            }
+            Action::NextBookmark => {
+                let _ = command_tx.send(PlayerCommand::NextBookmark);
+            }
+            Action::PreviousBookmark => {
+                let _ = command_tx.send(PlayerCommand::PreviousBookmark);
+            }
+            // Only one list shows at a time
+            Action::ShowBookmarks => {
+                display.queue_cursor = None;
+                display.bookmark_cursor = Some(0);
+            }
+            Action::ShowQueue => {
+                display.bookmark_cursor = None;
+                display.queue_cursor = Some(controls.queue.lock().unwrap().upcoming());
+            }
+            Action::ToggleFavorite | Action::Rate(_) => {
+                let Some(track) = controls.status.lock().unwrap().track else {
+                    continue;
+                };
+                let old = display.ratings[track];
+                let rating = match action {
+                    Action::Rate(stars) if stars == old.stars => Rating { stars: 0, ..old },
+                    Action::Rate(stars) => Rating { stars, ..old },
+                    _ => Rating { favorite: !old.favorite, ..old },
+                };
+                controls.set_message(rate(&controls, &music_files[track], old, rating));
+                display.ratings[track] = rating;
+            }
+            Action::VolumeUp => change_volume(&controls, &sink, true),
+            Action::VolumeDown => change_volume(&controls, &sink, false),
+            Action::Mute => {
+                let muted = !controls.muted.load(Ordering::SeqCst);
+                controls.muted.store(muted, Ordering::SeqCst);
+                sink.lock().unwrap().set_volume(controls.sink_volume());
+            }
+            Action::Mono => {
+                let mono = !controls.effects.mono.load(Ordering::SeqCst);
+                controls.effects.mono.store(mono, Ordering::SeqCst);
+            }
+            Action::Balance(right) => {
+                let step = if right { BALANCE_STEP } else { -BALANCE_STEP };
+                let balance = (controls.effects.balance.load(Ordering::SeqCst) + step).clamp(-dsp::MAX_BALANCE, dsp::MAX_BALANCE);
+                controls.effects.balance.store(balance, Ordering::SeqCst);
+                controls.set_message(format!("Balance: {}", balance_label(balance)));
+            }
+            Action::Bass(up) => {
+                let bass = change_tone(&controls.effects.bass, up);
+                controls.set_message(format!("Bass: {}", tone_label(bass)));
+            }
+            Action::Treble(up) => {
+                let treble = change_tone(&controls.effects.treble, up);
+                controls.set_message(format!("Treble: {}", tone_label(treble)));
+            }
+            Action::Shuffle => {
+                let enabled = !controls.shuffle.load(Ordering::SeqCst);
+                controls.shuffle.store(enabled, Ordering::SeqCst);
+            }
+            Action::Repeat => {
+                let mode = RepeatMode::from_u8(controls.repeat.load(Ordering::SeqCst)).next();
+                controls.repeat.store(mode as u8, Ordering::SeqCst);
+            }
+            Action::ToggleRemaining => display.show_remaining = !display.show_remaining,
+            Action::ToggleLyrics => display.show_lyrics = !display.show_lyrics,
+            Action::ToggleSpectrum => display.show_spectrum = !display.show_spectrum,
+            Action::ToggleMeter => display.show_meter = !display.show_meter,
+            Action::Sleep => display.sleep_prompt = Some((String::new(), None)),
+            Action::ExportQueue => display.export_prompt = Some((String::new(), None)),
+            Action::Stop => {
+                let _ = command_tx.send(PlayerCommand::Stop);
+            }
+            // Browse the list while the music keeps going
+            Action::ShowList => {
+                if let Some(index) = controls.status.lock().unwrap().track {
+                    active_rows(list_view, &mut list, &mut browser).select_track(index, height);
+                }
+                execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+                view = list_view;
+            }
+            Action::Help => help_open = true,
+            Action::Quit => break,
+        }
+    }
+
+    stop_player(&controls, &command_tx, &sink, player);
+    if let Some(status_file) = status_file.as_mut() {
+        status_file.stop(controls.volume.load(Ordering::SeqCst), controls.muted.load(Ordering::SeqCst));
+    }
+    if let Some(now_playing_file) = now_playing_file.as_mut() {
+        now_playing_file.update(String::new());
+    }
+    #[cfg(feature = "notify")]
+    if notifier.is_some() {
+        notify::clean_up();
+    }
+
+    // The tags and lengths read this time are kept for next time, of the
+    // files still there
+    save_indexes(&mut indexes.lock().unwrap(), &roots, &music_files, &sources, &vanished, &tags, &display.lengths);
+
+    // Kept for `export-queue`, unless nothing was played, which would only
+    // lose the last queue that was
+    if let Some(path) = queue::path().filter(|_| controls.queue.lock().unwrap().started()) {
+        let _ = queue::save(&path, &queue_files(&controls, &music_files));
+    }
+
+    // Cleanup. Clear first so nothing drawn in the alternate screen can show
+    // through on terminals that don't keep it separate.
+    execute!(io::stdout(), terminal::Clear(ClearType::All))?;
+    terminal::disable_raw_mode()?;
+    execute!(io::stdout(), DisableMouseCapture, LeaveAlternateScreen)?;
+    execute!(io::stdout(), cursor::Show)?;
+    println!("\nExiting...");
+    // Said here rather than while playing, as nothing was lost
+    let recovered = controls.retry.recovered();
+    if recovered > 0 {
+        println!("{} {} from the card failed at first, but went through when tried again", recovered, if recovered == 1 { "read" } else { "reads" });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{scratch, write_wav};
+
+    /// Controls for playing `files` in order, with nothing kept from one
+    /// run to the next.
+    fn controls(files: Vec<PathBuf>) -> Controls {
+        Controls {
+            is_paused: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            is_playing: AtomicBool::new(false),
+            shuffle: AtomicBool::new(false),
+            repeat: AtomicU8::new(RepeatMode::Off as u8),
+            volume: AtomicU32::new(0),
+            muted: AtomicBool::new(false),
+            replaygain: ReplayGain { mode: replaygain::Mode::Off, preamp: 0.0, fallback: 0.0 },
+            track_gain: AtomicU32::new(1.0f32.to_bits()),
+            fade: Duration::ZERO,
+            fade_level: AtomicU32::new(1.0f32.to_bits()),
+            speed: Arc::new(AtomicU32::new(100)),
+            keep_speed: false,
+            prefetch_limit: None,
+            retry: Retry::new(0, Duration::ZERO),
+            local_cache: None,
+            effects: Arc::default(),
+            tap: Arc::default(),
+            ab_loop: Arc::default(),
+            sleep_at: Mutex::new(None),
+            positions: None,
+            resume: false,
+            history: None,
+            plays: None,
+            plays_counted: AtomicU32::new(0),
+            ratings: None,
+            write_tags: false,
+            queue: Mutex::new(Queue::new((0..files.len()).collect())),
+            files: Mutex::new(files),
+            status: Mutex::new(PlayerStatus::default()),
+        }
+    }
+
+    #[test]
+    fn quitting_stops_the_queue_straight_away() {
+        let Ok((_stream, stream_handle)) = OutputStream::try_default() else {
+            eprintln!("skipped: there's no audio output to play to");
+            return;
+        };
+        let dir = scratch("quit");
+        let files: Vec<PathBuf> = (1..=3).map(|number| dir.join(format!("{}.wav", number))).collect();
+        for file in &files {
+            write_wav(file, 44100, 2, &vec![0; 30 * 44100 * 2], false);
+        }
+        let controls = Arc::new(controls(files));
+        let sink = Arc::new(Mutex::new(Sink::try_new(&stream_handle).unwrap()));
+        let (command_tx, command_rx) = mpsc::channel();
+        let player = {
+            let (controls, sink) = (Arc::clone(&controls), Arc::clone(&sink));
+            thread::spawn(move || {
+                let (event_tx, _events) = mpsc::channel();
+                run_player(Playback { controls: &controls, commands: &command_rx, sink: &sink, stream_handle: &stream_handle, events: &event_tx, failure: Failure::default() });
+            })
+        };
+
+        command_tx.send(PlayerCommand::Play(0)).unwrap();
+        let started = Instant::now();
+        while controls.status.lock().unwrap().track.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(10), "the first track never started");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let stopping = Instant::now();
+        stop_player(&controls, &command_tx, &sink, player);
+        assert!(stopping.elapsed() < Duration::from_secs(1), "took {:?} to stop", stopping.elapsed());
+        assert_eq!(controls.status.lock().unwrap().track, None);
+        // The rest of the queue wasn't started on the way out
+        assert_eq!(controls.queue.lock().unwrap().current(), Some(0));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn extensions_match_whatever_their_case() {
+        assert_eq!(music_extension(Path::new("song.flac")), Some("flac"));
+        assert_eq!(music_extension(Path::new("SONG.FLAC")), Some("flac"));
+        assert_eq!(music_extension(Path::new("Song.Flac")), Some("flac"));
+        assert_eq!(music_extension(Path::new("song.fLaC")), Some("flac"));
+        assert_eq!(music_extension(Path::new("Track.MP3")), Some("mp3"));
+    }
+
+    #[test]
+    fn only_the_last_extension_counts() {
+        assert_eq!(music_extension(Path::new("song.v2.FLAC")), Some("flac"));
+        assert_eq!(music_extension(Path::new("/music/a.b.c/song.v2.wav")), Some("wav"));
+        assert_eq!(music_extension(Path::new("song.flac.txt")), None);
+        assert_eq!(music_extension(Path::new("song.flac.part")), None);
+    }
+
+    #[test]
+    fn files_without_an_extension_are_not_music() {
+        assert_eq!(music_extension(Path::new("flac")), None);
+        assert_eq!(music_extension(Path::new("/music/README")), None);
+        assert_eq!(music_extension(Path::new(".flac")), None);
+        assert_eq!(music_extension(Path::new("song.")), None);
+        assert_eq!(music_extension(Path::new("")), None);
+    }
+
+    #[test]
+    fn times_under_an_hour_are_minutes_and_seconds() {
+        assert_eq!(format_time(0), "00:00");
+        assert_eq!(format_time(9), "00:09");
+        assert_eq!(format_time(65), "01:05");
+        assert_eq!(format_time(59 * 60 + 59), "59:59");
+    }
+
+    #[test]
+    fn times_of_an_hour_or_more_have_hours() {
+        assert_eq!(format_time(3600), "1:00:00");
+        assert_eq!(format_time(3600 + 5 * 60 + 30), "1:05:30");
+        assert_eq!(format_time(10 * 3600 + 59), "10:00:59");
+        assert_eq!(format_time(100 * 3600), "100:00:00");
+    }
+
+    #[test]
+    fn sizes_under_a_kilobyte_are_bytes() {
+        assert_eq!(format_size(0), "0 bytes");
+        assert_eq!(format_size(1023), "1023 bytes");
+    }
+
+    #[test]
+    fn sizes_are_in_the_unit_that_suits_them() {
+        assert_eq!(format_size(1024), "1.0 KB");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(12_900_000), "12.3 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+        assert_eq!(format_size(5 << 40), "5.0 TB");
+        // Nothing bigger than terabytes
+        assert_eq!(format_size(2048 << 40), "2048.0 TB");
+    }
+
+    fn short(path: &str, root: &str, width: usize) -> (String, String) {
+        short_path(Path::new(path), Path::new(root), width)
+    }
+
+    fn pair(directory: &str, name: &str) -> (String, String) {
+        (directory.to_string(), name.to_string())
+    }
+
+    #[test]
+    fn paths_are_shown_from_the_root() {
+        assert_eq!(short("/card/Artist/Album/01.flac", "/card", 80), pair("Artist/Album/", "01.flac"));
+        assert_eq!(short("/card/01.flac", "/card", 80), pair("", "01.flac"));
+        assert_eq!(short("music/Album/01.flac", "music", 80), pair("Album/", "01.flac"));
+    }
+
+    #[test]
+    fn root_with_a_trailing_slash() {
+        assert_eq!(short("/card/Artist/01.flac", "/card/", 80), pair("Artist/", "01.flac"));
+        assert_eq!(short("music/Artist/01.flac", "music/", 80), pair("Artist/", "01.flac"));
+    }
+
+    #[test]
+    fn path_that_is_the_root_is_shown_whole() {
+        // A music file given on its own is its own root
+        assert_eq!(short("/card/01.flac", "/card/01.flac", 80), pair("/card/", "01.flac"));
+        assert_eq!(short("01.flac", "01.flac", 80), pair("", "01.flac"));
+    }
+
+    #[test]
+    fn path_not_under_the_root_is_shown_whole() {
+        // Like a playlist's track somewhere else
+        assert_eq!(short("/home/me/Music/02.mp3", "/card", 80), pair("/home/me/Music/", "02.mp3"));
+        assert_eq!(short("/cardboard/02.mp3", "/card", 80), pair("/cardboard/", "02.mp3"));
+    }
+
+    #[test]
+    fn middle_directories_make_way_for_an_ellipsis() {
+        let path = "/card/Artist/Album Name/Disc 1/01.flac";
+        assert_eq!(short(path, "/card", 32), pair("Artist/Album Name/Disc 1/", "01.flac"));
+        assert_eq!(short(path, "/card", 24), pair("Artist/…/Disc 1/", "01.flac"));
+        assert_eq!(short(path, "/card", 10), pair("…/", "01.flac"));
+        assert_eq!(short("/home/me/Music/Artist/02.mp3", "/card", 20), pair("/…/Artist/", "02.mp3"));
+    }
+
+    #[test]
+    fn name_is_kept_whole_however_narrow() {
+        assert_eq!(short("/card/Artist/A very long name.flac", "/card", 5), pair("…/", "A very long name.flac"));
+        assert_eq!(short("/card/A very long name.flac", "/card", 0), pair("", "A very long name.flac"));
+    }
+
+    #[test]
+    fn ascii_bar_fills_from_empty_to_full() {
+        assert_eq!(progress_bar(0.0, 10, BarStyle::Ascii), (String::new(), "-".repeat(10)));
+        assert_eq!(progress_bar(0.5, 10, BarStyle::Ascii), ("=".repeat(5), "-".repeat(5)));
+        assert_eq!(progress_bar(0.99, 10, BarStyle::Ascii), ("=".repeat(9), "-".to_string()));
+        assert_eq!(progress_bar(1.0, 10, BarStyle::Ascii), ("=".repeat(10), String::new()));
+    }
+
+    #[test]
+    fn unicode_bar_fills_in_eighths() {
+        assert_eq!(progress_bar(0.0, 4, BarStyle::Unicode), (String::new(), " ".repeat(4)));
+        assert_eq!(progress_bar(1.0 / 32.0, 4, BarStyle::Unicode), ("▏".to_string(), " ".repeat(3)));
+        assert_eq!(progress_bar(0.5, 4, BarStyle::Unicode), ("██".to_string(), " ".repeat(2)));
+        assert_eq!(progress_bar(0.5 + 7.0 / 32.0, 4, BarStyle::Unicode), ("██▉".to_string(), " ".to_string()));
+        assert_eq!(progress_bar(1.0, 4, BarStyle::Unicode), ("████".to_string(), String::new()));
+    }
+
+    #[test]
+    fn bar_is_always_its_width() {
+        for style in [BarStyle::Ascii, BarStyle::Unicode] {
+            for step in 0..=100 {
+                let (filled, empty) = progress_bar(step as f64 / 100.0, 37, style);
+                assert_eq!(filled.chars().count() + empty.chars().count(), 37, "at {}%", step);
+            }
+            // Out of range is held at the ends
+            assert_eq!(progress_bar(-0.5, 8, style), progress_bar(0.0, 8, style));
+            assert_eq!(progress_bar(1.5, 8, style), progress_bar(1.0, 8, style));
+            assert_eq!(progress_bar(0.5, 0, style), (String::new(), String::new()));
+        }
+    }
+
+    #[test]
+    fn stopwatch_stands_still_while_paused() {
+        let mut clock = Stopwatch::new(false, 100);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        clock.resume();
+        thread::sleep(Duration::from_millis(30));
+        clock.pause();
+        let paused_at = clock.elapsed();
+        assert!(paused_at >= Duration::from_millis(30));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(clock.elapsed(), paused_at);
+        // Pausing again changes nothing
+        clock.pause();
+        assert_eq!(clock.elapsed(), paused_at);
+    }
+
+    #[test]
+    fn stopwatch_leaves_out_the_time_paused() {
+        let mut clock = Stopwatch::new(true, 100);
+        thread::sleep(Duration::from_millis(50));
+        clock.pause();
+        thread::sleep(Duration::from_millis(300));
+        clock.resume();
+        // Resuming again doesn't start it over
+        clock.resume();
+        thread::sleep(Duration::from_millis(50));
+        let elapsed = clock.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn stopwatch_jumps_and_changes_speed_without_losing_its_state() {
+        let mut clock = Stopwatch::new(false, 100);
+        clock.set(Duration::from_secs(90));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(clock.elapsed(), Duration::from_secs(90));
+
+        clock.set_speed(200);
+        assert_eq!(clock.elapsed(), Duration::from_secs(90));
+        clock.resume();
+        thread::sleep(Duration::from_millis(50));
+        clock.pause();
+        let played = clock.elapsed() - Duration::from_secs(90);
+        assert!(played >= Duration::from_millis(100), "{:?}", played);
+        assert!(played < Duration::from_millis(250), "{:?}", played);
+    }
+
+    fn settings(args: &[&str], config: &config::Config) -> Settings {
+        let args: Vec<String> = ["sdsupreme"].iter().chain(args).map(|arg| arg.to_string()).collect();
+        Settings::new(&parse_args(&args).unwrap(), config)
+    }
+
+    #[test]
+    fn settings_default_without_a_config_file() {
+        let settings = settings(&[], &config::Config::default());
+        assert!(settings.paths.is_none());
+        assert!(matches!(settings.volume, (100, Origin::Default)));
+        assert!(matches!(settings.shuffle, (false, Origin::Default)));
+        assert!(matches!(settings.bar_style, (BarStyle::Ascii, Origin::Default)));
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file() {
+        let config = config::Config {
+            music_path: Some(vec!["/media/card".to_string()]),
+            default_volume: Some(80),
+            shuffle: Some(true),
+            bar_style: Some(BarStyle::Unicode),
+            ..config::Config::default()
+        };
+        let from_file = settings(&[], &config);
+        assert!(matches!(&from_file.paths, Some((paths, Origin::ConfigFile)) if paths == &["/media/card"]));
+        assert!(matches!(from_file.volume, (80, Origin::ConfigFile)));
+        assert!(matches!(from_file.shuffle, (true, Origin::ConfigFile)));
+        assert!(matches!(from_file.bar_style, (BarStyle::Unicode, Origin::ConfigFile)));
+
+        let overridden = settings(&["--volume", "50", "--no-shuffle", "--bar", "ascii", "/media/other"], &config);
+        assert!(matches!(&overridden.paths, Some((paths, Origin::CommandLine)) if paths == &["/media/other"]));
+        assert!(matches!(overridden.volume, (50, Origin::CommandLine)));
+        assert!(matches!(overridden.shuffle, (false, Origin::CommandLine)));
+        assert!(matches!(overridden.bar_style, (BarStyle::Ascii, Origin::CommandLine)));
+        assert!(overridden.config_lines(&config).contains(&"default_volume = 50  # command line".to_string()));
+    }
+
+    /// Find the music at `paths` as given on the command line, without
+    /// keeping an index of any of them.
+    fn found(paths: &[&Path], follow_links: bool) -> Found {
+        let paths: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+        let extensions = MUSIC_EXTENSIONS.iter().copied().collect();
+        find_music(&paths, &extensions, &Exclude::default(), follow_links, None, true)
+    }
+
+    /// An empty file at `path`, and the directories it's in.
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+
+    #[test]
+    fn path_given_twice_is_looked_through_once() {
+        let dir = scratch("given-twice");
+        touch(&dir.join("a.flac"));
+        touch(&dir.join("Album/b.mp3"));
+        let with_slash = PathBuf::from(format!("{}/", dir.display()));
+        let found = found(&[&dir, &with_slash, &dir.join("Album/..")], false);
+        assert_eq!(found.files, [dir.join("a.flac"), dir.join("Album/b.mp3")]);
+        assert_eq!(found.roots, [dir]);
+        assert_eq!(found.sources, [0, 0]);
+    }
+
+    #[test]
+    fn directory_inside_another_is_left_to_it() {
+        let dir = scratch("inside-another");
+        touch(&dir.join("a.flac"));
+        touch(&dir.join("Album/b.flac"));
+        touch(&dir.join("Album/Disc 2/c.flac"));
+        // Given innermost first, but the outermost is looked through
+        let found = found(&[&dir.join("Album/Disc 2"), &dir.join("Album"), &dir], false);
+        assert_eq!(found.files, [dir.join("a.flac"), dir.join("Album/b.flac"), dir.join("Album/Disc 2/c.flac")]);
+        assert_eq!(found.roots, [dir]);
+    }
+
+    #[test]
+    fn separate_trees_are_listed_together() {
+        let dir = scratch("separate-trees");
+        touch(&dir.join("one/a.flac"));
+        touch(&dir.join("two/b.flac"));
+        let found = found(&[&dir.join("two"), &dir.join("missing"), &dir.join("one")], false);
+        assert_eq!(found.files, [dir.join("two/b.flac"), dir.join("one/a.flac")]);
+        assert_eq!(found.roots, [dir.join("two"), dir.join("one")]);
+        assert_eq!(found.sources, [0, 1]);
+    }
+
+    #[test]
+    fn file_inside_a_directory_given_is_listed_once() {
+        let dir = scratch("file-inside");
+        touch(&dir.join("a.flac"));
+        touch(&dir.join("b.flac"));
+        let found = found(&[&dir.join("b.flac"), &dir], false);
+        assert_eq!(found.files, [dir.join("a.flac"), dir.join("b.flac")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_reached_through_a_link_is_listed_once_following_links() {
+        let dir = scratch("through-a-link");
+        touch(&dir.join("Album/a.flac"));
+        std::os::unix::fs::symlink(dir.join("Album"), dir.join("Favourites")).unwrap();
+        assert_eq!(found(&[&dir], true).files, [dir.join("Album/a.flac")]);
+        // Not following them, the link isn't looked in at all
+        assert_eq!(found(&[&dir], false).files, [dir.join("Album/a.flac")]);
+    }
+
+    #[test]
+    fn now_playing_falls_back_when_tags_are_missing() {
+        let path = Path::new("/music/Album/01 Song.flac");
+        let none = Tags::default();
+        let field = |field| now_playing_field(field, path, &none, Duration::from_secs(75), Duration::ZERO);
+        assert_eq!(field(nowplaying::Field::Artist), "Unknown artist");
+        assert_eq!(field(nowplaying::Field::Title), "01 Song.flac");
+        assert_eq!(field(nowplaying::Field::Album), "Unknown album");
+        assert_eq!(field(nowplaying::Field::Elapsed), "01:15");
+        assert_eq!(field(nowplaying::Field::Duration), "--:--");
+        assert_eq!(field(nowplaying::Field::Filename), "01 Song.flac");
+
+        let tags = Tags { artist: Some("Band".to_string()), title: Some("Song".to_string()), album: Some("Album".to_string()), ..Tags::default() };
+        let field = |field| now_playing_field(field, path, &tags, Duration::ZERO, Duration::from_secs(3 * 60 + 5));
+        assert_eq!(field(nowplaying::Field::Artist), "Band");
+        assert_eq!(field(nowplaying::Field::Title), "Song");
+        assert_eq!(field(nowplaying::Field::Album), "Album");
+        assert_eq!(field(nowplaying::Field::Duration), "03:05");
+        assert_eq!(field(nowplaying::Field::Filename), "01 Song.flac");
+    }
+}
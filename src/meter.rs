@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+// The quietest level shown, in dBFS; the meter runs from here to 0
+pub const FLOOR_DB: f32 = -48.0;
+
+// How fast the bars fall back once it gets quieter, in dB per second
+const DECAY_DB_PER_SECOND: f32 = 20.0;
+
+// How long the highest level stays marked
+const HOLD: Duration = Duration::from_secs(1);
+
+/// A left and right peak meter, in dBFS, with each peak held for a moment.
+/// Above 0 dBFS it clips.
+#[derive(Default)]
+pub struct LevelMeter {
+    channels: [Channel; 2],
+    updated: Option<Instant>,
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    level: f32,
+    held: f32,
+    held_at: Option<Instant>,
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel { level: FLOOR_DB, held: FLOOR_DB, held_at: None }
+    }
+}
+
+impl LevelMeter {
+    /// Take in the left and right `peaks` since the last update, as
+    /// fractions of full scale.
+    pub fn update(&mut self, peaks: [f32; 2]) {
+        let now = Instant::now();
+        let decay = self.updated.map_or(0.0, |updated| (now - updated).as_secs_f32() * DECAY_DB_PER_SECOND);
+        self.updated = Some(now);
+        for (channel, peak) in self.channels.iter_mut().zip(peaks) {
+            let db = 20.0 * peak.max(1e-6).log10();
+            channel.level = db.max(channel.level - decay).max(FLOOR_DB);
+            let expired = channel.held_at.is_none_or(|held_at| now - held_at >= HOLD);
+            if channel.level >= channel.held || expired {
+                channel.held = channel.level;
+                channel.held_at = Some(now);
+            }
+        }
+    }
+
+    /// The level and the held peak of the left and right channels, in dBFS.
+    pub fn levels(&self) -> [(f32, f32); 2] {
+        self.channels.map(|channel| (channel.level, channel.held))
+    }
+}
@@ -0,0 +1,154 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use walkdir::WalkDir;
+
+// How long to wait for the media to be looked through. A network share
+// that's gone away can hang whatever touches it, so those are given up on.
+const LOOK_TIME: Duration = Duration::from_secs(2);
+
+// Entries looked at on each before deciding there's no music on it
+const MAX_ENTRIES: usize = 5000;
+
+// File systems that are shares over the network rather than media plugged in
+const NETWORK: &[&str] = &["nfs", "nfs4", "cifs", "smb3", "smbfs", "fuse.sshfs", "9p", "afs", "ceph", "glusterfs", "davfs", "fuse.rclone"];
+
+/// Where removable media, like an SD card, might be mounted: each directory
+/// in the usual places desktops mount them, and whatever `/proc/mounts`
+/// says is on a removable device. Nothing's opened but the directories
+/// they're in, so a share that's stopped answering can't hold this up.
+pub fn candidates() -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = Vec::new();
+    let user = env::var_os("USER").unwrap_or_default();
+    let mut roots = vec![PathBuf::from("/Volumes"), PathBuf::from("/media")];
+    if !user.is_empty() {
+        roots.insert(0, Path::new("/run/media").join(&user));
+        roots.insert(1, Path::new("/media").join(&user));
+    }
+    for root in &roots {
+        let Ok(entries) = fs::read_dir(root) else {
+            continue;
+        };
+        // The type comes with the listing, without looking at the entry itself
+        for entry in entries.flatten().filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir() || kind.is_symlink())) {
+            let path = entry.path();
+            // `/media/$USER` holds the media rather than being one, and
+            // macOS links the startup disk into `/Volumes`
+            if roots.contains(&path) || fs::read_link(&path).is_ok_and(|target| target == Path::new("/")) {
+                continue;
+            }
+            found.push(path);
+        }
+    }
+    for mount in removable_mounts() {
+        if !found.contains(&mount) {
+            found.push(mount);
+        }
+    }
+    found
+}
+
+/// The mount points in `/proc/mounts` of devices the kernel says are
+/// removable, and of SD cards, which readers don't always say are, leaving
+/// out the system's own.
+fn removable_mounts() -> Vec<PathBuf> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (device, mount, kind) = (fields.next()?, fields.next()?, fields.next()?);
+            let name = device.strip_prefix("/dev/")?;
+            if NETWORK.contains(&kind) || mount == "/" || mount.starts_with("/boot") {
+                return None;
+            }
+            let disk = disk_name(name);
+            let removable = fs::read_to_string(format!("/sys/block/{}/removable", disk)).is_ok_and(|flag| flag.trim() == "1");
+            (removable || disk.starts_with("mmcblk")).then(|| PathBuf::from(unescape(mount)))
+        })
+        .collect()
+}
+
+/// The UUID of the file system `path` is on, and where that's mounted, when
+/// `/proc/mounts` and `/dev/disk/by-uuid` say. `path` is a full path.
+pub fn filesystem_uuid(path: &Path) -> Option<(String, PathBuf)> {
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    // The mount nearest `path` is the one it's on
+    let (device, mount) = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (device, mount) = (fields.next()?, PathBuf::from(unescape(fields.next()?)));
+            path.starts_with(&mount).then(|| (unescape(device), mount))
+        })
+        .max_by_key(|(_, mount)| mount.components().count())?;
+    let device = fs::canonicalize(device).ok()?;
+    let uuid = fs::read_dir("/dev/disk/by-uuid")
+        .ok()?
+        .flatten()
+        .find(|entry| fs::canonicalize(entry.path()).is_ok_and(|target| target == device))?
+        .file_name();
+    Some((uuid.to_string_lossy().into_owned(), mount))
+}
+
+/// The disk a partition like `sdb1` or `mmcblk0p1` is on.
+fn disk_name(partition: &str) -> &str {
+    let without_number = partition.trim_end_matches(|c: char| c.is_ascii_digit());
+    match without_number.strip_suffix('p') {
+        // `mmcblk0p1` and `nvme0n1p1` number their partitions after a `p`
+        Some(disk) if disk.ends_with(|c: char| c.is_ascii_digit()) => disk,
+        _ => without_number,
+    }
+}
+
+/// A path from `/proc/mounts`, where spaces and the like are written as
+/// `\040` and so on.
+fn unescape(path: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let code = after.get(..3).and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match code {
+            Some(code) if byte == b'\\' => {
+                bytes.push(code);
+                rest = &after[3..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Those of `candidates` with music on them, going by `is_music`, in the
+/// same order. Each is looked through on a thread of its own, and any that
+/// haven't been by the time there's been long enough are left out.
+pub fn with_music(candidates: Vec<PathBuf>, is_music: impl Fn(&Path) -> bool + Send + Sync + 'static) -> Vec<PathBuf> {
+    let is_music = Arc::new(is_music);
+    let (found_tx, found_rx) = mpsc::channel();
+    for (index, candidate) in candidates.iter().enumerate() {
+        let (found_tx, is_music, candidate) = (found_tx.clone(), Arc::clone(&is_music), candidate.clone());
+        thread::spawn(move || {
+            let has_music = WalkDir::new(&candidate)
+                .into_iter()
+                .take(MAX_ENTRIES)
+                .flatten()
+                .any(|entry| entry.file_type().is_file() && is_music(entry.path()));
+            let _ = found_tx.send((index, has_music));
+        });
+    }
+    drop(found_tx);
+    let deadline = Instant::now() + LOOK_TIME;
+    let mut with_music = vec![false; candidates.len()];
+    while let Ok((index, has_music)) = found_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        with_music[index] = has_music;
+    }
+    candidates.into_iter().zip(with_music).filter(|(_, has_music)| *has_music).map(|(candidate, _)| candidate).collect()
+}
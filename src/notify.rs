@@ -0,0 +1,117 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cover;
+
+// How long a track has to play before it's told of, so skipping through
+// several at once only tells of the one that's stopped at
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Desktop notifications of each track as it starts, with --notify, sent
+/// with `notify-send` as the desktop's own tools do. Where there's no
+/// notification daemon, or no `notify-send`, nothing's shown.
+#[derive(Default)]
+pub struct Notifier {
+    /// The track last told of, until the queue stops, so a track that
+    /// starts again, as with repeat one, isn't told of twice.
+    shown: Option<usize>,
+    /// The track playing and since when, while it's not been told of yet.
+    settling: Option<(usize, Instant)>,
+}
+
+impl Notifier {
+    /// Note that `track` is playing, or nothing is. Once it's been playing a
+    /// moment, `track` is returned, to be told of.
+    pub fn playing(&mut self, track: Option<usize>) -> Option<usize> {
+        let Some(track) = track else {
+            self.shown = None;
+            self.settling = None;
+            return None;
+        };
+        if self.shown == Some(track) {
+            self.settling = None;
+            return None;
+        }
+        match self.settling {
+            Some((settling, since)) if settling == track => {
+                if since.elapsed() < SETTLE_TIME {
+                    return None;
+                }
+                self.settling = None;
+                self.shown = Some(track);
+                Some(track)
+            }
+            _ => {
+                self.settling = Some((track, Instant::now()));
+                None
+            }
+        }
+    }
+}
+
+/// Show a notification that the track at `path` has started, called
+/// `title` and by `artist` if that's known, with its cover art if it has
+/// any. It's sent from a thread of its own, so a daemon that's slow to
+/// answer can't hold up the player.
+pub fn track_started(path: PathBuf, title: String, artist: Option<String>) {
+    thread::spawn(move || {
+        let mut command = Command::new("notify-send");
+        command.arg("--app-name=sdsupreme");
+        if let Some(icon) = save_cover(&path) {
+            command.arg(format!("--icon={}", icon.display()));
+        }
+        command.arg("--").arg(title).args(artist);
+        // Anything it says would be drawn over the player
+        let _ = command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).status();
+    });
+}
+
+/// The cover art saved for notifications, removed once the next one's
+/// shown or the program quits.
+static SAVED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Where the cover art shown in notifications is put, for the daemon to
+/// read: the user's own runtime directory, which nobody else can get at,
+/// or the temporary directory when there isn't one.
+fn cover_dir() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from).filter(|dir| dir.is_absolute()).unwrap_or_else(env::temp_dir)
+}
+
+/// Write the front cover of the track at `path` where the notification can
+/// show it, if it has one. It's always a new file, never one that's there
+/// already, which in a shared temporary directory could be a link someone
+/// else left to have something of theirs written over.
+fn save_cover(path: &Path) -> Option<PathBuf> {
+    let picture = cover::front_cover(cover::pictures(path).ok()?).filter(|picture| matches!(picture.extension(), "jpg" | "png"))?;
+    let mut saved = SAVED.lock().unwrap();
+    for old in saved.drain(..) {
+        let _ = fs::remove_file(old);
+    }
+    let dir = cover_dir();
+    for attempt in 0..100 {
+        let cover = dir.join(format!("sdsupreme-cover-{}-{}.{}", process::id(), attempt, picture.extension()));
+        match OpenOptions::new().write(true).create_new(true).open(&cover) {
+            Ok(mut file) => {
+                saved.push(cover.clone());
+                return file.write_all(&picture.data).ok().map(|()| cover);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Remove the cover art left for the last notification, as the program's
+/// quitting.
+pub fn clean_up() {
+    for cover in SAVED.lock().unwrap().drain(..) {
+        let _ = fs::remove_file(cover);
+    }
+}
@@ -0,0 +1,193 @@
+use std::path::PathBuf;
+
+use crate::store;
+
+/// What --now-playing-format is unless it's set.
+pub const DEFAULT_FORMAT: &str = "{artist} – {title}";
+
+/// What a template can say about the track playing.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Artist,
+    Title,
+    Album,
+    Elapsed,
+    Duration,
+    Filename,
+}
+
+const FIELDS: [(&str, Field); 6] = [
+    ("artist", Field::Artist),
+    ("title", Field::Title),
+    ("album", Field::Album),
+    ("elapsed", Field::Elapsed),
+    ("duration", Field::Duration),
+    ("filename", Field::Filename),
+];
+
+#[derive(Clone)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// What the now-playing file says, like `{artist} – {title}`, with each
+/// placeholder in braces filled in from the track playing. `{{` and `}}`
+/// stand for braces themselves.
+#[derive(Clone)]
+pub struct Template {
+    /// As it was given, for --print-config.
+    text: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("'{{{}' isn't closed with '}}'; write '{{{{' for a brace", name)),
+                        }
+                    }
+                    let Some(&(_, field)) = FIELDS.iter().find(|(known, _)| *known == name) else {
+                        let known: Vec<String> = FIELDS.iter().map(|(known, _)| format!("{{{}}}", known)).collect();
+                        return Err(format!("unknown placeholder '{{{}}}', expected one of {}", name, known.join(", ")));
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err("'}' without a '{' before it; write '}}' for a brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(Template { text: text.to_string(), parts })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The template filled in, with `value` giving what each placeholder
+    /// stands for.
+    pub fn render(&self, value: impl Fn(Field) -> String) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(field) => value(*field),
+            })
+            .collect()
+    }
+}
+
+impl Default for Template {
+    fn default() -> Template {
+        Template::parse(DEFAULT_FORMAT).expect("the default format is valid")
+    }
+}
+
+/// A plain text file saying what's playing, with --now-playing-file, for a
+/// streaming program's text source to watch. It's only written when what it
+/// says changes: when the track does, or every second when the template
+/// has the time in it.
+pub struct NowPlayingFile {
+    path: PathBuf,
+    pub template: Template,
+    /// What was last written.
+    written: Option<String>,
+}
+
+impl NowPlayingFile {
+    pub fn new(path: PathBuf, template: Template) -> NowPlayingFile {
+        NowPlayingFile { path, template, written: None }
+    }
+
+    /// Make the file say `text`, empty when nothing's playing, if it doesn't
+    /// already. Failing to write it isn't worth interrupting the music for,
+    /// so it's left to be tried again next time.
+    pub fn update(&mut self, text: String) {
+        if self.written.as_ref() == Some(&text) {
+            return;
+        }
+        if store::replace(&self.path, &text).is_ok() {
+            self.written = Some(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> String {
+        Template::parse(template).unwrap().render(|field| match field {
+            Field::Artist => "Artist".to_string(),
+            Field::Title => "Title".to_string(),
+            _ => "?".to_string(),
+        })
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        assert_eq!(render("{artist} – {title}"), "Artist – Title");
+        assert_eq!(render("Now: {title}!"), "Now: Title!");
+        assert_eq!(render("no placeholders"), "no placeholders");
+        assert_eq!(render(""), "");
+    }
+
+    #[test]
+    fn doubled_braces_stand_for_braces() {
+        assert_eq!(render("{{{title}}}"), "{Title}");
+        assert_eq!(render("{{artist}}"), "{artist}");
+        assert_eq!(render("}}{{"), "}{");
+    }
+
+    #[test]
+    fn unclosed_brace_is_rejected() {
+        let error = Template::parse("{artist} – {title").err().unwrap();
+        assert_eq!(error, "'{title' isn't closed with '}'; write '{{' for a brace");
+        assert!(Template::parse("{").is_err());
+    }
+
+    #[test]
+    fn lone_closing_brace_is_rejected() {
+        let error = Template::parse("{title} }").err().unwrap();
+        assert_eq!(error, "'}' without a '{' before it; write '}}' for a brace");
+    }
+
+    #[test]
+    fn unknown_placeholder_is_rejected() {
+        let error = Template::parse("{foo}").err().unwrap();
+        assert!(error.starts_with("unknown placeholder '{foo}', expected one of {artist}, {title}"), "{}", error);
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{Title}").is_err());
+    }
+
+    #[test]
+    fn default_is_artist_and_title() {
+        let template = Template::default();
+        assert_eq!(template.text(), DEFAULT_FORMAT);
+        assert_eq!(render(template.text()), "Artist – Title");
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use crate::{probe, tags};
+
+/// A track in a playlist.
+pub struct Entry {
+    pub path: PathBuf,
+    /// None when it can't be worked out from the headers.
+    pub length: Option<Duration>,
+    /// The artist and title, as shown for it.
+    pub title: Option<String>,
+}
+
+impl Entry {
+    /// The entry for `path`, with its length and name read from the file.
+    pub fn read(path: &Path) -> Entry {
+        Entry {
+            path: path.to_path_buf(),
+            length: probe::estimate_duration(path),
+            title: tags::read(path).ok().and_then(|tags| tags.display_name()),
+        }
+    }
+}
+
+/// What a playlist lists, as far as it could be found.
+pub struct Playlist {
+    /// The tracks that are there, in order.
+    pub entries: Vec<Entry>,
+    /// The tracks that aren't, as the playlist has them.
+    pub missing: Vec<String>,
+}
+
+/// Whether `path` is a playlist that can be read, going by its extension.
+pub fn is_playlist(path: &Path) -> bool {
+    extension(path).is_some_and(|extension| matches!(extension.as_str(), "m3u" | "m3u8" | "pls"))
+}
+
+fn extension(path: &Path) -> Option<String> {
+    Some(path.extension()?.to_str()?.to_ascii_lowercase())
+}
+
+/// Read the M3U or PLS playlist at `path`. Relative paths are taken from
+/// where the playlist is. Playlists that aren't UTF-8 are taken to be
+/// Latin-1, as older ones often are.
+pub fn read(path: &Path) -> io::Result<Playlist> {
+    let text = match String::from_utf8(fs::read(path)?) {
+        Ok(text) => text,
+        Err(not_utf8) => not_utf8.into_bytes().iter().map(|&byte| byte as char).collect(),
+    };
+    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+    let listed = match extension(path).as_deref() {
+        Some("pls") => parse_pls(text),
+        _ => parse_m3u(text),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut playlist = Playlist { entries: Vec::new(), missing: Vec::new() };
+    for entry in listed {
+        let written = entry.path.to_string_lossy().into_owned();
+        match find(dir, &written) {
+            Some(path) => playlist.entries.push(Entry { path, ..entry }),
+            None => playlist.missing.push(written),
+        }
+    }
+    Ok(playlist)
+}
+
+/// Where `file`, as written in a playlist in `dir`, is, if it's there.
+/// Backslashes are tried as separators too, for playlists made on Windows.
+fn find(dir: &Path, file: &str) -> Option<PathBuf> {
+    [file.to_string(), file.replace('\\', "/")].into_iter().map(|file| dir.join(file)).find(|path| path.is_file())
+}
+
+/// The entries of an M3U playlist, with the length and name from the
+/// `#EXTINF` line before each, if there is one. Other lines starting with
+/// `#` are comments.
+fn parse_m3u(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut info = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            // Like `#EXTINF:215,Artist - Title`
+            info = extinf.split_once(',').map(|(length, title)| (parse_length(length), Some(title.trim().to_string()).filter(|title| !title.is_empty())));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let (length, title) = info.take().unwrap_or((None, None));
+            entries.push(Entry { path: PathBuf::from(line), length, title });
+        }
+    }
+    entries
+}
+
+/// The entries of a PLS playlist, with `FileN`, `TitleN` and `LengthN`
+/// for each, in the order of their numbers.
+fn parse_pls(text: &str) -> Vec<Entry> {
+    let mut entries: BTreeMap<u32, Entry> = BTreeMap::new();
+    for line in text.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let (name, number) = key.split_at(key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len()));
+        let Ok(number) = number.parse() else {
+            continue;
+        };
+        let value = value.trim();
+        let entry = entries.entry(number).or_insert_with(|| Entry { path: PathBuf::new(), length: None, title: None });
+        match name {
+            "file" => entry.path = PathBuf::from(value),
+            "length" => entry.length = parse_length(value),
+            "title" => entry.title = Some(value.to_string()).filter(|title| !title.is_empty()),
+            _ => {}
+        }
+    }
+    // A title or length without a file is no use
+    entries.into_values().filter(|entry| !entry.path.as_os_str().is_empty()).collect()
+}
+
+/// A length in seconds, where anything negative, usually -1, means it isn't
+/// known. `#EXTINF` lines can have attributes after it.
+fn parse_length(text: &str) -> Option<Duration> {
+    let seconds: f64 = text.split_whitespace().next()?.parse().ok()?;
+    (seconds >= 0.0 && seconds.is_finite()).then(|| Duration::from_secs_f64(seconds))
+}
+
+/// Write `entries` to `path` as an extended M3U playlist, with an `#EXTINF`
+/// line for each giving its length in seconds, or -1 when that's unknown,
+/// and its name. Paths are written relative to where the playlist is, so it
+/// still works when the card is mounted somewhere else, and the whole file
+/// is UTF-8, as `.m3u8` playlists are. An existing playlist is only
+/// replaced when `replace` is true.
+pub fn write_m3u(path: &Path, entries: &[Entry], replace: bool) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::canonicalize(dir)?,
+        _ => fs::canonicalize(".")?,
+    };
+    let mut text = String::from("#EXTM3U\n");
+    for entry in entries {
+        let length = entry.length.map_or(-1, |length| length.as_secs_f64().round() as i64);
+        let file = fs::canonicalize(&entry.path).unwrap_or_else(|_| entry.path.clone());
+        let title = match &entry.title {
+            Some(title) => title.clone(),
+            None => file.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned()),
+        };
+        // A line break in a tag would start a line of its own
+        text += &format!("#EXTINF:{},{}\n", length, title.replace(['\r', '\n'], " "));
+        text += &relative(&dir, &file).to_string_lossy();
+        text.push('\n');
+    }
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if replace {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    options.open(path)?.write_all(text.as_bytes())
+}
+
+/// The way to `file` from `dir`, both full paths. A file with nothing in
+/// common with `dir`, like one on another drive, keeps its full path.
+fn relative(dir: &Path, file: &Path) -> PathBuf {
+    let dir: Vec<Component> = dir.components().collect();
+    let file_components: Vec<Component> = file.components().collect();
+    let common = dir.iter().zip(&file_components).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return file.to_path_buf();
+    }
+    let mut relative = PathBuf::new();
+    for _ in common..dir.len() {
+        relative.push("..");
+    }
+    for component in &file_components[common..] {
+        relative.push(component);
+    }
+    relative
+}
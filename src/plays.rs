@@ -0,0 +1,89 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{config, store};
+
+/// How many times each track has been played to the end or past halfway.
+/// Kept in `plays` in the data directory, with a line for each track: the
+/// count, its size in bytes and its path under the card, separated by tabs.
+pub struct PlayCounts {
+    path: PathBuf,
+    /// Where the cards are mounted this time.
+    cards: Vec<PathBuf>,
+}
+
+pub struct Entry {
+    pub count: u32,
+    pub size: u64,
+    /// The path under the card.
+    pub file: String,
+}
+
+/// `plays` in the data directory.
+pub fn path() -> Option<PathBuf> {
+    Some(config::data_dir()?.join("plays"))
+}
+
+impl PlayCounts {
+    pub fn new(cards: &[PathBuf]) -> Option<PlayCounts> {
+        Some(PlayCounts { path: path()?, cards: cards.to_vec() })
+    }
+
+    /// Count another play of `file`.
+    pub fn add(&self, file: &Path) -> io::Result<()> {
+        let (relative, size) = store::track_key(&self.cards, file)?;
+        store::update(&self.path, |text| {
+            let mut entries = parse(text);
+            match entries.iter_mut().find(|entry| entry.size == size && entry.file == relative) {
+                Some(entry) => entry.count = entry.count.saturating_add(1),
+                None => entries.push(Entry { count: 1, size, file: relative }),
+            }
+            format(entries)
+        })
+    }
+
+    /// Keep the count of `file` now that it's changed size from `old_size`,
+    /// as when its tags have been written.
+    pub fn resized(&self, file: &Path, old_size: u64) -> io::Result<()> {
+        let (relative, size) = store::track_key(&self.cards, file)?;
+        store::update(&self.path, |text| {
+            let mut entries = parse(text);
+            for entry in entries.iter_mut().filter(|entry| entry.size == old_size && entry.file == relative) {
+                entry.size = size;
+            }
+            format(entries)
+        })
+    }
+
+    /// How many times each of `files` has been played.
+    pub fn counts(&self, files: &[PathBuf]) -> Vec<u32> {
+        let entries = read(&self.path).into_iter().map(|entry| ((entry.file, entry.size), entry.count));
+        store::lookup(&self.cards, files, entries).into_iter().map(|count| count.unwrap_or(0)).collect()
+    }
+}
+
+/// Every entry in the file at `path`. A missing file has none.
+pub fn read(path: &Path) -> Vec<Entry> {
+    parse(&fs::read_to_string(path).unwrap_or_default())
+}
+
+/// The entries in the file's `text`, skipping any line that doesn't make
+/// sense.
+fn parse(text: &str) -> Vec<Entry> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let count = fields.next()?.parse().ok()?;
+            let size = fields.next()?.parse().ok()?;
+            let file = fields.next().filter(|file| !file.is_empty())?.to_string();
+            Some(Entry { count, size, file })
+        })
+        .collect()
+}
+
+/// The text of the file for `entries`, most played first.
+fn format(mut entries: Vec<Entry>) -> String {
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.file.cmp(&b.file)));
+    entries.iter().map(|entry| format!("{}\t{}\t{}\n", entry.count, entry.size, entry.file)).collect()
+}
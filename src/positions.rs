@@ -0,0 +1,146 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{config, store};
+
+// Tracks shorter than this start from the beginning every time
+const MIN_LENGTH: Duration = Duration::from_secs(120);
+
+// Stopping this close to either end isn't worth coming back to
+const EDGE: Duration = Duration::from_secs(10);
+
+// Most files remembered just for where they were stopped; the ones played
+// longest ago go first. Files with bookmarks are always kept.
+const MAX_ENTRIES: usize = 500;
+
+/// Where each long track was stopped, so it can carry on from there next
+/// time, and the bookmarks in each track. Kept in `positions` in the data
+/// directory, with a line for each file: when it was saved, the position in
+/// milliseconds, the bookmarks in milliseconds separated by commas and the
+/// path, separated by tabs, with `-` for no position or no bookmarks. Lines
+/// from before there were bookmarks leave them out.
+pub struct Positions {
+    path: PathBuf,
+}
+
+struct Entry {
+    saved: u64,
+    position: Option<Duration>,
+    bookmarks: Vec<Duration>,
+    file: String,
+}
+
+impl Positions {
+    pub fn new() -> Option<Positions> {
+        Some(Positions { path: config::data_dir()?.join("positions") })
+    }
+
+    /// Where `file` was stopped last time, if it was remembered.
+    pub fn get(&self, file: &Path) -> Option<Duration> {
+        self.entry(file).and_then(|entry| entry.position)
+    }
+
+    /// The bookmarks in `file`, in order.
+    pub fn bookmarks(&self, file: &Path) -> Vec<Duration> {
+        self.entry(file).map(|entry| entry.bookmarks).unwrap_or_default()
+    }
+
+    /// Remember that `file`, `length` long, was stopped at `position`, or
+    /// forget it when that's too near either end or the track is short.
+    pub fn set(&self, file: &Path, position: Duration, length: Duration) -> io::Result<()> {
+        let worth_keeping = length >= MIN_LENGTH && position >= EDGE && position + EDGE <= length;
+        self.change(file, |entry| entry.position = worth_keeping.then_some(position))
+    }
+
+    /// Forget where `file` was stopped, as when it's been played to the end.
+    pub fn forget(&self, file: &Path) -> io::Result<()> {
+        self.change(file, |entry| entry.position = None)
+    }
+
+    /// Make `bookmarks` the bookmarks in `file`.
+    pub fn set_bookmarks(&self, file: &Path, bookmarks: &[Duration]) -> io::Result<()> {
+        self.change(file, |entry| entry.bookmarks = bookmarks.to_vec())
+    }
+
+    fn entry(&self, file: &Path) -> Option<Entry> {
+        let key = key(file);
+        parse(&fs::read_to_string(&self.path).unwrap_or_default()).into_iter().find(|entry| entry.file == key)
+    }
+
+    /// Change the entry for `file`, starting from an empty one, and drop it
+    /// if that leaves nothing to remember.
+    fn change(&self, file: &Path, change: impl FnOnce(&mut Entry)) -> io::Result<()> {
+        let key = key(file);
+        store::update(&self.path, |text| {
+            let mut entries = parse(text);
+            let mut entry = match entries.iter().position(|entry| entry.file == key) {
+                Some(index) => entries.remove(index),
+                None => Entry { saved: 0, position: None, bookmarks: Vec::new(), file: key },
+            };
+            change(&mut entry);
+            if entry.position.is_some() || !entry.bookmarks.is_empty() {
+                entry.saved = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+                entries.push(entry);
+            }
+            format(entries)
+        })
+    }
+}
+
+/// The text of the file for `entries`, keeping the most recent MAX_ENTRIES
+/// of the ones without bookmarks.
+fn format(mut entries: Vec<Entry>) -> String {
+    entries.sort_by_key(|entry| Reverse(entry.saved));
+    let mut kept = 0;
+    entries.retain(|entry| {
+        kept += entry.bookmarks.is_empty() as usize;
+        !entry.bookmarks.is_empty() || kept <= MAX_ENTRIES
+    });
+    let millis = |duration: &Duration| duration.as_millis().to_string();
+    entries
+        .iter()
+        .map(|entry| {
+            let position = entry.position.as_ref().map_or("-".to_string(), millis);
+            let bookmarks = match entry.bookmarks.is_empty() {
+                true => "-".to_string(),
+                false => entry.bookmarks.iter().map(millis).collect::<Vec<_>>().join(","),
+            };
+            format!("{}\t{}\t{}\t{}\n", entry.saved, position, bookmarks, entry.file)
+        })
+        .collect()
+}
+
+/// Files are remembered by their full path, however they were found.
+fn key(file: &Path) -> String {
+    fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf()).to_string_lossy().into_owned()
+}
+
+/// The entries in the file's `text`, skipping any line that doesn't make
+/// sense.
+fn parse(text: &str) -> Vec<Entry> {
+    let millis = |field: &str| field.parse().ok().map(Duration::from_millis);
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let saved = fields.next()?.parse().ok()?;
+            let position = match fields.next()? {
+                "-" => None,
+                field => Some(millis(field)?),
+            };
+            let rest = fields.next()?;
+            // Paths are full ones, so they never look like bookmarks
+            let (bookmarks, file) = match rest.split_once('\t') {
+                Some(("-", file)) => (Vec::new(), file),
+                Some((bookmarks, file)) if bookmarks.chars().all(|c| c.is_ascii_digit() || c == ',') => {
+                    (bookmarks.split(',').map(millis).collect::<Option<Vec<_>>>()?, file)
+                }
+                _ => (Vec::new(), rest),
+            };
+            let file = Some(file).filter(|file| !file.is_empty())?.to_string();
+            Some(Entry { saved, position, bookmarks, file })
+        })
+        .collect()
+}
@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::aiff::extended_to_f64;
+
+// Bitrates in kbps, indexed by [row][bitrate index]
+const BITRATES: [[u32; 16]; 5] = [
+    [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448, 0], // MPEG-1 Layer I
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 0],    // MPEG-1 Layer II
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0],     // MPEG-1 Layer III
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256, 0],    // MPEG-2/2.5 Layer I
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0],         // MPEG-2/2.5 Layer II & III
+];
+
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+/// Work out a track's duration from its headers, for formats where the
+/// decoder can't tell us.
+pub fn estimate_duration(path: &Path) -> Option<Duration> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "mp3" => mp3_duration(path).ok().flatten(),
+        "flac" => flac_duration(path).ok().flatten(),
+        "ogg" => ogg_duration(path).ok().flatten(),
+        "wav" => wav_format(path).ok().flatten().and_then(|f| f.duration()),
+        "m4a" => mp4_info(path).ok().flatten().and_then(|info| info.duration),
+        "aiff" | "aif" => aiff_duration(path).ok().flatten(),
+        _ => None,
+    }
+}
+
+/// Work out the duration of a track being streamed from the `start` of it
+/// that's arrived and its `length` in bytes, for the formats that say in
+/// their first few headers.
+pub fn estimate_stream_duration(extension: &str, start: &[u8], length: u64) -> Option<Duration> {
+    let mut start = Cursor::new(start);
+    match extension.to_ascii_lowercase().as_str() {
+        "mp3" => mp3_duration_of(&mut start, length).ok().flatten(),
+        "flac" => flac_stream_info_of(&mut start).ok().flatten().and_then(|info| info.duration()),
+        _ => None,
+    }
+}
+
+/// How much of the audio a WAV file says it has is really in it, when a
+/// copy of it has been cut off: that and how much it says. The decoder
+/// makes up the rest with silence rather than stopping.
+pub fn wav_cut_off(path: &Path) -> io::Result<Option<(Duration, Duration)>> {
+    let Some(format) = wav_format(path)? else {
+        return Ok(None);
+    };
+    let (Some(expected), Some(data_len)) = (format.duration(), format.data_len) else {
+        return Ok(None);
+    };
+    let there = fs::metadata(path)?.len().saturating_sub(format.data_start);
+    if there >= data_len {
+        return Ok(None);
+    }
+    let frames = there / u64::from(format.block_align);
+    Ok(Some((Duration::from_secs_f64(frames as f64 / f64::from(format.sample_rate)), expected)))
+}
+
+/// Reject files the decoder is known to choke on, with a readable reason,
+/// before they reach the playback thread.
+pub fn check_decodable(path: &Path) -> io::Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(|s| s.to_ascii_lowercase())
+        .unwrap_or_default();
+    if extension == "m4a" {
+        // Only ALAC can be decoded; AAC needs a decoder there isn't yet
+        let codec = match mp4_info(path)? {
+            Some(info) => info.codec.unwrap_or_else(|| "unknown".to_string()),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "not an MP4 file")),
+        };
+        if codec == "alac" {
+            return Ok(());
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported codec: {}", codec_name(&codec)),
+        ));
+    }
+    if extension == "wav" {
+        if let Some(format) = wav_format(path)? {
+            // rodio only handles these sample formats and panics on anything else
+            let supported = matches!(
+                (format.is_float(), format.bits_per_sample),
+                (false, 8) | (false, 16) | (false, 24) | (true, 32)
+            );
+            if !supported {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "unsupported WAV sample format: {}-bit {}",
+                        format.bits_per_sample,
+                        if format.is_float() { "float" } else { "integer" }
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+struct FrameHeader {
+    bitrate: u32,
+    sample_rate: u32,
+    samples_per_frame: u32,
+    side_info_len: u64,
+}
+
+fn parse_frame_header(header: [u8; 4]) -> Option<FrameHeader> {
+    let word = u32::from_be_bytes(header);
+    if word & 0xFFE0_0000 != 0xFFE0_0000 {
+        return None;
+    }
+    let version = (word >> 19) & 0b11; // 0 = 2.5, 2 = 2, 3 = 1
+    let layer = (word >> 17) & 0b11; // 1 = III, 2 = II, 3 = I
+    let bitrate_index = ((word >> 12) & 0b1111) as usize;
+    let sample_rate_index = ((word >> 10) & 0b11) as usize;
+    let mono = (word >> 6) & 0b11 == 0b11;
+    if version == 1 || layer == 0 || sample_rate_index == 3 {
+        return None;
+    }
+
+    let mpeg1 = version == 3;
+    let row = match (mpeg1, layer) {
+        (true, 3) => 0,
+        (true, 2) => 1,
+        (true, _) => 2,
+        (false, 3) => 3,
+        (false, _) => 4,
+    };
+    let bitrate = BITRATES[row][bitrate_index];
+    if bitrate == 0 {
+        return None;
+    }
+
+    let sample_rate = SAMPLE_RATES[sample_rate_index]
+        >> match version {
+            3 => 0,
+            2 => 1,
+            _ => 2,
+        };
+    let samples_per_frame = match (layer, mpeg1) {
+        (3, _) => 384,
+        (1, false) => 576,
+        _ => 1152,
+    };
+    let side_info_len = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+
+    Some(FrameHeader {
+        bitrate: bitrate * 1000,
+        sample_rate,
+        samples_per_frame,
+        side_info_len,
+    })
+}
+
+/// Size of a leading ID3v2 tag, so we can skip to the first audio frame.
+pub fn id3v2_len<R: Read + Seek>(file: &mut R) -> io::Result<u64> {
+    let mut header = [0u8; 10];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        return Ok(0);
+    }
+    let size = header[6..10]
+        .iter()
+        .fold(0u64, |acc, &b| (acc << 7) | (b & 0x7F) as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Ok(10 + size + footer)
+}
+
+/// Estimate the duration of an MP3 file. Uses the Xing/Info or VBRI frame
+/// count when present (accurate for VBR), otherwise falls back to file size
+/// and the bitrate of the first frame.
+fn mp3_duration(path: &Path) -> io::Result<Option<Duration>> {
+    let mut file = fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+    mp3_duration_of(&mut file, file_len)
+}
+
+fn mp3_duration_of<R: Read + Seek>(file: &mut R, file_len: u64) -> io::Result<Option<Duration>> {
+    let audio_start = id3v2_len(file)?;
+
+    // Look for the first frame sync within a reasonable window
+    let mut buf = vec![0u8; 64 * 1024];
+    file.seek(SeekFrom::Start(audio_start))?;
+    let read = file.read(&mut buf)?;
+    buf.truncate(read);
+
+    let Some((offset, frame)) = (0..buf.len().saturating_sub(4)).find_map(|i| {
+        parse_frame_header([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]).map(|h| (i, h))
+    }) else {
+        return Ok(None);
+    };
+
+    let frame_count = |tag_offset: usize| -> Option<u32> {
+        let tag = buf.get(tag_offset..tag_offset + 16)?;
+        match &tag[..4] {
+            b"Xing" | b"Info" => {
+                let flags = u32::from_be_bytes([tag[4], tag[5], tag[6], tag[7]]);
+                (flags & 1 != 0).then(|| u32::from_be_bytes([tag[8], tag[9], tag[10], tag[11]]))
+            }
+            _ => None,
+        }
+    };
+    let vbri_frames = || -> Option<u32> {
+        let tag = buf.get(offset + 36..offset + 36 + 18)?;
+        (&tag[..4] == b"VBRI").then(|| u32::from_be_bytes([tag[14], tag[15], tag[16], tag[17]]))
+    };
+
+    if let Some(frames) = frame_count(offset + 4 + frame.side_info_len as usize).or_else(vbri_frames) {
+        let samples = frames as u64 * frame.samples_per_frame as u64;
+        return Ok(Some(Duration::from_secs_f64(samples as f64 / frame.sample_rate as f64)));
+    }
+
+    // Don't count a trailing ID3v1 tag as audio
+    let mut tail = [0u8; 3];
+    let mut audio_end = file_len;
+    if file_len >= 128 {
+        file.seek(SeekFrom::Start(file_len - 128))?;
+        if file.read_exact(&mut tail).is_ok() && &tail == b"TAG" {
+            audio_end -= 128;
+        }
+    }
+    let audio_len = audio_end.saturating_sub(audio_start + offset as u64);
+    Ok(Some(Duration::from_secs_f64(audio_len as f64 * 8.0 / frame.bitrate as f64)))
+}
+
+/// Bits per sample of a lossless file, from its headers. Lossy formats
+/// don't have a bit depth, so give None.
+pub fn bits_per_sample(path: &Path) -> Option<u32> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "flac" => flac_stream_info(path).ok().flatten().map(|info| info.bits_per_sample),
+        "wav" => wav_format(path).ok().flatten().map(|format| format.bits_per_sample as u32),
+        "aiff" | "aif" => aiff_bits_per_sample(path).ok().flatten(),
+        "m4a" => mp4_info(path)
+            .ok()
+            .flatten()
+            .filter(|info| info.codec.as_deref() == Some("alac"))
+            .and_then(|info| info.bits_per_sample),
+        _ => None,
+    }
+}
+
+struct FlacStreamInfo {
+    sample_rate: u64,
+    bits_per_sample: u32,
+    /// Zero when the encoder didn't know it.
+    total_samples: u64,
+}
+
+/// Read a FLAC file's STREAMINFO block, which always comes first.
+fn flac_stream_info(path: &Path) -> io::Result<Option<FlacStreamInfo>> {
+    flac_stream_info_of(&mut fs::File::open(path)?)
+}
+
+fn flac_stream_info_of<R: Read + Seek>(file: &mut R) -> io::Result<Option<FlacStreamInfo>> {
+    // Some taggers put an ID3v2 tag in front of the stream marker
+    let start = id3v2_len(file)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut header = [0u8; 8];
+    file.read_exact(&mut header)?;
+    if &header[..4] != b"fLaC" || header[4] & 0x7F != 0 {
+        return Ok(None);
+    }
+    let mut info = [0u8; 18];
+    file.read_exact(&mut info)?;
+    // 20 bits of sample rate, 3 of channels, 5 of bit depth, 36 of total samples
+    let packed = u64::from_be_bytes(info[10..18].try_into().unwrap());
+    Ok(Some(FlacStreamInfo {
+        sample_rate: packed >> 44,
+        bits_per_sample: ((packed >> 36) & 0x1F) as u32 + 1,
+        total_samples: packed & 0xF_FFFF_FFFF,
+    }))
+}
+
+/// Duration of a FLAC file from the total sample count in its STREAMINFO.
+fn flac_duration(path: &Path) -> io::Result<Option<Duration>> {
+    Ok(flac_stream_info(path)?.and_then(|info| info.duration()))
+}
+
+impl FlacStreamInfo {
+    fn duration(&self) -> Option<Duration> {
+        (self.sample_rate > 0 && self.total_samples > 0).then(|| Duration::from_secs_f64(self.total_samples as f64 / self.sample_rate as f64))
+    }
+}
+
+/// Duration of an Ogg Vorbis file, read from the page headers: the last
+/// granule position of each logical stream is its length in samples.
+/// Chained files contain several streams one after another, so their lengths
+/// add up.
+fn ogg_duration(path: &Path) -> io::Result<Option<Duration>> {
+    let mut file = fs::File::open(path)?;
+    // serial -> (sample rate, last granule position)
+    let mut streams: HashMap<u32, (u32, u64)> = HashMap::new();
+    let mut pos = 0u64;
+
+    loop {
+        let mut header = [0u8; 27];
+        file.seek(SeekFrom::Start(pos))?;
+        if file.read_exact(&mut header).is_err() || &header[..4] != b"OggS" {
+            break;
+        }
+        let granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let mut segments = vec![0u8; header[26] as usize];
+        file.read_exact(&mut segments)?;
+        let body_len: u64 = segments.iter().map(|&s| s as u64).sum();
+
+        if header[5] & 0x02 != 0 {
+            // Beginning of stream: the first packet is the codec identification header
+            let mut ident = [0u8; 16];
+            file.read_exact(&mut ident)?;
+            if &ident[..7] == b"\x01vorbis" {
+                let rate = u32::from_le_bytes(ident[12..16].try_into().unwrap());
+                streams.insert(serial, (rate, 0));
+            }
+        } else if granule != u64::MAX {
+            // A granule of -1 means no packet finishes on this page
+            if let Some(stream) = streams.get_mut(&serial) {
+                stream.1 = granule;
+            }
+        }
+
+        pos += 27 + segments.len() as u64 + body_len;
+    }
+
+    let seconds: f64 = streams
+        .values()
+        .filter(|(rate, _)| *rate > 0)
+        .map(|&(rate, samples)| samples as f64 / rate as f64)
+        .sum();
+    Ok((seconds > 0.0).then(|| Duration::from_secs_f64(seconds)))
+}
+
+struct WavFormat {
+    format_tag: u16,
+    sample_rate: u32,
+    block_align: u16,
+    bits_per_sample: u16,
+    data_len: Option<u64>,
+    /// Where the audio starts in the file.
+    data_start: u64,
+}
+
+impl WavFormat {
+    fn is_float(&self) -> bool {
+        self.format_tag == 3
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        if self.sample_rate == 0 || self.block_align == 0 {
+            return None;
+        }
+        let frames = self.data_len? / self.block_align as u64;
+        Some(Duration::from_secs_f64(frames as f64 / self.sample_rate as f64))
+    }
+}
+
+/// Read the `fmt ` and `data` chunk headers of a RIFF/WAVE file.
+fn wav_format(path: &Path) -> io::Result<Option<WavFormat>> {
+    let mut file = fs::File::open(path)?;
+    let mut riff = [0u8; 12];
+    if file.read_exact(&mut riff).is_err() || &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Ok(None);
+    }
+
+    let mut format: Option<WavFormat> = None;
+    let mut chunk = [0u8; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        let len = u32::from_le_bytes(chunk[4..].try_into().unwrap()) as u64;
+        match &chunk[..4] {
+            b"fmt " => {
+                let mut fmt = [0u8; 40];
+                let wanted = len.min(40) as usize;
+                file.read_exact(&mut fmt[..wanted])?;
+                let mut format_tag = u16::from_le_bytes([fmt[0], fmt[1]]);
+                if format_tag == 0xFFFE && wanted >= 26 {
+                    // WAVE_FORMAT_EXTENSIBLE keeps the real format in the sub-format GUID
+                    format_tag = u16::from_le_bytes([fmt[24], fmt[25]]);
+                }
+                format = Some(WavFormat {
+                    format_tag,
+                    sample_rate: u32::from_le_bytes(fmt[4..8].try_into().unwrap()),
+                    block_align: u16::from_le_bytes([fmt[12], fmt[13]]),
+                    bits_per_sample: u16::from_le_bytes([fmt[14], fmt[15]]),
+                    data_len: None,
+                    data_start: 0,
+                });
+                file.seek(SeekFrom::Current((len - wanted as u64 + (len & 1)) as i64))?;
+            }
+            b"data" => {
+                if let Some(format) = format.as_mut() {
+                    format.data_len = Some(len);
+                    format.data_start = file.stream_position()?;
+                }
+                break;
+            }
+            _ => {
+                // Chunks are padded to an even length
+                file.seek(SeekFrom::Current((len + (len & 1)) as i64))?;
+            }
+        }
+    }
+    Ok(format)
+}
+
+/// Sample size from the COMM chunk of an AIFF or AIFF-C file.
+fn aiff_bits_per_sample(path: &Path) -> io::Result<Option<u32>> {
+    Ok(aiff_comm(path)?.map(|comm| u16::from_be_bytes([comm[6], comm[7]]) as u32))
+}
+
+/// Duration of an AIFF or AIFF-C file, from the frame count and sample rate
+/// in its COMM chunk.
+fn aiff_duration(path: &Path) -> io::Result<Option<Duration>> {
+    Ok(aiff_comm(path)?.and_then(|comm| {
+        let frames = u32::from_be_bytes(comm[2..6].try_into().unwrap());
+        let rate = extended_to_f64(comm[8..18].try_into().unwrap());
+        (rate > 0.0).then(|| Duration::from_secs_f64(frames as f64 / rate))
+    }))
+}
+
+/// The start of the COMM chunk of an AIFF or AIFF-C file: channels, frame
+/// count, sample size and sample rate.
+fn aiff_comm(path: &Path) -> io::Result<Option<[u8; 18]>> {
+    let mut file = fs::File::open(path)?;
+    let mut form = [0u8; 12];
+    if file.read_exact(&mut form).is_err() || &form[..4] != b"FORM" {
+        return Ok(None);
+    }
+    let mut chunk = [0u8; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        let len = u32::from_be_bytes(chunk[4..].try_into().unwrap()) as u64;
+        if &chunk[..4] == b"COMM" {
+            let mut comm = [0u8; 18];
+            file.read_exact(&mut comm)?;
+            return Ok(Some(comm));
+        }
+        // Chunks are padded to an even length
+        file.seek(SeekFrom::Current((len + (len & 1)) as i64))?;
+    }
+    Ok(None)
+}
+
+fn codec_name(fourcc: &str) -> &str {
+    match fourcc {
+        "mp4a" => "AAC",
+        "alac" => "ALAC (Apple Lossless)",
+        other => other,
+    }
+}
+
+struct Mp4Info {
+    duration: Option<Duration>,
+    codec: Option<String>,
+    /// The sample size of the first sample description, which only means
+    /// something for lossless codecs.
+    bits_per_sample: Option<u32>,
+}
+
+/// Child atoms of an MP4 box, as (type, body start, body end) offsets.
+pub fn mp4_children<R: Read + Seek>(file: &mut R, start: u64, end: u64) -> io::Result<Vec<([u8; 4], u64, u64)>> {
+    let mut children = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let kind: [u8; 4] = header[4..].try_into().unwrap();
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // Size 0 means "until the end of the enclosing box"
+            0 => (end - pos, 8),
+            1 => {
+                let mut large = [0u8; 8];
+                file.read_exact(&mut large)?;
+                (u64::from_be_bytes(large), 16)
+            }
+            n => (n as u64, 8),
+        };
+        if size < header_len || pos + size > end {
+            break;
+        }
+        children.push((kind, pos + header_len, pos + size));
+        pos += size;
+    }
+    Ok(children)
+}
+
+pub fn find_mp4_atom<R: Read + Seek>(file: &mut R, start: u64, end: u64, path: &[&[u8; 4]]) -> io::Result<Option<(u64, u64)>> {
+    let mut range = (start, end);
+    for kind in path {
+        match mp4_children(file, range.0, range.1)?.into_iter().find(|(k, _, _)| k == *kind) {
+            Some((_, body_start, body_end)) => range = (body_start, body_end),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(range))
+}
+
+/// Duration from the `mvhd` atom and codec from the first sample description.
+/// Other atoms (cover art in `udta`, `free` padding, ...) are skipped untouched.
+fn mp4_info(path: &Path) -> io::Result<Option<Mp4Info>> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let Some((moov_start, moov_end)) = find_mp4_atom(&mut file, 0, len, &[b"moov"])? else {
+        return Ok(None);
+    };
+
+    let mut duration = None;
+    if let Some((start, _)) = find_mp4_atom(&mut file, moov_start, moov_end, &[b"mvhd"])? {
+        let mut mvhd = [0u8; 32];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut mvhd)?;
+        let (timescale, units) = if mvhd[0] == 1 {
+            (
+                u32::from_be_bytes(mvhd[20..24].try_into().unwrap()),
+                u64::from_be_bytes(mvhd[24..32].try_into().unwrap()),
+            )
+        } else {
+            (
+                u32::from_be_bytes(mvhd[12..16].try_into().unwrap()),
+                u32::from_be_bytes(mvhd[16..20].try_into().unwrap()) as u64,
+            )
+        };
+        if timescale > 0 {
+            duration = Some(Duration::from_secs_f64(units as f64 / timescale as f64));
+        }
+    }
+
+    let mut codec = None;
+    let mut bits_per_sample = None;
+    let stsd_path: [&[u8; 4]; 5] = [b"trak", b"mdia", b"minf", b"stbl", b"stsd"];
+    if let Some((start, _)) = find_mp4_atom(&mut file, moov_start, moov_end, &stsd_path)? {
+        // Skip version/flags and entry count, then read the first entry's
+        // type and, past its reserved fields, channel count and sample size
+        let mut entry = [0u8; 36];
+        file.seek(SeekFrom::Start(start))?;
+        if file.read_exact(&mut entry[..16]).is_ok() {
+            codec = Some(String::from_utf8_lossy(&entry[12..16]).into_owned());
+            if file.read_exact(&mut entry[16..]).is_ok() {
+                bits_per_sample = Some(u16::from_be_bytes([entry[34], entry[35]]) as u32);
+            }
+        }
+    }
+
+    Ok(Some(Mp4Info { duration, codec, bits_per_sample }))
+}
@@ -0,0 +1,155 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{config, store};
+
+/// The tracks a queue plays, in order, and where playback has got to. The
+/// same track can be in it more than once, so entries are known by where
+/// they are rather than by track, and every edit keeps the current entry
+/// pointing at the same one.
+pub struct Queue {
+    tracks: Vec<usize>,
+    /// Where the entry playing is, or where the one after it is once it's
+    /// been removed.
+    current: Option<usize>,
+    /// The entry playing has been removed.
+    removed: bool,
+}
+
+impl Queue {
+    pub fn new(tracks: Vec<usize>) -> Queue {
+        Queue { tracks, current: None, removed: false }
+    }
+
+    pub fn tracks(&self) -> &[usize] {
+        &self.tracks
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// The track at `position`, counting from 0.
+    pub fn get(&self, position: usize) -> Option<usize> {
+        self.tracks.get(position).copied()
+    }
+
+    /// Where `track` first comes up.
+    pub fn find(&self, track: usize) -> Option<usize> {
+        self.tracks.iter().position(|&queued| queued == track)
+    }
+
+    /// Whether anything in it has started playing.
+    pub fn started(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Where the entry playing is, unless it's been removed.
+    pub fn current(&self) -> Option<usize> {
+        self.current.filter(|_| !self.removed)
+    }
+
+    /// The entry at `position` is the one playing now.
+    pub fn play(&mut self, position: usize) {
+        self.current = Some(position);
+        self.removed = false;
+    }
+
+    /// Where the upcoming entries start: the one after the entry playing,
+    /// or where the entry playing was before it was removed.
+    pub fn upcoming(&self) -> usize {
+        match self.current {
+            Some(current) if self.removed => current,
+            Some(current) => current + 1,
+            None => 0,
+        }
+    }
+
+    /// Where the entry playing is, or where the upcoming ones start when
+    /// it's been removed: the first entry worth showing.
+    pub fn first_shown(&self) -> usize {
+        self.current.unwrap_or(0)
+    }
+
+    /// Where the entry after the one playing is, or None at the end.
+    pub fn after(&self) -> Option<usize> {
+        Some(self.upcoming()).filter(|&position| self.current.is_some() && position < self.tracks.len())
+    }
+
+    /// Where the entry before the one playing is, or None at the start.
+    pub fn before(&self) -> Option<usize> {
+        self.current?.checked_sub(1)
+    }
+
+    /// Take out the entry at `position`. Gives whether it was the one
+    /// playing.
+    pub fn remove(&mut self, position: usize) -> bool {
+        if position >= self.tracks.len() {
+            return false;
+        }
+        self.tracks.remove(position);
+        match self.current {
+            Some(current) if position < current => self.current = Some(current - 1),
+            Some(current) if position == current && !self.removed => {
+                self.removed = true;
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Swap the entry at `position` with the one after it, or the one
+    /// before when `down` is false. Gives where it's gone, or None when
+    /// there's nowhere for it to go.
+    pub fn shift(&mut self, position: usize, down: bool) -> Option<usize> {
+        let other = if down { position + 1 } else { position.checked_sub(1)? };
+        if position >= self.tracks.len() || other >= self.tracks.len() {
+            return None;
+        }
+        self.tracks.swap(position, other);
+        if let Some(current) = self.current.filter(|_| !self.removed) {
+            if current == position {
+                self.current = Some(other);
+            } else if current == other {
+                self.current = Some(position);
+            }
+        }
+        Some(other)
+    }
+
+    /// Add `track` at the end.
+    pub fn push(&mut self, track: usize) {
+        self.tracks.push(track);
+    }
+
+    /// Add `track` right after the entry playing, to play next.
+    pub fn insert_next(&mut self, track: usize) {
+        let upcoming = self.upcoming();
+        self.tracks.insert(upcoming.min(self.tracks.len()), track);
+    }
+
+    /// Take out every entry after the one playing.
+    pub fn clear_upcoming(&mut self) {
+        let upcoming = self.upcoming();
+        self.tracks.truncate(upcoming);
+    }
+}
+
+/// `queue` in the data directory: the queue from the last time the program
+/// quit, with the full path of each track on a line of its own.
+pub fn path() -> Option<PathBuf> {
+    Some(config::data_dir()?.join("queue"))
+}
+
+/// Keep `files` as the queue in the file at `path`.
+pub fn save(path: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let files: Vec<PathBuf> = files.iter().map(|file| fs::canonicalize(file).unwrap_or_else(|_| file.clone())).collect();
+    store::update(path, |_| files.iter().map(|file| format!("{}\n", file.to_string_lossy())).collect())
+}
+
+/// The tracks kept in the file at `path`. A missing file has none.
+pub fn read(path: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(path).unwrap_or_default().lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect()
+}
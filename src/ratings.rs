@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{config, store};
+
+// Most stars a track can have
+pub const MAX_STARS: u8 = 5;
+
+/// Favorite tracks and star ratings, kept in `ratings` in the data
+/// directory with a line for each track rated: `*` for a favorite or `-`,
+/// the stars, its size in bytes and its path under the card, separated by
+/// tabs. Tracks are known by the same key as their play counts. Lines for
+/// files that are no longer on the card are kept, in case they come back.
+pub struct Ratings {
+    path: PathBuf,
+    /// Where the cards are mounted this time.
+    cards: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rating {
+    pub favorite: bool,
+    /// From 1 to MAX_STARS, or 0 when it hasn't been rated.
+    pub stars: u8,
+}
+
+struct Entry {
+    rating: Rating,
+    size: u64,
+    file: String,
+}
+
+impl Ratings {
+    pub fn new(cards: &[PathBuf]) -> Option<Ratings> {
+        Some(Ratings { path: config::data_dir()?.join("ratings"), cards: cards.to_vec() })
+    }
+
+    /// The rating of each of `files`.
+    pub fn all(&self, files: &[PathBuf]) -> Vec<Rating> {
+        let text = fs::read_to_string(&self.path).unwrap_or_default();
+        let entries = parse(&text).into_iter().map(|entry| ((entry.file, entry.size), entry.rating));
+        store::lookup(&self.cards, files, entries).into_iter().map(Option::unwrap_or_default).collect()
+    }
+
+    /// Give `file` `rating`, replacing any it had, even at a different size.
+    pub fn set(&self, file: &Path, rating: Rating) -> io::Result<()> {
+        let (relative, size) = store::track_key(&self.cards, file)?;
+        store::update(&self.path, |text| {
+            let mut entries = parse(text);
+            entries.retain(|entry| entry.file != relative);
+            if rating != Rating::default() {
+                entries.push(Entry { rating, size, file: relative });
+            }
+            entries.sort_by(|a, b| a.file.cmp(&b.file));
+            entries
+                .iter()
+                .map(|entry| format!("{}\t{}\t{}\t{}\n", if entry.rating.favorite { '*' } else { '-' }, entry.rating.stars, entry.size, entry.file))
+                .collect()
+        })
+    }
+}
+
+/// The entries in the file's `text`, skipping any line that doesn't make
+/// sense.
+fn parse(text: &str) -> Vec<Entry> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let favorite = match fields.next()? {
+                "*" => true,
+                "-" => false,
+                _ => return None,
+            };
+            let stars = fields.next()?.parse().ok().filter(|&stars| stars <= MAX_STARS)?;
+            let size = fields.next()?.parse().ok()?;
+            let file = fields.next().filter(|file| !file.is_empty())?.to_string();
+            Some(Entry { rating: Rating { favorite, stars }, size, file })
+        })
+        .collect()
+}
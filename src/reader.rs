@@ -0,0 +1,305 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How much of a file `load` reads at a time, between looking to see if it's
+// been told to stop
+const CHUNK: usize = 256 * 1024;
+
+/// The last error reading the file playing, shared between the reader the
+/// decoder has and the playback thread. The decoders all just stop when a
+/// read fails, the same as at the end of the file, so this is how the
+/// playback thread tells the card going from the track ending.
+#[derive(Clone, Default)]
+pub struct Failure(Arc<Mutex<Option<io::Error>>>);
+
+impl Failure {
+    /// The error since this was last asked, if there's been one.
+    pub fn take(&self) -> Option<io::Error> {
+        self.0.lock().unwrap().take()
+    }
+
+    /// Note `error`, as what went wrong with the file playing.
+    pub fn note(&self, error: &io::Error) {
+        *self.0.lock().unwrap() = Some(io::Error::new(error.kind(), error.to_string()));
+    }
+}
+
+/// A reader that notes in a `Failure` any error reading or seeking it.
+pub struct Noting<R> {
+    inner: R,
+    failure: Failure,
+}
+
+impl<R> Noting<R> {
+    pub fn new(inner: R, failure: Failure) -> Noting<R> {
+        Noting { inner, failure }
+    }
+
+    fn note<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(e) = &result {
+            if e.kind() != io::ErrorKind::Interrupted {
+                self.failure.note(e);
+            }
+        }
+        result
+    }
+}
+
+impl<R: Read> Read for Noting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.note(result)
+    }
+}
+
+impl<R: Seek> Seek for Noting<R> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let result = self.inner.seek(to);
+        self.note(result)
+    }
+}
+
+/// How many times to try a read again after it fails, and how long to wait
+/// before the first time, which doubles each time after. Card readers can
+/// give an error once and then read fine.
+#[derive(Clone)]
+pub struct Retry {
+    attempts: u32,
+    delay: Duration,
+    /// How many reads have gone through after failing at first, with every
+    /// reader given one of these clones.
+    recovered: Arc<AtomicUsize>,
+}
+
+impl Retry {
+    pub fn new(attempts: u32, delay: Duration) -> Retry {
+        Retry { attempts, delay, recovered: Arc::default() }
+    }
+
+    pub fn recovered(&self) -> usize {
+        self.recovered.load(Ordering::SeqCst)
+    }
+}
+
+/// A reader that tries again, as `retry` says, when reading or seeking it
+/// fails, only giving the error once it's failed every time.
+pub struct Retrying<R> {
+    inner: R,
+    retry: Retry,
+}
+
+impl<R> Retrying<R> {
+    pub fn new(inner: R, retry: Retry) -> Retrying<R> {
+        Retrying { inner, retry }
+    }
+
+    fn again<T>(&mut self, mut attempt: impl FnMut(&mut R) -> io::Result<T>) -> io::Result<T> {
+        let mut delay = self.retry.delay;
+        let mut failed = 0;
+        loop {
+            match attempt(&mut self.inner) {
+                Ok(value) => {
+                    if failed > 0 {
+                        self.retry.recovered.fetch_add(1, Ordering::SeqCst);
+                    }
+                    return Ok(value);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if failed >= self.retry.attempts => return Err(e),
+                Err(_) => {
+                    failed += 1;
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Retrying<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.again(|inner| inner.read(buf))
+    }
+}
+
+impl<R: Seek> Seek for Retrying<R> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        self.again(|inner| inner.seek(to))
+    }
+}
+
+/// The whole of a file, read into memory to decode from rather than the
+/// card. Cloning it doesn't copy what's in it, so starting over after a seek
+/// doesn't need it read again.
+#[derive(Clone)]
+pub struct Loaded(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for Loaded {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Read the file at `path`, `length` bytes long, into memory, counting what's
+/// been read so far in `read` and trying again as `retry` says. None if
+/// `cancel` is set before it's done.
+pub fn load(path: &Path, length: u64, retry: &Retry, read: &AtomicU64, cancel: &AtomicBool) -> io::Result<Option<Loaded>> {
+    let mut file = Retrying::new(File::open(path)?, retry.clone());
+    let mut bytes = Vec::with_capacity(length as usize);
+    let mut chunk = vec![0; CHUNK];
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        match file.read(&mut chunk) {
+            Ok(0) => return Ok(Some(Loaded(Arc::new(bytes)))),
+            Ok(count) => {
+                bytes.extend_from_slice(&chunk[..count]);
+                read.fetch_add(count as u64, Ordering::SeqCst);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether the file at `path` can't be read at all any more, as when the
+/// card it's on has been taken out, rather than there being something wrong
+/// with what's in it. A file that's only been deleted, from a directory
+/// that's still there, can't be read either, but the card's fine.
+pub fn unreadable(path: &Path) -> bool {
+    match File::open(path).and_then(|mut file| file.read(&mut [0; 1])) {
+        Ok(_) => false,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => path.parent().is_some_and(|dir| fs::metadata(dir).is_err()),
+        Err(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Instant;
+
+    /// A reader over `data` whose next `failing` reads or seeks fail, as a
+    /// card reader's can, counting how often it's been tried.
+    struct Flaky {
+        data: Cursor<Vec<u8>>,
+        failing: u32,
+        kind: io::ErrorKind,
+        tries: u32,
+    }
+
+    impl Flaky {
+        fn new(failing: u32) -> Flaky {
+            Flaky { data: Cursor::new(b"music".to_vec()), failing, kind: io::ErrorKind::Other, tries: 0 }
+        }
+
+        fn attempt(&mut self) -> io::Result<()> {
+            self.tries += 1;
+            if self.failing == 0 {
+                return Ok(());
+            }
+            self.failing -= 1;
+            Err(io::Error::new(self.kind, "the card didn't answer"))
+        }
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.attempt()?;
+            self.data.read(buf)
+        }
+    }
+
+    impl Seek for Flaky {
+        fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+            self.attempt()?;
+            self.data.seek(to)
+        }
+    }
+
+    #[test]
+    fn read_goes_through_after_failing_a_few_times() {
+        let retry = Retry::new(3, Duration::ZERO);
+        let mut reader = Retrying::new(Flaky::new(2), retry.clone());
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"music");
+        assert_eq!(reader.inner.tries, 4);
+        assert_eq!(retry.recovered(), 1);
+    }
+
+    #[test]
+    fn error_comes_through_once_every_try_has_failed() {
+        let retry = Retry::new(3, Duration::ZERO);
+        let mut reader = Retrying::new(Flaky::new(10), retry.clone());
+        let error = reader.read(&mut [0; 4]).unwrap_err();
+        assert_eq!(error.to_string(), "the card didn't answer");
+        assert_eq!(reader.inner.tries, 4);
+        assert_eq!(retry.recovered(), 0);
+    }
+
+    #[test]
+    fn no_retries_gives_the_first_error() {
+        let mut reader = Retrying::new(Flaky::new(1), Retry::new(0, Duration::ZERO));
+        assert!(reader.read(&mut [0; 4]).is_err());
+        assert_eq!(reader.inner.tries, 1);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 4);
+    }
+
+    #[test]
+    fn seeks_are_tried_again_too() {
+        let retry = Retry::new(2, Duration::ZERO);
+        let mut reader = Retrying::new(Flaky::new(2), retry.clone());
+        assert_eq!(reader.seek(SeekFrom::Start(2)).unwrap(), 2);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "sic");
+        assert_eq!(retry.recovered(), 1);
+    }
+
+    #[test]
+    fn interruptions_do_not_count_as_failing() {
+        let mut flaky = Flaky::new(5);
+        flaky.kind = io::ErrorKind::Interrupted;
+        let retry = Retry::new(0, Duration::ZERO);
+        let mut reader = Retrying::new(flaky, retry.clone());
+        assert_eq!(reader.read(&mut [0; 5]).unwrap(), 5);
+        assert_eq!(reader.inner.tries, 6);
+        assert_eq!(retry.recovered(), 0);
+    }
+
+    #[test]
+    fn delay_doubles_each_time() {
+        let mut reader = Retrying::new(Flaky::new(3), Retry::new(3, Duration::from_millis(10)));
+        let started = Instant::now();
+        assert_eq!(reader.read(&mut [0; 5]).unwrap(), 5);
+        // 10, 20 and then 40 milliseconds
+        assert!(started.elapsed() >= Duration::from_millis(70), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn failure_is_noted_until_taken() {
+        let failure = Failure::default();
+        let mut reader = Noting::new(Flaky::new(1), failure.clone());
+        assert!(reader.read(&mut [0; 5]).is_err());
+        assert_eq!(reader.read(&mut [0; 5]).unwrap(), 5);
+        assert_eq!(failure.take().unwrap().to_string(), "the card didn't answer");
+        assert!(failure.take().is_none());
+    }
+
+    #[test]
+    fn interruptions_are_not_noted() {
+        let failure = Failure::default();
+        let mut flaky = Flaky::new(1);
+        flaky.kind = io::ErrorKind::Interrupted;
+        let mut reader = Noting::new(flaky, failure.clone());
+        assert!(reader.seek(SeekFrom::Start(0)).is_err());
+        assert!(failure.take().is_none());
+    }
+}
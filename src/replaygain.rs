@@ -0,0 +1,72 @@
+use crate::tags::Tags;
+
+// Largest pre-amp or fallback gain, either way, in dB
+pub const MAX_GAIN_DB: f32 = 20.0;
+
+/// Which of a track's ReplayGain tags set its volume.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Every track at the same loudness.
+    Track,
+    /// Whole albums at the same loudness, leaving the quiet and loud tracks
+    /// within them as they were. Tracks with only track gain use that.
+    Album,
+    Off,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "track" => Some(Mode::Track),
+            "album" => Some(Mode::Album),
+            "off" => Some(Mode::Off),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Track => "track",
+            Mode::Album => "album",
+            Mode::Off => "off",
+        }
+    }
+}
+
+/// How ReplayGain tags are turned into volume.
+#[derive(Clone, Copy)]
+pub struct ReplayGain {
+    pub mode: Mode,
+    /// Added to the gain of tagged tracks, in dB.
+    pub preamp: f32,
+    /// The gain of tracks without tags, in dB, so they aren't much louder
+    /// than the tagged ones around them.
+    pub fallback: f32,
+}
+
+impl ReplayGain {
+    /// What to multiply the volume by to play a track with `tags`. Positive
+    /// gain is held back as far as its peak allows, so it doesn't clip.
+    pub fn factor(&self, tags: &Tags) -> f32 {
+        let (gain, peak) = match self.mode {
+            Mode::Off => return 1.0,
+            Mode::Track => (tags.track_gain, tags.track_peak),
+            Mode::Album => match tags.album_gain {
+                Some(gain) => (Some(gain), tags.album_peak.or(tags.track_peak)),
+                None => (tags.track_gain, tags.track_peak),
+            },
+        };
+        let Some(gain) = gain else {
+            return db_to_factor(self.fallback);
+        };
+        let factor = db_to_factor(gain + self.preamp);
+        match peak.filter(|&peak| peak > 0.0) {
+            Some(peak) if factor * peak > 1.0 => (1.0 / peak).max(1.0).min(factor),
+            _ => factor,
+        }
+    }
+}
+
+fn db_to_factor(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
@@ -0,0 +1,493 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::terminal;
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::exclude::Exclude;
+use crate::index::{self, Directory, Index, Kind};
+use crate::music_extension;
+use crate::sort::natural_cmp;
+
+// Directories looked through at once. Most of the time goes on waiting for
+// the card to answer, so this is more than there are cores.
+const THREADS: usize = 8;
+
+// How long a scan goes before the count is shown, so quick ones don't flash
+const QUIET_TIME: Duration = Duration::from_millis(300);
+
+/// What `list_music_files` found.
+pub struct Scanned {
+    pub files: Vec<PathBuf>,
+    /// How many directories weren't looked in, being past the depth limit.
+    pub too_deep: usize,
+    /// Links that loop or lead nowhere, which were left out.
+    pub warnings: Vec<String>,
+    /// Each entry that couldn't be read, with what went wrong.
+    pub unreadable: Vec<Unreadable>,
+    /// Whether any of those couldn't be for a reason other than not being
+    /// allowed to or having just gone, as when the card's been pulled out.
+    pub failing: bool,
+    /// What was in each directory, for the index, from where the scan
+    /// started.
+    pub directories: Vec<(String, Directory)>,
+}
+
+impl Scanned {
+    fn new() -> Scanned {
+        Scanned { files: Vec::new(), too_deep: 0, warnings: Vec::new(), unreadable: Vec::new(), failing: false, directories: Vec::new() }
+    }
+
+    fn note_unreadable(&mut self, path: &Path, error: &io::Error) {
+        self.unreadable.push(Unreadable { path: path.to_path_buf(), error: error.to_string() });
+        self.failing |= !matches!(error.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound);
+    }
+
+    /// The warnings, then what went wrong with each entry that couldn't be
+    /// read, to be printed.
+    pub fn reasons(&self) -> impl Iterator<Item = String> + '_ {
+        self.warnings.iter().cloned().chain(self.unreadable.iter().map(Unreadable::reason))
+    }
+
+    fn append(&mut self, other: Scanned) {
+        self.files.extend(other.files);
+        self.too_deep += other.too_deep;
+        self.warnings.extend(other.warnings);
+        self.unreadable.extend(other.unreadable);
+        self.failing |= other.failing;
+        self.directories.extend(other.directories);
+    }
+}
+
+/// An entry a scan couldn't read.
+pub struct Unreadable {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+impl Unreadable {
+    /// What went wrong, to be printed.
+    pub fn reason(&self) -> String {
+        format!("can't read {}: {}", self.path.display(), self.error)
+    }
+}
+
+/// What's said after a scan about the entries that couldn't be read.
+pub fn unreadable_note(unreadable: usize) -> String {
+    format!("{} {} could not be read", unreadable, if unreadable == 1 { "entry" } else { "entries" })
+}
+
+/// Every music file under `path` with one of `extensions`, leaving out what
+/// `exclude` says to. The directories in `path` are looked through on
+/// threads of their own, as a card in a slow reader spends most of the
+/// time waiting on each, with a count shown meanwhile when the scan takes
+/// a while. What's in a directory that hasn't changed since `index` was
+/// written is taken from that rather than read again. Given `found`, the
+/// music files are counted there as they're found, for a scan going on
+/// while the player's open to show instead. The files come out
+/// in the same order however the threads go: those in `path` itself
+/// first, then those in each directory in it, all by name, with numbers in
+/// them in order of their value.
+pub fn list_music_files(path: &Path, extensions: &HashSet<&'static str>, exclude: &Exclude, follow_links: bool, index: Option<&Index>, found: Option<&AtomicUsize>) -> Scanned {
+    // A file given as the path is all there is to look at
+    if fs::metadata(path).is_ok_and(|metadata| metadata.is_file()) {
+        let mut scanned = Scanned::new();
+        if music_extension(path).is_some_and(|ext| extensions.contains(ext)) {
+            scanned.files.push(path.to_path_buf());
+        }
+        return scanned;
+    }
+    let walk = Walk { root: path, full_root: fs::canonicalize(path).ok(), extensions, exclude, follow_links, index, seen: AtomicUsize::new(0), found };
+    let (finished, finishing) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        scope.spawn(|| show_progress(&walk.seen, finishing));
+        let mut scanned = Scanned::new();
+        let mut directories = Vec::new();
+        let full_root = walk.full_root.clone().filter(|_| follow_links);
+        walk.directory(path, 0, full_root.clone(), &mut Vec::new(), &mut scanned, &mut |directory, full| directories.push((directory, full)));
+        let next = AtomicUsize::new(0);
+        let results: Vec<Mutex<Option<Scanned>>> = directories.iter().map(|_| Mutex::new(None)).collect();
+        thread::scope(|workers| {
+            for _ in 0..THREADS.min(directories.len()) {
+                workers.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let Some((directory, full)) = directories.get(index) else {
+                        return;
+                    };
+                    let mut scanned = Scanned::new();
+                    let mut ancestors: Vec<PathBuf> = full_root.iter().cloned().collect();
+                    walk.directory(directory, 1, full.clone(), &mut ancestors, &mut scanned, &mut |_, _| {});
+                    *results[index].lock().unwrap() = Some(scanned);
+                });
+            }
+        });
+        for result in results {
+            scanned.append(result.into_inner().unwrap().unwrap_or_else(Scanned::new));
+        }
+        drop(finished);
+        scanned
+    })
+}
+
+/// Show how many files have been looked at while a scan goes on, once it's
+/// been going long enough to be worth it, until `finished` hangs up.
+fn show_progress(seen: &AtomicUsize, finished: Receiver<()>) {
+    let started = Instant::now();
+    let mut spinner: Option<ProgressBar> = None;
+    while let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(Duration::from_millis(50)) {
+        // Not over the player, when it's a scan going on while that's open
+        if started.elapsed() < QUIET_TIME || !io::stderr().is_terminal() || terminal::is_raw_mode_enabled().unwrap_or(false) {
+            continue;
+        }
+        let spinner = spinner.get_or_insert_with(|| {
+            let spinner = ProgressBar::new_spinner();
+            if let Ok(style) = ProgressStyle::with_template("{spinner} {msg}") {
+                spinner.set_style(style);
+            }
+            spinner
+        });
+        spinner.set_message(format!("Scanned {} files…", seen.load(Ordering::SeqCst)));
+        spinner.tick();
+    }
+    if let Some(spinner) = spinner {
+        spinner.finish_and_clear();
+    }
+}
+
+/// A scan under way, shared between the threads doing it.
+struct Walk<'a> {
+    root: &'a Path,
+    /// Where the root really is, to tell when a link leads back to it.
+    full_root: Option<PathBuf>,
+    extensions: &'a HashSet<&'static str>,
+    exclude: &'a Exclude,
+    follow_links: bool,
+    index: Option<&'a Index>,
+    /// How many files have been looked at so far.
+    seen: AtomicUsize,
+    /// Where to count the music files found, if anywhere.
+    found: Option<&'a AtomicUsize>,
+}
+
+impl Walk<'_> {
+    /// Look through `directory`, `depth` directories down from the root.
+    /// Following links, `full` is where it really is and `ancestors` where
+    /// the directories it's in really are, to notice a link back to one of
+    /// them. At the top, the directories in the root are given to
+    /// `defer` rather than looked through, to be shared out.
+    fn directory(&self, directory: &Path, depth: usize, full: Option<PathBuf>, ancestors: &mut Vec<PathBuf>, scanned: &mut Scanned, defer: &mut dyn FnMut(PathBuf, Option<PathBuf>)) {
+        let Some(entries) = self.entries(directory, scanned) else {
+            return;
+        };
+        for (name, kind) in entries {
+            let path = directory.join(&name);
+            let (is_dir, is_file) = match kind {
+                Kind::Directory => (true, false),
+                Kind::File => (false, true),
+                Kind::Other => (false, false),
+                // A link is whatever it leads to
+                Kind::Link => match fs::metadata(&path) {
+                    Ok(target) if target.is_dir() && !self.follow_links => continue,
+                    Ok(target) => (target.is_dir(), target.is_file()),
+                    Err(_) => {
+                        scanned.warnings.push(format!("{} is a link to something that isn't there", path.display()));
+                        continue;
+                    }
+                },
+            };
+            // Excluded directories aren't even looked in
+            if self.exclude.excludes(self.root, &path, is_dir) {
+                continue;
+            }
+            if !is_dir {
+                self.seen.fetch_add(1, Ordering::Relaxed);
+                if is_file && music_extension(&path).is_some_and(|ext| self.extensions.contains(ext)) {
+                    scanned.files.push(path);
+                    if let Some(found) = self.found {
+                        found.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                continue;
+            }
+            if Some(depth + 1) == self.exclude.max_depth {
+                scanned.too_deep += 1;
+                continue;
+            }
+            // Following links, a directory linking back to one it's in is
+            // noticed rather than looked through forever
+            let full_path = match (&full, kind) {
+                (_, Kind::Link) => fs::canonicalize(&path).ok(),
+                (Some(full), _) => Some(full.join(&name)),
+                (None, _) => None,
+            };
+            let loops = full_path.as_ref().is_some_and(|target| ancestors.contains(target) || self.full_root.as_ref().is_some_and(|root| root.starts_with(target)));
+            if kind == Kind::Link && loops {
+                scanned.warnings.push(format!("{} links back to a directory it's in, so it isn't looked in again", path.display()));
+                continue;
+            }
+            if depth == 0 {
+                defer(path, full_path);
+                continue;
+            }
+            if let Some(full) = &full {
+                ancestors.push(full.clone());
+            }
+            self.directory(&path, depth + 1, full_path, ancestors, scanned, defer);
+            if full.is_some() {
+                ancestors.pop();
+            }
+        }
+    }
+
+    /// What's in `directory`, by name: from the index when it hasn't changed
+    /// since, or else read afresh. What was in it goes in `scanned` for the
+    /// index, unless a name in it isn't UTF-8, which the index can't keep.
+    fn entries(&self, directory: &Path, scanned: &mut Scanned) -> Option<Vec<(OsString, Kind)>> {
+        let relative = directory.strip_prefix(self.root).ok().and_then(Path::to_str).map(str::to_string);
+        let modified = fs::metadata(directory).and_then(|metadata| metadata.modified()).ok().map(index::nanoseconds);
+        if let (Some(index), Some(relative), Some(modified)) = (self.index, &relative, modified) {
+            if let Some(listing) = index.listing(relative, modified) {
+                scanned.directories.push((relative.clone(), Directory { modified, entries: listing.to_vec() }));
+                return Some(listing.iter().map(|(name, kind)| (OsString::from(name), *kind)).collect());
+            }
+        }
+        let read = match fs::read_dir(directory) {
+            Ok(read) => read,
+            // Like a directory that can't be opened, or one gone from a card
+            // that's come loose; the rest can still be looked through
+            Err(e) => {
+                scanned.note_unreadable(directory, &e);
+                return None;
+            }
+        };
+        let mut entries = Vec::new();
+        for entry in read {
+            let kind = match entry.as_ref().map(|entry| (entry.file_type(), entry)) {
+                Ok((Ok(kind), _)) if kind.is_dir() => Kind::Directory,
+                Ok((Ok(kind), _)) if kind.is_file() => Kind::File,
+                Ok((Ok(kind), _)) if kind.is_symlink() => Kind::Link,
+                Ok((Ok(_), _)) => Kind::Other,
+                Ok((Err(e), entry)) => {
+                    scanned.note_unreadable(&entry.path(), &e);
+                    continue;
+                }
+                Err(e) => {
+                    scanned.note_unreadable(directory, e);
+                    continue;
+                }
+            };
+            entries.push((entry.map(|entry| entry.file_name()).unwrap_or_default(), kind));
+        }
+        // By name as the list has them, so Track 2 comes before Track 10 even
+        // where the files aren't sorted again
+        entries.sort_by(|a, b| natural_cmp(&a.0.to_string_lossy(), &b.0.to_string_lossy()));
+        let names: Option<Vec<(String, Kind)>> = entries.iter().map(|(name, kind)| Some((name.to_str()?.to_string(), *kind))).collect();
+        if let (Some(relative), Some(modified), Some(names)) = (relative, modified, names) {
+            scanned.directories.push((relative, Directory { modified, entries: names }));
+        }
+        Some(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch;
+
+    /// An empty file at `path` under `dir`, and the directories it's in.
+    fn touch(dir: &Path, path: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"").unwrap();
+    }
+
+    /// What's listed under `dir`, from there.
+    fn listed(dir: &Path, exclude: &Exclude) -> Vec<String> {
+        let extensions = HashSet::from(["flac", "mp3"]);
+        let scanned = list_music_files(dir, &extensions, exclude, false, None, None);
+        scanned.files.iter().map(|file| file.strip_prefix(dir).unwrap().to_string_lossy().into_owned()).collect()
+    }
+
+    fn patterns(patterns: &[&str]) -> Exclude {
+        Exclude::new(&patterns.iter().map(|pattern| pattern.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn everything_is_listed_in_order() {
+        let dir = scratch("scan-order");
+        for file in ["b.flac", "a.mp3", "notes.txt", "Album 10/1.flac", "Album 9/10.flac", "Album 9/2.flac"] {
+            touch(&dir, file);
+        }
+        assert_eq!(listed(&dir, &Exclude::default()), ["a.mp3", "b.flac", "Album 9/2.flac", "Album 9/10.flac", "Album 10/1.flac"]);
+    }
+
+    #[test]
+    fn excluded_directory_is_not_looked_in() {
+        let dir = scratch("scan-prune");
+        for file in ["a.flac", "Recordings/b.flac", "Recordings/Old/c.flac", "Music/Recordings/d.flac"] {
+            touch(&dir, file);
+        }
+        // The pattern only matches the directory itself, so anything in it
+        // being left out comes from not going in
+        assert_eq!(listed(&dir, &patterns(&["Recordings"])), ["a.flac", "Music/Recordings/d.flac"]);
+        assert_eq!(listed(&dir, &patterns(&["**/Recordings"])), ["a.flac"]);
+    }
+
+    #[test]
+    fn excluded_files_are_left_out_one_by_one() {
+        let dir = scratch("scan-files");
+        for file in ["a.flac", "a.demo.flac", "Artist/b.flac", "Artist/b.demo.flac", "Artist/Album/c.demo.flac"] {
+            touch(&dir, file);
+        }
+        assert_eq!(listed(&dir, &patterns(&["**/*.demo.flac"])), ["a.flac", "Artist/b.flac"]);
+        assert_eq!(listed(&dir, &patterns(&["*.demo.flac"])), ["a.flac", "Artist/Album/c.demo.flac", "Artist/b.demo.flac", "Artist/b.flac"]);
+    }
+
+    #[test]
+    fn directory_only_pattern_keeps_files_of_that_name() {
+        let dir = scratch("scan-dir-only");
+        for file in ["Demos.flac", "Demos/a.flac"] {
+            touch(&dir, file);
+        }
+        assert_eq!(listed(&dir, &patterns(&["Demos*/"])), ["Demos.flac"]);
+        assert!(listed(&dir, &patterns(&["Demos*"])).is_empty());
+    }
+
+    #[test]
+    fn nomedia_directories_are_skipped() {
+        let dir = scratch("scan-nomedia");
+        for file in ["a.flac", "Ringtones/.nomedia", "Ringtones/ring.mp3", "Ringtones/More/ring2.mp3", "Music/b.flac", "Music/Alarms/.nomedia", "Music/Alarms/alarm.mp3", "Music/Albums/c.flac"] {
+            touch(&dir, file);
+        }
+        let mut skipping = Exclude::default();
+        skipping.nomedia = true;
+        assert_eq!(listed(&dir, &skipping), ["a.flac", "Music/Albums/c.flac", "Music/b.flac"]);
+        assert_eq!(listed(&dir, &Exclude::default()).len(), 6);
+    }
+
+    #[test]
+    fn nomedia_in_the_path_given_does_not_skip_it() {
+        let dir = scratch("scan-nomedia-root");
+        for file in [".nomedia", "a.flac"] {
+            touch(&dir, file);
+        }
+        let mut skipping = Exclude::default();
+        skipping.nomedia = true;
+        assert_eq!(listed(&dir, &skipping), ["a.flac"]);
+    }
+
+    #[test]
+    fn hidden_and_apple_double_files_are_skipped() {
+        let dir = scratch("scan-hidden");
+        for file in ["01.flac", "._01.flac", ".hidden.flac", ".Trashes/trashed.flac", ".Spotlight-V100/x.mp3", "Album/02.flac", "Album/._02.flac"] {
+            touch(&dir, file);
+        }
+        let mut skipping = Exclude::default();
+        skipping.hidden = true;
+        assert_eq!(listed(&dir, &skipping), ["01.flac", "Album/02.flac"]);
+    }
+
+    #[test]
+    fn hidden_files_can_be_kept_but_apple_double_ones_are_not() {
+        let dir = scratch("scan-hidden-kept");
+        for file in ["01.flac", "._01.flac", ".hidden.flac", ".Trashes/trashed.flac", ".Trashes/._trashed.flac"] {
+            touch(&dir, file);
+        }
+        assert_eq!(listed(&dir, &Exclude::default()), [".hidden.flac", "01.flac", ".Trashes/trashed.flac"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_that_are_not_utf8_are_kept_as_they_are() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = scratch("scan-latin1");
+        // "Café.flac" and a directory "Mötley" in Latin-1, as old FAT cards
+        // written by Windows have them
+        let file = dir.join(OsStr::from_bytes(b"Caf\xe9.flac"));
+        let nested = dir.join(OsStr::from_bytes(b"M\xf6tley")).join("a.flac");
+        fs::write(&file, b"not really music").unwrap();
+        fs::create_dir_all(nested.parent().unwrap()).unwrap();
+        fs::write(&nested, b"").unwrap();
+        let scanned = list_music_files(&dir, &HashSet::from(["flac"]), &Exclude::default(), false, None, None);
+        assert_eq!(scanned.files, [file.clone(), nested]);
+        assert!(scanned.unreadable.is_empty());
+        assert_eq!(fs::read(&scanned.files[0]).unwrap(), b"not really music");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_that_cannot_be_read_are_noted_and_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch("scan-denied");
+        for file in ["a.flac", "Locked/b.flac", "Open/c.flac", "Open/Locked/d.flac", "Open/e.flac"] {
+            touch(&dir, file);
+        }
+        let locked = [dir.join("Locked"), dir.join("Open/Locked")];
+        for locked in &locked {
+            fs::set_permissions(locked, fs::Permissions::from_mode(0o000)).unwrap();
+        }
+        // Root can read them anyway, so there's nothing to see
+        let readable = fs::read_dir(&locked[0]).is_ok();
+        let scanned = list_music_files(&dir, &HashSet::from(["flac"]), &Exclude::default(), false, None, None);
+        for locked in &locked {
+            fs::set_permissions(locked, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        if readable {
+            return;
+        }
+        assert_eq!(scanned.files, [dir.join("a.flac"), dir.join("Open/c.flac"), dir.join("Open/e.flac")]);
+        let unreadable: Vec<&Path> = scanned.unreadable.iter().map(|entry| entry.path.as_path()).collect();
+        assert_eq!(unreadable, [locked[0].as_path(), locked[1].as_path()]);
+        assert!(scanned.reasons().all(|reason| reason.starts_with("can't read ")));
+        assert!(!scanned.failing);
+    }
+
+    #[test]
+    fn unreadable_entries_are_counted() {
+        assert_eq!(unreadable_note(1), "1 entry could not be read");
+        assert_eq!(unreadable_note(3), "3 entries could not be read");
+    }
+
+    // Run with `cargo test -- --ignored --nocapture` to see the timings. The
+    // files are on the local disk, so this shows the cost of the threads
+    // more than what they save on a card that's slow to answer.
+    #[test]
+    #[ignore]
+    fn threads_against_one_walk_over_ten_thousand_files() {
+        let dir = scratch("scan-timing");
+        for artist in 0..100 {
+            for album in 0..10 {
+                let album = dir.join(format!("Artist {}/Album {}", artist, album));
+                fs::create_dir_all(&album).unwrap();
+                for track in 0..10 {
+                    fs::write(album.join(format!("{:02}.flac", track)), b"").unwrap();
+                }
+            }
+        }
+        let extensions = HashSet::from(["flac"]);
+        let started = Instant::now();
+        let threaded = list_music_files(&dir, &extensions, &Exclude::default(), false, None, None).files.len();
+        let threads = started.elapsed();
+        let started = Instant::now();
+        let serial = walkdir::WalkDir::new(&dir)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file() && music_extension(entry.path()).is_some_and(|ext| extensions.contains(ext)))
+            .count();
+        let one = started.elapsed();
+        println!("{} threads: {:?}, one walk: {:?}", THREADS, threads, one);
+        assert_eq!((threaded, serial), (10_000, 10_000));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::{Duration, Instant};
+
+// How long the music fades out for before the timer runs out
+pub const FADE: Duration = Duration::from_secs(10);
+
+// Longest timer that can be set, so a typo can't set one for years
+const MAX: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What's typed at the sleep prompt.
+pub enum Change {
+    /// Stop after this long from now.
+    Set(Duration),
+    /// Stop this much later than already set, or from now without a timer.
+    Extend(Duration),
+    Cancel,
+}
+
+/// Parse what's typed at the sleep prompt: a duration, `+` and a duration to
+/// add to the timer, or `off` or `0` to cancel it.
+pub fn parse_change(text: &str) -> Result<Change, String> {
+    let text = text.trim();
+    if text.eq_ignore_ascii_case("off") || text == "0" {
+        return Ok(Change::Cancel);
+    }
+    match text.strip_prefix('+') {
+        Some(extra) => parse_duration(extra).map(Change::Extend),
+        None => parse_duration(text).map(Change::Set),
+    }
+}
+
+/// Parse a duration like `45m`, `1h30m` or `90s`. A plain number is minutes.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}': expected something like 45m, 1h30m or 90s", text);
+    let text = text.trim();
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+        let minutes: u64 = text.parse().map_err(|_| invalid())?;
+        return check(Duration::from_secs(minutes.saturating_mul(60))).ok_or_else(invalid);
+    }
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let amount: u64 = digits.parse().map_err(|_| invalid())?;
+        total = total.saturating_add(amount.saturating_mul(unit));
+        digits.clear();
+    }
+    if !digits.is_empty() || text.is_empty() {
+        return Err(invalid());
+    }
+    check(Duration::from_secs(total)).ok_or_else(invalid)
+}
+
+fn check(duration: Duration) -> Option<Duration> {
+    (duration > Duration::ZERO && duration <= MAX).then_some(duration)
+}
+
+/// The deadline after applying `change` to the timer running out at
+/// `deadline`, if any.
+pub fn apply(deadline: Option<Instant>, change: Change) -> Option<Instant> {
+    let now = Instant::now();
+    match change {
+        Change::Set(duration) => Some(now + duration),
+        Change::Extend(duration) => Some(deadline.filter(|&deadline| deadline > now).unwrap_or(now) + duration),
+        Change::Cancel => None,
+    }
+}
+
+/// What to multiply the volume by with the timer running out at `deadline`:
+/// falling from 1 to 0 over the last FADE.
+pub fn fade_level(deadline: Option<Instant>) -> f32 {
+    match deadline {
+        Some(deadline) => (deadline.saturating_duration_since(Instant::now()).as_secs_f32() / FADE.as_secs_f32()).min(1.0),
+        None => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minutes(minutes: u64) -> Duration {
+        Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn durations_in_hours_minutes_and_seconds() {
+        assert_eq!(parse_duration("45m"), Ok(minutes(45)));
+        assert_eq!(parse_duration("1h30m"), Ok(minutes(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1H5M10S"), Ok(Duration::from_secs(3910)));
+        assert_eq!(parse_duration(" 2h "), Ok(minutes(120)));
+    }
+
+    #[test]
+    fn plain_number_is_minutes() {
+        assert_eq!(parse_duration("45"), Ok(minutes(45)));
+        assert_eq!(parse_duration("1440"), Ok(minutes(1440)));
+    }
+
+    #[test]
+    fn malformed_durations_are_rejected() {
+        assert_eq!(parse_duration("soon"), Err("Invalid duration 'soon': expected something like 45m, 1h30m or 90s".to_string()));
+        for text in ["", "m", "1h30", "1.5h", "-5m", "5 m", "1d"] {
+            assert!(parse_duration(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn durations_out_of_range_are_rejected() {
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("24h1s").is_err());
+        assert!(parse_duration("1441").is_err());
+        assert!(parse_duration("99999999999999999999h").is_err());
+        assert_eq!(parse_duration("24h"), Ok(minutes(24 * 60)));
+    }
+
+    #[test]
+    fn changes_set_extend_or_cancel() {
+        assert!(matches!(parse_change("45m"), Ok(Change::Set(duration)) if duration == minutes(45)));
+        assert!(matches!(parse_change("+15"), Ok(Change::Extend(duration)) if duration == minutes(15)));
+        assert!(matches!(parse_change(" +1h "), Ok(Change::Extend(duration)) if duration == minutes(60)));
+        assert!(matches!(parse_change("off"), Ok(Change::Cancel)));
+        assert!(matches!(parse_change("OFF"), Ok(Change::Cancel)));
+        assert!(matches!(parse_change("0"), Ok(Change::Cancel)));
+        assert!(parse_change("+").is_err());
+        assert!(parse_change("++5m").is_err());
+        assert!(parse_change("-5m").is_err());
+    }
+
+    #[test]
+    fn extending_adds_to_the_time_left() {
+        let now = Instant::now();
+        let deadline = apply(Some(now + minutes(10)), Change::Extend(minutes(5))).unwrap();
+        assert!(deadline >= now + minutes(15));
+        assert!(deadline < now + minutes(16));
+        // Without a timer, or one that's run out, from now
+        let fresh = apply(None, Change::Extend(minutes(5))).unwrap();
+        assert!(fresh >= now + minutes(5) && fresh < now + minutes(6));
+        assert!(apply(Some(now + minutes(10)), Change::Cancel).is_none());
+    }
+
+    #[test]
+    fn fade_falls_over_the_last_seconds() {
+        assert_eq!(fade_level(None), 1.0);
+        assert_eq!(fade_level(Some(Instant::now() + minutes(5))), 1.0);
+        let halfway = fade_level(Some(Instant::now() + FADE / 2));
+        assert!((0.45..=0.5).contains(&halfway), "{}", halfway);
+        assert_eq!(fade_level(Some(Instant::now())), 0.0);
+    }
+}
@@ -0,0 +1,250 @@
+use std::cmp::Ordering;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::tags::TagCache;
+use crate::track_name;
+
+/// What the track list and queue are sorted by.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// The whole path, so each directory's files stay together.
+    Path,
+    /// The file name alone.
+    Name,
+    /// Modification time, newest first.
+    Mtime,
+    /// Modification time, oldest first.
+    MtimeAscending,
+    /// File size, biggest first.
+    Size,
+    /// File size, smallest first.
+    SizeAscending,
+    /// Album, then track number from the tags.
+    Track,
+}
+
+const SORT_KEYS: [SortKey; 7] = [SortKey::Path, SortKey::Name, SortKey::Mtime, SortKey::MtimeAscending, SortKey::Size, SortKey::SizeAscending, SortKey::Track];
+
+impl SortKey {
+    pub fn from_name(name: &str) -> Option<SortKey> {
+        SORT_KEYS.iter().copied().find(|key| key.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortKey::Path => "path",
+            SortKey::Name => "name",
+            SortKey::Mtime => "mtime",
+            SortKey::MtimeAscending => "mtime-asc",
+            SortKey::Size => "size",
+            SortKey::SizeAscending => "size-asc",
+            SortKey::Track => "track",
+        }
+    }
+
+    /// What the list's footer says it's sorted by.
+    pub fn label(self) -> &'static str {
+        match self {
+            SortKey::Path => "sorted by path",
+            SortKey::Name => "sorted by name",
+            SortKey::Mtime => "sorted by date, newest first",
+            SortKey::MtimeAscending => "sorted by date, oldest first",
+            SortKey::Size => "sorted by size, biggest first",
+            SortKey::SizeAscending => "sorted by size, smallest first",
+            SortKey::Track => "sorted by track",
+        }
+    }
+
+    /// The key after this one, for cycling through them with a key press.
+    pub fn next(self) -> SortKey {
+        let index = SORT_KEYS.iter().position(|&key| key == self).unwrap_or(0);
+        SORT_KEYS[(index + 1) % SORT_KEYS.len()]
+    }
+}
+
+/// The order tracks are listed or played in, of all of them or just some,
+/// like an album's. Tracks keep their index into the file list whatever the
+/// order, so the one playing or highlighted is still the same file after
+/// sorting again.
+#[derive(Clone)]
+pub struct Order {
+    tracks: Vec<usize>,
+    /// Where each track is in `tracks`, indexed by track; None for tracks
+    /// left out.
+    positions: Vec<Option<usize>>,
+}
+
+impl Order {
+    pub fn new(tracks: Vec<usize>) -> Order {
+        let len = tracks.iter().max().map_or(0, |&last| last + 1);
+        let mut positions = vec![None; len];
+        for (position, &track) in tracks.iter().enumerate() {
+            positions[track] = Some(position);
+        }
+        Order { tracks, positions }
+    }
+
+    /// Sort `music_files` by `key`. Ties keep the order of their paths, so
+    /// the result doesn't depend on the order the files were found in, or
+    /// of their names when sorting by date or size.
+    pub fn sorted(music_files: &[PathBuf], tags: &TagCache, key: SortKey) -> Order {
+        let mut tracks: Vec<usize> = (0..music_files.len()).collect();
+        tracks.sort_by(|&a, &b| natural_cmp(&music_files[a].to_string_lossy(), &music_files[b].to_string_lossy()));
+        match key {
+            SortKey::Path => {}
+            SortKey::Name => sort_by_name(&mut tracks, music_files),
+            SortKey::Mtime | SortKey::MtimeAscending => {
+                let modified: Vec<Option<SystemTime>> = music_files
+                    .iter()
+                    .map(|file| fs::metadata(file).and_then(|metadata| metadata.modified()).ok())
+                    .collect();
+                sort_by_name(&mut tracks, music_files);
+                // Files whose time can't be read go last either way
+                match key {
+                    SortKey::Mtime => tracks.sort_by(|&a, &b| modified[b].cmp(&modified[a])),
+                    _ => tracks.sort_by(|&a, &b| modified[a].is_none().cmp(&modified[b].is_none()).then_with(|| modified[a].cmp(&modified[b]))),
+                }
+            }
+            SortKey::Size | SortKey::SizeAscending => {
+                let sizes: Vec<Option<u64>> = music_files.iter().map(|file| fs::metadata(file).map(|metadata| metadata.len()).ok()).collect();
+                sort_by_name(&mut tracks, music_files);
+                match key {
+                    SortKey::Size => tracks.sort_by(|&a, &b| sizes[b].cmp(&sizes[a])),
+                    _ => tracks.sort_by(|&a, &b| sizes[a].is_none().cmp(&sizes[b].is_none()).then_with(|| sizes[a].cmp(&sizes[b]))),
+                }
+            }
+            SortKey::Track => tracks.sort_by(|&a, &b| {
+                let (a, b) = (tags.get(a, &music_files[a]), tags.get(b, &music_files[b]));
+                natural_cmp(a.album.as_deref().unwrap_or(""), b.album.as_deref().unwrap_or(""))
+                    // Tracks without a number go after the numbered ones
+                    .then_with(|| a.track.is_none().cmp(&b.track.is_none()))
+                    .then_with(|| a.track.cmp(&b.track))
+            }),
+        }
+        Order::new(tracks)
+    }
+
+    /// The order of only those tracks `keep` says to.
+    pub fn retain(&self, keep: impl Fn(usize) -> bool) -> Order {
+        Order::new(self.tracks.iter().copied().filter(|&track| keep(track)).collect())
+    }
+
+    pub fn tracks(&self) -> &[usize] {
+        &self.tracks
+    }
+
+    /// Where `track` is in the order, counting from 0.
+    pub fn position(&self, track: usize) -> Option<usize> {
+        self.positions.get(track).copied().flatten()
+    }
+}
+
+/// Sort `tracks` by the names of their files in `music_files`.
+fn sort_by_name(tracks: &mut [usize], music_files: &[PathBuf]) {
+    tracks.sort_by(|&a, &b| natural_cmp(&track_name(&music_files[a]), &track_name(&music_files[b])));
+}
+
+/// Compare names the way people read them rather than by the locale:
+/// ignoring case, and with runs of digits compared by their value, so
+/// "Track 2" comes before "Track 10". Names that only differ in case or
+/// leading zeros fall back to comparing their bytes, so only identical
+/// names are equal.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut left, mut right) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let ordering = match (left.peek().copied(), right.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut left);
+                let y = take_number(&mut right);
+                let (x, y) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                x.len().cmp(&y.len()).then_with(|| x.cmp(y))
+            }
+            (Some(x), Some(y)) => {
+                left.next();
+                right.next();
+                x.to_lowercase().cmp(y.to_lowercase())
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        names.sort_by(|a, b| natural_cmp(a, b));
+        names
+    }
+
+    #[test]
+    fn numbers_go_by_their_value() {
+        assert_eq!(sorted(&["Track 10.flac", "Track 2.flac", "Track 1.flac"]), ["Track 1.flac", "Track 2.flac", "Track 10.flac"]);
+        assert_eq!(natural_cmp("9", "10"), Ordering::Less);
+        assert_eq!(natural_cmp("a100", "a99"), Ordering::Greater);
+    }
+
+    #[test]
+    fn leading_zeros_do_not_change_the_value() {
+        assert_eq!(sorted(&["10", "02", "1"]), ["1", "02", "10"]);
+        assert_eq!(natural_cmp("007", "8"), Ordering::Less);
+        // The same value, so the bytes decide, and only the same name is equal
+        assert_eq!(natural_cmp("01", "1"), Ordering::Less);
+        assert_eq!(natural_cmp("1", "01"), Ordering::Greater);
+        assert_eq!(natural_cmp("01", "01"), Ordering::Equal);
+    }
+
+    #[test]
+    fn every_number_group_counts() {
+        assert_eq!(sorted(&["Disc 2 Track 1", "Disc 1 Track 10", "Disc 1 Track 9", "Disc 10 Track 1"]), ["Disc 1 Track 9", "Disc 1 Track 10", "Disc 2 Track 1", "Disc 10 Track 1"]);
+        assert_eq!(natural_cmp("1.10.2", "1.2.10"), Ordering::Greater);
+        assert_eq!(natural_cmp("v2-rc3", "v2-rc12"), Ordering::Less);
+    }
+
+    #[test]
+    fn numbers_too_big_for_an_integer() {
+        assert_eq!(natural_cmp("99999999999999999999999", "100000000000000000000000"), Ordering::Less);
+        assert_eq!(natural_cmp("000000000000000000000005", "6"), Ordering::Less);
+    }
+
+    #[test]
+    fn case_is_ignored() {
+        assert_eq!(sorted(&["b", "C", "a", "B"]), ["a", "B", "b", "C"]);
+        assert_eq!(natural_cmp("ABBA", "abba"), Ordering::Less);
+        assert_eq!(natural_cmp("abba", "Accept"), Ordering::Less);
+    }
+
+    #[test]
+    fn unicode_names() {
+        assert_eq!(natural_cmp("Éclair 2", "éclair 10"), Ordering::Less);
+        assert_eq!(natural_cmp("ÖSTERREICH", "österreich"), Ordering::Less);
+        assert_eq!(sorted(&["Кино 10", "кино 9", "Кино 1"]), ["Кино 1", "кино 9", "Кино 10"]);
+        assert_eq!(sorted(&["日本 12", "日本 3"]), ["日本 3", "日本 12"]);
+        // Only ASCII digits are numbers
+        assert_eq!(natural_cmp("٣", "10"), Ordering::Greater);
+    }
+
+    #[test]
+    fn shorter_name_comes_first() {
+        assert_eq!(natural_cmp("Track", "Track 1"), Ordering::Less);
+        assert_eq!(natural_cmp("", "a"), Ordering::Less);
+        assert_eq!(natural_cmp("", ""), Ordering::Equal);
+    }
+}
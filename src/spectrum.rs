@@ -0,0 +1,155 @@
+use std::f32::consts::PI;
+use std::time::Instant;
+
+use crate::BarStyle;
+
+// Samples in each window the spectrum is worked out from; a power of two
+pub const WINDOW: usize = 1024;
+
+// Rows the bars are drawn in, and the columns each takes with its gap
+const ROWS: usize = 4;
+const BAND_COLUMNS: usize = 3;
+// Fewer bands than this fit and the spectrum isn't worth showing
+const MIN_BANDS: usize = 8;
+const MAX_BANDS: usize = 32;
+
+// What the bands cover, in Hz, and the range of levels shown, in dBFS
+const LOWEST: f32 = 40.0;
+const HIGHEST: f32 = 16000.0;
+const FLOOR_DB: f32 = -60.0;
+
+// How fast bars fall back, in full heights per second, so they don't flicker
+const FALL_RATE: f32 = 1.5;
+
+const EIGHTHS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Bars for the loudness of each band of frequencies in what's playing,
+/// worked out again on each redraw.
+#[derive(Default)]
+pub struct Spectrum {
+    /// From 0 for nothing to 1 for full scale, lowest band first.
+    levels: Vec<f32>,
+    updated: Option<Instant>,
+}
+
+impl Spectrum {
+    /// Work out the bands again from `frames`, the latest ones played, for a
+    /// terminal `columns` wide. Without any frames, as while paused, the
+    /// bars fall away.
+    pub fn update(&mut self, frames: &[[f32; 2]], sample_rate: u32, columns: usize) {
+        let bands = (columns / BAND_COLUMNS).min(MAX_BANDS);
+        let now = Instant::now();
+        let fall = self.updated.map_or(1.0, |updated| (now - updated).as_secs_f32() * FALL_RATE);
+        self.updated = Some(now);
+        if bands < MIN_BANDS {
+            self.levels.clear();
+            return;
+        }
+        if self.levels.len() != bands {
+            self.levels = vec![0.0; bands];
+        }
+        let measured = if frames.len() == WINDOW && sample_rate > 0 {
+            band_levels(frames, sample_rate, bands)
+        } else {
+            vec![0.0; bands]
+        };
+        for (level, new) in self.levels.iter_mut().zip(measured) {
+            *level = new.max(*level - fall);
+        }
+    }
+
+    /// The rows of bars, top first, or none when the terminal is too narrow.
+    pub fn lines(&self, style: BarStyle) -> Vec<String> {
+        if self.levels.is_empty() {
+            return Vec::new();
+        }
+        (0..ROWS)
+            .map(|row| {
+                // In eighths of a row, from the bottom
+                let base = (ROWS - 1 - row) * 8;
+                let mut line = String::new();
+                for level in &self.levels {
+                    let eighths = ((level * (ROWS * 8) as f32).round() as usize).saturating_sub(base).min(8);
+                    let cell = match style {
+                        _ if eighths == 0 => ' ',
+                        BarStyle::Unicode => EIGHTHS[eighths - 1],
+                        BarStyle::Ascii if eighths >= 4 => '#',
+                        BarStyle::Ascii => ' ',
+                    };
+                    line.extend(std::iter::repeat_n(cell, BAND_COLUMNS - 1));
+                    line.push(' ');
+                }
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+}
+
+/// The level of each of `bands` bands, spaced evenly in pitch, of the mono
+/// mix of `frames`.
+fn band_levels(frames: &[[f32; 2]], sample_rate: u32, bands: usize) -> Vec<f32> {
+    // A Hann window, so a tone between two bins doesn't smear over the rest
+    let mut re: Vec<f32> = frames
+        .iter()
+        .enumerate()
+        .map(|(i, [left, right])| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / WINDOW as f32).cos();
+            (left + right) / 2.0 * window
+        })
+        .collect();
+    let mut im = vec![0.0; WINDOW];
+    fft(&mut re, &mut im);
+
+    let bin_width = sample_rate as f32 / WINDOW as f32;
+    let highest = HIGHEST.min(sample_rate as f32 / 2.0);
+    let ratio = (highest / LOWEST).powf(1.0 / bands as f32);
+    (0..bands)
+        .map(|band| {
+            let low = LOWEST * ratio.powi(band as i32);
+            let first = ((low / bin_width) as usize).max(1);
+            // Low bands are narrower than a bin, and share one
+            let last = ((low * ratio / bin_width) as usize).clamp(first + 1, WINDOW / 2);
+            let magnitude = (first..last).map(|bin| re[bin].hypot(im[bin])).fold(0.0, f32::max);
+            // A full-scale sine comes to a quarter of the window, with the
+            // Hann window's loss
+            let db = 20.0 * (magnitude * 4.0 / WINDOW as f32).max(1e-10).log10();
+            ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+        })
+        .collect()
+}
+
+/// An in-place radix-2 FFT of the complex signal `re` + i`im`, whose length
+/// has to be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_re, w_im) = ((angle * k as f32).cos(), (angle * k as f32).sin());
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len *= 2;
+    }
+}
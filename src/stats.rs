@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::history::quote;
+use crate::reader::{Failure, Retry};
+use crate::{open_source, probe};
+
+/// How much there is of one kind of file.
+#[derive(Default)]
+pub struct Tally {
+    pub files: usize,
+    pub bytes: u64,
+    /// How long those whose length could be told last, together.
+    pub playtime: Duration,
+}
+
+impl Tally {
+    fn add(&mut self, bytes: u64, length: Option<Duration>) {
+        self.files += 1;
+        self.bytes += bytes;
+        self.playtime += length.unwrap_or_default();
+    }
+
+    fn to_json(&self) -> String {
+        format!("{{\"files\":{},\"bytes\":{},\"playtime_ms\":{}}}", self.files, self.bytes, self.playtime.as_millis())
+    }
+}
+
+/// What's in a library, added up a file at a time.
+#[derive(Default)]
+pub struct Stats {
+    pub total: Tally,
+    /// The same, for each extension.
+    pub formats: BTreeMap<&'static str, Tally>,
+    pub longest: Option<(PathBuf, Duration)>,
+    pub shortest: Option<(PathBuf, Duration)>,
+    /// How many files' lengths couldn't be told.
+    pub unknown_length: usize,
+    /// What went wrong with each file or directory that couldn't be read.
+    pub unreadable: Vec<String>,
+}
+
+impl Stats {
+    /// Count the file at `path`, with the extension `format`, `bytes` long and
+    /// lasting `length`, if that could be told.
+    pub fn add(&mut self, path: &Path, format: &'static str, bytes: u64, length: Option<Duration>) {
+        self.total.add(bytes, length);
+        self.formats.entry(format).or_default().add(bytes, length);
+        let Some(length) = length else {
+            self.unknown_length += 1;
+            return;
+        };
+        if self.longest.as_ref().is_none_or(|(_, longest)| length > *longest) {
+            self.longest = Some((path.to_path_buf(), length));
+        }
+        if self.shortest.as_ref().is_none_or(|(_, shortest)| length < *shortest) {
+            self.shortest = Some((path.to_path_buf(), length));
+        }
+    }
+
+    /// The stats as a JSON object, for scripts.
+    pub fn to_json(&self) -> String {
+        let formats: Vec<String> = self.formats.iter().map(|(format, tally)| format!("{}:{}", quote(format), tally.to_json())).collect();
+        let track = |track: &Option<(PathBuf, Duration)>| match track {
+            Some((path, length)) => format!("{{\"path\":{},\"length_ms\":{}}}", quote(&path.to_string_lossy()), length.as_millis()),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"files\":{},\"bytes\":{},\"playtime_ms\":{},\"unknown_length\":{},\"unreadable\":{},\"formats\":{{{}}},\"longest\":{},\"shortest\":{}}}",
+            self.total.files,
+            self.total.bytes,
+            self.total.playtime.as_millis(),
+            self.unknown_length,
+            self.unreadable.len(),
+            formats.join(","),
+            track(&self.longest),
+            track(&self.shortest)
+        )
+    }
+}
+
+/// How long the track at `path` lasts: from its headers, which is quick,
+/// unless `accurate` says to decode the whole of it, reading it as `retry`
+/// says. None when it can't be told either way.
+pub fn length(path: &Path, accurate: bool, retry: &Retry) -> Option<Duration> {
+    if !accurate {
+        if let Some(length) = probe::estimate_duration(path) {
+            return Some(length);
+        }
+    }
+    let source = open_source(path, None, retry, &Failure::default()).ok()?;
+    if !accurate {
+        return source.total_duration();
+    }
+    let rate = f64::from(source.sample_rate()) * f64::from(source.channels());
+    (rate > 0.0).then(|| Duration::from_secs_f64(source.count() as f64 / rate))
+}
@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::history::quote;
+use crate::store;
+
+/// The track playing, as the status file says.
+pub struct Playing<'a> {
+    pub path: &'a Path,
+    pub title: Option<&'a str>,
+    pub artist: Option<&'a str>,
+    pub elapsed: Duration,
+    /// None when the length is unknown, as for a stream.
+    pub duration: Option<Duration>,
+    pub paused: bool,
+}
+
+/// A small JSON file saying what's playing, with --status-file, for a
+/// status bar or the like to read. It's written again whenever anything in
+/// it changes, but how far into the track it is only every so often, so the
+/// disk isn't written to all the time.
+pub struct StatusFile {
+    path: PathBuf,
+    /// How often at most the time into the track is written.
+    interval: Duration,
+    /// What was last written, apart from the time, and when, so the time
+    /// can be written only as often as `interval` says.
+    written: Option<(String, Instant)>,
+    /// The whole seconds into the track last written.
+    elapsed: u64,
+}
+
+impl StatusFile {
+    pub fn new(path: PathBuf, interval: Duration) -> StatusFile {
+        StatusFile { path, interval, written: None, elapsed: 0 }
+    }
+
+    /// Say that `playing` is playing, or nothing is, at `volume` percent,
+    /// if that's different from what the file says. Failing to write it
+    /// isn't worth interrupting the music for, so it's left to be tried
+    /// again next time.
+    pub fn update(&mut self, playing: Option<&Playing>, volume: u32, muted: bool) {
+        let fields = fields(playing, volume, muted);
+        let elapsed = playing.map_or(0, |playing| playing.elapsed.as_secs());
+        let due = match &self.written {
+            Some((written, at)) => *written != fields || (elapsed != self.elapsed && at.elapsed() >= self.interval),
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        let elapsed_ms = playing.map_or("null".to_string(), |playing| playing.elapsed.as_millis().to_string());
+        if store::replace(&self.path, &format!("{{{},\"elapsed_ms\":{}}}\n", fields, elapsed_ms)).is_ok() {
+            self.written = Some((fields, Instant::now()));
+            self.elapsed = elapsed;
+        }
+    }
+
+    /// Say that it's stopped, as the program's quitting.
+    pub fn stop(&mut self, volume: u32, muted: bool) {
+        self.written = None;
+        self.update(None, volume, muted);
+    }
+}
+
+/// Everything the status file says but the time into the track, as JSON
+/// object members.
+fn fields(playing: Option<&Playing>, volume: u32, muted: bool) -> String {
+    let text = |text: Option<&str>| text.map_or("null".to_string(), quote);
+    let state = match playing {
+        Some(playing) if playing.paused => "paused",
+        Some(_) => "playing",
+        None => "stopped",
+    };
+    format!(
+        "\"state\":{},\"path\":{},\"title\":{},\"artist\":{},\"duration_ms\":{},\"paused\":{},\"volume\":{},\"muted\":{}",
+        quote(state),
+        text(playing.map(|playing| playing.path.to_string_lossy()).as_deref()),
+        text(playing.and_then(|playing| playing.title)),
+        text(playing.and_then(|playing| playing.artist)),
+        playing.and_then(|playing| playing.duration).map_or("null".to_string(), |duration| duration.as_millis().to_string()),
+        playing.is_some_and(|playing| playing.paused),
+        volume,
+        muted
+    )
+}
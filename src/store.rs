@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+// How long to wait for another copy of the program to finish writing, and
+// how old its lock has to be to have been left behind by a crash
+const LOCK_WAIT: Duration = Duration::from_secs(1);
+const STALE_LOCK: Duration = Duration::from_secs(10);
+
+/// Read the file at `path`, change its text and write it back, holding a
+/// lock so other copies of the program playing at the same time don't lose
+/// each other's changes. The new file is written beside the old one and
+/// moved over it, so it's never left half written. A missing file reads as
+/// empty.
+pub fn update(path: &Path, change: impl FnOnce(&str) -> String) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let lock = path.with_extension("lock");
+    acquire(&lock)?;
+    let result = (|| {
+        let text = fs::read_to_string(path).or_else(|e| if e.kind() == ErrorKind::NotFound { Ok(String::new()) } else { Err(e) })?;
+        replace(path, &change(&text))
+    })();
+    let _ = fs::remove_file(&lock);
+    result
+}
+
+/// Write `text` to the file at `path` beside it and move it over the old
+/// one, so what's reading it never sees it half written.
+pub fn replace(path: &Path, text: &str) -> io::Result<()> {
+    let temp = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// What a track on the card is known by in what's kept about it, like its
+/// play count: its path under whichever of `cards`, where the cards are
+/// mounted this time, it's on, and its size in bytes. That way it carries
+/// over when a card is mounted somewhere else.
+pub fn track_key(cards: &[PathBuf], file: &Path) -> io::Result<(String, u64)> {
+    Ok((relative(cards, file), fs::metadata(file)?.len()))
+}
+
+/// The path of `file` under the outermost of `cards` it's in, so it's the
+/// same whichever of them were given.
+fn relative(cards: &[PathBuf], file: &Path) -> String {
+    cards
+        .iter()
+        .filter_map(|card| file.strip_prefix(card).ok())
+        .max_by_key(|relative| relative.components().count())
+        .unwrap_or(file)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// What's kept in `entries`, by track key, for each of `files`. Only the
+/// files with an entry for their path are looked at on the card, to check
+/// their sizes, so files that have gone are simply never matched.
+pub fn lookup<T: Copy>(cards: &[PathBuf], files: &[PathBuf], entries: impl IntoIterator<Item = ((String, u64), T)>) -> Vec<Option<T>> {
+    let mut by_file: HashMap<String, Vec<(u64, T)>> = HashMap::new();
+    for ((file, size), value) in entries {
+        by_file.entry(file).or_default().push((size, value));
+    }
+    files
+        .iter()
+        .map(|file| {
+            let sizes = by_file.get(&relative(cards, file))?;
+            let size = fs::metadata(file).ok()?.len();
+            sizes.iter().find(|(entry_size, _)| *entry_size == size).map(|&(_, value)| value)
+        })
+        .collect()
+}
+
+/// Create the lock file at `lock`, waiting for whoever has it, and taking it
+/// over once it's old enough that they must have gone.
+fn acquire(lock: &Path) -> io::Result<()> {
+    let mut waited = Duration::ZERO;
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(lock) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+        let age = fs::metadata(lock).and_then(|metadata| metadata.modified()).ok().and_then(|modified| modified.elapsed().ok());
+        if age.is_some_and(|age| age > STALE_LOCK) {
+            let _ = fs::remove_file(lock);
+            continue;
+        }
+        if waited >= LOCK_WAIT {
+            return Err(io::Error::new(ErrorKind::WouldBlock, format!("{} is locked", lock.display())));
+        }
+        thread::sleep(Duration::from_millis(20));
+        waited += Duration::from_millis(20);
+    }
+}
@@ -0,0 +1,636 @@
+use std::cell::OnceCell;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::index::{self, Stamped};
+use crate::probe::{find_mp4_atom, id3v2_len, mp4_children};
+
+// Longest tag value shown; anything past it is cut off
+const MAX_VALUE_CHARS: usize = 200;
+
+// Most of a tag that's read, so embedded cover art in a huge tag can't
+// make listing crawl
+const MAX_TAG_READ: usize = 1 << 20;
+
+/// The tags shown in place of a file's name, and used for sorting and
+/// filtering.
+#[derive(Clone, Default)]
+pub struct Tags {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    /// Position on the album.
+    pub track: Option<u32>,
+    pub genre: Option<String>,
+    /// Year of release, from a date that may be more precise.
+    pub year: Option<u32>,
+    /// ReplayGain adjustments in dB, and the peaks they were worked out
+    /// from, as a fraction of full scale.
+    pub track_gain: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl Tags {
+    /// `Artist – Title`, or just the title when there's no artist.
+    pub fn display_name(&self) -> Option<String> {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => Some(format!("{} – {}", artist, title)),
+            (None, Some(title)) => Some(title.clone()),
+            _ => None,
+        }
+    }
+
+    /// Store `value` under the field a tag called `name` stands for. `name`
+    /// is a Vorbis comment field name; the ID3 and MP4 readers translate
+    /// theirs first. The first value of a field wins.
+    fn set(&mut self, name: &str, value: &str) {
+        let name = name.to_ascii_uppercase();
+        // Often written as "3/12", with the number of tracks, or as a whole
+        // date like "1971-03-08"
+        let digits: String = value.trim().chars().take_while(char::is_ascii_digit).collect();
+        let field = match name.as_str() {
+            "TRACKNUMBER" => {
+                self.track = self.track.or(digits.parse().ok());
+                return;
+            }
+            "DATE" | "YEAR" => {
+                self.year = self.year.or(digits.parse().ok().filter(|_| digits.len() == 4));
+                return;
+            }
+            // Gains are written like "-7.03 dB"
+            "REPLAYGAIN_TRACK_GAIN" | "REPLAYGAIN_ALBUM_GAIN" | "REPLAYGAIN_TRACK_PEAK" | "REPLAYGAIN_ALBUM_PEAK" => {
+                let number = value.split_whitespace().next().unwrap_or("");
+                let number = number.strip_suffix("dB").or_else(|| number.strip_suffix("db")).unwrap_or(number);
+                // Anything wilder than this is a broken tag
+                let number = number.parse::<f32>().ok().filter(|number| number.abs() < 100.0);
+                let field = match name.as_str() {
+                    "REPLAYGAIN_TRACK_GAIN" => &mut self.track_gain,
+                    "REPLAYGAIN_ALBUM_GAIN" => &mut self.album_gain,
+                    "REPLAYGAIN_TRACK_PEAK" => &mut self.track_peak,
+                    _ => &mut self.album_peak,
+                };
+                *field = field.or(number);
+                return;
+            }
+            "ARTIST" => &mut self.artist,
+            "TITLE" => &mut self.title,
+            "ALBUM" => &mut self.album,
+            "GENRE" => &mut self.genre,
+            _ => return,
+        };
+        if field.is_none() {
+            *field = sanitize(value);
+        }
+    }
+}
+
+/// Make a tag value safe to draw: control characters, which could move the
+/// cursor or change colors, become spaces, and long values are cut short.
+fn sanitize(value: &str) -> Option<String> {
+    let clean: String = value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(MAX_VALUE_CHARS)
+        .collect();
+    let clean = clean.trim();
+    (!clean.is_empty()).then(|| clean.to_string())
+}
+
+/// Tags read on first use, so only the files that are shown get opened.
+pub struct TagCache {
+    tags: Vec<OnceCell<Tags>>,
+    /// What to call each track when its tags don't say, as a playlist may.
+    names: Vec<Option<String>>,
+    /// The tags of each track from the index, used while the file's the
+    /// same as when they were read.
+    indexed: Vec<Option<Stamped>>,
+}
+
+impl TagCache {
+    /// A cache for a track for each of `names`, with the tags `indexed` for
+    /// those the index has.
+    pub fn new(names: Vec<Option<String>>, indexed: Vec<Option<Stamped>>) -> TagCache {
+        TagCache { tags: (0..names.len()).map(|_| OnceCell::new()).collect(), names, indexed }
+    }
+
+    /// The tags of track `index`, found at `path`. Files that can't be read
+    /// or have no tags just have none, apart from the title they were given.
+    pub fn get(&self, index: usize, path: &Path) -> &Tags {
+        self.tags[index].get_or_init(|| {
+            let indexed = self.indexed.get(index).and_then(Option::as_ref).filter(|indexed| index::stamp(path) == Some((indexed.size, indexed.modified)));
+            let mut tags = indexed.map_or_else(|| read(path).unwrap_or_default(), |indexed| indexed.tags.clone());
+            if tags.display_name().is_none() {
+                tags.title.clone_from(&self.names[index]);
+            }
+            tags
+        })
+    }
+
+    /// The tags of track `index` if they've been read, and are the file's
+    /// own rather than a title it was given, for the index to keep.
+    pub fn read_so_far(&self, index: usize) -> Option<&Tags> {
+        self.tags[index].get().filter(|_| self.names[index].is_none())
+    }
+
+    /// Make room for a track found after the cache was made, with the tags
+    /// `indexed` if the index has them.
+    pub fn push(&mut self, indexed: Option<Stamped>) {
+        self.tags.push(OnceCell::new());
+        self.names.push(None);
+        self.indexed.push(indexed);
+    }
+}
+
+/// Read the tags of the file at `path`, going by its extension.
+pub fn read(path: &Path) -> io::Result<Tags> {
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("").to_ascii_lowercase();
+    let mut file = fs::File::open(path)?;
+    let mut tags = Tags::default();
+    match extension.as_str() {
+        "flac" => {
+            read_id3v2(&mut file, &mut tags)?;
+            read_flac(&mut file, &mut tags)?;
+        }
+        "ogg" => read_ogg(&mut file, &mut tags)?,
+        "m4a" => read_mp4(&mut file, &mut tags)?,
+        "mp3" => read_id3v2(&mut file, &mut tags)?,
+        _ => {}
+    }
+    Ok(tags)
+}
+
+/// Read the fields of a Vorbis comment block, as used by FLAC and Ogg
+/// Vorbis: a vendor string, then `FIELD=value` strings, all prefixed by
+/// their little-endian length.
+fn parse_vorbis_comments(data: &[u8], tags: &mut Tags) {
+    let mut rest = data;
+    let Some(vendor_len) = take_le_u32(&mut rest) else { return };
+    if take(&mut rest, vendor_len as usize).is_none() {
+        return;
+    }
+    let Some(count) = take_le_u32(&mut rest) else { return };
+    for _ in 0..count {
+        let Some(comment) = take_le_u32(&mut rest).and_then(|len| take(&mut rest, len as usize)) else {
+            return;
+        };
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((name, value)) = comment.split_once('=') {
+            tags.set(name, value);
+        }
+    }
+}
+
+/// Split `len` bytes off the front of `rest`, if there are that many.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (taken, remaining) = rest.split_at(len);
+    *rest = remaining;
+    Some(taken)
+}
+
+fn take_le_u32(rest: &mut &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(take(rest, 4)?.try_into().ok()?))
+}
+
+/// Read the VORBIS_COMMENT block among a FLAC file's metadata blocks.
+fn read_flac<R: Read + Seek>(file: &mut R, tags: &mut Tags) -> io::Result<()> {
+    if let Some(block) = flac_blocks(file, FLAC_VORBIS_COMMENT, true)?.first() {
+        parse_vorbis_comments(block, tags);
+    }
+    Ok(())
+}
+
+pub const FLAC_VORBIS_COMMENT: u8 = 4;
+pub const FLAC_PICTURE: u8 = 6;
+
+/// Read the metadata blocks of type `kind` from a FLAC file, starting at its
+/// `fLaC` marker, skipping past the others without reading them. With
+/// `first_only`, stops at the first one found.
+pub fn flac_blocks<R: Read + Seek>(file: &mut R, kind: u8, first_only: bool) -> io::Result<Vec<Vec<u8>>> {
+    let mut blocks = Vec::new();
+    let mut marker = [0u8; 4];
+    if file.read_exact(&mut marker).is_err() || &marker != b"fLaC" {
+        return Ok(blocks);
+    }
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        if header[0] & 0x7F == kind {
+            let mut block = vec![0u8; len];
+            file.read_exact(&mut block)?;
+            blocks.push(block);
+            if first_only {
+                return Ok(blocks);
+            }
+        } else {
+            file.seek(SeekFrom::Current(len as i64))?;
+        }
+        if header[0] & 0x80 != 0 {
+            return Ok(blocks);
+        }
+    }
+}
+
+/// Rewrite the Vorbis comments of the FLAC file at `path`, replacing the
+/// fields named in `fields`, whatever their case, and keeping the rest. A
+/// field with an empty value is removed. The new file is written beside the old one and renamed over it, so stopping
+/// partway leaves the original as it was.
+pub fn write_flac_comments(path: &Path, fields: &[(&str, String)]) -> io::Result<()> {
+    let mut file = fs::File::open(path)?;
+    let mut id3 = vec![0u8; id3v2_len(&mut file)? as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut id3)?;
+    let mut marker = [0u8; 4];
+    file.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a FLAC file"));
+    }
+    let mut blocks: Vec<(u8, Vec<u8>)> = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let mut block = vec![0u8; u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize];
+        file.read_exact(&mut block)?;
+        blocks.push((header[0] & 0x7F, block));
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    let comment = blocks.iter().position(|(kind, _)| *kind == FLAC_VORBIS_COMMENT);
+    let body = edit_vorbis_comments(comment.map(|index| blocks[index].1.as_slice()), fields);
+    match comment {
+        Some(index) => blocks[index].1 = body,
+        // Right after STREAMINFO, which has to come first
+        None => blocks.insert(1.min(blocks.len()), (FLAC_VORBIS_COMMENT, body)),
+    }
+    if blocks.iter().any(|(_, block)| block.len() >= 1 << 24) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "metadata block too large"));
+    }
+
+    let name = path.file_name().map_or("flac".into(), |name| name.to_string_lossy());
+    let temp = path.with_file_name(format!(".{}.sdsupreme-tmp", name));
+    let written = write_flac(&temp, &id3, &blocks, &mut file).and_then(|()| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Write a FLAC file to `path` from the ID3 tag before it, its metadata
+/// blocks and the rest of `audio`, which is copied as it is.
+fn write_flac(path: &Path, id3: &[u8], blocks: &[(u8, Vec<u8>)], audio: &mut fs::File) -> io::Result<()> {
+    let mut out = io::BufWriter::new(fs::File::create(path)?);
+    out.write_all(id3)?;
+    out.write_all(b"fLaC")?;
+    for (index, (kind, block)) in blocks.iter().enumerate() {
+        let last = if index + 1 == blocks.len() { 0x80 } else { 0 };
+        let len = (block.len() as u32).to_be_bytes();
+        out.write_all(&[kind | last, len[1], len[2], len[3]])?;
+        out.write_all(block)?;
+    }
+    io::copy(audio, &mut out)?;
+    let out = out.into_inner().map_err(|e| e.into_error())?;
+    out.sync_all()?;
+    fs::set_permissions(path, audio.metadata()?.permissions())
+}
+
+/// A Vorbis comment block like `block`, or a new one, with `fields` in
+/// place of any of the same name.
+fn edit_vorbis_comments(block: Option<&[u8]>, fields: &[(&str, String)]) -> Vec<u8> {
+    let mut vendor: &[u8] = b"sdsupreme";
+    let mut comments: Vec<&[u8]> = Vec::new();
+    if let Some(mut rest) = block {
+        if let Some(found) = take_le_u32(&mut rest).and_then(|len| take(&mut rest, len as usize)) {
+            vendor = found;
+            let count = take_le_u32(&mut rest).unwrap_or(0);
+            for _ in 0..count {
+                let Some(comment) = take_le_u32(&mut rest).and_then(|len| take(&mut rest, len as usize)) else {
+                    break;
+                };
+                comments.push(comment);
+            }
+        }
+    }
+    let replaced = |comment: &&[u8]| {
+        let name = comment.split(|&b| b == b'=').next().unwrap_or_default();
+        fields.iter().any(|(field, _)| name.eq_ignore_ascii_case(field.as_bytes()))
+    };
+    comments.retain(|comment| !replaced(comment));
+    let added: Vec<Vec<u8>> = fields.iter().filter(|(_, value)| !value.is_empty()).map(|(name, value)| format!("{}={}", name, value).into_bytes()).collect();
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    body.extend_from_slice(vendor);
+    body.extend_from_slice(&((comments.len() + added.len()) as u32).to_le_bytes());
+    for comment in comments.into_iter().chain(added.iter().map(Vec::as_slice)) {
+        body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        body.extend_from_slice(comment);
+    }
+    body
+}
+
+/// Read the comment header, the second packet of an Ogg Vorbis stream. It
+/// usually starts on the second page and can run over several.
+fn read_ogg<R: Read + Seek>(file: &mut R, tags: &mut Tags) -> io::Result<()> {
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+    let mut serial = None;
+    while packets.len() < 3 {
+        let mut header = [0u8; 27];
+        if file.read_exact(&mut header).is_err() || &header[..4] != b"OggS" {
+            break;
+        }
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        let mut segments = vec![0u8; header[26] as usize];
+        file.read_exact(&mut segments)?;
+        let body_len: usize = segments.iter().map(|&s| s as usize).sum();
+        if *serial.get_or_insert(page_serial) != page_serial {
+            // A page of some other stream interleaved with ours
+            file.seek(SeekFrom::Current(body_len as i64))?;
+            continue;
+        }
+        let mut body = vec![0u8; body_len];
+        file.read_exact(&mut body)?;
+        let mut start = 0;
+        for &segment in &segments {
+            let end = start + segment as usize;
+            let packet = packets.last_mut().unwrap();
+            if packet.len() < MAX_TAG_READ {
+                packet.extend_from_slice(&body[start..end]);
+            }
+            start = end;
+            // A segment shorter than 255 bytes ends the packet
+            if segment < 255 {
+                packets.push(Vec::new());
+                if packets.len() == 3 {
+                    break;
+                }
+            }
+        }
+    }
+    let Some(comments) = packets.get(1) else { return Ok(()) };
+    if let Some(body) = comments.strip_prefix(b"\x03vorbis") {
+        parse_vorbis_comments(body, tags);
+    }
+    Ok(())
+}
+
+/// Read the text frames of a leading ID3v2 tag, versions 2.2 to 2.4.
+fn read_id3v2<R: Read + Seek>(file: &mut R, tags: &mut Tags) -> io::Result<()> {
+    id3v2_frames(file, MAX_TAG_READ, |id, body| {
+        let name = match id {
+            "TPE1" | "TP1" => "ARTIST",
+            "TIT2" | "TT2" => "TITLE",
+            "TALB" | "TAL" => "ALBUM",
+            "TRCK" | "TRK" => "TRACKNUMBER",
+            "TCON" | "TCO" => "GENRE",
+            // 2.4 replaced the year with a whole recording date
+            "TYER" | "TYE" | "TDRC" => "DATE",
+            // User-defined text, which is where ReplayGain goes: a
+            // description, then the value
+            "TXXX" | "TXX" => {
+                if let Some((description, value)) = id3_text(&body).as_deref().and_then(|text| text.split_once('/')) {
+                    if description.to_ascii_uppercase().starts_with("REPLAYGAIN_") {
+                        tags.set(description, value);
+                    }
+                }
+                return;
+            }
+            _ => return,
+        };
+        if let Some(text) = id3_text(&body) {
+            let text = if name == "GENRE" { id3_genre(&text) } else { text };
+            tags.set(name, &text);
+        }
+    })
+}
+
+/// Call `frame` with the ID and body of each frame of a leading ID3v2 tag,
+/// reading at most `max_read` bytes of it. Bodies have their unsynchronisation
+/// undone; compressed and encrypted frames are left out. The file is left at
+/// the end of the tag, or at the start when there isn't one.
+pub fn id3v2_frames<R: Read + Seek>(file: &mut R, max_read: usize, mut frame: impl FnMut(&str, Vec<u8>)) -> io::Result<()> {
+    let mut header = [0u8; 10];
+    file.seek(SeekFrom::Start(0))?;
+    if file.read_exact(&mut header).is_err() || &header[..3] != b"ID3" {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(());
+    }
+    let version = header[3];
+    let flags = header[5];
+    let size = syncsafe(&header[6..10]);
+    let end = 10 + size + if flags & 0x10 != 0 { 10 } else { 0 };
+
+    // Unsynchronisation of the whole tag changes every frame's bytes and
+    // offsets, so such tags are undone in memory first
+    if flags & 0x80 != 0 && version < 4 {
+        let mut tag = vec![0u8; size.min(max_read as u64) as usize];
+        file.read_exact(&mut tag)?;
+        read_id3v2_frames(&mut Cursor::new(resynchronise(&tag)), version, flags, &mut frame)?;
+    } else {
+        let mut tag = Vec::new();
+        // The text frames come before any cover art in practically every
+        // tagger's output, so a bounded read does for them
+        file.by_ref().take(size.min(max_read as u64)).read_to_end(&mut tag)?;
+        read_id3v2_frames(&mut Cursor::new(tag), version, flags, &mut frame)?;
+    }
+    // Leave the file at the end of the tag, for FLAC's marker
+    file.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+fn syncsafe(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |acc, &b| (acc << 7) | (b & 0x7F) as u64)
+}
+
+/// Undo ID3 unsynchronisation, which puts a zero byte after every 0xFF.
+fn resynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (index, &byte) in data.iter().enumerate() {
+        if byte == 0 && index > 0 && data[index - 1] == 0xFF {
+            continue;
+        }
+        out.push(byte);
+    }
+    out
+}
+
+fn read_id3v2_frames(tag: &mut Cursor<Vec<u8>>, version: u8, flags: u8, frame: &mut impl FnMut(&str, Vec<u8>)) -> io::Result<()> {
+    if flags & 0x40 != 0 && version >= 3 {
+        let mut size = [0u8; 4];
+        tag.read_exact(&mut size)?;
+        // The extended header's size counts itself in 2.4 but not in 2.3
+        let skip = match version {
+            3 => u32::from_be_bytes(size) as i64,
+            _ => syncsafe(&size) as i64 - 4,
+        };
+        tag.seek(SeekFrom::Current(skip))?;
+    }
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    loop {
+        let mut header = [0u8; 10];
+        if tag.read_exact(&mut header[..header_len]).is_err() || header[0] == 0 {
+            return Ok(());
+        }
+        let id = String::from_utf8_lossy(&header[..id_len]).into_owned();
+        let size = match version {
+            2 => u32::from_be_bytes([0, header[3], header[4], header[5]]) as u64,
+            3 => u32::from_be_bytes(header[4..8].try_into().unwrap()) as u64,
+            _ => syncsafe(&header[4..8]),
+        };
+        let frame_flags = u16::from_be_bytes([header[8], header[9]]);
+        let mut body = vec![0u8; size as usize];
+        if tag.read_exact(&mut body).is_err() {
+            return Ok(());
+        }
+        let (compressed_or_encrypted, unsynchronised, length_indicator) = match version {
+            2 => (false, false, false),
+            3 => (frame_flags & 0x00C0 != 0, false, false),
+            _ => (frame_flags & 0x000C != 0, frame_flags & 0x0002 != 0, frame_flags & 0x0001 != 0),
+        };
+        if compressed_or_encrypted {
+            continue;
+        }
+        if unsynchronised {
+            body = resynchronise(&body);
+        }
+        if length_indicator {
+            body.drain(..4.min(body.len()));
+        }
+        frame(&id, body);
+    }
+}
+
+/// Decode an ID3 text frame: an encoding byte, then the text. Version 2.4
+/// separates several values with zeros; they're shown joined by slashes.
+fn id3_text(body: &[u8]) -> Option<String> {
+    let (&encoding, text) = body.split_first()?;
+    let decoded = match encoding {
+        0 => text.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let (big_endian, text) = match text {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                _ => (encoding == 2, text),
+            };
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|pair| if big_endian { u16::from_be_bytes([pair[0], pair[1]]) } else { u16::from_le_bytes([pair[0], pair[1]]) })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    let values: Vec<&str> = decoded.split('\0').filter(|value| !value.is_empty()).collect();
+    (!values.is_empty()).then(|| values.join("/"))
+}
+
+/// Tidy up an ID3 genre. Taggers following 2.3 write ID3v1 genre numbers in
+/// brackets, like "(8)Jazz" or just "(8)", which are replaced by the name.
+fn id3_genre(text: &str) -> String {
+    let Some(rest) = text.strip_prefix('(') else {
+        return text.to_string();
+    };
+    let Some((number, after)) = rest.split_once(')') else {
+        return text.to_string();
+    };
+    match number.parse::<usize>().ok().and_then(|number| ID3V1_GENRES.get(number)) {
+        Some(_) if !after.is_empty() => after.to_string(),
+        Some(genre) => genre.to_string(),
+        None => text.to_string(),
+    }
+}
+
+// The first genres of ID3v1, by number; the later Winamp extensions are rare
+// enough in the bracketed form to leave as they are
+const ID3V1_GENRES: [&str; 80] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz", "Metal", "New Age", "Oldies",
+    "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno", "Industrial", "Alternative", "Ska", "Death Metal", "Pranks",
+    "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk", "Fusion", "Trance", "Classical",
+    "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise", "Alternative Rock", "Bass", "Soul", "Punk",
+    "Space", "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic", "Darkwave", "Techno-Industrial",
+    "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta", "Top 40",
+    "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret", "New Wave", "Psychedelic", "Rave", "Showtunes",
+    "Trailer", "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock",
+];
+
+/// Read the iTunes-style metadata in `moov/udta/meta/ilst` of an MP4 file.
+fn read_mp4<R: Read + Seek>(file: &mut R, tags: &mut Tags) -> io::Result<()> {
+    let len = file.seek(SeekFrom::End(0))?;
+    let Some((meta_start, meta_end)) = find_mp4_atom(file, 0, len, &[b"moov", b"udta", b"meta"])? else {
+        return Ok(());
+    };
+    // `meta` has a version and flags before its children
+    let Some((ilst_start, ilst_end)) = find_mp4_atom(file, meta_start + 4, meta_end, &[b"ilst"])? else {
+        return Ok(());
+    };
+    for (kind, start, end) in mp4_children(file, ilst_start, ilst_end)? {
+        let name = match &kind {
+            b"\xA9ART" => "ARTIST",
+            b"\xA9nam" => "TITLE",
+            b"\xA9alb" => "ALBUM",
+            b"trkn" => "TRACKNUMBER",
+            b"\xA9gen" => "GENRE",
+            b"\xA9day" => "DATE",
+            b"----" => {
+                read_mp4_freeform(file, start, end, tags)?;
+                continue;
+            }
+            _ => continue,
+        };
+        let Some((data_start, data_end)) = find_mp4_atom(file, start, end, &[b"data"])? else {
+            continue;
+        };
+        // A type indicator and locale come before the value
+        let value_len = data_end.saturating_sub(data_start + 8).min(MAX_TAG_READ as u64);
+        let mut value = vec![0u8; value_len as usize];
+        file.seek(SeekFrom::Start(data_start + 8))?;
+        file.read_exact(&mut value)?;
+        if name == "TRACKNUMBER" {
+            // Binary: two bytes of padding, the track number, the track count
+            if let Some(track) = value.get(2..4) {
+                tags.set(name, &u16::from_be_bytes([track[0], track[1]]).to_string());
+            }
+            continue;
+        }
+        tags.set(name, &String::from_utf8_lossy(&value));
+    }
+    Ok(())
+}
+
+/// Read a `----` atom, which holds a value under a name of its own, like
+/// the ReplayGain tags foobar2000 and others write.
+fn read_mp4_freeform<R: Read + Seek>(file: &mut R, start: u64, end: u64, tags: &mut Tags) -> io::Result<()> {
+    let mut name = None;
+    let mut value = None;
+    for (kind, child_start, child_end) in mp4_children(file, start, end)? {
+        // Both have a version and flags first, and data a locale too
+        let skip = match &kind {
+            b"name" => 4,
+            b"data" => 8,
+            _ => continue,
+        };
+        let len = child_end.saturating_sub(child_start + skip).min(MAX_VALUE_CHARS as u64);
+        let mut text = vec![0u8; len as usize];
+        file.seek(SeekFrom::Start(child_start + skip))?;
+        file.read_exact(&mut text)?;
+        let text = String::from_utf8_lossy(&text).into_owned();
+        if &kind == b"name" {
+            name = Some(text);
+        } else {
+            value = Some(text);
+        }
+    }
+    if let (Some(name), Some(value)) = (name, value) {
+        if name.to_ascii_uppercase().starts_with("REPLAYGAIN_") {
+            tags.set(&name, &value);
+        }
+    }
+    Ok(())
+}
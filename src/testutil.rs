@@ -0,0 +1,38 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// An empty directory of its own for `test` to put files in.
+pub fn scratch(test: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("sdsupreme-test-{}-{}", test, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write `samples` to `path` as a 16-bit WAV file of `channels` at
+/// `sample_rate`, with a tag chunk before them if `tagged`, so the file
+/// differs but the audio doesn't.
+pub fn write_wav(path: &Path, sample_rate: u32, channels: u16, samples: &[i16], tagged: bool) {
+    let tag: &[u8] = if tagged { b"LIST\x04\x00\x00\x00INFO" } else { b"" };
+    let data = samples.len() as u32 * 2;
+    let mut wav = Vec::new();
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + tag.len() as u32 + data).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * channels as u32 * 2).to_le_bytes());
+    wav.extend_from_slice(&(channels * 2).to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(tag);
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data.to_le_bytes());
+    for sample in samples {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    fs::write(path, wav).unwrap();
+}
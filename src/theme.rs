@@ -0,0 +1,70 @@
+use crossterm::style::Color;
+
+/// Colors the interface is drawn in. `Color::Reset` leaves a part in the
+/// terminal's own colors; the selection and status line then fall back to
+/// reverse video so they still stand out.
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub name: &'static str,
+    /// Background and text of the highlighted row in the list.
+    pub selection: Color,
+    pub selection_text: Color,
+    /// The played and unplayed parts of the progress bar.
+    pub bar_filled: Color,
+    pub bar_empty: Color,
+    /// The directories leading up to a file name.
+    pub directory: Color,
+    /// Background and text of the status line.
+    pub status: Color,
+    pub status_text: Color,
+    /// The PAUSED indicator on the status line.
+    pub paused: Color,
+}
+
+pub const DARK: Theme = Theme {
+    name: "dark",
+    selection: Color::DarkBlue,
+    selection_text: Color::White,
+    bar_filled: Color::Green,
+    bar_empty: Color::DarkGrey,
+    directory: Color::DarkGrey,
+    status: Color::DarkGrey,
+    status_text: Color::White,
+    paused: Color::Yellow,
+};
+
+pub const LIGHT: Theme = Theme {
+    name: "light",
+    selection: Color::Blue,
+    selection_text: Color::White,
+    bar_filled: Color::DarkGreen,
+    bar_empty: Color::Grey,
+    directory: Color::Grey,
+    status: Color::Grey,
+    status_text: Color::Black,
+    paused: Color::DarkRed,
+};
+
+pub const NO_COLOR: Theme = Theme {
+    name: "no-color",
+    selection: Color::Reset,
+    selection_text: Color::Reset,
+    bar_filled: Color::Reset,
+    bar_empty: Color::Reset,
+    directory: Color::Reset,
+    status: Color::Reset,
+    status_text: Color::Reset,
+    paused: Color::Reset,
+};
+
+const THEMES: &[Theme] = &[DARK, LIGHT, NO_COLOR];
+
+/// The built-in theme called `name`.
+pub fn by_name(name: &str) -> Option<Theme> {
+    THEMES.iter().find(|theme| theme.name == name).copied()
+}
+
+/// The names of the built-in themes, for error messages.
+pub fn names() -> String {
+    THEMES.iter().map(|theme| format!("'{}'", theme.name)).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,135 @@
+/// Cursor and scroll position of the interactive track list. The list shows
+/// `entries`, indices into the full track list, so a filtered view still
+/// knows which file each row stands for.
+pub struct TrackList {
+    entries: Vec<usize>,
+    /// Position of the highlighted row within `entries`.
+    pub cursor: usize,
+    /// Position within `entries` of the row shown at the top.
+    pub offset: usize,
+}
+
+impl TrackList {
+    pub fn new(entries: Vec<usize>) -> TrackList {
+        TrackList { entries, cursor: 0, offset: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Replace the rows shown, going back to the top.
+    pub fn set_entries(&mut self, entries: Vec<usize>) {
+        self.entries = entries;
+        self.cursor = 0;
+        self.offset = 0;
+    }
+
+    /// The track the cursor is on.
+    pub fn selected(&self) -> Option<usize> {
+        self.entries.get(self.cursor).copied()
+    }
+
+    /// Move the cursor by `delta` rows, stopping at either end, and scroll
+    /// so it stays within a viewport `height` rows tall.
+    pub fn move_by(&mut self, delta: isize, height: usize) {
+        let last = self.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(delta).min(last);
+        self.scroll_to_cursor(height);
+    }
+
+    pub fn select(&mut self, row: usize, height: usize) {
+        self.cursor = row.min(self.len().saturating_sub(1));
+        self.scroll_to_cursor(height);
+    }
+
+    /// Put the cursor on `track`, if it's one of the rows shown.
+    pub fn select_track(&mut self, track: usize, height: usize) {
+        if let Some(row) = self.entries.iter().position(|&entry| entry == track) {
+            self.select(row, height);
+        }
+    }
+
+    /// Move to the next row after the cursor whose name starts with
+    /// `initial`, ignoring case and wrapping around, so pressing the same
+    /// letter again cycles through the matches. `names` is indexed by track.
+    pub fn jump_to_initial(&mut self, initial: char, names: &[String], height: usize) {
+        let initial = initial.to_lowercase().collect::<String>();
+        let len = self.len();
+        let found = (1..=len)
+            .map(|step| (self.cursor + step) % len)
+            .find(|&row| names[self.entries[row]].to_lowercase().starts_with(&initial));
+        if let Some(row) = found {
+            self.select(row, height);
+        }
+    }
+
+    /// Jump `delta` whole pages, showing the new page from its first entry.
+    /// The cursor keeps its row within the page where the list allows.
+    pub fn turn_page(&mut self, delta: isize, height: usize) {
+        let height = height.max(1);
+        let row = self.cursor % height;
+        let pages = self.len().div_ceil(height).max(1);
+        let page = (self.cursor / height).saturating_add_signed(delta).min(pages - 1);
+        self.offset = page * height;
+        self.cursor = (self.offset + row).min(self.len().saturating_sub(1));
+    }
+
+    /// The page the cursor is on and the number of pages, both counting from 1.
+    pub fn page(&self, height: usize) -> (usize, usize) {
+        let height = height.max(1);
+        (self.cursor / height + 1, self.len().div_ceil(height).max(1))
+    }
+
+    /// Scroll the viewport by `delta` rows without moving the cursor,
+    /// stopping once the first or last entry is at the edge.
+    pub fn scroll_by(&mut self, delta: isize, height: usize) {
+        let max_offset = self.len().saturating_sub(height.max(1));
+        self.offset = self.offset.saturating_add_signed(delta).min(max_offset);
+    }
+
+    /// Scroll just far enough that the cursor is on screen.
+    pub fn scroll_to_cursor(&mut self, height: usize) {
+        let height = height.max(1);
+        if self.cursor < self.offset {
+            self.offset = self.cursor;
+        } else if self.cursor >= self.offset + height {
+            self.offset = self.cursor + 1 - height;
+        }
+    }
+
+    /// The rows that fit in a viewport `height` rows tall.
+    pub fn visible(&self, height: usize) -> &[usize] {
+        let start = self.offset.min(self.len());
+        &self.entries[start..start.saturating_add(height).min(self.len())]
+    }
+}
+
+/// How well `name` matches a search `query`, ignoring case: 0 for a
+/// substring match, 1 when the query's characters only appear in order with
+/// gaps between them, None when they don't appear at all.
+fn match_rank(query: &str, name: &str) -> Option<u8> {
+    let name = name.to_lowercase();
+    if name.contains(query) {
+        return Some(0);
+    }
+    let mut chars = name.chars();
+    query.chars().all(|wanted| chars.any(|c| c == wanted)).then_some(1)
+}
+
+/// Indices of the `names` matching `query`, substring matches first and
+/// otherwise in the same order as `order`.
+pub fn filter(names: &[String], order: &[usize], query: &str) -> Vec<usize> {
+    let query = query.to_lowercase();
+    let mut ranked: Vec<(u8, usize)> = order
+        .iter()
+        .filter_map(|&index| match_rank(&query, &names[index]).map(|rank| (rank, index)))
+        .collect();
+    // Stable, so tracks with the same rank keep their order
+    ranked.sort_by_key(|&(rank, _)| rank);
+    ranked.into_iter().map(|(_, index)| index).collect()
+}
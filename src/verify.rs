@@ -0,0 +1,64 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use crate::md5::Md5;
+use crate::probe;
+
+/// What checking a FLAC file's audio against its MD5 found.
+pub enum Outcome {
+    Ok,
+    /// The audio doesn't match, or couldn't be decoded to see, for this
+    /// reason.
+    Failed(String),
+    /// The encoder left the MD5 out, so there's nothing to check against.
+    Unverifiable,
+}
+
+/// Decode the whole of the FLAC file at `path` and check the audio against
+/// the MD5 of it in the STREAMINFO block, which is over every sample in turn,
+/// each channel's after the last, little-endian in as few bytes as hold it.
+pub fn verify(path: &Path) -> Outcome {
+    let mut reader = match open(path) {
+        Ok(reader) => reader,
+        Err(e) => return Outcome::Failed(format!("can't be read: {}", e)),
+    };
+    let info = reader.streaminfo();
+    if info.md5sum == [0; 16] {
+        return Outcome::Unverifiable;
+    }
+    let width = info.bits_per_sample.div_ceil(8) as usize;
+    let mut md5 = Md5::new();
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+    let mut bytes = Vec::new();
+    loop {
+        let block = match blocks.read_next_or_eof(buffer) {
+            Ok(Some(block)) => block,
+            Ok(None) => break,
+            Err(e) => return Outcome::Failed(format!("can't be decoded: {}", e)),
+        };
+        bytes.clear();
+        for sample in 0..block.duration() {
+            for channel in 0..block.channels() {
+                bytes.extend_from_slice(&block.sample(channel, sample).to_le_bytes()[..width]);
+            }
+        }
+        md5.update(&bytes);
+        buffer = block.into_buffer();
+    }
+    if md5.finish() == info.md5sum {
+        Outcome::Ok
+    } else {
+        Outcome::Failed("the audio doesn't match its MD5".to_string())
+    }
+}
+
+/// Open the FLAC file at `path` to decode, skipping any ID3 tag some taggers
+/// put in front of it.
+pub fn open(path: &Path) -> Result<claxon::FlacReader<File>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let start = probe::id3v2_len(&mut file)?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok(claxon::FlacReader::new(file)?)
+}
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use std::fs;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::exclude::Exclude;
+use crate::index::{self, Index};
+use crate::scan::list_music_files;
+
+// How often the directories are looked at for changes. Copying an album
+// changes them many times over, so a change is only acted on once they've
+// been left alone for as long again.
+const POLL: Duration = Duration::from_secs(2);
+
+// Looking at every directory on a big card takes a while itself, so a path
+// with more than this many is looked at that much less often.
+const DIRECTORIES_PER_POLL: usize = 500;
+
+/// What's changed in one of the paths being watched.
+pub enum Change {
+    /// Every music file in `roots[source]` now, as it was looked through
+    /// again after something in it changed, or when `asked` to.
+    Found { source: usize, files: Vec<PathBuf>, asked: bool },
+    /// `roots[source]` isn't there any more, or can't be read, as when a
+    /// card's taken out.
+    Gone { source: usize },
+}
+
+/// A thread keeping an eye on the directories looked through, going by when
+/// each last changed as the index has it, and looking through any path with
+/// a change again. Nothing is told about changes as they happen, which some
+/// file systems on cards can't do anyway, so it looks at one path every
+/// `POLL`, or less often on a path with many directories.
+pub struct Watcher {
+    pub changes: Receiver<Change>,
+    refresh: Sender<()>,
+    progress: Arc<Progress>,
+}
+
+/// How a scan the thread's doing is getting on.
+#[derive(Default)]
+struct Progress {
+    scanning: AtomicBool,
+    /// The music files found so far.
+    found: AtomicUsize,
+}
+
+/// What the thread knows about one path.
+struct Watched {
+    source: usize,
+    root: PathBuf,
+    /// Each directory in it and when it last changed.
+    directories: Vec<(PathBuf, u128)>,
+    /// When the directories changed to at the last look, while waiting for
+    /// them to be left alone.
+    changing: Option<Vec<Option<u128>>>,
+    gone: bool,
+}
+
+impl Watched {
+    /// How long to wait before looking at the path again.
+    fn wait(&self) -> Duration {
+        POLL * (1 + self.directories.len() / DIRECTORIES_PER_POLL) as u32
+    }
+
+    fn modified(&self) -> Vec<Option<u128>> {
+        self.directories
+            .iter()
+            .map(|(directory, _)| fs::metadata(directory).and_then(|metadata| metadata.modified()).ok().map(index::nanoseconds))
+            .collect()
+    }
+}
+
+impl Watcher {
+    /// Watch each of `roots` that has an index in `indexes`, looking through
+    /// them as `extensions`, `exclude` and `follow_links` say.
+    pub fn start(roots: &[PathBuf], indexes: Arc<Mutex<Vec<(usize, Index)>>>, extensions: HashSet<&'static str>, exclude: Exclude, follow_links: bool) -> Watcher {
+        let (changes_tx, changes) = mpsc::channel();
+        let (refresh, refreshes) = mpsc::channel();
+        let progress = Arc::new(Progress::default());
+        let shared = Arc::clone(&progress);
+        let mut watched: Vec<Watched> = indexes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(source, index)| {
+                let root = roots[*source].clone();
+                let directories = index.directories().map(|(relative, modified)| (root.join(relative), modified)).collect();
+                Watched { source: *source, root, directories, changing: None, gone: false }
+            })
+            .collect();
+        // One path is looked at each time round, in turn
+        let mut next = 0;
+        thread::spawn(move || loop {
+            // Asked to look again, everything's looked through right away
+            let asked = match refreshes.recv_timeout(watched.get(next).map_or(POLL, Watched::wait)) {
+                Ok(()) => true,
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let looking = if asked || watched.is_empty() {
+                0..watched.len()
+            } else {
+                next..next + 1
+            };
+            if !watched.is_empty() {
+                next = (next + 1) % watched.len();
+            }
+            for watched in &mut watched[looking] {
+                // A card pulled out without being unmounted is still there,
+                // but can't be read, which isn't the same as being empty
+                if fs::read_dir(&watched.root).is_err() {
+                    if !watched.gone {
+                        watched.gone = true;
+                        let _ = changes_tx.send(Change::Gone { source: watched.source });
+                    }
+                    continue;
+                }
+                // Come back, it may not even be the same card
+                if !asked && !watched.gone {
+                    let modified = watched.modified();
+                    if watched.directories.iter().zip(&modified).all(|((_, known), now)| Some(*known) == *now) {
+                        watched.changing = None;
+                        continue;
+                    }
+                    if watched.changing.as_ref() != Some(&modified) {
+                        watched.changing = Some(modified);
+                        continue;
+                    }
+                }
+                let started = SystemTime::now();
+                // Looked through with a copy, so the index isn't held while
+                // the tags and lengths of what's playing are kept in it
+                let Some(snapshot) = indexes.lock().unwrap().iter().find(|(source, _)| *source == watched.source).map(|(_, index)| index.snapshot()) else {
+                    continue;
+                };
+                shared.found.store(0, Ordering::SeqCst);
+                shared.scanning.store(true, Ordering::SeqCst);
+                let mut scanned = list_music_files(&watched.root, &extensions, &exclude, follow_links, Some(&snapshot), Some(&shared.found));
+                shared.scanning.store(false, Ordering::SeqCst);
+                // What couldn't be read isn't taken to have gone
+                if scanned.failing {
+                    if !watched.gone {
+                        watched.gone = true;
+                        let _ = changes_tx.send(Change::Gone { source: watched.source });
+                    }
+                    continue;
+                }
+                watched.directories = scanned.directories.iter().map(|(relative, directory)| (watched.root.join(relative), directory.modified)).collect();
+                let files: Vec<String> = scanned.files.iter().filter_map(|file| Some(file.strip_prefix(&watched.root).ok()?.to_str()?.to_string())).collect();
+                if let Some((_, index)) = indexes.lock().unwrap().iter_mut().find(|(source, _)| *source == watched.source) {
+                    index.set_directories(mem::take(&mut scanned.directories), started);
+                    let _ = index.save(&files);
+                }
+                watched.changing = None;
+                watched.gone = false;
+                // Following links, the same file can be reached more than one way
+                let mut seen = HashSet::new();
+                let files = scanned.files.into_iter().filter(|file| !follow_links || seen.insert(fs::canonicalize(file).unwrap_or_else(|_| file.clone()))).collect();
+                if changes_tx.send(Change::Found { source: watched.source, files, asked }).is_err() {
+                    return;
+                }
+            }
+        });
+        Watcher { changes, refresh, progress }
+    }
+
+    /// Look through every path again now, rather than waiting for a change
+    /// to be noticed, for file systems whose directories don't say when
+    /// they last changed.
+    pub fn refresh(&self) {
+        let _ = self.refresh.send(());
+    }
+
+    /// How many music files have been found so far, while a path's being
+    /// looked through again.
+    pub fn rescanning(&self) -> Option<usize> {
+        self.progress.scanning.load(Ordering::SeqCst).then(|| self.progress.found.load(Ordering::SeqCst))
+    }
+}